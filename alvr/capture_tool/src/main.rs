@@ -0,0 +1,71 @@
+mod replay;
+mod summary;
+
+use alvr_sockets::CaptureDirection;
+use pico_args::Arguments;
+use std::path::PathBuf;
+
+const HELP_STR: &str = r#"
+alvr_capture_tool
+Inspect stream socket captures, recorded with the "Shard capture" setting.
+
+USAGE:
+    alvr_capture_tool <SUBCOMMAND> [FLAGS] <CAPTURE_FILE>
+
+SUBCOMMANDS:
+    summary             Print per-stream statistics: packet loss, video frame sizes, tracking rate,
+                        audio gaps
+    replay              Reconstruct the packets from the shards, like the stream socket does, and
+                        print them
+
+FLAGS:
+    --help              Print this text
+    --sent              Use the shards sent by the capturing peer instead of the received ones
+"#;
+
+fn main() {
+    let mut args = Arguments::from_env();
+
+    if args.contains(["-h", "--help"]) {
+        println!("{HELP_STR}");
+        return;
+    }
+
+    let Ok(Some(subcommand)) = args.subcommand() else {
+        println!("\nMissing subcommand.");
+        println!("{HELP_STR}");
+        return;
+    };
+
+    let direction = if args.contains("--sent") {
+        CaptureDirection::Sent
+    } else {
+        CaptureDirection::Received
+    };
+
+    let Ok(path) = args.free_from_str::<PathBuf>() else {
+        println!("\nMissing capture file.");
+        println!("{HELP_STR}");
+        return;
+    };
+
+    if !args.finish().is_empty() {
+        println!("\nWrong arguments.");
+        println!("{HELP_STR}");
+        return;
+    }
+
+    let res = match subcommand.as_str() {
+        "summary" => summary::print_summary(&path, direction),
+        "replay" => replay::replay(&path, direction),
+        _ => {
+            println!("\nUnrecognized subcommand.");
+            println!("{HELP_STR}");
+            return;
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("Error: {e}");
+    }
+}
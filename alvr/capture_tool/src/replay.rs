@@ -0,0 +1,122 @@
+use alvr_common::{anyhow::Result, ConnectionError};
+use alvr_packets::{
+    ClientStatistics, Haptics, Tracking, VideoPacketHeader, AUDIO, HAPTICS, STATISTICS, TRACKING,
+    VIDEO,
+};
+use alvr_sockets::{CaptureDirection, ShardCaptureReader, StreamReceiver, StreamSocket};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{path::Path, time::Duration};
+
+const MAX_UNREAD_PACKETS: usize = 32;
+
+struct ReplayedStream<H> {
+    name: &'static str,
+    receiver: StreamReceiver<H>,
+    describe_header: fn(&H) -> String,
+    packets_count: usize,
+    packet_losses_count: usize,
+}
+
+impl<H: DeserializeOwned + Serialize> ReplayedStream<H> {
+    fn new(
+        socket: &mut StreamSocket,
+        stream_id: u16,
+        name: &'static str,
+        describe_header: fn(&H) -> String,
+    ) -> Self {
+        Self {
+            name,
+            receiver: socket.subscribe_to_stream(stream_id, MAX_UNREAD_PACKETS),
+            describe_header,
+            packets_count: 0,
+            packet_losses_count: 0,
+        }
+    }
+
+    fn print_packets(&mut self) {
+        while let Ok(data) = self.receiver.recv(Duration::ZERO) {
+            self.packets_count += 1;
+            if data.had_packet_loss() {
+                self.packet_losses_count += 1;
+                println!("{}: packet loss", self.name);
+            }
+
+            match data.get() {
+                Ok((header, payload)) => println!(
+                    "{}: {}, payload {}B, {} shards recovered",
+                    self.name,
+                    (self.describe_header)(&header),
+                    payload.len(),
+                    data.recovered_shards_count()
+                ),
+                Err(e) => println!("{}: invalid header: {e}", self.name),
+            }
+        }
+    }
+
+    fn print_totals(&self) {
+        println!(
+            "{}: {} packets, {} losses",
+            self.name, self.packets_count, self.packet_losses_count
+        );
+    }
+}
+
+pub fn replay(path: &Path, direction: CaptureDirection) -> Result<()> {
+    let mut socket = StreamSocket::replay_capture(ShardCaptureReader::open(path)?, direction);
+
+    let mut video =
+        ReplayedStream::new(&mut socket, VIDEO, "Video", |header: &VideoPacketHeader| {
+            format!(
+                "timestamp {:?}{}",
+                header.timestamp,
+                if header.is_idr { " (IDR)" } else { "" }
+            )
+        });
+    let mut audio =
+        ReplayedStream::new(&mut socket, AUDIO, "Audio", |_: &()| String::from("packet"));
+    let mut tracking =
+        ReplayedStream::new(&mut socket, TRACKING, "Tracking", |header: &Tracking| {
+            format!(
+                "target timestamp {:?}, {} devices",
+                header.target_timestamp,
+                header.device_motions.len()
+            )
+        });
+    let mut haptics = ReplayedStream::new(&mut socket, HAPTICS, "Haptics", |header: &Haptics| {
+        format!(
+            "device {}, duration {:?}",
+            header.device_id, header.duration
+        )
+    });
+    let mut statistics = ReplayedStream::new(
+        &mut socket,
+        STATISTICS,
+        "Statistics",
+        |header: &ClientStatistics| format!("target timestamp {:?}", header.target_timestamp),
+    );
+
+    loop {
+        let res = socket.recv();
+
+        video.print_packets();
+        audio.print_packets();
+        tracking.print_packets();
+        haptics.print_packets();
+        statistics.print_packets();
+
+        if let Err(ConnectionError::Other(e)) = res {
+            println!("{e}");
+            break;
+        }
+    }
+
+    println!();
+    video.print_totals();
+    audio.print_totals();
+    tracking.print_totals();
+    haptics.print_totals();
+    statistics.print_totals();
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use alvr_common::anyhow::Result;
+use alvr_packets::{VideoPacketHeader, AUDIO, HAPTICS, STATISTICS, TRACKING, VIDEO};
+use alvr_sockets::{CaptureDirection, ShardCaptureReader, WireFormat, SHARD_FLAG_RETRANSMIT};
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+const NACK_STREAM_ID: u16 = u16::MAX;
+
+// An audio packet arriving later than this multiple of the median interval is considered a gap
+const AUDIO_GAP_INTERVAL_MULTIPLIER: u32 = 3;
+
+struct PacketSummary {
+    first_shard_timestamp: Duration,
+    shards_count: usize,
+    data_shards_received: usize,
+    data_bytes: usize,
+    is_idr: Option<bool>,
+}
+
+#[derive(Default)]
+struct StreamSummary {
+    shards: usize,
+    parity_shards: usize,
+    retransmitted_shards: usize,
+    bytes: usize,
+    packets: BTreeMap<u32, PacketSummary>,
+}
+
+fn stream_name(stream_id: u16) -> String {
+    match stream_id {
+        TRACKING => "Tracking".into(),
+        HAPTICS => "Haptics".into(),
+        AUDIO => "Audio".into(),
+        VIDEO => "Video".into(),
+        STATISTICS => "Statistics".into(),
+        id => format!("Stream {id}"),
+    }
+}
+
+fn median(values: &mut [Duration]) -> Duration {
+    values.sort();
+
+    values.get(values.len() / 2).cloned().unwrap_or_default()
+}
+
+pub fn print_summary(path: &Path, direction: CaptureDirection) -> Result<()> {
+    let capture = ShardCaptureReader::open(path)?;
+
+    let wire_format = capture.wire_format();
+
+    println!("Wire format: v{}", wire_format.version());
+    println!("Max packet size: {}B", capture.max_packet_size());
+    for (stream_id, group_size) in capture.fec_group_sizes() {
+        println!(
+            "FEC: {}, 1 parity shard every {group_size} data shards",
+            stream_name(*stream_id)
+        );
+    }
+
+    let mut streams = BTreeMap::<u16, StreamSummary>::new();
+    let mut nacks_count = 0;
+
+    for shard in capture {
+        let shard = shard?;
+        if shard.direction != direction {
+            continue;
+        }
+
+        if shard.stream_id() == NACK_STREAM_ID {
+            nacks_count += 1;
+            continue;
+        }
+
+        let stream = streams.entry(shard.stream_id()).or_default();
+        stream.shards += 1;
+        stream.bytes += shard.shard.len();
+        if shard.flags() & SHARD_FLAG_RETRANSMIT != 0 {
+            stream.retransmitted_shards += 1;
+        }
+
+        let packet = stream
+            .packets
+            .entry(shard.packet_index())
+            .or_insert_with(|| PacketSummary {
+                first_shard_timestamp: shard.timestamp,
+                shards_count: shard.shards_count(),
+                data_shards_received: 0,
+                data_bytes: 0,
+                is_idr: None,
+            });
+
+        if shard.is_parity() {
+            stream.parity_shards += 1;
+        } else {
+            packet.data_shards_received += 1;
+            packet.data_bytes += shard.payload().len();
+
+            if shard.stream_id() == VIDEO && shard.shard_index() == 0 {
+                packet.is_idr = bincode::deserialize::<VideoPacketHeader>(shard.payload())
+                    .ok()
+                    .map(|header| header.is_idr);
+            }
+        }
+    }
+
+    println!("NACKs: {nacks_count}");
+
+    for (stream_id, stream) in &streams {
+        println!();
+        println!("{}:", stream_name(*stream_id));
+
+        let (Some(first), Some(last)) = (
+            stream.packets.values().next(),
+            stream.packets.values().next_back(),
+        ) else {
+            continue;
+        };
+        let duration = last
+            .first_shard_timestamp
+            .saturating_sub(first.first_shard_timestamp);
+        let duration_secs = f32::max(duration.as_secs_f32(), f32::EPSILON);
+
+        let first_index = *stream.packets.keys().next().unwrap();
+        let last_index = *stream.packets.keys().next_back().unwrap();
+        let missing_packets = (last_index.wrapping_sub(first_index) as usize + 1)
+            .saturating_sub(stream.packets.len());
+        let incomplete_packets = stream
+            .packets
+            .values()
+            .filter(|packet| packet.data_shards_received < packet.shards_count)
+            .count();
+
+        println!(
+            "    shards: {} ({} parity), {:.2} Mbps",
+            stream.shards,
+            stream.parity_shards,
+            stream.bytes as f32 * 8.0 / 1e6 / duration_secs
+        );
+        // Retransmitted shards are flagged only with the v2 wire format
+        if wire_format == WireFormat::V2 {
+            println!("    retransmitted shards: {}", stream.retransmitted_shards);
+        }
+        println!(
+            "    packets: {}, {:.1}/s, {missing_packets} missing, {incomplete_packets} incomplete",
+            stream.packets.len(),
+            stream.packets.len() as f32 / duration_secs,
+        );
+
+        match *stream_id {
+            VIDEO => {
+                let sizes = stream
+                    .packets
+                    .values()
+                    .map(|packet| packet.data_bytes)
+                    .collect::<Vec<_>>();
+                let idr_count = stream
+                    .packets
+                    .values()
+                    .filter(|packet| packet.is_idr == Some(true))
+                    .count();
+
+                println!(
+                    "    frame size: min {:.1} KB, avg {:.1} KB, max {:.1} KB",
+                    *sizes.iter().min().unwrap() as f32 / 1e3,
+                    sizes.iter().sum::<usize>() as f32 / sizes.len() as f32 / 1e3,
+                    *sizes.iter().max().unwrap() as f32 / 1e3,
+                );
+                println!("    IDR frames: {idr_count}");
+            }
+            AUDIO => {
+                let mut intervals = stream
+                    .packets
+                    .values()
+                    .zip(stream.packets.values().skip(1))
+                    .map(|(prev, next)| {
+                        next.first_shard_timestamp
+                            .saturating_sub(prev.first_shard_timestamp)
+                    })
+                    .collect::<Vec<_>>();
+                let max_interval = intervals.iter().max().cloned().unwrap_or_default();
+                let gap_threshold = median(&mut intervals) * AUDIO_GAP_INTERVAL_MULTIPLIER;
+                let gaps_count = intervals
+                    .iter()
+                    .filter(|interval| **interval > gap_threshold)
+                    .count();
+
+                println!(
+                    "    gaps: {gaps_count}, longest interval between packets: {:.1} ms",
+                    max_interval.as_secs_f32() * 1e3
+                );
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
@@ -284,23 +284,90 @@ pub extern "C" fn alvr_poll_nal(out_nal: *mut c_char, out_timestamp_ns: *mut u64
     }
 }
 
-// Returns the length of the message. message_buffer can be null.
-#[no_mangle]
-pub extern "C" fn alvr_hud_message(message_buffer: *mut c_char) -> u64 {
-    let cstring = CString::new(HUD_MESSAGE.lock().clone()).unwrap();
-    if !message_buffer.is_null() {
+fn copy_string_to_buffer(string: String, buffer: *mut c_char) -> u64 {
+    let cstring = CString::new(string).unwrap();
+    if !buffer.is_null() {
         unsafe {
-            ptr::copy_nonoverlapping(
-                cstring.as_ptr(),
-                message_buffer,
-                cstring.as_bytes_with_nul().len(),
-            );
+            ptr::copy_nonoverlapping(cstring.as_ptr(), buffer, cstring.as_bytes_with_nul().len());
         }
     }
 
     cstring.as_bytes_with_nul().len() as u64
 }
 
+// Saved streamer addresses, separated by new lines. Returns the length of the string.
+// addresses_buffer can be null.
+#[no_mangle]
+pub extern "C" fn alvr_saved_server_addresses(addresses_buffer: *mut c_char) -> u64 {
+    copy_string_to_buffer(crate::saved_server_addresses().join("\n"), addresses_buffer)
+}
+
+// Returns None if the string is null or not valid UTF-8
+unsafe fn string_from_ptr(string: *const c_char) -> Option<String> {
+    if string.is_null() {
+        None
+    } else {
+        CStr::from_ptr(string).to_str().ok().map(str::to_owned)
+    }
+}
+
+// address: "host:port", the port is optional. Returns false if the address is null or not valid
+// UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn alvr_add_server_address(address: *const c_char) -> bool {
+    if let Some(address) = string_from_ptr(address) {
+        crate::add_server_address(address);
+
+        true
+    } else {
+        false
+    }
+}
+
+// Returns false if the address is null or not valid UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn alvr_remove_server_address(address: *const c_char) -> bool {
+    if let Some(address) = string_from_ptr(address) {
+        crate::remove_server_address(&address);
+
+        true
+    } else {
+        false
+    }
+}
+
+// Returns the length of the address of the streamer the client connects to, or 0 if the client
+// waits to be discovered. address_buffer can be null.
+#[no_mangle]
+pub extern "C" fn alvr_active_server_address(address_buffer: *mut c_char) -> u64 {
+    if let Some(address) = crate::active_server_address() {
+        copy_string_to_buffer(address, address_buffer)
+    } else {
+        0
+    }
+}
+
+// Connect to the streamer at the given address, bypassing discovery. address can be null, to go
+// back to discovery. Returns false if the address is not valid UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn alvr_set_active_server_address(address: *const c_char) -> bool {
+    if address.is_null() {
+        crate::set_active_server_address(None);
+    } else if let Some(address) = string_from_ptr(address) {
+        crate::set_active_server_address(Some(address));
+    } else {
+        return false;
+    }
+
+    true
+}
+
+// Returns the length of the message. message_buffer can be null.
+#[no_mangle]
+pub extern "C" fn alvr_hud_message(message_buffer: *mut c_char) -> u64 {
+    copy_string_to_buffer(HUD_MESSAGE.lock().clone(), message_buffer)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn alvr_send_views_config(fov: *const AlvrFov, ipd_m: f32) {
     let fov = slice::from_raw_parts(fov, 2);
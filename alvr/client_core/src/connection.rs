@@ -4,29 +4,33 @@ use crate::{
     decoder::{self, DECODER_INIT_CONFIG},
     logging_backend::{LogMirrorData, LOG_CHANNEL_SENDER},
     platform,
-    sockets::AnnouncerSocket,
+    sockets::{self, AnnouncerSocket},
     statistics::StatisticsManager,
-    storage::Config,
+    storage::{self, Config, ConnectionMode},
     ClientCoreEvent, EVENT_QUEUE, IS_ALIVE, IS_RESUMED, IS_STREAMING, STATISTICS_MANAGER,
 };
 use alvr_audio::AudioDevice;
 use alvr_common::{
-    debug, error, glam::UVec2, info, warn, AnyhowToCon, ConResult, ConnectionError, LazyMutOpt,
-    ToCon, ALVR_VERSION,
+    con_bail, debug, error, glam::UVec2, info, warn, AnyhowToCon, ClockSynchronizer, ConResult,
+    ConnectionError, LazyMutOpt, RelaxedAtomic, ToCon, ALVR_VERSION,
 };
 use alvr_packets::{
-    ClientConnectionResult, ClientControlPacket, ClientStatistics, Haptics, ServerControlPacket,
-    StreamConfigPacket, Tracking, VideoPacketHeader, VideoStreamingCapabilities, AUDIO, HAPTICS,
-    STATISTICS, TRACKING, VIDEO,
+    BandwidthProbeBurstTiming, BandwidthProbeHeader, BandwidthProbeReport, Capabilities,
+    ClientConnectionResult, ClientControlPacket, ClientStatistics, ClockSyncReport, Haptics,
+    IdentityChallenge, IdentityProof, IdentityVerificationResult, NegotiatedConfig,
+    ServerControlPacket, SessionResumeResult, StreamConfigPacket, StreamFeature, Tracking,
+    VideoPacketHeader, VideoStreamingCapabilities, AUDIO, BANDWIDTH_PROBE, HAPTICS, STATISTICS,
+    TRACKING, VIDEO,
 };
-use alvr_session::{settings_schema::Switch, SessionConfig};
+use alvr_session::{settings_schema::Switch, DisconnectReason, ImpairmentDirection, SessionConfig};
 use alvr_sockets::{
-    ControlSocketSender, PeerType, ProtoControlSocket, StreamSender, StreamSocketBuilder,
-    KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT,
+    ClientIdentity, ControlSocketSender, PeerType, ProtoControlSocket, StreamKeyExchange,
+    StreamSender, StreamSocketBuilder, WireFormat, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT,
 };
 use serde_json as json;
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
+    net::IpAddr,
     sync::{mpsc, Arc},
     thread,
     time::{Duration, Instant},
@@ -42,6 +46,22 @@ const INITIAL_MESSAGE: &str = concat!(
     "Open ALVR on your PC then click \"Trust\"\n",
     "next to the client entry",
 );
+const PAIRING_MESSAGE: &str = concat!(
+    "Pairing required\n",
+    "Enter the following PIN on the PC\n",
+    "next to the client entry:",
+);
+const IDENTITY_REJECTED_MESSAGE: &str = concat!(
+    "The streamer rejected the identity\n",
+    "of this device. Remove the client\n",
+    "entry on the PC to pair again",
+);
+const PAIRING_FAILED_MESSAGE: &str = concat!(
+    "Pairing failed\n",
+    "The streamer sent an invalid\n",
+    "pairing secret",
+);
+const CONNECTING_MESSAGE: &str = "Connecting to the streamer at";
 const NETWORK_UNREACHABLE_MESSAGE: &str = "Cannot connect to the internet";
 // const INCOMPATIBLE_VERSIONS_MESSAGE: &str = concat!(
 //     "Streamer and client have\n",
@@ -54,14 +74,37 @@ const SERVER_RESTART_MESSAGE: &str = "The streamer is restarting\nPlease wait...
 const SERVER_DISCONNECTED_MESSAGE: &str = "The streamer has disconnected.";
 const CONNECTION_TIMEOUT_MESSAGE: &str = "Connection timeout.";
 
+fn disconnect_reason_message(reason: DisconnectReason) -> String {
+    let cause = match reason {
+        DisconnectReason::RestartRequired => return SERVER_RESTART_MESSAGE.into(),
+        DisconnectReason::Timeout => "Connection timeout",
+        DisconnectReason::ClientRequest => "Disconnected from the PC",
+        DisconnectReason::ServerShutdown => "The streamer is shutting down",
+        DisconnectReason::ProtocolMismatch => "Incompatible streamer version",
+        DisconnectReason::SocketError => "Network error",
+        DisconnectReason::Untrusted => "This device is not trusted",
+    };
+
+    format!("{SERVER_DISCONNECTED_MESSAGE}\n{cause}")
+}
+
 const DISCOVERY_RETRY_PAUSE: Duration = Duration::from_millis(500);
 const RETRY_CONNECT_MIN_INTERVAL: Duration = Duration::from_secs(1);
 const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 const HANDSHAKE_ACTION_TIMEOUT: Duration = Duration::from_secs(2);
+const ACTIVE_CONNECT_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
 const STREAMING_RECV_TIMEOUT: Duration = Duration::from_millis(500);
 
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 const MAX_UNREAD_PACKETS: usize = 10; // Applies per stream
 
+// Probe bursts arrive faster than they can be consumed
+const MAX_UNREAD_BANDWIDTH_PROBE_PACKETS: usize = 1024;
+
+// Set when the connection mode or the chosen streamer changes, to stop the current attempt
+pub static CONNECTION_MODE_CHANGED: RelaxedAtomic = RelaxedAtomic::new(false);
+
 static DISCONNECT_SERVER_NOTIFIER: LazyMutOpt<mpsc::Sender<()>> = alvr_common::lazy_mut_none();
 
 pub static CONTROL_SENDER: LazyMutOpt<ControlSocketSender<ClientControlPacket>> =
@@ -69,6 +112,19 @@ pub static CONTROL_SENDER: LazyMutOpt<ControlSocketSender<ClientControlPacket>>
 pub static TRACKING_SENDER: LazyMutOpt<StreamSender<Tracking>> = alvr_common::lazy_mut_none();
 pub static STATISTICS_SENDER: LazyMutOpt<StreamSender<ClientStatistics>> =
     alvr_common::lazy_mut_none();
+pub static CLOCK_SYNCHRONIZER: LazyMutOpt<ClockSynchronizer> = alvr_common::lazy_mut_none();
+// Set when the bandwidth probe is complete, taken by the next statistics packet
+pub static BANDWIDTH_PROBE_REPORT: LazyMutOpt<BandwidthProbeReport> = alvr_common::lazy_mut_none();
+
+struct ResumeToken {
+    server_ip: IpAddr,
+    token: [u8; 16],
+    grace_period: Duration,
+    // Set when the connection is lost
+    deadline: Option<Instant>,
+}
+
+static RESUME_TOKEN: LazyMutOpt<ResumeToken> = alvr_common::lazy_mut_none();
 
 fn set_hud_message(message: &str) {
     let message = format!(
@@ -110,36 +166,82 @@ fn connection_pipeline(
     recommended_view_resolution: UVec2,
     supported_refresh_rates: Vec<f32>,
 ) -> ConResult {
-    let (mut proto_control_socket, server_ip) = {
-        let config = Config::load();
-        let announcer_socket = AnnouncerSocket::new(&config.hostname).to_con()?;
-        let listener_socket =
-            alvr_sockets::get_server_listener(HANDSHAKE_ACTION_TIMEOUT).to_con()?;
-
-        loop {
-            if !IS_ALIVE.value() {
-                return Ok(());
-            }
+    let config = Config::load();
 
-            if let Err(e) = announcer_socket.broadcast() {
-                warn!("Broadcast error: {e:?}");
+    if CONNECTION_MODE_CHANGED.value() {
+        CONNECTION_MODE_CHANGED.set(false);
 
-                set_hud_message(NETWORK_UNREACHABLE_MESSAGE);
+        if config.connection_mode == ConnectionMode::Discovery {
+            set_hud_message(INITIAL_MESSAGE);
+        }
+    }
 
-                thread::sleep(RETRY_CONNECT_MIN_INTERVAL);
+    let (mut proto_control_socket, server_ip) = match &config.connection_mode {
+        ConnectionMode::Discovery => {
+            let mut announcer_socket = AnnouncerSocket::new(&config.hostname).to_con()?;
+            let listener_socket =
+                alvr_sockets::get_server_listener(HANDSHAKE_ACTION_TIMEOUT).to_con()?;
 
-                set_hud_message(INITIAL_MESSAGE);
+            loop {
+                if !IS_ALIVE.value() || CONNECTION_MODE_CHANGED.value() {
+                    return Ok(());
+                }
 
-                return Ok(());
-            }
+                if let Err(e) = announcer_socket.announce() {
+                    warn!("Announce error: {e:?}");
 
-            if let Ok(pair) = ProtoControlSocket::connect_to(
-                DISCOVERY_RETRY_PAUSE,
-                PeerType::Server(&listener_socket),
-            ) {
-                break pair;
+                    set_hud_message(NETWORK_UNREACHABLE_MESSAGE);
+
+                    thread::sleep(RETRY_CONNECT_MIN_INTERVAL);
+
+                    set_hud_message(INITIAL_MESSAGE);
+
+                    return Ok(());
+                }
+
+                if let Ok(pair) = ProtoControlSocket::connect_to(
+                    DISCOVERY_RETRY_PAUSE,
+                    PeerType::Server(&listener_socket),
+                ) {
+                    break pair;
+                }
             }
         }
+        ConnectionMode::ActiveConnect { server_address } => {
+            let addresses = match sockets::resolve_server_address(server_address) {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    warn!("Failed to resolve {server_address}: {e}");
+                    set_hud_message(&format!(
+                        "{CONNECTING_MESSAGE} {server_address}\nCannot resolve the address"
+                    ));
+
+                    return Ok(());
+                }
+            };
+
+            set_hud_message(&format!("{CONNECTING_MESSAGE} {server_address}..."));
+
+            let Some(mut pair) = addresses.into_iter().find_map(|address| {
+                ProtoControlSocket::connect_to(
+                    HANDSHAKE_ACTION_TIMEOUT,
+                    PeerType::ServerAddress(address),
+                )
+                .ok()
+            }) else {
+                set_hud_message(&format!(
+                    "{CONNECTING_MESSAGE} {server_address}\nThe streamer is not reachable"
+                ));
+
+                return Ok(());
+            };
+
+            pair.0
+                .send_announcement(&sockets::announcement_packet(&config.hostname))
+                .to_con()?;
+
+            pair
+        }
     };
 
     let (disconnect_sender, disconnect_receiver) = mpsc::channel();
@@ -153,23 +255,91 @@ fn connection_pipeline(
     }
     let _connection_drop_guard = DropGuard;
 
+    // The streamer polls actively connecting clients in between the discovery attempts, so the
+    // challenge can take longer to arrive
+    let challenge_timeout = match config.connection_mode {
+        ConnectionMode::Discovery => HANDSHAKE_ACTION_TIMEOUT,
+        ConnectionMode::ActiveConnect { .. } => ACTIVE_CONNECT_CHALLENGE_TIMEOUT,
+    };
+
+    let identity = ClientIdentity::from_secret_key(&config.identity_key);
+    let key_exchange = StreamKeyExchange::new();
+    let challenge = proto_control_socket.recv::<IdentityChallenge>(challenge_timeout)?;
+    proto_control_socket
+        .send(&IdentityProof {
+            public_key: identity.public_key(),
+            stream_public_key: key_exchange.public_key(),
+            signature: identity.sign_challenge(
+                &challenge.nonce,
+                &challenge.stream_public_key,
+                &key_exchange.public_key(),
+            ),
+        })
+        .to_con()?;
+    match proto_control_socket.recv(HANDSHAKE_ACTION_TIMEOUT)? {
+        IdentityVerificationResult::Verified => (),
+        IdentityVerificationResult::PairingRequired { pairing_secret } => {
+            // Otherwise the secret could have been chosen after receiving the public key
+            if alvr_sockets::pairing_commitment(&pairing_secret) != challenge.pairing_commitment {
+                set_hud_message(PAIRING_FAILED_MESSAGE);
+                con_bail!("The pairing secret of the streamer does not match its commitment");
+            }
+
+            let pin = alvr_sockets::pairing_pin(&identity.public_key(), &pairing_secret);
+            set_hud_message(&format!("{PAIRING_MESSAGE}\n{pin}"));
+
+            return Ok(());
+        }
+        IdentityVerificationResult::Rejected => {
+            set_hud_message(IDENTITY_REJECTED_MESSAGE);
+
+            return Ok(());
+        }
+    }
+
     let microphone_sample_rate = AudioDevice::new_input(None)
         .unwrap()
         .input_sample_rate()
         .unwrap();
 
-    proto_control_socket
-        .send(&ClientConnectionResult::ConnectionAccepted {
-            client_protocol_id: alvr_common::protocol_id(),
-            display_name: platform::device_model(),
-            server_ip,
-            streaming_capabilities: Some(VideoStreamingCapabilities {
-                default_view_resolution: recommended_view_resolution,
-                supported_refresh_rates,
-                microphone_sample_rate,
-            }),
-        })
-        .to_con()?;
+    // The session of a connection lost shortly before can be resumed without negotiating it again
+    let resume_token = RESUME_TOKEN.lock().take().filter(|token| {
+        token.server_ip == server_ip
+            && token
+                .deadline
+                .map(|deadline| Instant::now() < deadline)
+                .unwrap_or(false)
+    });
+    let is_resumed = if let Some(token) = resume_token {
+        proto_control_socket
+            .send(&ClientConnectionResult::ResumeSession { token: token.token })
+            .to_con()?;
+
+        matches!(
+            proto_control_socket.recv(HANDSHAKE_ACTION_TIMEOUT)?,
+            SessionResumeResult::Resumed
+        )
+    } else {
+        false
+    };
+
+    if is_resumed {
+        info!("Resuming the session");
+    } else {
+        proto_control_socket
+            .send(&ClientConnectionResult::ConnectionAccepted {
+                client_version: ALVR_VERSION.to_string(),
+                capabilities: Capabilities::current(),
+                display_name: platform::device_model(),
+                server_ip,
+                streaming_capabilities: Some(VideoStreamingCapabilities {
+                    default_view_resolution: recommended_view_resolution,
+                    supported_refresh_rates,
+                    microphone_sample_rate,
+                }),
+            })
+            .to_con()?;
+    }
     let config_packet =
         proto_control_socket.recv::<StreamConfigPacket>(HANDSHAKE_ACTION_TIMEOUT)?;
 
@@ -181,21 +351,41 @@ fn connection_pipeline(
         session_desc.to_settings()
     };
 
-    let negotiated_config =
-        json::from_str::<HashMap<String, json::Value>>(&config_packet.negotiated).to_con()?;
-
-    let view_resolution = negotiated_config
-        .get("view_resolution")
-        .and_then(|v| json::from_value(v.clone()).ok())
-        .unwrap_or(UVec2::ZERO);
-    let refresh_rate_hint = negotiated_config
-        .get("refresh_rate_hint")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(60.0) as f32;
-    let game_audio_sample_rate = negotiated_config
-        .get("game_audio_sample_rate")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(44100) as u32;
+    let NegotiatedConfig::V1 {
+        view_resolution,
+        refresh_rate_hint,
+        game_audio_sample_rate,
+        stream_public_key,
+        capabilities,
+        ..
+    } = config_packet.negotiated;
+    // Only the key signed with the identity challenge is accepted
+    let stream_keys = match stream_public_key {
+        Some(key) if key == challenge.stream_public_key => {
+            Some(key_exchange.client_stream_keys(key))
+        }
+        Some(_) => con_bail!("The stream key of the server does not match the signed one"),
+        None => None,
+    };
+
+    // Stream features not supported by the server are disabled
+    let forward_error_correction =
+        if capabilities.supports_feature(StreamFeature::ForwardErrorCorrection) {
+            settings.connection.forward_error_correction.clone()
+        } else {
+            vec![]
+        };
+    let shard_retransmission = if capabilities.supports_feature(StreamFeature::ShardRetransmission)
+    {
+        settings.connection.shard_retransmission.clone()
+    } else {
+        vec![]
+    };
+    let wire_format = if capabilities.supports_feature(StreamFeature::WireFormatV2) {
+        WireFormat::V2
+    } else {
+        WireFormat::V1
+    };
 
     let streaming_start_event = ClientCoreEvent::StreamingStarted {
         view_resolution,
@@ -257,8 +447,27 @@ fn connection_pipeline(
     let mut stream_socket = stream_socket_builder.accept_from_server(
         server_ip,
         settings.connection.stream_port,
+        wire_format,
         settings.connection.packet_size as _,
         HANDSHAKE_ACTION_TIMEOUT,
+        &forward_error_correction,
+        &shard_retransmission,
+        Duration::from_secs_f32(1.0 / refresh_rate_hint),
+        &settings.connection.stream_priorities,
+        stream_keys,
+        settings
+            .connection
+            .debug
+            .network_impairment
+            .clone()
+            .into_option()
+            .filter(|config| config.direction != ImpairmentDirection::StreamerToClient),
+        settings
+            .connection
+            .debug
+            .shard_capture
+            .then(storage::shard_capture_path)
+            .as_deref(),
     )?;
 
     info!("Connected to server");
@@ -278,6 +487,20 @@ fn connection_pipeline(
     let mut haptics_receiver =
         stream_socket.subscribe_to_stream::<Haptics>(HAPTICS, MAX_UNREAD_PACKETS);
     let statistics_sender = stream_socket.request_stream(STATISTICS);
+    let bandwidth_probe_receiver = settings
+        .connection
+        .bandwidth_probe
+        .as_option()
+        .filter(|_| capabilities.supports_feature(StreamFeature::BandwidthProbe))
+        .map(|config| {
+            (
+                stream_socket.subscribe_to_stream::<BandwidthProbeHeader>(
+                    BANDWIDTH_PROBE,
+                    MAX_UNREAD_BANDWIDTH_PROBE_PACKETS,
+                ),
+                Duration::from_millis(config.burst_interval_ms) + STREAMING_RECV_TIMEOUT,
+            )
+        });
 
     // Important: To make sure this is successfully unset when stopping streaming, the rest of the
     // function MUST be infallible
@@ -285,9 +508,18 @@ fn connection_pipeline(
     *CONTROL_SENDER.lock() = Some(control_sender);
     *TRACKING_SENDER.lock() = Some(tracking_sender);
     *STATISTICS_SENDER.lock() = Some(statistics_sender);
+    *BANDWIDTH_PROBE_REPORT.lock() = None;
+    *CLOCK_SYNCHRONIZER.lock() = Some(ClockSynchronizer::new());
+
+    // Older servers can't answer
+    let time_sync_supported = capabilities.supports_packet("ServerControlPacket::TimeSyncResponse");
 
     let (log_channel_sender, log_channel_receiver) = mpsc::channel();
-    if let Switch::Enabled(filter_level) = settings.logging.client_log_report_level {
+    // Older servers can't receive the logs
+    if let (Switch::Enabled(filter_level), true) = (
+        settings.logging.client_log_report_level,
+        capabilities.supports_packet("ClientControlPacket::Log"),
+    ) {
         *LOG_CHANNEL_SENDER.lock() = Some(LogMirrorData {
             sender: log_channel_sender,
             filter_level,
@@ -308,8 +540,22 @@ fn connection_pipeline(
                 return;
             };
 
+            // The send time is converted to the client clock, since the clock mapping is estimated
+            // from the client side
+            let arrival_time =
+                alvr_common::monotonic_time().saturating_sub(data.arrival_instant().elapsed());
+            let network_latency = crate::server_time_to_client_time(header.send_time)
+                .map(|send_time| arrival_time.saturating_sub(send_time));
+
             if let Some(stats) = &mut *STATISTICS_MANAGER.lock() {
-                stats.report_video_packet_received(header.timestamp);
+                stats.report_video_packet_received(header.timestamp, network_latency);
+
+                if data.recovered_shards_count() > 0 {
+                    stats.report_fec_recovered_packet();
+                }
+                if data.had_packet_loss() {
+                    stats.report_fec_unrecovered_packet();
+                }
             }
 
             if header.is_idr {
@@ -339,6 +585,64 @@ fn connection_pipeline(
         }
     });
 
+    let bandwidth_probe_thread = if let Some((mut receiver, end_timeout)) = bandwidth_probe_receiver
+    {
+        thread::spawn(move || {
+            // burst index -> (first arrival, last arrival, received packets)
+            let mut bursts = BTreeMap::<u32, (Instant, Instant, u32)>::new();
+            while IS_STREAMING.value() {
+                // Once the probe has started, a timeout means that the last packets were lost
+                let timeout = if bursts.is_empty() {
+                    STREAMING_RECV_TIMEOUT
+                } else {
+                    end_timeout
+                };
+                let data = match receiver.recv(timeout) {
+                    Ok(data) => data,
+                    Err(ConnectionError::TryAgain(_)) if bursts.is_empty() => continue,
+                    Err(ConnectionError::TryAgain(_)) => break,
+                    Err(ConnectionError::Other(_)) => return,
+                };
+                let Ok(header) = data.get_header() else {
+                    return;
+                };
+
+                // Taken by the socket, since the packets can wait in the receiver queue
+                let arrival = data.arrival_instant();
+                let (_, last_arrival, received_packets) = bursts
+                    .entry(header.burst_index)
+                    .or_insert((arrival, arrival, 0));
+                *last_arrival = arrival;
+                *received_packets += 1;
+
+                if header.burst_index + 1 >= header.bursts_count
+                    && header.packet_index + 1 >= header.packets_per_burst
+                {
+                    break;
+                }
+            }
+
+            if !bursts.is_empty() {
+                let bursts = bursts
+                    .into_iter()
+                    .map(
+                        |(burst_index, (first_arrival, last_arrival, received_packets))| {
+                            BandwidthProbeBurstTiming {
+                                burst_index,
+                                received_packets,
+                                arrival_span: last_arrival - first_arrival,
+                            }
+                        },
+                    )
+                    .collect();
+
+                *BANDWIDTH_PROBE_REPORT.lock() = Some(BandwidthProbeReport { bursts });
+            }
+        })
+    } else {
+        thread::spawn(|| ())
+    };
+
     let game_audio_thread = if let Switch::Enabled(config) = settings.audio.game_audio {
         let device = AudioDevice::new_output(None, None).to_con()?;
 
@@ -405,6 +709,7 @@ fn connection_pipeline(
 
     let control_send_thread = thread::spawn(move || {
         let mut keepalive_deadline = Instant::now();
+        let mut time_sync_deadline = Instant::now();
 
         #[cfg(target_os = "android")]
         let battery_manager = platform::android::BatteryManager::new();
@@ -432,6 +737,18 @@ fn connection_pipeline(
                 }
             }
 
+            if time_sync_supported && Instant::now() > time_sync_deadline {
+                if let Some(sender) = &mut *CONTROL_SENDER.lock() {
+                    sender
+                        .send(&ClientControlPacket::TimeSyncRequest {
+                            client_send_time: alvr_common::monotonic_time(),
+                        })
+                        .ok();
+
+                    time_sync_deadline = Instant::now() + TIME_SYNC_INTERVAL;
+                }
+            }
+
             #[cfg(target_os = "android")]
             if Instant::now() > battery_deadline {
                 let (gauge_value, is_plugged) = battery_manager.status();
@@ -463,9 +780,58 @@ fn connection_pipeline(
                 Ok(ServerControlPacket::InitializeDecoder(config)) => {
                     decoder::create_decoder(config);
                 }
+                Ok(ServerControlPacket::TimeSyncResponse(response)) => {
+                    let client_receive_time = alvr_common::monotonic_time();
+
+                    let report = CLOCK_SYNCHRONIZER.lock().as_mut().and_then(|sync| {
+                        let mapping = sync.report_exchange(
+                            response.client_send_time,
+                            response.server_receive_time,
+                            response.server_send_time,
+                            client_receive_time,
+                        );
+                        let (client_to_server_latency, server_to_client_latency) =
+                            sync.one_way_latencies()?;
+
+                        Some(ClockSyncReport {
+                            mapping,
+                            round_trip: sync.round_trip()?,
+                            client_to_server_latency,
+                            server_to_client_latency,
+                        })
+                    });
+
+                    if let (Some(report), Some(sender)) = (report, &mut *CONTROL_SENDER.lock()) {
+                        sender.send(&ClientControlPacket::ClockSync(report)).ok();
+                    }
+                }
+                Ok(ServerControlPacket::SessionResumeToken {
+                    token,
+                    grace_period,
+                }) => {
+                    *RESUME_TOKEN.lock() = Some(ResumeToken {
+                        server_ip,
+                        token,
+                        grace_period,
+                        deadline: None,
+                    });
+                }
+                Ok(ServerControlPacket::Disconnecting(reason)) => {
+                    info!("Server disconnected. Reason: {reason:?}");
+                    set_hud_message(&disconnect_reason_message(reason));
+                    // The streamer closed the session, it cannot be resumed
+                    *RESUME_TOKEN.lock() = None;
+                    if let Some(notifier) = &*DISCONNECT_SERVER_NOTIFIER.lock() {
+                        notifier.send(()).ok();
+                    }
+
+                    return;
+                }
                 Ok(ServerControlPacket::Restarting) => {
                     info!("{SERVER_RESTART_MESSAGE}");
                     set_hud_message(SERVER_RESTART_MESSAGE);
+                    // The session does not survive the restart
+                    *RESUME_TOKEN.lock() = None;
                     if let Some(notifier) = &*DISCONNECT_SERVER_NOTIFIER.lock() {
                         notifier.send(()).ok();
                     }
@@ -523,11 +889,17 @@ fn connection_pipeline(
     // Block here
     disconnect_receiver.recv().ok();
 
+    if let Some(token) = &mut *RESUME_TOKEN.lock() {
+        token.deadline = Some(Instant::now() + token.grace_period);
+    }
+
     IS_STREAMING.set(false);
     *CONTROL_SENDER.lock() = None;
     *LOG_CHANNEL_SENDER.lock() = None;
     *TRACKING_SENDER.lock() = None;
     *STATISTICS_SENDER.lock() = None;
+    *BANDWIDTH_PROBE_REPORT.lock() = None;
+    *CLOCK_SYNCHRONIZER.lock() = None;
 
     EVENT_QUEUE
         .lock()
@@ -540,6 +912,7 @@ fn connection_pipeline(
     }
 
     video_receive_thread.join().ok();
+    bandwidth_probe_thread.join().ok();
     game_audio_thread.join().ok();
     microphone_thread.join().ok();
     haptics_receive_thread.join().ok();
@@ -33,7 +33,10 @@ use alvr_common::{
 };
 use alvr_packets::{BatteryPacket, ButtonEntry, ClientControlPacket, Tracking, ViewsConfig};
 use alvr_session::{CodecType, Settings};
-use connection::{CONTROL_SENDER, STATISTICS_SENDER, TRACKING_SENDER};
+use connection::{
+    BANDWIDTH_PROBE_REPORT, CLOCK_SYNCHRONIZER, CONNECTION_MODE_CHANGED, CONTROL_SENDER,
+    STATISTICS_SENDER, TRACKING_SENDER,
+};
 use decoder::EXTERNAL_DECODER;
 use serde::{Deserialize, Serialize};
 use statistics::StatisticsManager;
@@ -43,7 +46,7 @@ use std::{
     thread::{self, JoinHandle},
     time::Duration,
 };
-use storage::Config;
+use storage::{Config, ConnectionMode};
 
 static STATISTICS_MANAGER: LazyMutOpt<StatisticsManager> = alvr_common::lazy_mut_none();
 
@@ -96,16 +99,20 @@ pub fn initialize(
 ) {
     logging_backend::init_logging();
 
-    // Make sure to reset config in case of version compat mismatch.
-    if Config::load().protocol_id != alvr_common::protocol_id() {
-        // NB: Config::default() sets the current protocol ID
-        Config::default().store();
+    // Fields added in newer versions are filled with their defaults when loading. The identity key
+    // and the saved streamers are kept, so that the client stays paired after an update
+    let mut config = Config::load();
+    if config.protocol_id != alvr_common::protocol_id() {
+        config.protocol_id = alvr_common::protocol_id();
+        config.store();
     }
 
     #[cfg(target_os = "android")]
     platform::try_get_permission(platform::MICROPHONE_PERMISSION);
     #[cfg(target_os = "android")]
     platform::acquire_wifi_lock();
+    #[cfg(target_os = "android")]
+    platform::acquire_multicast_lock();
 
     IS_ALIVE.set(true);
     EXTERNAL_DECODER.set(external_decoder);
@@ -122,6 +129,8 @@ pub fn destroy() {
         thread.join().ok();
     }
 
+    #[cfg(target_os = "android")]
+    platform::release_multicast_lock();
     #[cfg(target_os = "android")]
     platform::release_wifi_lock();
 }
@@ -134,6 +143,54 @@ pub fn pause() {
     IS_RESUMED.set(false);
 }
 
+/// Streamer addresses saved by the user, in the "host:port" format (the port is optional)
+pub fn saved_server_addresses() -> Vec<String> {
+    Config::load().server_addresses
+}
+
+pub fn add_server_address(address: String) {
+    let mut config = Config::load();
+    if !config.server_addresses.contains(&address) {
+        config.server_addresses.push(address);
+        config.store();
+    }
+}
+
+pub fn remove_server_address(address: &str) {
+    let mut config = Config::load();
+    config.server_addresses.retain(|a| a != address);
+    config.store();
+}
+
+/// Returns the address of the streamer the client connects to, or None if it waits for a streamer
+/// to find it with discovery
+pub fn active_server_address() -> Option<String> {
+    match Config::load().connection_mode {
+        ConnectionMode::Discovery => None,
+        ConnectionMode::ActiveConnect { server_address } => Some(server_address),
+    }
+}
+
+/// Connect to the streamer at the given address, bypassing discovery. The address is saved. Use
+/// None to go back to discovery. Does not interrupt an ongoing stream.
+pub fn set_active_server_address(address: Option<String>) {
+    let mut config = Config::load();
+    config.connection_mode = if let Some(address) = address {
+        if !config.server_addresses.contains(&address) {
+            config.server_addresses.push(address.clone());
+        }
+
+        ConnectionMode::ActiveConnect {
+            server_address: address,
+        }
+    } else {
+        ConnectionMode::Discovery
+    };
+    config.store();
+
+    CONNECTION_MODE_CHANGED.set(true);
+}
+
 pub fn poll_event() -> Option<ClientCoreEvent> {
     EVENT_QUEUE.lock().pop_front()
 }
@@ -216,7 +273,20 @@ pub fn report_submit(target_timestamp: Duration, vsync_queue: Duration) {
 
         if let Some(sender) = &mut *STATISTICS_SENDER.lock() {
             if let Some(stats) = stats.summary(target_timestamp) {
-                sender.send_header(&stats).ok();
+                // The bandwidth probe report is sent only once, as payload of the statistics
+                let probe_report = BANDWIDTH_PROBE_REPORT.lock().take();
+                if let Some(report_bytes) =
+                    probe_report.and_then(|report| bincode::serialize(&report).ok())
+                {
+                    if let Ok(mut buffer) = sender.get_buffer(&stats) {
+                        buffer
+                            .get_range_mut(0, report_bytes.len())
+                            .copy_from_slice(&report_bytes);
+                        sender.send(buffer).ok();
+                    }
+                } else {
+                    sender.send_header(&stats).ok();
+                }
             } else {
                 error!("Statistics summary not ready!");
             }
@@ -224,6 +294,20 @@ pub fn report_submit(target_timestamp: Duration, vsync_queue: Duration) {
     }
 }
 
+/// Convert a time of the streamer clock to the clock of the client (alvr_common::monotonic_time()).
+/// Returns None if the clocks are not synchronized yet
+pub fn server_time_to_client_time(server_time: Duration) -> Option<Duration> {
+    let mapping = CLOCK_SYNCHRONIZER.lock().as_ref()?.mapping()?;
+
+    Some(mapping.server_to_client_time(server_time))
+}
+
+pub fn client_time_to_server_time(client_time: Duration) -> Option<Duration> {
+    let mapping = CLOCK_SYNCHRONIZER.lock().as_ref()?.mapping()?;
+
+    Some(mapping.client_to_server_time(client_time))
+}
+
 /// Call only with external decoder
 pub fn request_idr() {
     if let Some(sender) = &mut *CONTROL_SENDER.lock() {
@@ -13,6 +13,7 @@ use std::net::{IpAddr, Ipv4Addr};
 pub const MICROPHONE_PERMISSION: &str = "android.permission.RECORD_AUDIO";
 
 static WIFI_LOCK: LazyMutOpt<GlobalRef> = alvr_common::lazy_mut_none();
+static MULTICAST_LOCK: LazyMutOpt<GlobalRef> = alvr_common::lazy_mut_none();
 
 pub fn vm() -> JavaVM {
     unsafe { JavaVM::from_raw(ndk_context::android_context().vm().cast()).unwrap() }
@@ -183,6 +184,43 @@ pub fn release_wifi_lock() {
     }
 }
 
+// Android filters out multicast packets by default. This is needed to receive mDNS responses.
+pub fn acquire_multicast_lock() {
+    let mut maybe_multicast_lock = MULTICAST_LOCK.lock();
+
+    if maybe_multicast_lock.is_none() {
+        let vm = vm();
+        let mut env = vm.attach_current_thread().unwrap();
+
+        let wifi_manager = get_system_service(&mut env, "wifi");
+        let multicast_lock_jstring = env.new_string("alvr_multicast_lock").unwrap();
+        let multicast_lock = env
+            .call_method(
+                wifi_manager,
+                "createMulticastLock",
+                "(Ljava/lang/String;)Landroid/net/wifi/WifiManager$MulticastLock;",
+                &[(&multicast_lock_jstring).into()],
+            )
+            .unwrap()
+            .l()
+            .unwrap();
+        env.call_method(&multicast_lock, "acquire", "()V", &[])
+            .unwrap();
+
+        *maybe_multicast_lock = Some(env.new_global_ref(multicast_lock).unwrap());
+    }
+}
+
+pub fn release_multicast_lock() {
+    if let Some(multicast_lock) = MULTICAST_LOCK.lock().take() {
+        let vm = vm();
+        let mut env = vm.attach_current_thread().unwrap();
+
+        env.call_method(multicast_lock.as_obj(), "release", "()V", &[])
+            .unwrap();
+    }
+}
+
 pub struct BatteryManager {
     intent: GlobalRef,
 }
@@ -1,10 +1,53 @@
-use alvr_common::{anyhow::Result, ALVR_NAME};
-use alvr_sockets::{CONTROL_PORT, LOCAL_IP};
-use std::net::{Ipv4Addr, UdpSocket};
+use alvr_common::{anyhow::Result, info, warn, ALVR_NAME};
+use alvr_packets::HANDSHAKE_VERSION;
+use alvr_sockets::{
+    CONTROL_PORT, DISCOVERY_MULTICAST_IPV6, HANDSHAKE_PACKET_SIZE_BYTES, LOCAL_IP,
+    MDNS_HANDSHAKE_VERSION_KEY, MDNS_HOSTNAME_KEY, MDNS_SERVICE_TYPE,
+};
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
 
+pub fn announcement_packet(hostname: &str) -> [u8; HANDSHAKE_PACKET_SIZE_BYTES] {
+    let mut packet = [0; HANDSHAKE_PACKET_SIZE_BYTES];
+    packet[0..ALVR_NAME.len()].copy_from_slice(ALVR_NAME.as_bytes());
+    packet[16..24].copy_from_slice(&HANDSHAKE_VERSION.to_le_bytes());
+    packet[24..24 + hostname.len()].copy_from_slice(hostname.as_bytes());
+
+    packet
+}
+
+// Accepts "host", "host:port", "ip", "ip:port" and "[ipv6]:port". Link-local IPv6 addresses can
+// specify the interface index, like "fe80::1%3". The port defaults to the control port
+pub fn resolve_server_address(address: &str) -> Result<Vec<SocketAddr>> {
+    let address = address.trim();
+
+    if let Some(address) = alvr_sockets::parse_peer_address(address, CONTROL_PORT) {
+        return Ok(vec![address]);
+    }
+
+    let addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses.collect(),
+        // No port specified
+        Err(_) => (address, CONTROL_PORT).to_socket_addrs()?.collect(),
+    };
+
+    Ok(addresses)
+}
+
+// Streamers found with mDNS receive the announcement directly. If none is found, the announcement
+// is broadcast (IPv4) and multicast (IPv6) to the local network.
 pub struct AnnouncerSocket {
     socket: UdpSocket,
-    packet: [u8; 56],
+    // None if IPv6 is not available
+    socket_ipv6: Option<UdpSocket>,
+    packet: [u8; HANDSHAKE_PACKET_SIZE_BYTES],
+    // None if mDNS is not available
+    mdns: Option<(ServiceDaemon, Receiver<ServiceEvent>)>,
+    // Service full name -> streamer addresses
+    mdns_servers: HashMap<String, Vec<SocketAddr>>,
 }
 
 impl AnnouncerSocket {
@@ -12,18 +55,114 @@ impl AnnouncerSocket {
         let socket = UdpSocket::bind((LOCAL_IP, CONTROL_PORT))?;
         socket.set_broadcast(true)?;
 
-        let mut packet = [0; 56];
-        packet[0..ALVR_NAME.len()].copy_from_slice(ALVR_NAME.as_bytes());
-        packet[16..24].copy_from_slice(&alvr_common::protocol_id().to_le_bytes());
-        packet[24..24 + hostname.len()].copy_from_slice(hostname.as_bytes());
+        let socket_ipv6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok();
+
+        let mdns = ServiceDaemon::new().and_then(|daemon| {
+            let receiver = daemon.browse(MDNS_SERVICE_TYPE)?;
 
-        Ok(Self { socket, packet })
+            Ok((daemon, receiver))
+        });
+        let mdns = match mdns {
+            Ok(mdns) => Some(mdns),
+            Err(e) => {
+                warn!("mDNS not available: {e}");
+                None
+            }
+        };
+
+        Ok(Self {
+            socket,
+            socket_ipv6,
+            packet: announcement_packet(hostname),
+            mdns,
+            mdns_servers: HashMap::new(),
+        })
     }
 
-    pub fn broadcast(&self) -> Result<()> {
-        self.socket
-            .send_to(&self.packet, (Ipv4Addr::BROADCAST, CONTROL_PORT))?;
+    fn update_mdns_servers(&mut self) {
+        let Some((_, receiver)) = &self.mdns else {
+            return;
+        };
+
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    let hostname = info
+                        .get_property_val_str(MDNS_HOSTNAME_KEY)
+                        .unwrap_or_default();
+                    let handshake_version = info
+                        .get_property_val_str(MDNS_HANDSHAKE_VERSION_KEY)
+                        .and_then(|version| version.parse::<u64>().ok());
+
+                    if handshake_version == Some(HANDSHAKE_VERSION) {
+                        info!("Found streamer {hostname} with mDNS");
+
+                        let addresses = info
+                            .get_addresses()
+                            .iter()
+                            .map(|ip| SocketAddr::new(IpAddr::from(*ip), info.get_port()))
+                            .collect();
+                        self.mdns_servers
+                            .insert(info.get_fullname().to_owned(), addresses);
+                    } else {
+                        warn!("Found incompatible streamer {hostname} with mDNS");
+                    }
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    self.mdns_servers.remove(&fullname);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Fails only if the announcement could not be sent at all
+    pub fn announce(&mut self) -> Result<()> {
+        self.update_mdns_servers();
+
+        if !self.mdns_servers.is_empty() {
+            let mut announced = false;
+            for address in self.mdns_servers.values().flatten() {
+                let socket = match (address, &self.socket_ipv6) {
+                    (SocketAddr::V6(_), Some(socket_ipv6)) => socket_ipv6,
+                    _ => &self.socket,
+                };
+                announced |= socket.send_to(&self.packet, address).is_ok();
+            }
+
+            if announced {
+                return Ok(());
+            }
+
+            warn!("Failed to announce to the streamers found with mDNS, falling back to broadcast");
+        }
+
+        let res = self
+            .socket
+            .send_to(&self.packet, (Ipv4Addr::BROADCAST, CONTROL_PORT));
+
+        if let Some(socket) = &self.socket_ipv6 {
+            let sent_ipv6 = alvr_sockets::send_multicast_ipv6(
+                socket,
+                &self.packet,
+                DISCOVERY_MULTICAST_IPV6,
+                CONTROL_PORT,
+            );
+            if res.is_err() && sent_ipv6 {
+                return Ok(());
+            }
+        }
+
+        res?;
 
         Ok(())
     }
 }
+
+impl Drop for AnnouncerSocket {
+    fn drop(&mut self) {
+        if let Some((daemon, _)) = &self.mdns {
+            daemon.shutdown().ok();
+        }
+    }
+}
@@ -2,6 +2,7 @@ use alvr_common::SlidingWindowAverage;
 use alvr_packets::ClientStatistics;
 use std::{
     collections::VecDeque,
+    mem,
     time::{Duration, Instant},
 };
 
@@ -17,6 +18,8 @@ pub struct StatisticsManager {
     prev_vsync: Instant,
     total_pipeline_latency_average: SlidingWindowAverage<Duration>,
     steamvr_pipeline_latency: Duration,
+    fec_recovered_packets_partial_sum: u32,
+    fec_unrecovered_packets_partial_sum: u32,
 }
 
 impl StatisticsManager {
@@ -36,6 +39,8 @@ impl StatisticsManager {
             steamvr_pipeline_latency: Duration::from_secs_f32(
                 steamvr_pipeline_frames * nominal_server_frame_interval.as_secs_f32(),
             ),
+            fec_recovered_packets_partial_sum: 0,
+            fec_unrecovered_packets_partial_sum: 0,
         }
     }
 
@@ -61,16 +66,31 @@ impl StatisticsManager {
         }
     }
 
-    pub fn report_video_packet_received(&mut self, target_timestamp: Duration) {
+    // network_latency is None if the clocks are not synchronized yet
+    pub fn report_video_packet_received(
+        &mut self,
+        target_timestamp: Duration,
+        network_latency: Option<Duration>,
+    ) {
         if let Some(frame) = self
             .history_buffer
             .iter_mut()
             .find(|frame| frame.client_stats.target_timestamp == target_timestamp)
         {
             frame.video_packet_received = Instant::now();
+            frame.client_stats.network_latency = network_latency;
         }
     }
 
+    pub fn report_fec_recovered_packet(&mut self) {
+        self.fec_recovered_packets_partial_sum += 1;
+    }
+
+    // Packets that have been lost and that could not be rebuilt using parity shards
+    pub fn report_fec_unrecovered_packet(&mut self) {
+        self.fec_unrecovered_packets_partial_sum += 1;
+    }
+
     pub fn report_frame_decoded(&mut self, target_timestamp: Duration) {
         if let Some(frame) = self
             .history_buffer
@@ -118,6 +138,11 @@ impl StatisticsManager {
             let vsync = now + vsync_queue;
             frame.client_stats.frame_interval = vsync.saturating_duration_since(self.prev_vsync);
             self.prev_vsync = vsync;
+
+            frame.client_stats.fec_recovered_packets =
+                mem::take(&mut self.fec_recovered_packets_partial_sum);
+            frame.client_stats.fec_unrecovered_packets =
+                mem::take(&mut self.fec_unrecovered_packets_partial_sum);
         }
     }
 
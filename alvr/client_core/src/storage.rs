@@ -2,9 +2,13 @@ use alvr_common::{error, info};
 use app_dirs2::{AppDataType, AppInfo};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-fn config_path() -> PathBuf {
+fn app_root() -> PathBuf {
     app_dirs2::app_root(
         AppDataType::UserConfig,
         &AppInfo {
@@ -13,13 +17,48 @@ fn config_path() -> PathBuf {
         },
     )
     .unwrap()
-    .join("session.json")
+}
+
+fn config_path() -> PathBuf {
+    app_root().join("session.json")
+}
+
+pub fn shard_capture_path() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    app_root().join(format!("shards.{timestamp}.alvrcap"))
+}
+
+fn generate_identity_key() -> [u8; 32] {
+    rand::thread_rng().gen()
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Default, Debug)]
+pub enum ConnectionMode {
+    // Wait for a streamer to find this client with discovery
+    #[default]
+    Discovery,
+    // Connect to the streamer at the given address, bypassing discovery
+    ActiveConnect {
+        server_address: String,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub protocol_id: u64,
     pub hostname: String,
+    // Secret key used to prove the identity of this client to paired streamers
+    #[serde(default = "generate_identity_key")]
+    pub identity_key: [u8; 32],
+    // Streamer addresses saved by the user, in the "host:port" format (the port is optional)
+    #[serde(default)]
+    pub server_addresses: Vec<String>,
+    #[serde(default)]
+    pub connection_mode: ConnectionMode,
 }
 
 impl Default for Config {
@@ -35,6 +74,9 @@ impl Default for Config {
                 rng.gen_range(0..10),
                 rng.gen_range(0..10),
             ),
+            identity_key: generate_identity_key(),
+            server_addresses: vec![],
+            connection_mode: ConnectionMode::Discovery,
         }
     }
 }
@@ -44,7 +86,12 @@ impl Config {
         if let Ok(config_string) = fs::read_to_string(config_path()) {
             // Failure happens if the Config signature changed between versions.
             // todo: recover data from mismatched Config signature. low priority
-            if let Ok(config) = serde_json::from_str(&config_string) {
+            if let Ok(config) = serde_json::from_str::<Config>(&config_string) {
+                // Persist fields that were missing and have been generated
+                if serde_json::to_string(&config).ok().as_ref() != Some(&config_string) {
+                    config.store();
+                }
+
                 return config;
             } else {
                 info!("Error parsing ALVR config. Using default");
@@ -0,0 +1,321 @@
+// NTP-style clock synchronization. The client periodically sends a request with its send time t0,
+// the server answers with its receive time t1 and send time t2, and the client notes the receive
+// time t3. Each exchange gives an offset sample ((t1 - t0) + (t2 - t3)) / 2, which is exact if the
+// network latency is the same in both directions. Queueing makes it asymmetric, so only the
+// exchanges with the lowest round trip times are used. The drift of the clocks is the slope of the
+// offset over time.
+// Times are measured with the monotonic clock of each peer (see monotonic_time()), whose epochs are
+// unrelated.
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const MAX_SAMPLES: usize = 64;
+// Latencies are averaged over the most recent exchanges
+const LATENCY_SAMPLES: usize = 8;
+// The drift cannot be estimated reliably over shorter intervals
+const MIN_DRIFT_INTERVAL_S: f64 = 10.0;
+// Crystal oscillators are not this inaccurate, larger values are measurement errors
+const MAX_DRIFT_PPM: f64 = 200.0;
+
+static CLOCK_EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Time of the monotonic clock used for clock synchronization
+pub fn monotonic_time() -> Duration {
+    CLOCK_EPOCH.elapsed()
+}
+
+/// Mapping between the clocks of the client and the server:
+/// server time = client time + offset + drift * (client time - reference client time)
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct ClockMapping {
+    pub reference_client_time: Duration,
+    // Server time minus client time at the reference time
+    pub offset_ns: i64,
+    pub drift_ppm: f64,
+}
+
+impl ClockMapping {
+    fn offset_s(&self, client_time_s: f64) -> f64 {
+        self.offset_ns as f64 / 1e9
+            + self.drift_ppm / 1e6 * (client_time_s - self.reference_client_time.as_secs_f64())
+    }
+
+    pub fn client_to_server_time(&self, client_time: Duration) -> Duration {
+        let client_time_s = client_time.as_secs_f64();
+
+        Duration::from_secs_f64(f64::max(client_time_s + self.offset_s(client_time_s), 0.0))
+    }
+
+    pub fn server_to_client_time(&self, server_time: Duration) -> Duration {
+        let drift = self.drift_ppm / 1e6;
+        let client_time_s = (server_time.as_secs_f64() - self.offset_ns as f64 / 1e9
+            + drift * self.reference_client_time.as_secs_f64())
+            / (1.0 + drift);
+
+        Duration::from_secs_f64(f64::max(client_time_s, 0.0))
+    }
+}
+
+// Timestamps of a request-response exchange, in seconds
+#[derive(Clone, Copy)]
+struct TimeSyncSample {
+    client_send: f64,
+    server_receive: f64,
+    server_send: f64,
+    client_receive: f64,
+}
+
+impl TimeSyncSample {
+    fn round_trip(&self) -> f64 {
+        (self.client_receive - self.client_send) - (self.server_send - self.server_receive)
+    }
+
+    fn offset(&self) -> f64 {
+        ((self.server_receive - self.client_send) + (self.server_send - self.client_receive)) / 2.0
+    }
+
+    fn client_time(&self) -> f64 {
+        (self.client_send + self.client_receive) / 2.0
+    }
+}
+
+#[derive(Default)]
+pub struct ClockSynchronizer {
+    samples: VecDeque<TimeSyncSample>,
+    mapping: Option<ClockMapping>,
+}
+
+impl ClockSynchronizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the updated mapping
+    pub fn report_exchange(
+        &mut self,
+        client_send_time: Duration,
+        server_receive_time: Duration,
+        server_send_time: Duration,
+        client_receive_time: Duration,
+    ) -> ClockMapping {
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(TimeSyncSample {
+            client_send: client_send_time.as_secs_f64(),
+            server_receive: server_receive_time.as_secs_f64(),
+            server_send: server_send_time.as_secs_f64(),
+            client_receive: client_receive_time.as_secs_f64(),
+        });
+
+        let mut best_samples = self.samples.iter().copied().collect::<Vec<_>>();
+        best_samples.sort_by(|a, b| a.round_trip().total_cmp(&b.round_trip()));
+        best_samples.truncate((best_samples.len() + 1) / 2);
+
+        let count = best_samples.len() as f64;
+        let mean_time = best_samples.iter().map(|s| s.client_time()).sum::<f64>() / count;
+        let mean_offset = best_samples.iter().map(|s| s.offset()).sum::<f64>() / count;
+
+        let (min_time, max_time) = best_samples
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), s| {
+                (min.min(s.client_time()), max.max(s.client_time()))
+            });
+        let drift = if max_time - min_time >= MIN_DRIFT_INTERVAL_S {
+            let (numerator, denominator) =
+                best_samples
+                    .iter()
+                    .fold((0.0, 0.0), |(numerator, denominator), s| {
+                        let dx = s.client_time() - mean_time;
+
+                        (
+                            numerator + dx * (s.offset() - mean_offset),
+                            denominator + dx * dx,
+                        )
+                    });
+
+            f64::max(
+                f64::min(numerator / denominator * 1e6, MAX_DRIFT_PPM),
+                -MAX_DRIFT_PPM,
+            )
+        } else {
+            0.0
+        };
+
+        let mapping = ClockMapping {
+            reference_client_time: Duration::from_secs_f64(mean_time),
+            offset_ns: (mean_offset * 1e9) as i64,
+            drift_ppm: drift,
+        };
+        self.mapping = Some(mapping);
+
+        mapping
+    }
+
+    pub fn mapping(&self) -> Option<ClockMapping> {
+        self.mapping
+    }
+
+    /// Average round trip time of the most recent exchanges
+    pub fn round_trip(&self) -> Option<Duration> {
+        let recent = self.samples.iter().rev().take(LATENCY_SAMPLES);
+        let count = recent.len();
+        (count > 0).then(|| {
+            Duration::from_secs_f64(f64::max(
+                recent.map(|s| s.round_trip()).sum::<f64>() / count as f64,
+                0.0,
+            ))
+        })
+    }
+
+    /// Average client to server and server to client latencies of the most recent exchanges,
+    /// measured with the synchronized clocks. The offset is estimated assuming that the latency is
+    /// the same in both directions, so both are close to half the round trip time: they differ
+    /// only by the jitter of the recent exchanges and by the drift. A constant asymmetry of the
+    /// latencies cannot be measured.
+    pub fn one_way_latencies(&self) -> Option<(Duration, Duration)> {
+        let mapping = self.mapping?;
+
+        let recent = self.samples.iter().rev().take(LATENCY_SAMPLES);
+        let count = recent.len() as f64;
+        let (uplink_sum, downlink_sum) = recent.fold((0.0, 0.0), |(uplink, downlink), s| {
+            (
+                uplink + s.server_receive - (s.client_send + mapping.offset_s(s.client_send)),
+                downlink + s.client_receive + mapping.offset_s(s.client_receive) - s.server_send,
+            )
+        });
+
+        Some((
+            Duration::from_secs_f64(f64::max(uplink_sum / count, 0.0)),
+            Duration::from_secs_f64(f64::max(downlink_sum / count, 0.0)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    // Exchange with a server clock ahead of the client clock by offset_s
+    fn report_exchange(
+        synchronizer: &mut ClockSynchronizer,
+        client_send_s: f64,
+        offset_s: f64,
+        uplink_s: f64,
+        downlink_s: f64,
+    ) -> ClockMapping {
+        let processing_s = 0.001;
+        let server_receive_s = client_send_s + uplink_s + offset_s;
+
+        synchronizer.report_exchange(
+            secs(client_send_s),
+            secs(server_receive_s),
+            secs(server_receive_s + processing_s),
+            secs(client_send_s + uplink_s + processing_s + downlink_s),
+        )
+    }
+
+    #[test]
+    fn offset_and_round_trip() {
+        let sample = TimeSyncSample {
+            client_send: 1.0,
+            server_receive: 11.01,
+            server_send: 11.012,
+            client_receive: 1.022,
+        };
+
+        assert!((sample.round_trip() - 0.02).abs() < 1e-9);
+        assert!((sample.offset() - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lowest_round_trips_are_used() {
+        let mut synchronizer = ClockSynchronizer::new();
+
+        for i in 0..20 {
+            // Every other exchange is delayed by queueing in one direction only
+            let uplink_s = if i % 2 == 0 { 0.005 } else { 0.05 };
+            report_exchange(&mut synchronizer, i as f64 * 0.1, 10.0, uplink_s, 0.005);
+        }
+
+        let mapping = synchronizer.mapping().unwrap();
+        assert!((mapping.offset_ns as f64 / 1e9 - 10.0).abs() < 1e-6);
+        assert_eq!(mapping.drift_ppm, 0.0);
+    }
+
+    #[test]
+    fn drift() {
+        let mut synchronizer = ClockSynchronizer::new();
+
+        // The server clock runs 50 ppm faster
+        let mut mapping = ClockMapping::default();
+        for i in 0..40 {
+            let client_send_s = i as f64;
+            mapping = report_exchange(
+                &mut synchronizer,
+                client_send_s,
+                10.0 + client_send_s * 50e-6,
+                0.005,
+                0.005,
+            );
+        }
+        assert!((mapping.drift_ppm - 50.0).abs() < 0.1);
+
+        // Unrealistic drifts are clamped
+        let mut synchronizer = ClockSynchronizer::new();
+        for i in 0..40 {
+            let client_send_s = i as f64;
+            mapping = report_exchange(
+                &mut synchronizer,
+                client_send_s,
+                10.0 + client_send_s * 1e-3,
+                0.005,
+                0.005,
+            );
+        }
+        assert_eq!(mapping.drift_ppm, MAX_DRIFT_PPM);
+    }
+
+    #[test]
+    fn mapping_round_trip() {
+        let mapping = ClockMapping {
+            reference_client_time: secs(100.0),
+            offset_ns: 5_000_000_000,
+            drift_ppm: 30.0,
+        };
+
+        let client_time = secs(130.0);
+        let server_time = mapping.client_to_server_time(client_time);
+        // 30 s after the reference time, the clocks drifted apart by 30 * 30 us
+        assert!((server_time.as_secs_f64() - (135.0 + 30.0 * 30e-6)).abs() < 1e-6);
+        assert!((mapping.server_to_client_time(server_time).as_secs_f64() - 130.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn latencies() {
+        let mut synchronizer = ClockSynchronizer::new();
+        assert!(synchronizer.round_trip().is_none());
+        assert!(synchronizer.one_way_latencies().is_none());
+
+        for i in 0..10 {
+            report_exchange(&mut synchronizer, i as f64 * 0.1, 10.0, 0.004, 0.006);
+        }
+
+        let round_trip = synchronizer.round_trip().unwrap().as_secs_f64();
+        assert!((round_trip - 0.01).abs() < 1e-6);
+
+        // The asymmetry is not measurable: both latencies are half the round trip time
+        let (uplink, downlink) = synchronizer.one_way_latencies().unwrap();
+        assert!((uplink.as_secs_f64() - 0.005).abs() < 1e-6);
+        assert!((downlink.as_secs_f64() - 0.005).abs() < 1e-6);
+    }
+}
@@ -1,4 +1,5 @@
 mod average;
+mod clock_sync;
 mod connection_result;
 mod inputs;
 mod logging;
@@ -17,6 +18,7 @@ pub use semver;
 pub use settings_schema;
 
 pub use average::*;
+pub use clock_sync::*;
 pub use connection_result::*;
 pub use inputs::*;
 pub use log::{debug, error, info, warn};
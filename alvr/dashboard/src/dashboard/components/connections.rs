@@ -7,18 +7,25 @@ use eframe::{
     emath::{Align, Align2},
     epaint::Color32,
 };
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+};
 
 struct EditPopupState {
     new_client: bool,
     hostname: String,
     ips: Vec<String>,
+    // Settings path, JSON value
+    overrides: Vec<(String, String)>,
 }
 
 pub struct ConnectionsTab {
     new_clients: Option<Vec<(String, ClientConnectionConfig)>>,
     trusted_clients: Option<Vec<(String, ClientConnectionConfig)>>,
     edit_popup_state: Option<EditPopupState>,
+    pairing_pins: HashMap<String, String>,
+    active_client: Option<String>,
 }
 
 impl ConnectionsTab {
@@ -27,6 +34,8 @@ impl ConnectionsTab {
             new_clients: None,
             trusted_clients: None,
             edit_popup_state: None,
+            pairing_pins: HashMap::new(),
+            active_client: None,
         }
     }
 
@@ -40,6 +49,7 @@ impl ConnectionsTab {
 
         self.trusted_clients = Some(trusted_clients);
         self.new_clients = Some(untrusted_clients);
+        self.active_client = session.active_client.clone();
     }
 
     pub fn ui(&mut self, ui: &mut Ui, connected_to_server: bool) -> Vec<ServerRequest> {
@@ -85,13 +95,30 @@ impl ConnectionsTab {
                         });
 
                         Grid::new(1).num_columns(2).show(ui, |ui| {
-                            for (hostname, _) in clients {
+                            for (hostname, data) in clients {
                                 ui.horizontal(|ui| {
                                     ui.add_space(10.0);
                                     ui.label(hostname);
                                 });
                                 ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
-                                    if ui.button("Trust").clicked() {
+                                    if data.pairing_request.is_some() {
+                                        let pin =
+                                            self.pairing_pins.entry(hostname.clone()).or_default();
+                                        if ui.button("Pair").clicked() {
+                                            requests.push(ServerRequest::UpdateClientList {
+                                                hostname: hostname.clone(),
+                                                action: ClientListAction::ConfirmPairing {
+                                                    pin: pin.clone(),
+                                                },
+                                            });
+                                            pin.clear();
+                                        }
+                                        ui.add(
+                                            TextEdit::singleline(pin)
+                                                .hint_text("PIN")
+                                                .desired_width(60.0),
+                                        );
+                                    } else if ui.button("Trust").clicked() {
                                         requests.push(ServerRequest::UpdateClientList {
                                             hostname: hostname.clone(),
                                             action: ClientListAction::Trust,
@@ -135,12 +162,19 @@ impl ConnectionsTab {
                                             ui.colored_label(theme::OK_GREEN, "Connected")
                                         }
                                         ConnectionState::Streaming => {
-                                            ui.colored_label(theme::OK_GREEN, "Streaming")
+                                            ui.colored_label(theme::OK_GREEN, "Streaming (primary)")
+                                        }
+                                        ConnectionState::Spectating => {
+                                            ui.colored_label(theme::OK_GREEN, "Spectating")
                                         }
-                                        ConnectionState::Disconnecting { .. } => ui.colored_label(
-                                            log_colors::WARNING_LIGHT,
-                                            "Disconnecting",
-                                        ),
+                                        ConnectionState::Disconnecting { reason, .. } => ui
+                                            .colored_label(
+                                                log_colors::WARNING_LIGHT,
+                                                format!("Disconnecting ({reason:?})"),
+                                            ),
+                                    };
+                                    if self.active_client.as_ref() == Some(hostname) {
+                                        ui.label(RichText::new("Active").strong());
                                     }
                                 });
                                 ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
@@ -150,6 +184,15 @@ impl ConnectionsTab {
                                             action: ClientListAction::RemoveEntry,
                                         });
                                     }
+                                    if self.active_client.as_ref() == Some(hostname) {
+                                        if ui.button("Unset active").clicked() {
+                                            requests.push(ServerRequest::SetActiveClient(None));
+                                        }
+                                    } else if ui.button("Make active").clicked() {
+                                        requests.push(ServerRequest::SetActiveClient(Some(
+                                            hostname.clone(),
+                                        )));
+                                    }
                                     if ui.button("Edit").clicked() {
                                         self.edit_popup_state = Some(EditPopupState {
                                             new_client: false,
@@ -159,6 +202,13 @@ impl ConnectionsTab {
                                                 .iter()
                                                 .map(|addr| addr.to_string())
                                                 .collect::<Vec<String>>(),
+                                            overrides: data
+                                                .settings_overrides
+                                                .iter()
+                                                .map(|(path, value)| {
+                                                    (path.clone(), value.to_string())
+                                                })
+                                                .collect(),
                                         });
                                     }
                                 });
@@ -171,6 +221,7 @@ impl ConnectionsTab {
                                 hostname: "XXXX.client.alvr".into(),
                                 new_client: true,
                                 ips: Vec::new(),
+                                overrides: Vec::new(),
                             });
                         }
                     });
@@ -197,18 +248,57 @@ impl ConnectionsTab {
                             state.ips.push("192.168.X.X".to_string());
                         }
                     });
+                    ui.separator();
+                    ui.label("Settings overrides (path, JSON value):");
+                    ui.columns(2, |ui| {
+                        for (path, value) in &mut state.overrides {
+                            ui[0].add(TextEdit::singleline(path).hint_text("video.preferred_fps"));
+                            ui[1].add(TextEdit::singleline(value).hint_text("72"));
+                        }
+                        if ui[0].button("Add override").clicked() {
+                            state.overrides.push((String::new(), String::new()));
+                        }
+                        if ui[1].button("Clear overrides").clicked() {
+                            state.overrides.clear();
+                        }
+                    });
                     ui.columns(2, |ui| {
                         if ui[0].button("Cancel").clicked() {
                             return;
                         }
 
                         if ui[1].button("Save").clicked() {
-                            let manual_ips =
-                                state.ips.iter().filter_map(|s| s.parse().ok()).collect();
+                            // IPv6 addresses can be written with or without brackets
+                            let manual_ips = state
+                                .ips
+                                .iter()
+                                .filter_map(|s| {
+                                    s.trim()
+                                        .trim_start_matches('[')
+                                        .trim_end_matches(']')
+                                        .parse()
+                                        .ok()
+                                })
+                                .collect();
+
+                            // Values that are not valid JSON are taken as strings
+                            let overrides = state
+                                .overrides
+                                .iter()
+                                .filter(|(path, _)| !path.trim().is_empty())
+                                .map(|(path, value)| {
+                                    (
+                                        path.trim().to_owned(),
+                                        serde_json::from_str(value.trim()).unwrap_or_else(|_| {
+                                            serde_json::Value::String(value.clone())
+                                        }),
+                                    )
+                                })
+                                .collect();
 
                             if state.new_client {
                                 requests.push(ServerRequest::UpdateClientList {
-                                    hostname: state.hostname,
+                                    hostname: state.hostname.clone(),
                                     action: ClientListAction::AddIfMissing {
                                         trusted: true,
                                         manual_ips,
@@ -216,10 +306,14 @@ impl ConnectionsTab {
                                 });
                             } else {
                                 requests.push(ServerRequest::UpdateClientList {
-                                    hostname: state.hostname,
+                                    hostname: state.hostname.clone(),
                                     action: ClientListAction::SetManualIps(manual_ips),
                                 });
                             }
+                            requests.push(ServerRequest::UpdateClientList {
+                                hostname: state.hostname,
+                                action: ClientListAction::SetSettingsOverrides(overrides),
+                            });
                         } else {
                             self.edit_popup_state = Some(state);
                         }
@@ -1,5 +1,5 @@
 use crate::{dashboard::theme::graph_colors, dashboard::ServerRequest};
-use alvr_events::{GraphStatistics, StatisticsSummary};
+use alvr_events::{BandwidthProbeResult, GraphStatistics, StatisticsSummary};
 use alvr_gui_common::theme;
 use eframe::{
     egui::{
@@ -22,6 +22,7 @@ fn draw_lines(painter: &Painter, points: Vec<Pos2>, color: Color32) {
 pub struct StatisticsTab {
     history: VecDeque<GraphStatistics>,
     last_statistics_summary: Option<StatisticsSummary>,
+    last_bandwidth_probe: Option<BandwidthProbeResult>,
 }
 
 impl StatisticsTab {
@@ -31,6 +32,7 @@ impl StatisticsTab {
                 .into_iter()
                 .collect(),
             last_statistics_summary: None,
+            last_bandwidth_probe: None,
         }
     }
 
@@ -38,6 +40,10 @@ impl StatisticsTab {
         self.last_statistics_summary = Some(statistics);
     }
 
+    pub fn update_bandwidth_probe(&mut self, result: BandwidthProbeResult) {
+        self.last_bandwidth_probe = Some(result);
+    }
+
     pub fn update_graph_statistics(&mut self, statistics: GraphStatistics) {
         self.history.pop_front();
         self.history.push_back(statistics);
@@ -358,6 +364,21 @@ impl StatisticsTab {
             ui[0].label("Transport latency:");
             ui[1].label(&format!("{:.2} ms", statistics.network_latency_ms));
 
+            if let (Some(uplink), Some(downlink)) = (
+                statistics.client_to_server_latency_ms,
+                statistics.server_to_client_latency_ms,
+            ) {
+                ui[0].label("One-way latency:");
+                ui[1].label(&format!(
+                    "{uplink:.2} ms client to streamer, {downlink:.2} ms streamer to client"
+                ));
+            }
+
+            if let Some(drift) = statistics.clock_drift_ppm {
+                ui[0].label("Clock drift:");
+                ui[1].label(&format!("{drift:.1} ppm"));
+            }
+
             ui[0].label("Decoder latency:");
             ui[1].label(&format!("{:.2} ms", statistics.decode_latency_ms));
 
@@ -367,6 +388,38 @@ impl StatisticsTab {
                 statistics.packets_lost_total, statistics.packets_lost_per_sec
             ));
 
+            ui[0].label("FEC overhead:");
+            ui[1].label(&format!(
+                "{:.1} Mbps ({:.1}%)",
+                statistics.fec_overhead_mbits_per_sec, statistics.fec_overhead_percentage
+            ));
+
+            ui[0].label("FEC packets recovered:");
+            ui[1].label(&format!(
+                "{} packets ({} unrecovered)",
+                statistics.fec_recovered_packets_total, statistics.fec_unrecovered_packets_total
+            ));
+
+            if let Some(probe) = &self.last_bandwidth_probe {
+                ui[0].label("Bandwidth probe:");
+                ui[1].label(&format!(
+                    "{:.1} Mbps ({:.1}% loss)",
+                    probe.estimated_bandwidth_bps / 1e6,
+                    probe.packet_loss_percentage
+                ));
+            }
+
+            ui[0].label("Send queue peak:");
+            ui[1].label(&format!("{} shards", statistics.send_queue_peak_shards));
+
+            ui[0].label("Pacing delay:");
+            ui[1].label(&format!(
+                "{:.2} ms average, {:.2} ms max ({} packets/s)",
+                statistics.pacing_delay_average_ms,
+                statistics.pacing_delay_max_ms,
+                statistics.paced_packets_per_sec
+            ));
+
             ui[0].label("Client FPS:");
             ui[1].label(&format!("{} FPS", statistics.client_fps));
 
@@ -161,6 +161,9 @@ impl eframe::App for Dashboard {
                 EventType::StatisticsSummary(statistics) => {
                     self.statistics_tab.update_statistics(statistics)
                 }
+                EventType::BandwidthProbe(result) => {
+                    self.statistics_tab.update_bandwidth_probe(result)
+                }
                 EventType::Session(session) => {
                     let settings = session.to_settings();
 
@@ -118,6 +118,11 @@ impl DataSources {
 
                                     report_session_local(&context, &events_sender, data_manager);
                                 }
+                                ServerRequest::SetActiveClient(hostname) => {
+                                    data_manager.session_mut().active_client = hostname;
+
+                                    report_session_local(&context, &events_sender, data_manager);
+                                }
                                 ServerRequest::GetAudioDevices => {
                                     if let Ok(list) = data_manager.get_audio_devices_list() {
                                         report_event_local(
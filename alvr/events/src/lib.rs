@@ -1,6 +1,6 @@
 use alvr_common::{info, DeviceMotion, LogEntry, Pose};
 use alvr_packets::{AudioDevicesList, ButtonValue};
-use alvr_session::SessionConfig;
+use alvr_session::{ConnectionState, SessionConfig};
 use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, time::Duration};
 
@@ -16,6 +16,18 @@ pub struct StatisticsSummary {
     pub decode_latency_ms: f32,
     pub packets_lost_total: usize,
     pub packets_lost_per_sec: usize,
+    pub fec_overhead_mbits_per_sec: f32,
+    pub fec_overhead_percentage: f32,
+    pub fec_recovered_packets_total: usize,
+    pub fec_unrecovered_packets_total: usize,
+    pub send_queue_peak_shards: usize,
+    pub paced_packets_per_sec: usize,
+    pub pacing_delay_average_ms: f32,
+    pub pacing_delay_max_ms: f32,
+    // Measured with the synchronized clocks. None if the client doesn't support clock sync
+    pub client_to_server_latency_ms: Option<f32>,
+    pub server_to_client_latency_ms: Option<f32>,
+    pub clock_drift_ppm: Option<f32>,
     pub client_fps: u32,
     pub server_fps: u32,
     pub battery_hmd: u32,
@@ -51,6 +63,22 @@ pub struct GraphStatistics {
     pub actual_bitrate_bps: f32,
 }
 
+// Result of the bandwidth probe at the start of the stream
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BandwidthProbeResult {
+    pub estimated_bandwidth_bps: f32,
+    pub packet_loss_percentage: f32,
+}
+
+// Connection state transition of a client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionLifecycleEvent {
+    pub hostname: String,
+    pub state: ConnectionState,
+    // Since the UNIX epoch. Unlike the event timestamp, it includes the date
+    pub timestamp: Duration,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TrackingEvent {
     pub head_motion: Option<DeviceMotion>,
@@ -83,6 +111,8 @@ pub enum EventType {
     Session(Box<SessionConfig>),
     StatisticsSummary(StatisticsSummary),
     GraphStatistics(GraphStatistics),
+    BandwidthProbe(BandwidthProbeResult),
+    ConnectionLifecycle(ConnectionLifecycleEvent),
     Tracking(Box<TrackingEvent>),
     Buttons(Vec<ButtonEvent>),
     Haptics(HapticsEvent),
@@ -1,21 +1,48 @@
 use alvr_common::{
     glam::{UVec2, Vec2},
-    DeviceMotion, Fov, LogEntry, LogSeverity, Pose,
+    ClockMapping, DeviceMotion, Fov, LogEntry, LogSeverity, Pose,
 };
-use alvr_session::{CodecType, ConnectionState, SessionConfig};
+use alvr_session::{CodecType, ConnectionState, DisconnectReason, PairingRequest, SessionConfig};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fmt::{self, Debug},
     net::IpAddr,
     path::PathBuf,
     time::Duration,
 };
 
+// Defines an enum together with the list of the names of its variants, used as capabilities
+macro_rules! named_variants {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident $(($($tuple:tt)*))? $({ $($fields:tt)* })?,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant $(($($tuple)*))? $({ $($fields)* })?,
+            )*
+        }
+
+        impl $name {
+            pub const VARIANT_NAMES: &'static [&'static str] = &[$(stringify!($variant)),*];
+        }
+    };
+}
+
 pub const TRACKING: u16 = 0;
 pub const HAPTICS: u16 = 1;
 pub const AUDIO: u16 = 2;
 pub const VIDEO: u16 = 3;
 pub const STATISTICS: u16 = 4;
+pub const BANDWIDTH_PROBE: u16 = 5;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct VideoStreamingCapabilities {
@@ -24,22 +51,205 @@ pub struct VideoStreamingCapabilities {
     pub microphone_sample_rate: u32,
 }
 
+// The stream public keys are the ephemeral X25519 keys used for the stream socket encryption. The
+// client signs them together with the nonce, so that they cannot be replaced by a third party
+#[derive(Serialize, Deserialize)]
+pub struct IdentityChallenge {
+    pub nonce: [u8; 32],
+    pub stream_public_key: [u8; 32],
+    // Hash of the pairing secret, revealed with PairingRequired
+    pub pairing_commitment: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IdentityProof {
+    pub public_key: [u8; 32],
+    pub stream_public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum IdentityVerificationResult {
+    Verified,
+    PairingRequired { pairing_secret: [u8; 32] },
+    Rejected,
+}
+
+// Version of the handshake, that is the discovery packet and the packets exchanged from
+// IdentityChallenge to StreamConfigPacket. Peers with the same handshake version can connect even
+// if their ALVR versions differ, then they use only the capabilities supported by both. Increase
+// this only for breaking changes of the handshake.
+pub const HANDSHAKE_VERSION: u64 = 1;
+
+// Version of the headers of the stream packets, like VideoPacketHeader, Tracking and
+// ClientStatistics. Unlike the handshake packets, they are not negotiated: they are bincode-encoded
+// without field names for every packet, so fields cannot be added or removed compatibly. Increase
+// this for any change of their layout. The version is advertised as a stream feature, and peers
+// with different versions don't stream with each other.
+pub const STREAM_HEADERS_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StreamFeature {
+    ForwardErrorCorrection,
+    ShardRetransmission,
+    StreamEncryption,
+    Quic,
+    WireFormatV2,
+    BandwidthProbe,
+}
+
+impl StreamFeature {
+    pub const ALL: &'static [StreamFeature] = &[
+        StreamFeature::ForwardErrorCorrection,
+        StreamFeature::ShardRetransmission,
+        StreamFeature::StreamEncryption,
+        StreamFeature::Quic,
+        StreamFeature::WireFormatV2,
+        StreamFeature::BandwidthProbe,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamFeature::ForwardErrorCorrection => "forward_error_correction",
+            StreamFeature::ShardRetransmission => "shard_retransmission",
+            StreamFeature::StreamEncryption => "stream_encryption",
+            StreamFeature::Quic => "quic",
+            StreamFeature::WireFormatV2 => "wire_format_v2",
+            StreamFeature::BandwidthProbe => "bandwidth_probe",
+        }
+    }
+}
+
+pub fn codec_name(codec: CodecType) -> &'static str {
+    match codec {
+        CodecType::H264 => "h264",
+        CodecType::Hevc => "hevc",
+    }
+}
+
+fn stream_headers_feature_name() -> String {
+    format!("stream_headers_v{STREAM_HEADERS_VERSION}")
+}
+
+// Capabilities are identified by name, so that a peer can deserialize the capabilities of a newer
+// peer: the ones it doesn't know are left out of the intersection. Packet variants are named
+// "<enum>::<variant>"; new variants must be appended to their enum, and sent only to peers that
+// support them.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Capabilities {
+    pub negotiated_config_version: u32,
+    pub packet_variants: BTreeSet<String>,
+    pub codecs: BTreeSet<String>,
+    pub stream_features: BTreeSet<String>,
+}
+
+impl Capabilities {
+    // Capabilities of this build
+    pub fn current() -> Self {
+        let packet_variants = ServerControlPacket::VARIANT_NAMES
+            .iter()
+            .map(|name| format!("ServerControlPacket::{name}"))
+            .chain(
+                ClientControlPacket::VARIANT_NAMES
+                    .iter()
+                    .map(|name| format!("ClientControlPacket::{name}")),
+            )
+            .collect();
+
+        Self {
+            negotiated_config_version: NegotiatedConfig::LATEST_VERSION,
+            packet_variants,
+            codecs: [CodecType::H264, CodecType::Hevc]
+                .into_iter()
+                .map(|codec| codec_name(codec).to_owned())
+                .collect(),
+            stream_features: StreamFeature::ALL
+                .iter()
+                .map(|feature| feature.name().to_owned())
+                .chain([stream_headers_feature_name()])
+                .collect(),
+        }
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            negotiated_config_version: u32::min(
+                self.negotiated_config_version,
+                other.negotiated_config_version,
+            ),
+            packet_variants: &self.packet_variants & &other.packet_variants,
+            codecs: &self.codecs & &other.codecs,
+            stream_features: &self.stream_features & &other.stream_features,
+        }
+    }
+
+    pub fn supports_packet(&self, name: &str) -> bool {
+        self.packet_variants.contains(name)
+    }
+
+    pub fn supports_codec(&self, codec: CodecType) -> bool {
+        self.codecs.contains(codec_name(codec))
+    }
+
+    pub fn supports_feature(&self, feature: StreamFeature) -> bool {
+        self.stream_features.contains(feature.name())
+    }
+
+    pub fn supports_stream_headers(&self) -> bool {
+        self.stream_features
+            .contains(&stream_headers_feature_name())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum ClientConnectionResult {
     ConnectionAccepted {
-        client_protocol_id: u64,
+        client_version: String,
+        capabilities: Capabilities,
         display_name: String,
         server_ip: IpAddr,
         streaming_capabilities: Option<VideoStreamingCapabilities>,
     },
     ClientStandby,
+    // Sent instead of ConnectionAccepted to reattach to a session interrupted by a connection loss.
+    // Only sent to servers that issued the token.
+    ResumeSession {
+        token: [u8; 16],
+    },
+}
+
+// Reply to ClientConnectionResult::ResumeSession. If rejected, the client continues the handshake
+// with ConnectionAccepted
+#[derive(Serialize, Deserialize)]
+pub enum SessionResumeResult {
+    Resumed,
+    Rejected,
+}
+
+// Configuration chosen by the server for the connection. Each variant is a version: new versions
+// are appended as new variants and the server sends the newest version supported by the client.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum NegotiatedConfig {
+    V1 {
+        view_resolution: UVec2,
+        refresh_rate_hint: f32,
+        game_audio_sample_rate: u32,
+        codec: CodecType,
+        // Sent only if the stream encryption is enabled. Same key as in IdentityChallenge
+        stream_public_key: Option<[u8; 32]>,
+        // Intersection of the capabilities of the client and the server
+        capabilities: Capabilities,
+    },
+}
+
+impl NegotiatedConfig {
+    pub const LATEST_VERSION: u32 = 1;
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct StreamConfigPacket {
     pub session: String, // JSON session that allows for extrapolation
-    pub negotiated: String, // JSON dictionary containing negotiated configuration. Can be extended
-                         // without a breaking protocol change, but entries can't be removed.
+    pub negotiated: NegotiatedConfig,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -48,15 +258,43 @@ pub struct DecoderInitializationConfig {
     pub config_buffer: Vec<u8>, // e.g. SPS + PPS NALs
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ServerControlPacket {
-    StartStream,
-    InitializeDecoder(DecoderInitializationConfig),
-    Restarting,
-    KeepAlive,
-    ServerPredictionAverage(Duration), // todo: remove
-    Reserved(String),
-    ReservedBuffer(Vec<u8>),
+// Times of the monotonic clocks of the peers, see alvr_common::monotonic_time()
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimeSyncResponse {
+    pub client_send_time: Duration,
+    pub server_receive_time: Duration,
+    pub server_send_time: Duration,
+}
+
+// Clock synchronization state of the client, sent after each exchange. The one-way latencies are
+// not measured independently, see ClockSynchronizer::one_way_latencies()
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClockSyncReport {
+    pub mapping: ClockMapping,
+    pub round_trip: Duration,
+    pub client_to_server_latency: Duration,
+    pub server_to_client_latency: Duration,
+}
+
+named_variants! {
+    #[derive(Serialize, Deserialize)]
+    pub enum ServerControlPacket {
+        StartStream,
+        InitializeDecoder(DecoderInitializationConfig),
+        Restarting,
+        KeepAlive,
+        ServerPredictionAverage(Duration), // todo: remove
+        Reserved(String),
+        ReservedBuffer(Vec<u8>),
+        TimeSyncResponse(TimeSyncResponse),
+        // Allows the client to resume the session after a connection loss, within the grace period
+        SessionResumeToken {
+            token: [u8; 16],
+            grace_period: Duration,
+        },
+        // Last packet before the streamer closes the connection
+        Disconnecting(DisconnectReason),
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -85,20 +323,24 @@ pub struct ButtonEntry {
     pub value: ButtonValue,
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ClientControlPacket {
-    PlayspaceSync(Option<Vec2>),
-    RequestIdr,
-    KeepAlive,
-    StreamReady, // This flag notifies the server the client streaming socket is ready listening
-    ViewsConfig(ViewsConfig),
-    Battery(BatteryPacket),
-    VideoErrorReport, // legacy
-    Buttons(Vec<ButtonEntry>),
-    ActiveInteractionProfile { device_id: u64, profile_id: u64 },
-    Log { level: LogSeverity, message: String },
-    Reserved(String),
-    ReservedBuffer(Vec<u8>),
+named_variants! {
+    #[derive(Serialize, Deserialize)]
+    pub enum ClientControlPacket {
+        PlayspaceSync(Option<Vec2>),
+        RequestIdr,
+        KeepAlive,
+        StreamReady, // This flag notifies the server the client streaming socket is ready listening
+        ViewsConfig(ViewsConfig),
+        Battery(BatteryPacket),
+        VideoErrorReport, // legacy
+        Buttons(Vec<ButtonEntry>),
+        ActiveInteractionProfile { device_id: u64, profile_id: u64 },
+        Log { level: LogSeverity, message: String },
+        Reserved(String),
+        ReservedBuffer(Vec<u8>),
+        TimeSyncRequest { client_send_time: Duration },
+        ClockSync(ClockSyncReport),
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -109,12 +351,16 @@ pub struct FaceData {
     pub htc_lip_expression: Option<Vec<f32>>, // issue: Serialize does not support [f32; 37]
 }
 
+// Stream header, see STREAM_HEADERS_VERSION
 #[derive(Serialize, Deserialize)]
 pub struct VideoPacketHeader {
     pub timestamp: Duration,
     pub is_idr: bool,
+    // Streamer clock (alvr_common::monotonic_time()) when the packet is queued for sending
+    pub send_time: Duration,
 }
 
+// Stream header, see STREAM_HEADERS_VERSION
 // Note: face_data does not respect target_timestamp.
 #[derive(Serialize, Deserialize, Default)]
 pub struct Tracking {
@@ -184,12 +430,19 @@ pub enum ClientListAction {
     },
     SetDisplayName(String),
     Trust,
+    SetPublicKey([u8; 32]),
+    SetPairingRequest(Option<PairingRequest>),
+    ConfirmPairing {
+        pin: String,
+    },
     SetManualIps(Vec<IpAddr>),
     RemoveEntry,
     UpdateCurrentIp(Option<IpAddr>),
     SetConnectionState(ConnectionState),
+    SetSettingsOverrides(BTreeMap<String, serde_json::Value>),
 }
 
+// Stream header, see STREAM_HEADERS_VERSION
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct ClientStatistics {
     pub target_timestamp: Duration, // identifies the frame
@@ -199,6 +452,35 @@ pub struct ClientStatistics {
     pub rendering: Duration,
     pub vsync_queue: Duration,
     pub total_pipeline_latency: Duration,
+    // Counted since the previous statistics packet
+    pub fec_recovered_packets: u32,
+    pub fec_unrecovered_packets: u32,
+    // Measured with the synchronized clocks. None if the clocks are not synchronized yet
+    pub network_latency: Option<Duration>,
+}
+
+// Header of the padding packets sent in bursts at the start of the stream
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BandwidthProbeHeader {
+    pub burst_index: u32,
+    pub bursts_count: u32,
+    pub packet_index: u32,
+    pub packets_per_burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BandwidthProbeBurstTiming {
+    pub burst_index: u32,
+    pub received_packets: u32,
+    // Interval between the arrival of the first and the last received packet of the burst
+    pub arrival_span: Duration,
+}
+
+// Sent once by the client after the last probe burst, as payload of a statistics packet. Older
+// streamers ignore the payload.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BandwidthProbeReport {
+    pub bursts: Vec<BandwidthProbeBurstTiming>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -234,4 +516,6 @@ pub enum ServerRequest {
     GetDriverList,
     RestartSteamvr,
     ShutdownSteamvr,
+    // Hand the stream over to another client. None lets the first client that connects stream
+    SetActiveClient(Option<String>),
 }
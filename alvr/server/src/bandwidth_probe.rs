@@ -0,0 +1,98 @@
+// Bandwidth probe, run at the start of the stream. Bursts of padding packets are sent back to back
+// on a dedicated stream, and the client reports how long each burst took to arrive. Packets sent
+// back to back leave the bottleneck of the path spaced by its transmission time, so the arrival rate
+// of a burst estimates the capacity of the path. The median over all bursts filters out the bursts
+// disturbed by other traffic. Bursts are spaced so that the network queues can drain in between.
+
+use crate::BITRATE_MANAGER;
+use alvr_common::{anyhow::Result, info, warn};
+use alvr_events::{BandwidthProbeResult, EventType};
+use alvr_packets::{BandwidthProbeHeader, BandwidthProbeReport};
+use alvr_session::BandwidthProbeConfig;
+use alvr_sockets::StreamSender;
+use std::{thread, time::Duration};
+
+// Small enough to always fit in a single shard
+const PROBE_PACKET_SIZE: usize = 1000;
+
+pub fn send_probe(
+    sender: &mut StreamSender<BandwidthProbeHeader>,
+    config: &BandwidthProbeConfig,
+) -> Result<()> {
+    for burst_index in 0..config.bursts_count {
+        if burst_index > 0 {
+            thread::sleep(Duration::from_millis(config.burst_interval_ms));
+        }
+
+        for packet_index in 0..config.packets_per_burst {
+            let mut buffer = sender.get_buffer(&BandwidthProbeHeader {
+                burst_index,
+                bursts_count: config.bursts_count,
+                packet_index,
+                packets_per_burst: config.packets_per_burst,
+            })?;
+            buffer.set_len(PROBE_PACKET_SIZE);
+
+            sender.send(buffer)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn estimate_bandwidth(
+    report: &BandwidthProbeReport,
+    config: &BandwidthProbeConfig,
+) -> Option<BandwidthProbeResult> {
+    // The first packet of a burst marks the start of the arrival span
+    let mut burst_rates_bps = report
+        .bursts
+        .iter()
+        .filter(|burst| burst.received_packets >= 2 && !burst.arrival_span.is_zero())
+        .map(|burst| {
+            (burst.received_packets - 1) as f32 * (PROBE_PACKET_SIZE * 8) as f32
+                / burst.arrival_span.as_secs_f32()
+        })
+        .collect::<Vec<_>>();
+    if burst_rates_bps.is_empty() {
+        return None;
+    }
+    burst_rates_bps.sort_by(f32::total_cmp);
+
+    let sent_packets = config.bursts_count * config.packets_per_burst;
+    let received_packets = report
+        .bursts
+        .iter()
+        .map(|burst| burst.received_packets)
+        .sum::<u32>();
+    let packet_loss_percentage = if sent_packets > 0 {
+        f32::max(1.0 - received_packets as f32 / sent_packets as f32, 0.0) * 100.0
+    } else {
+        0.0
+    };
+
+    Some(BandwidthProbeResult {
+        estimated_bandwidth_bps: burst_rates_bps[burst_rates_bps.len() / 2],
+        packet_loss_percentage,
+    })
+}
+
+pub fn handle_report(report: &BandwidthProbeReport, config: &BandwidthProbeConfig) {
+    let Some(result) = estimate_bandwidth(report, config) else {
+        warn!("Bandwidth probe failed: too few packets received");
+
+        return;
+    };
+
+    info!(
+        "Bandwidth probe: {:.1} Mbps, {:.1}% packet loss",
+        result.estimated_bandwidth_bps / 1e6,
+        result.packet_loss_percentage
+    );
+
+    BITRATE_MANAGER
+        .lock()
+        .report_bandwidth_estimate(result.estimated_bandwidth_bps);
+
+    alvr_events::send_event(EventType::BandwidthProbe(result));
+}
@@ -1,4 +1,7 @@
-use crate::FfiDynamicEncoderParams;
+use crate::{
+    congestion_control::{DelayGradientController, DelayGradientParams},
+    FfiDynamicEncoderParams,
+};
 use alvr_common::SlidingWindowAverage;
 use alvr_events::NominalBitrateStats;
 use alvr_session::{
@@ -10,6 +13,31 @@ use std::{
 };
 
 const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+// Lower bound for the delay gradient controller when no minimum bitrate is set. The bitrate would
+// never recover from zero
+const DELAY_GRADIENT_MIN_BITRATE_BPS: f32 = 1e6;
+
+fn apply_manual_limits(
+    mut bitrate_bps: f32,
+    max_bitrate_mbps: &Switch<u64>,
+    min_bitrate_mbps: &Switch<u64>,
+    stats: &mut NominalBitrateStats,
+) -> f32 {
+    if let Switch::Enabled(max) = max_bitrate_mbps {
+        let max = *max as f32 * 1e6;
+        bitrate_bps = f32::min(bitrate_bps, max);
+
+        stats.manual_max_bps = Some(max);
+    }
+    if let Switch::Enabled(min) = min_bitrate_mbps {
+        let min = *min as f32 * 1e6;
+        bitrate_bps = f32::max(bitrate_bps, min);
+
+        stats.manual_min_bps = Some(min);
+    }
+
+    bitrate_bps
+}
 
 pub struct BitrateManager {
     nominal_frame_interval: Duration,
@@ -24,6 +52,10 @@ pub struct BitrateManager {
     last_frame_instant: Instant,
     last_update_instant: Instant,
     dynamic_max_bitrate: f32,
+    delay_gradient_controller: Option<DelayGradientController>,
+    bandwidth_estimate_bps: Option<f32>,
+    last_requested_bitrate_bps: f32,
+    last_frame_interval: Duration,
     previous_config: Option<BitrateConfig>,
     update_needed: bool,
 }
@@ -50,6 +82,10 @@ impl BitrateManager {
             last_frame_instant: Instant::now(),
             last_update_instant: Instant::now(),
             dynamic_max_bitrate: f32::MAX,
+            delay_gradient_controller: None,
+            bandwidth_estimate_bps: None,
+            last_requested_bitrate_bps: 30_000_000.0,
+            last_frame_interval: Duration::from_secs_f32(1. / initial_framerate),
             previous_config: None,
             update_needed: true,
         }
@@ -106,10 +142,12 @@ impl BitrateManager {
 
         self.network_latency_average.submit_sample(network_latency);
 
+        let mut frame_size_bits = None;
         while let Some(&(timestamp_, size_bits)) = self.packet_sizes_bits_history.front() {
             if timestamp_ == timestamp {
                 self.bitrate_average
                     .submit_sample(size_bits as f32 / network_latency.as_secs_f32());
+                frame_size_bits = Some(size_bits);
 
                 self.packet_sizes_bits_history.pop_front();
 
@@ -140,6 +178,64 @@ impl BitrateManager {
                 self.decoder_latency_overstep_count = 0;
             }
         }
+
+        if let BitrateMode::DelayGradient {
+            initial_bitrate_mbps,
+            max_bitrate_mbps,
+            min_bitrate_mbps,
+            decrease_multiplier,
+            increase_percentage,
+            trendline_window_size,
+        } = config
+        {
+            let params = DelayGradientParams {
+                min_bitrate_bps: min_bitrate_mbps
+                    .as_option()
+                    .map(|min| *min as f32 * 1e6)
+                    .unwrap_or(DELAY_GRADIENT_MIN_BITRATE_BPS),
+                max_bitrate_bps: max_bitrate_mbps
+                    .as_option()
+                    .map(|max| *max as f32 * 1e6)
+                    .unwrap_or(f32::MAX),
+                decrease_multiplier: *decrease_multiplier,
+                increase_per_second: *increase_percentage / 100.0,
+                trendline_window_size: *trendline_window_size,
+            };
+
+            let initial_bitrate_bps = self
+                .bandwidth_estimate_bps
+                .unwrap_or(*initial_bitrate_mbps as f32 * 1e6);
+            let decreased = self
+                .delay_gradient_controller
+                .get_or_insert_with(|| DelayGradientController::new(initial_bitrate_bps))
+                .report_frame(&params, timestamp, network_latency, frame_size_bits);
+
+            // Decreases are applied immediately, increases at the next periodic update
+            if decreased {
+                self.update_needed = true;
+            }
+        } else {
+            self.delay_gradient_controller = None;
+        }
+    }
+
+    // The bandwidth probe runs at the start of the stream, so the estimate replaces the hardcoded
+    // initial bitrate instead of waiting for enough frames to be reported
+    pub fn report_bandwidth_estimate(&mut self, bandwidth_bps: f32) {
+        self.bitrate_average.retain(0);
+        self.bitrate_average.submit_sample(bandwidth_bps);
+
+        self.bandwidth_estimate_bps = Some(bandwidth_bps);
+        if let Some(controller) = &mut self.delay_gradient_controller {
+            controller.set_target_bitrate_bps(bandwidth_bps);
+        }
+
+        self.update_needed = true;
+    }
+
+    // Bitrate and frame interval last requested to the encoder, used to pace video packets
+    pub fn pacing_target(&self) -> (f32, Duration) {
+        (self.last_requested_bitrate_bps, self.last_frame_interval)
     }
 
     pub fn get_encoder_params(
@@ -215,20 +311,23 @@ impl BitrateManager {
                     }
                 }
 
-                if let Switch::Enabled(max) = max_bitrate_mbps {
-                    let max = *max as f32 * 1e6;
-                    bitrate_bps = f32::min(bitrate_bps, max);
-
-                    stats.manual_max_bps = Some(max);
-                }
-                if let Switch::Enabled(min) = min_bitrate_mbps {
-                    let min = *min as f32 * 1e6;
-                    bitrate_bps = f32::max(bitrate_bps, min);
-
-                    stats.manual_min_bps = Some(min);
-                }
+                apply_manual_limits(bitrate_bps, max_bitrate_mbps, min_bitrate_mbps, &mut stats)
+            }
+            BitrateMode::DelayGradient {
+                initial_bitrate_mbps,
+                max_bitrate_mbps,
+                min_bitrate_mbps,
+                ..
+            } => {
+                let bitrate_bps = self
+                    .delay_gradient_controller
+                    .as_ref()
+                    .map(|controller| controller.target_bitrate_bps())
+                    .or(self.bandwidth_estimate_bps)
+                    .unwrap_or(*initial_bitrate_mbps as f32 * 1e6);
+                stats.scaled_calculated_bps = Some(bitrate_bps);
 
-                bitrate_bps
+                apply_manual_limits(bitrate_bps, max_bitrate_mbps, min_bitrate_mbps, &mut stats)
             }
         };
 
@@ -240,6 +339,9 @@ impl BitrateManager {
             self.nominal_frame_interval
         };
 
+        self.last_requested_bitrate_bps = bitrate_bps;
+        self.last_frame_interval = frame_interval;
+
         (
             FfiDynamicEncoderParams {
                 updated: 1,
@@ -0,0 +1,401 @@
+// Delay-based congestion control, in the style of Google Congestion Control (GCC).
+// The variation of the network latency between consecutive frames is accumulated and smoothed. The
+// slope of its trendline tells whether queues are building up in the network (overuse), draining
+// (underuse) or stable. The overuse threshold adapts to the trend, so that the detector is not
+// starved by concurrent TCP-like flows nor triggered by jitter alone.
+// The target bitrate follows an AIMD scheme: it is decreased multiplicatively relative to the
+// received bitrate on overuse, held on underuse (queues are draining) and increased otherwise.
+// Frame timestamps are used as clock, so the controller does not depend on when statistics arrive.
+// The received bitrate is measured over the arrival times of the frames at the client, estimated
+// as their timestamp plus their network latency.
+
+use std::{collections::VecDeque, time::Duration};
+
+const TRENDLINE_SMOOTHING_COEFF: f64 = 0.9;
+const TRENDLINE_THRESHOLD_GAIN: f64 = 4.0;
+const MAX_TRENDLINE_DELTAS: usize = 60;
+
+const OVERUSE_TIME_THRESHOLD: Duration = Duration::from_millis(10);
+const INITIAL_THRESHOLD_MS: f64 = 12.5;
+const MIN_THRESHOLD_MS: f64 = 6.0;
+const MAX_THRESHOLD_MS: f64 = 600.0;
+const THRESHOLD_GAIN_UP: f64 = 0.0087;
+const THRESHOLD_GAIN_DOWN: f64 = 0.039;
+// Trends too far above the threshold (like latency spikes) don't make it adapt
+const MAX_THRESHOLD_ADAPTATION_MS: f64 = 15.0;
+const MAX_THRESHOLD_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+const RECEIVED_RATE_WINDOW: Duration = Duration::from_secs(1);
+const MIN_DECREASE_INTERVAL: Duration = Duration::from_millis(300);
+// The target bitrate cannot grow too far above what the client is actually receiving
+const MAX_TARGET_TO_RECEIVED_RATIO: f32 = 1.5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BandwidthUsage {
+    Normal,
+    Overusing,
+    Underusing,
+}
+
+pub struct DelayGradientParams {
+    pub min_bitrate_bps: f32,
+    pub max_bitrate_bps: f32,
+    pub decrease_multiplier: f32,
+    pub increase_per_second: f32,
+    pub trendline_window_size: usize,
+}
+
+pub struct DelayGradientController {
+    target_bitrate_bps: f32,
+    // timestamp, network latency
+    previous_sample: Option<(Duration, Duration)>,
+    accumulated_delay_ms: f64,
+    smoothed_delay_ms: f64,
+    deltas_count: usize,
+    // timestamp (ms), smoothed accumulated delay (ms)
+    trendline_samples: VecDeque<(f64, f64)>,
+    previous_trend: f64,
+    threshold_ms: f64,
+    overuse_start: Option<Duration>,
+    usage: BandwidthUsage,
+    // estimated arrival time, size in bits
+    received_frames: VecDeque<(Duration, usize)>,
+    last_decrease: Option<Duration>,
+}
+
+impl DelayGradientController {
+    pub fn new(initial_bitrate_bps: f32) -> Self {
+        Self {
+            target_bitrate_bps: initial_bitrate_bps,
+            previous_sample: None,
+            accumulated_delay_ms: 0.0,
+            smoothed_delay_ms: 0.0,
+            deltas_count: 0,
+            trendline_samples: VecDeque::new(),
+            previous_trend: 0.0,
+            threshold_ms: INITIAL_THRESHOLD_MS,
+            overuse_start: None,
+            usage: BandwidthUsage::Normal,
+            received_frames: VecDeque::new(),
+            last_decrease: None,
+        }
+    }
+
+    pub fn target_bitrate_bps(&self) -> f32 {
+        self.target_bitrate_bps
+    }
+
+    // Limits are applied at the next frame report
+    pub fn set_target_bitrate_bps(&mut self, bitrate_bps: f32) {
+        self.target_bitrate_bps = bitrate_bps;
+    }
+
+    fn received_bitrate_bps(&self) -> Option<f32> {
+        // Arrival times can be out of order, since the network latency varies
+        let (first_arrival, first_size_bits) = self
+            .received_frames
+            .iter()
+            .min_by_key(|(arrival, _)| *arrival)?;
+        let last_arrival = self
+            .received_frames
+            .iter()
+            .map(|(arrival, _)| *arrival)
+            .max()?;
+        let span = last_arrival - *first_arrival;
+        if span.is_zero() {
+            return None;
+        }
+
+        // The first frame marks the start of the window
+        let bits = self
+            .received_frames
+            .iter()
+            .map(|(_, size_bits)| size_bits)
+            .sum::<usize>()
+            - first_size_bits;
+
+        Some(bits as f32 / span.as_secs_f32())
+    }
+
+    // Least squares slope of the smoothed delay over time
+    fn trendline_slope(&self) -> Option<f64> {
+        let count = self.trendline_samples.len() as f64;
+        let mean_x = self.trendline_samples.iter().map(|(x, _)| x).sum::<f64>() / count;
+        let mean_y = self.trendline_samples.iter().map(|(_, y)| y).sum::<f64>() / count;
+
+        let (numerator, denominator) =
+            self.trendline_samples
+                .iter()
+                .fold((0.0, 0.0), |(numerator, denominator), (x, y)| {
+                    (
+                        numerator + (x - mean_x) * (y - mean_y),
+                        denominator + (x - mean_x) * (x - mean_x),
+                    )
+                });
+
+        (denominator != 0.0).then(|| numerator / denominator)
+    }
+
+    fn update_threshold(&mut self, modified_trend: f64, interval: Duration) {
+        if modified_trend.abs() > self.threshold_ms + MAX_THRESHOLD_ADAPTATION_MS {
+            return;
+        }
+
+        let gain = if modified_trend.abs() < self.threshold_ms {
+            THRESHOLD_GAIN_DOWN
+        } else {
+            THRESHOLD_GAIN_UP
+        };
+        let interval_ms = interval.min(MAX_THRESHOLD_UPDATE_INTERVAL).as_secs_f64() * 1000.0;
+
+        self.threshold_ms = (self.threshold_ms
+            + gain * (modified_trend.abs() - self.threshold_ms) * interval_ms)
+            .clamp(MIN_THRESHOLD_MS, MAX_THRESHOLD_MS);
+    }
+
+    fn detect_usage(&mut self, timestamp: Duration, interval: Duration) {
+        let trend = if self.trendline_samples.len() >= 2 {
+            self.trendline_slope().unwrap_or(self.previous_trend)
+        } else {
+            self.previous_trend
+        };
+        let modified_trend = usize::min(self.deltas_count, MAX_TRENDLINE_DELTAS) as f64
+            * trend
+            * TRENDLINE_THRESHOLD_GAIN;
+
+        if modified_trend > self.threshold_ms {
+            let overuse_start = *self.overuse_start.get_or_insert(timestamp);
+
+            if timestamp.saturating_sub(overuse_start) >= OVERUSE_TIME_THRESHOLD
+                && trend >= self.previous_trend
+            {
+                self.usage = BandwidthUsage::Overusing;
+            }
+        } else if modified_trend < -self.threshold_ms {
+            self.overuse_start = None;
+            self.usage = BandwidthUsage::Underusing;
+        } else {
+            self.overuse_start = None;
+            self.usage = BandwidthUsage::Normal;
+        }
+
+        self.update_threshold(modified_trend, interval);
+        self.previous_trend = trend;
+    }
+
+    // Returns true if the target bitrate has been decreased and should be applied immediately
+    pub fn report_frame(
+        &mut self,
+        params: &DelayGradientParams,
+        timestamp: Duration,
+        network_latency: Duration,
+        size_bits: Option<usize>,
+    ) -> bool {
+        let arrival = timestamp + network_latency;
+        if let Some(size_bits) = size_bits {
+            self.received_frames.push_back((arrival, size_bits));
+        }
+        while let Some((first_arrival, _)) = self.received_frames.front() {
+            if arrival.saturating_sub(*first_arrival) > RECEIVED_RATE_WINDOW {
+                self.received_frames.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let Some((previous_timestamp, previous_latency)) = self.previous_sample else {
+            self.previous_sample = Some((timestamp, network_latency));
+
+            return false;
+        };
+        // Statistics can arrive out of order
+        if timestamp <= previous_timestamp {
+            return false;
+        }
+        self.previous_sample = Some((timestamp, network_latency));
+        let interval = timestamp - previous_timestamp;
+
+        let delay_variation_ms =
+            (network_latency.as_secs_f64() - previous_latency.as_secs_f64()) * 1000.0;
+        self.accumulated_delay_ms += delay_variation_ms;
+        self.smoothed_delay_ms = TRENDLINE_SMOOTHING_COEFF * self.smoothed_delay_ms
+            + (1.0 - TRENDLINE_SMOOTHING_COEFF) * self.accumulated_delay_ms;
+        self.deltas_count += 1;
+
+        self.trendline_samples
+            .push_back((timestamp.as_secs_f64() * 1000.0, self.smoothed_delay_ms));
+        while self.trendline_samples.len() > params.trendline_window_size {
+            self.trendline_samples.pop_front();
+        }
+
+        self.detect_usage(timestamp, interval);
+
+        let received_bitrate_bps = self.received_bitrate_bps();
+
+        let mut decreased = false;
+        match self.usage {
+            BandwidthUsage::Overusing => {
+                if self
+                    .last_decrease
+                    .map(|last| timestamp.saturating_sub(last) >= MIN_DECREASE_INTERVAL)
+                    .unwrap_or(true)
+                {
+                    let reference_bps = received_bitrate_bps
+                        .map(|received| f32::min(received, self.target_bitrate_bps))
+                        .unwrap_or(self.target_bitrate_bps);
+                    self.target_bitrate_bps = reference_bps * params.decrease_multiplier;
+                    self.last_decrease = Some(timestamp);
+
+                    decreased = true;
+                }
+            }
+            BandwidthUsage::Normal => {
+                self.target_bitrate_bps *=
+                    (1.0 + params.increase_per_second).powf(interval.as_secs_f32());
+
+                if let Some(received) = received_bitrate_bps {
+                    self.target_bitrate_bps = f32::min(
+                        self.target_bitrate_bps,
+                        f32::max(
+                            received * MAX_TARGET_TO_RECEIVED_RATIO,
+                            params.min_bitrate_bps,
+                        ),
+                    );
+                }
+            }
+            BandwidthUsage::Underusing => (),
+        }
+
+        // Note: not using clamp, which panics if the bounds are inverted
+        self.target_bitrate_bps = f32::max(
+            f32::min(self.target_bitrate_bps, params.max_bitrate_bps),
+            params.min_bitrate_bps,
+        );
+
+        decreased
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_INTERVAL: Duration = Duration::from_millis(10);
+    // 10 Mbps at 100 FPS
+    const FRAME_SIZE_BITS: usize = 100_000;
+    const INITIAL_BITRATE_BPS: f32 = 10e6;
+
+    fn params() -> DelayGradientParams {
+        DelayGradientParams {
+            min_bitrate_bps: 1e6,
+            max_bitrate_bps: 100e6,
+            decrease_multiplier: 0.85,
+            increase_per_second: 0.5,
+            trendline_window_size: 20,
+        }
+    }
+
+    // Reports the frames from first_frame to last_frame, with the network latency given by
+    // latency_ms. Returns the timestamps of the decreases
+    fn report_frames(
+        controller: &mut DelayGradientController,
+        params: &DelayGradientParams,
+        frames: std::ops::Range<u32>,
+        latency_ms: impl Fn(u32) -> f32,
+    ) -> Vec<Duration> {
+        let mut decreases = vec![];
+        for frame in frames {
+            let timestamp = FRAME_INTERVAL * frame;
+            let latency = Duration::from_secs_f32(latency_ms(frame) / 1000.0);
+            if controller.report_frame(params, timestamp, latency, Some(FRAME_SIZE_BITS)) {
+                decreases.push(timestamp);
+            }
+        }
+
+        decreases
+    }
+
+    #[test]
+    fn trendline_slope() {
+        let mut controller = DelayGradientController::new(INITIAL_BITRATE_BPS);
+
+        controller.trendline_samples = [(0.0, 1.0)].into_iter().collect();
+        assert_eq!(controller.trendline_slope(), None);
+
+        controller.trendline_samples = (0..10).map(|x| (x as f64, 2.0 * x as f64 + 1.0)).collect();
+        let slope = controller.trendline_slope().unwrap();
+        assert!((slope - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stable_latency_increases_bitrate() {
+        let mut controller = DelayGradientController::new(INITIAL_BITRATE_BPS);
+
+        let decreases = report_frames(&mut controller, &params(), 0..100, |_| 20.0);
+
+        assert!(decreases.is_empty());
+        assert_eq!(controller.usage, BandwidthUsage::Normal);
+        assert!(controller.target_bitrate_bps() > INITIAL_BITRATE_BPS);
+        // Limited by the received bitrate
+        assert!(
+            controller.target_bitrate_bps() <= INITIAL_BITRATE_BPS * MAX_TARGET_TO_RECEIVED_RATIO
+        );
+    }
+
+    #[test]
+    fn growing_latency_is_overuse() {
+        let mut controller = DelayGradientController::new(INITIAL_BITRATE_BPS);
+
+        report_frames(&mut controller, &params(), 0..50, |_| 20.0);
+        let stable_bitrate_bps = controller.target_bitrate_bps();
+
+        // A queue builds up: the latency grows by 1 ms every frame
+        let decreases = report_frames(&mut controller, &params(), 50..150, |frame| {
+            20.0 + (frame - 50) as f32
+        });
+
+        assert_eq!(controller.usage, BandwidthUsage::Overusing);
+        assert!(!decreases.is_empty());
+        for pair in decreases.windows(2) {
+            assert!(pair[1] - pair[0] >= MIN_DECREASE_INTERVAL);
+        }
+        // Decreased relative to the received bitrate, which is lower than the encoded bitrate
+        assert!(
+            controller.target_bitrate_bps() < stable_bitrate_bps * params().decrease_multiplier
+        );
+    }
+
+    #[test]
+    fn decreasing_latency_holds_bitrate() {
+        let mut controller = DelayGradientController::new(INITIAL_BITRATE_BPS);
+
+        // A queue drains: the latency decreases by 1 ms every frame
+        report_frames(&mut controller, &params(), 0..50, |frame| {
+            200.0 - frame as f32
+        });
+        assert_eq!(controller.usage, BandwidthUsage::Underusing);
+        let bitrate_bps = controller.target_bitrate_bps();
+
+        report_frames(&mut controller, &params(), 50..60, |frame| {
+            200.0 - frame as f32
+        });
+        assert_eq!(controller.usage, BandwidthUsage::Underusing);
+        assert_eq!(controller.target_bitrate_bps(), bitrate_bps);
+    }
+
+    #[test]
+    fn bitrate_limits() {
+        let params = DelayGradientParams {
+            max_bitrate_bps: 5e6,
+            ..params()
+        };
+        let mut controller = DelayGradientController::new(INITIAL_BITRATE_BPS);
+
+        report_frames(&mut controller, &params, 0..2, |_| 20.0);
+        assert_eq!(controller.target_bitrate_bps(), params.max_bitrate_bps);
+
+        controller.set_target_bitrate_bps(1.0);
+        report_frames(&mut controller, &params, 2..3, |_| 20.0);
+        assert_eq!(controller.target_bitrate_bps(), params.min_bitrate_bps);
+    }
+}
@@ -1,14 +1,16 @@
 use crate::{
+    bandwidth_probe,
     bitrate::BitrateManager,
     face_tracking::FaceTrackingSink,
     hand_gestures::{trigger_hand_gesture_actions, HandGestureManager, HAND_GESTURE_BUTTON_SET},
     haptics,
+    hooks::{self, HookContext},
     input_mapping::ButtonMappingManager,
-    sockets::WelcomeSocket,
+    sockets::{ClientListener, MdnsService, WelcomeSocket},
     statistics::StatisticsManager,
     tracking::{self, TrackingManager},
-    FfiFov, FfiViewsConfig, VideoPacket, BITRATE_MANAGER, DECODER_CONFIG, SERVER_DATA_MANAGER,
-    STATISTICS_MANAGER, VIDEO_MIRROR_SENDER, VIDEO_RECORDING_FILE,
+    FfiFov, FfiViewsConfig, VideoPacket, BITRATE_MANAGER, DECODER_CONFIG, FILESYSTEM_LAYOUT,
+    SERVER_DATA_MANAGER, STATISTICS_MANAGER, VIDEO_MIRROR_SENDER, VIDEO_RECORDING_FILE,
 };
 use alvr_audio::AudioDevice;
 use alvr_common::{
@@ -18,29 +20,36 @@ use alvr_common::{
     once_cell::sync::Lazy,
     parking_lot::Mutex,
     settings_schema::Switch,
-    warn, AnyhowToCon, ConResult, ConnectionError, LazyMutOpt, RelaxedAtomic, ToCon, BUTTON_INFO,
-    CONTROLLER_PROFILE_INFO, DEVICE_ID_TO_PATH, HEAD_ID, LEFT_HAND_ID,
+    warn, AnyhowToCon, ConResult, ConnectionError, LazyMutOpt, RelaxedAtomic, ToCon, ALVR_VERSION,
+    BUTTON_INFO, CONTROLLER_PROFILE_INFO, DEVICE_ID_TO_PATH, HEAD_ID, LEFT_HAND_ID,
     QUEST_CONTROLLER_PROFILE_PATH, RIGHT_HAND_ID,
 };
 use alvr_events::{ButtonEvent, EventType, HapticsEvent, TrackingEvent};
 use alvr_packets::{
-    ClientConnectionResult, ClientControlPacket, ClientListAction, ClientStatistics, Haptics,
-    ServerControlPacket, StreamConfigPacket, Tracking, VideoPacketHeader, AUDIO, HAPTICS,
-    STATISTICS, TRACKING, VIDEO,
+    BandwidthProbeReport, Capabilities, ClientConnectionResult, ClientControlPacket,
+    ClientListAction, ClientStatistics, Haptics, IdentityChallenge, IdentityProof,
+    IdentityVerificationResult, NegotiatedConfig, ServerControlPacket, SessionResumeResult,
+    StreamConfigPacket, StreamFeature, TimeSyncResponse, Tracking, VideoPacketHeader,
+    VideoStreamingCapabilities, AUDIO, BANDWIDTH_PROBE, HAPTICS, STATISTICS, TRACKING, VIDEO,
+};
+use alvr_session::{
+    CodecType, ConnectionState, ControllersEmulationMode, DisconnectReason, DiscoveryMethod,
+    FrameSize, HookEvent, ImpairmentDirection, OpenvrConfig, PairingRequest, Settings,
+    SocketProtocol,
 };
-use alvr_session::{CodecType, ConnectionState, ControllersEmulationMode, FrameSize, OpenvrConfig};
 use alvr_sockets::{
-    PeerType, ProtoControlSocket, StreamSender, StreamSocketBuilder, KEEPALIVE_INTERVAL,
+    BufferPool, NetworkFilter, Pacer, PeerType, ProtoControlSocket, StreamKeyExchange, StreamKeys,
+    StreamSender, StreamSocket, StreamSocketBuilder, WireFormat, KEEPALIVE_INTERVAL,
     KEEPALIVE_TIMEOUT,
 };
 use std::{
     collections::HashMap,
     io::Write,
     net::IpAddr,
-    process::Command,
+    path::Path,
     ptr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
         Arc,
     },
@@ -51,6 +60,8 @@ use std::{
 const RETRY_CONNECT_MIN_INTERVAL: Duration = Duration::from_secs(1);
 const HANDSHAKE_ACTION_TIMEOUT: Duration = Duration::from_secs(2);
 const STREAMING_RECV_TIMEOUT: Duration = Duration::from_millis(500);
+const SPECTATOR_DROP_LOG_INTERVAL: Duration = Duration::from_secs(1);
+const STANDBY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 
 const MAX_UNREAD_PACKETS: usize = 10; // Applies per stream
 
@@ -58,18 +69,79 @@ pub static SHOULD_CONNECT_TO_CLIENTS: Lazy<Arc<RelaxedAtomic>> =
     Lazy::new(|| Arc::new(RelaxedAtomic::new(false)));
 pub static IS_STREAMING: Lazy<Arc<RelaxedAtomic>> =
     Lazy::new(|| Arc::new(RelaxedAtomic::new(false)));
-static VIDEO_CHANNEL_SENDER: LazyMutOpt<SyncSender<VideoPacket>> = alvr_common::lazy_mut_none();
+static VIDEO_CHANNEL_SENDER: LazyMutOpt<(SyncSender<VideoPacket>, BufferPool<VideoPacketHeader>)> =
+    alvr_common::lazy_mut_none();
 static HAPTICS_SENDER: LazyMutOpt<StreamSender<Haptics>> = alvr_common::lazy_mut_none();
 
+// Outcome of a handshake that did not fail
+enum HandshakeResult {
+    Completed,
+    // The client is not the active client and there is no stream to spectate
+    Standby,
+}
+
 pub enum ClientDisconnectRequest {
     Disconnect,
+    // The client stopped responding. Its session can be resumed
+    ConnectionLost(DisconnectReason),
     ServerShutdown,
     ServerRestart,
 }
 
+impl ClientDisconnectRequest {
+    fn reason(&self) -> DisconnectReason {
+        match self {
+            ClientDisconnectRequest::Disconnect => DisconnectReason::ClientRequest,
+            ClientDisconnectRequest::ConnectionLost(reason) => *reason,
+            ClientDisconnectRequest::ServerShutdown => DisconnectReason::ServerShutdown,
+            ClientDisconnectRequest::ServerRestart => DisconnectReason::RestartRequired,
+        }
+    }
+}
+
 pub static DISCONNECT_CLIENT_NOTIFIER: LazyMutOpt<mpsc::Sender<ClientDisconnectRequest>> =
     alvr_common::lazy_mut_none();
 
+// Result of the negotiation with the client, reused when the session is resumed
+#[derive(Clone)]
+struct StreamNegotiation {
+    settings: Settings,
+    session_json: String,
+    capabilities: Capabilities,
+    streaming_caps: VideoStreamingCapabilities,
+    codec: CodecType,
+    stream_view_resolution: UVec2,
+    target_view_resolution: UVec2,
+    fps: f32,
+    game_audio_sample_rate: u32,
+}
+
+struct ResumableSession {
+    client_hostname: String,
+    // The identity key verified when the session started. The session can be resumed only by a
+    // client that passes the identity challenge with the same key
+    client_public_key: [u8; 32],
+    token: [u8; 16],
+    grace_period: Duration,
+    negotiation: StreamNegotiation,
+    // Set when the connection is lost. The stream resources of the driver are kept until then
+    deadline: Option<Instant>,
+    disconnect_reason: Option<DisconnectReason>,
+}
+
+static RESUMABLE_SESSION: LazyMutOpt<ResumableSession> = alvr_common::lazy_mut_none();
+
+// Negotiation of the stream of the primary client, shared with the spectators
+static STREAM_NEGOTIATION: LazyMutOpt<StreamNegotiation> = alvr_common::lazy_mut_none();
+static SPECTATOR_VIDEO_SENDERS: Lazy<Mutex<HashMap<String, SpectatorVideoSender>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct SpectatorVideoSender {
+    sender: SyncSender<VideoPacket>,
+    // Packets dropped because the queue of the spectator was full
+    dropped_packets: Arc<AtomicUsize>,
+}
+
 fn align32(value: f32) -> u32 {
     ((value / 32.).floor() * 32.) as u32
 }
@@ -209,14 +281,122 @@ pub fn handshake_loop() {
         }
     };
 
+    let mut mdns_service = None;
+    let mut client_listener = None;
+    let mut network_filter = NetworkFilter::new();
+    // Clients in standby are not connected again until the deadline, unless they become active
+    let mut standby_deadlines = HashMap::<String, Instant>::new();
+
     while SHOULD_CONNECT_TO_CLIENTS.value() {
+        end_suspended_session(true);
+
+        let (
+            discovery_config,
+            web_server_port,
+            accept_client_connections,
+            allowed_networks,
+            denied_networks,
+            active_client,
+        ) = {
+            let data_manager = SERVER_DATA_MANAGER.read();
+            let connection = &data_manager.settings().connection;
+
+            (
+                connection.client_discovery.clone(),
+                connection.web_server_port,
+                connection.accept_client_connections,
+                connection.allowed_networks.clone(),
+                connection.denied_networks.clone(),
+                data_manager.session().active_client.clone(),
+            )
+        };
+
+        let now = Instant::now();
+        standby_deadlines.retain(|hostname, deadline| {
+            *deadline > now
+                && active_client
+                    .as_ref()
+                    .map(|active_client| active_client != hostname)
+                    .unwrap_or(false)
+        });
+
+        // The rules apply both to discovered clients and to clients that connect directly
+        network_filter.update(&allowed_networks, &denied_networks);
+
+        let advertise_mdns = matches!(
+            &discovery_config,
+            Switch::Enabled(config) if config.method == DiscoveryMethod::Mdns
+        );
+        // The service is registered again if the advertised web server port changed
+        let mdns_outdated = mdns_service
+            .as_ref()
+            .map(|service| service.web_server_port() != web_server_port)
+            .unwrap_or(false);
+        if !advertise_mdns || mdns_outdated {
+            mdns_service = None;
+        }
+        if advertise_mdns && mdns_service.is_none() {
+            match MdnsService::new(web_server_port) {
+                Ok(service) => mdns_service = Some(service),
+                Err(e) => warn!("Failed to advertise mDNS service: {e:?}"),
+            }
+        }
+
+        if !accept_client_connections {
+            client_listener = None;
+        } else if client_listener.is_none() {
+            match ClientListener::new() {
+                Ok(listener) => client_listener = Some(listener),
+                Err(e) => warn!("Failed to listen for client connections: {e:?}"),
+            }
+        }
+
+        if let Some(listener) = &mut client_listener {
+            match listener.accept(HANDSHAKE_ACTION_TIMEOUT, &network_filter) {
+                Ok((proto_socket, client_hostname, client_ip)) => {
+                    // Unlike discovered clients, clients that connect directly can come from
+                    // anywhere: they are not added to the client list, they must have been added
+                    // from the dashboard first
+                    let connection_state = SERVER_DATA_MANAGER
+                        .read()
+                        .client_list()
+                        .get(&client_hostname)
+                        .map(|c| c.connection_state.clone());
+
+                    if connection_state.is_none() {
+                        warn!(
+                            "Connection from unknown client {client_hostname} ({client_ip}) \
+                            refused. Add the client from the dashboard first"
+                        );
+                    } else if connection_state == Some(ConnectionState::Disconnected)
+                        && !standby_deadlines.contains_key(&client_hostname)
+                    {
+                        match connection_pipeline(proto_socket, client_ip, client_hostname.clone())
+                        {
+                            Ok(HandshakeResult::Completed) => (),
+                            Ok(HandshakeResult::Standby) => {
+                                standby_deadlines
+                                    .insert(client_hostname, now + STANDBY_RETRY_INTERVAL);
+                            }
+                            Err(e) => error!("Handshake error for {client_hostname}: {e}"),
+                        }
+                    }
+                }
+                Err(ConnectionError::TryAgain(_)) => (),
+                Err(ConnectionError::Other(e)) => warn!("Client connection error: {e:?}"),
+            }
+        }
+
         let available_manual_client_ips = {
             let mut manual_client_ips = HashMap::new();
             for (hostname, connection_info) in SERVER_DATA_MANAGER
                 .read()
                 .client_list()
                 .iter()
-                .filter(|(_, info)| info.connection_state == ConnectionState::Disconnected)
+                .filter(|(hostname, info)| {
+                    info.connection_state == ConnectionState::Disconnected
+                        && !standby_deadlines.contains_key(*hostname)
+                })
             {
                 for ip in &connection_info.manual_ips {
                     manual_client_ips.insert(*ip, hostname.clone());
@@ -225,32 +405,33 @@ pub fn handshake_loop() {
             manual_client_ips
         };
 
-        if !available_manual_client_ips.is_empty()
-            && try_connect(available_manual_client_ips).is_ok()
-        {
-            thread::sleep(RETRY_CONNECT_MIN_INTERVAL);
-            continue;
+        if !available_manual_client_ips.is_empty() {
+            match try_connect(available_manual_client_ips) {
+                Ok((client_hostname, HandshakeResult::Standby)) => {
+                    standby_deadlines.insert(client_hostname, now + STANDBY_RETRY_INTERVAL);
+                }
+                Ok((_, HandshakeResult::Completed)) => {
+                    thread::sleep(RETRY_CONNECT_MIN_INTERVAL);
+                    continue;
+                }
+                Err(_) => (),
+            }
         }
 
-        let discovery_config = SERVER_DATA_MANAGER
-            .read()
-            .settings()
-            .connection
-            .client_discovery
-            .clone();
         if let Switch::Enabled(config) = discovery_config {
-            let (client_hostname, client_ip) = match welcome_socket.recv() {
-                Ok(pair) => pair,
-                Err(e) => {
-                    if let ConnectionError::Other(e) = e {
-                        warn!("UDP handshake listening error: {e:?}");
-                    }
+            let (client_hostname, client_ip) =
+                match welcome_socket.recv(&network_filter, config.max_packets_per_second) {
+                    Ok(pair) => pair,
+                    Err(e) => {
+                        if let ConnectionError::Other(e) = e {
+                            warn!("UDP handshake listening error: {e:?}");
+                        }
 
-                    continue;
-                }
-            };
+                        continue;
+                    }
+                };
 
-            let trusted = {
+            {
                 let mut data_manager = SERVER_DATA_MANAGER.write();
 
                 data_manager.update_client_list(
@@ -265,27 +446,24 @@ pub fn handshake_loop() {
                     data_manager
                         .update_client_list(client_hostname.clone(), ClientListAction::Trust);
                 }
+            }
 
-                data_manager
-                    .client_list()
-                    .get(&client_hostname)
-                    .map(|c| c.trusted)
-                    .unwrap_or(false)
-            };
-
-            // do not attempt connection if the client is already connected
-            if trusted
-                && SERVER_DATA_MANAGER
-                    .read()
-                    .client_list()
-                    .get(&client_hostname)
-                    .map(|c| c.connection_state == ConnectionState::Disconnected)
-                    .unwrap_or(false)
+            // Untrusted clients are connected too, to start the pairing procedure. Do not attempt
+            // connection if the client is already connected
+            if SERVER_DATA_MANAGER
+                .read()
+                .client_list()
+                .get(&client_hostname)
+                .map(|c| c.connection_state == ConnectionState::Disconnected)
+                .unwrap_or(false)
+                && !standby_deadlines.contains_key(&client_hostname)
             {
-                if let Err(e) =
-                    try_connect([(client_ip, client_hostname.clone())].into_iter().collect())
-                {
-                    error!("Handshake error for {client_hostname}: {e}");
+                match try_connect([(client_ip, client_hostname.clone())].into_iter().collect()) {
+                    Ok((_, HandshakeResult::Completed)) => (),
+                    Ok((_, HandshakeResult::Standby)) => {
+                        standby_deadlines.insert(client_hostname, now + STANDBY_RETRY_INTERVAL);
+                    }
+                    Err(e) => error!("Handshake error for {client_hostname}: {e}"),
                 }
             }
         }
@@ -294,96 +472,269 @@ pub fn handshake_loop() {
     }
 }
 
-fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
-    let (mut proto_socket, client_ip) = ProtoControlSocket::connect_to(
+// Returns the hostname of the client that answered
+fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult<(String, HandshakeResult)> {
+    let (proto_socket, client_ip) = ProtoControlSocket::connect_to(
         Duration::from_secs(1),
         PeerType::AnyClient(client_ips.keys().cloned().collect()),
     )?;
 
-    let (disconnect_sender, disconnect_receiver) = mpsc::channel();
-    *DISCONNECT_CLIENT_NOTIFIER.lock() = Some(disconnect_sender);
-
     let Some(client_hostname) = client_ips.remove(&client_ip) else {
         con_bail!("unreachable");
     };
 
-    struct DropGuard {
-        hostname: String,
-    }
-    impl Drop for DropGuard {
-        fn drop(&mut self) {
-            let mut data_manager_lock = SERVER_DATA_MANAGER.write();
-            if let Some(entry) = data_manager_lock.client_list().get(&self.hostname) {
-                if entry.connection_state
-                    == (ConnectionState::Disconnecting {
-                        should_be_removed: true,
-                    })
-                {
-                    data_manager_lock
-                        .update_client_list(self.hostname.clone(), ClientListAction::RemoveEntry);
+    let result = connection_pipeline(proto_socket, client_ip, client_hostname.clone())?;
 
-                    return;
-                }
-            }
+    Ok((client_hostname, result))
+}
 
-            data_manager_lock.update_client_list(
-                self.hostname.clone(),
-                ClientListAction::SetConnectionState(ConnectionState::Disconnected),
-            );
+/// Sets the client allowed to stream. If another client is streaming, its stream is stopped and the
+/// handshake loop connects the new active client. SteamVR is restarted only if the OpenVR
+/// configuration required by the new client differs.
+pub fn set_active_client(hostname: Option<String>) {
+    let mut data_manager = SERVER_DATA_MANAGER.write();
+    data_manager.session_mut().active_client = hostname.clone();
+
+    let Some(hostname) = hostname else {
+        return;
+    };
+
+    let Some(primary_hostname) = data_manager
+        .client_list()
+        .iter()
+        .find(|(_, info)| info.connection_state == ConnectionState::Streaming)
+        .map(|(primary_hostname, _)| primary_hostname.clone())
+    else {
+        return;
+    };
+
+    if primary_hostname != hostname {
+        info!("Handing the stream over from {primary_hostname} to {hostname}");
+
+        data_manager.update_client_list(
+            primary_hostname,
+            ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
+                should_be_removed: false,
+                reason: DisconnectReason::ClientRequest,
+            }),
+        );
 
-            *DISCONNECT_CLIENT_NOTIFIER.lock() = None;
+        if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
+            notifier.send(ClientDisconnectRequest::Disconnect).ok();
         }
     }
-    let _connection_drop_guard = DropGuard {
-        hostname: client_hostname.clone(),
-    };
+}
 
-    SERVER_DATA_MANAGER.write().update_client_list(
-        client_hostname.clone(),
-        ClientListAction::SetConnectionState(ConnectionState::Connecting),
-    );
+// Token that lets the client resume its session after a connection loss
+fn generate_session_token() -> [u8; 16] {
+    rand::random()
+}
 
-    SERVER_DATA_MANAGER.write().update_client_list(
-        client_hostname.clone(),
-        ClientListAction::UpdateCurrentIp(Some(client_ip)),
-    );
+// Returns the negotiation of the session if the token is valid and the grace period is not over.
+// The session is replaced only once the resumed stream has started
+fn find_resumable_session(
+    client_hostname: &str,
+    client_public_key: &[u8; 32],
+    token: &[u8; 16],
+) -> Option<StreamNegotiation> {
+    RESUMABLE_SESSION
+        .lock()
+        .as_ref()
+        .filter(|session| {
+            session.client_hostname == client_hostname
+                && session.client_public_key == *client_public_key
+                && session.token == *token
+                && session
+                    .deadline
+                    .map(|deadline| Instant::now() < deadline)
+                    .unwrap_or(false)
+        })
+        .map(|session| session.negotiation.clone())
+}
 
-    let maybe_streaming_caps = if let ClientConnectionResult::ConnectionAccepted {
-        client_protocol_id,
-        display_name,
-        streaming_capabilities,
-        ..
-    } = proto_socket.recv(HANDSHAKE_ACTION_TIMEOUT)?
-    {
-        SERVER_DATA_MANAGER.write().update_client_list(
-            client_hostname.clone(),
-            ClientListAction::SetDisplayName(display_name),
+// Starts the grace period of the streaming session. Returns false if it cannot be resumed
+fn suspend_session(reason: DisconnectReason) -> bool {
+    if let Some(session) = &mut *RESUMABLE_SESSION.lock() {
+        session.deadline = Some(Instant::now() + session.grace_period);
+        session.disconnect_reason = Some(reason);
+
+        info!(
+            "Session of {} can be resumed for {}s",
+            session.client_hostname,
+            session.grace_period.as_secs()
         );
 
-        if client_protocol_id != alvr_common::protocol_id() {
-            warn!(
-                "Trusted client is incompatible! Expected protocol ID: {}, found: {}",
-                alvr_common::protocol_id(),
-                client_protocol_id,
-            );
+        true
+    } else {
+        false
+    }
+}
 
-            return Ok(());
+/// Stops the stream of a session waiting to be resumed. If `only_if_expired`, the stream is
+/// stopped only if the grace period is over
+pub fn end_suspended_session(only_if_expired: bool) {
+    let session = {
+        let mut session_lock = RESUMABLE_SESSION.lock();
+        match session_lock.as_ref().and_then(|session| session.deadline) {
+            Some(deadline) if !only_if_expired || Instant::now() >= deadline => session_lock.take(),
+            _ => None,
         }
+    };
+
+    if let Some(session) = session {
+        info!("Session of {} ended", session.client_hostname);
+
+        deinitialize_streaming(
+            session
+                .disconnect_reason
+                .unwrap_or(DisconnectReason::Timeout),
+        );
+    }
+}
+
+fn deinitialize_streaming(reason: DisconnectReason) {
+    *VIDEO_RECORDING_FILE.lock() = None;
+
+    unsafe { crate::DeinitializeStreaming() };
+
+    SERVER_DATA_MANAGER.write().set_settings_client(None);
 
-        streaming_capabilities
+    hooks::end_stream(reason);
+}
+
+fn check_required_features(
+    client_hostname: &str,
+    settings: &Settings,
+    capabilities: &Capabilities,
+) -> ConResult {
+    if !capabilities.supports_stream_headers() {
+        con_bail!("Client {client_hostname} uses incompatible stream packet headers");
+    }
+    if settings.connection.stream_encryption
+        && !capabilities.supports_feature(StreamFeature::StreamEncryption)
+    {
+        con_bail!("Client {client_hostname} does not support stream encryption");
+    }
+    if matches!(
+        settings.connection.stream_protocol,
+        SocketProtocol::Quic { .. }
+    ) && !capabilities.supports_feature(StreamFeature::Quic)
+    {
+        con_bail!("Client {client_hostname} does not support the QUIC stream protocol");
+    }
+
+    Ok(())
+}
+
+// The public keys have been authenticated by the identity challenge
+fn stream_key_exchange(
+    settings: &Settings,
+    key_exchange: StreamKeyExchange,
+    client_public_key: [u8; 32],
+) -> (Option<StreamKeys>, Option<[u8; 32]>) {
+    if settings.connection.stream_encryption {
+        let public_key = key_exchange.public_key();
+
+        (
+            Some(key_exchange.server_stream_keys(client_public_key)),
+            Some(public_key),
+        )
     } else {
-        debug!("Found client in standby. Retrying");
-        return Ok(());
-    };
+        (None, None)
+    }
+}
 
-    let streaming_caps = if let Some(streaming_caps) = maybe_streaming_caps {
-        streaming_caps
+// Stream features not supported by the client are disabled
+fn connect_stream_socket(
+    client_ip: IpAddr,
+    settings: &Settings,
+    capabilities: &Capabilities,
+    fps: f32,
+    stream_keys: Option<StreamKeys>,
+    capture_path: Option<&Path>,
+) -> ConResult<(StreamSocket, StreamSender<VideoPacketHeader>)> {
+    let forward_error_correction =
+        if capabilities.supports_feature(StreamFeature::ForwardErrorCorrection) {
+            settings.connection.forward_error_correction.as_slice()
+        } else {
+            &[]
+        };
+    let shard_retransmission = if capabilities.supports_feature(StreamFeature::ShardRetransmission)
+    {
+        settings.connection.shard_retransmission.as_slice()
     } else {
-        con_bail!("Only streaming clients are supported for now");
+        &[]
+    };
+    let wire_format = if capabilities.supports_feature(StreamFeature::WireFormatV2) {
+        WireFormat::V2
+    } else {
+        WireFormat::V1
     };
 
+    let mut stream_socket = StreamSocketBuilder::connect_to_client(
+        HANDSHAKE_ACTION_TIMEOUT,
+        client_ip,
+        settings.connection.stream_port,
+        settings.connection.stream_protocol,
+        settings.connection.server_send_buffer_bytes,
+        settings.connection.server_recv_buffer_bytes,
+        wire_format,
+        settings.connection.packet_size as _,
+        forward_error_correction,
+        shard_retransmission,
+        Duration::from_secs_f32(1.0 / fps),
+        &settings.connection.stream_priorities,
+        stream_keys,
+        settings
+            .connection
+            .debug
+            .network_impairment
+            .clone()
+            .into_option()
+            .filter(|config| config.direction != ImpairmentDirection::ClientToStreamer),
+        capture_path,
+    )?;
+
+    let mut video_sender = stream_socket.request_stream(VIDEO);
+    if let Switch::Enabled(config) = &settings.connection.video_pacing {
+        video_sender.set_pacer(Some(Pacer::new(
+            config.frame_interval_fraction,
+            config.min_paced_frame_size_kb as usize * 1024,
+        )));
+    }
+
+    Ok((stream_socket, video_sender))
+}
+
+fn negotiate_stream(
+    client_hostname: &str,
+    streaming_caps: VideoStreamingCapabilities,
+    client_capabilities: &Capabilities,
+) -> ConResult<StreamNegotiation> {
     let settings = SERVER_DATA_MANAGER.read().settings().clone();
 
+    let capabilities = Capabilities::current().intersection(client_capabilities);
+    if capabilities.negotiated_config_version < 1 {
+        con_bail!("Client {client_hostname} does not support any known stream configuration");
+    }
+
+    let codec = if capabilities.supports_codec(settings.video.preferred_codec) {
+        settings.video.preferred_codec
+    } else if let Some(codec) = [CodecType::H264, CodecType::Hevc]
+        .into_iter()
+        .find(|codec| capabilities.supports_codec(*codec))
+    {
+        warn!(
+            "Client {client_hostname} does not support the preferred codec. Using {}",
+            alvr_packets::codec_name(codec)
+        );
+        codec
+    } else {
+        con_bail!("Client {client_hostname} does not support any codec");
+    };
+
+    check_required_features(client_hostname, &settings, &capabilities)?;
+
     fn get_view_res(config: FrameSize, default_res: UVec2) -> UVec2 {
         let res = match config {
             FrameSize::Scale(scale) => default_res.as_vec2() * scale,
@@ -403,12 +754,12 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
     }
 
     let stream_view_resolution = get_view_res(
-        settings.video.transcoding_view_resolution,
+        settings.video.transcoding_view_resolution.clone(),
         streaming_caps.default_view_resolution,
     );
 
     let target_view_resolution = get_view_res(
-        settings.video.emulated_headset_view_resolution,
+        settings.video.emulated_headset_view_resolution.clone(),
         streaming_caps.default_view_resolution,
     );
 
@@ -459,17 +810,328 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
             0
         };
 
+    let session_json = {
+        let session = SERVER_DATA_MANAGER.read().session().clone();
+        let session =
+            alvr_server_io::with_client_overrides(&session, client_hostname).unwrap_or(session);
+        serde_json::to_string(&session).to_con()?
+    };
+
+    Ok(StreamNegotiation {
+        settings,
+        session_json,
+        capabilities,
+        streaming_caps,
+        codec,
+        stream_view_resolution,
+        target_view_resolution,
+        fps,
+        game_audio_sample_rate,
+    })
+}
+
+struct ConnectionDropGuard {
+    hostname: String,
+    // Recorded if the connection ends without going through the Disconnecting state. None if the
+    // connection is closed without a failure, like for clients in standby
+    reason: Option<DisconnectReason>,
+    // The settings overrides of the client are applied but the stream did not start. Once the
+    // stream started, they are removed when the stream is deinitialized
+    reset_settings_client: bool,
+}
+
+impl Drop for ConnectionDropGuard {
+    fn drop(&mut self) {
+        let mut data_manager_lock = SERVER_DATA_MANAGER.write();
+        if self.reset_settings_client {
+            data_manager_lock.set_settings_client(None);
+        }
+
+        if let Some(entry) = data_manager_lock.client_list().get(&self.hostname) {
+            match entry.connection_state {
+                ConnectionState::Disconnecting {
+                    should_be_removed: true,
+                    ..
+                } => {
+                    data_manager_lock
+                        .update_client_list(self.hostname.clone(), ClientListAction::RemoveEntry);
+
+                    return;
+                }
+                ConnectionState::Disconnecting { .. } => (),
+                _ => {
+                    if let Some(reason) = self.reason {
+                        data_manager_lock.update_client_list(
+                            self.hostname.clone(),
+                            ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
+                                should_be_removed: false,
+                                reason,
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+
+        data_manager_lock.update_client_list(
+            self.hostname.clone(),
+            ClientListAction::SetConnectionState(ConnectionState::Disconnected),
+        );
+    }
+}
+
+fn connection_pipeline(
+    mut proto_socket: ProtoControlSocket,
+    client_ip: IpAddr,
+    client_hostname: String,
+) -> ConResult<HandshakeResult> {
+    // Handshake failures not classified otherwise are caused by the socket
+    let mut connection_drop_guard = ConnectionDropGuard {
+        hostname: client_hostname.clone(),
+        reason: Some(DisconnectReason::SocketError),
+        reset_settings_client: false,
+    };
+
+    SERVER_DATA_MANAGER.write().update_client_list(
+        client_hostname.clone(),
+        ClientListAction::SetConnectionState(ConnectionState::Connecting),
+    );
+
+    SERVER_DATA_MANAGER.write().update_client_list(
+        client_hostname.clone(),
+        ClientListAction::UpdateCurrentIp(Some(client_ip)),
+    );
+
+    let Some(client_config) = SERVER_DATA_MANAGER
+        .read()
+        .client_list()
+        .get(&client_hostname)
+        .cloned()
+    else {
+        con_bail!("Client {client_hostname} not found");
+    };
+
+    // Reuse the secret of a pending pairing request, so the PIN shown on the headset stays the same
+    let pairing_secret = client_config
+        .pairing_request
+        .as_ref()
+        .map(|request| request.pairing_secret)
+        .unwrap_or_else(alvr_sockets::generate_identity_nonce);
+    let nonce = alvr_sockets::generate_identity_nonce();
+    let key_exchange = StreamKeyExchange::new();
+    proto_socket
+        .send(&IdentityChallenge {
+            nonce,
+            stream_public_key: key_exchange.public_key(),
+            pairing_commitment: alvr_sockets::pairing_commitment(&pairing_secret),
+        })
+        .to_con()?;
+    let proof = proto_socket.recv::<IdentityProof>(HANDSHAKE_ACTION_TIMEOUT)?;
+
+    if !alvr_sockets::verify_identity_challenge(
+        &proof.public_key,
+        &nonce,
+        &key_exchange.public_key(),
+        &proof.stream_public_key,
+        &proof.signature,
+    ) {
+        connection_drop_guard.reason = Some(DisconnectReason::Untrusted);
+        proto_socket
+            .send(&IdentityVerificationResult::Rejected)
+            .to_con()?;
+        con_bail!("Client {client_hostname} failed the identity challenge");
+    }
+
+    match client_config.public_key {
+        Some(public_key) if public_key == proof.public_key => (),
+        Some(_) => {
+            connection_drop_guard.reason = Some(DisconnectReason::Untrusted);
+            proto_socket
+                .send(&IdentityVerificationResult::Rejected)
+                .to_con()?;
+            con_bail!(
+                "Client {client_hostname} presented a different identity than the paired one"
+            );
+        }
+        None if client_config.trusted => {
+            // The client was trusted manually or automatically before its identity was known
+            SERVER_DATA_MANAGER.write().update_client_list(
+                client_hostname.clone(),
+                ClientListAction::SetPublicKey(proof.public_key),
+            );
+        }
+        None => {
+            connection_drop_guard.reason = Some(DisconnectReason::Untrusted);
+
+            // The pending request is never replaced by another key, which could have been chosen
+            // knowing the revealed secret
+            if client_config
+                .pairing_request
+                .as_ref()
+                .map(|request| request.public_key != proof.public_key)
+                .unwrap_or(false)
+            {
+                proto_socket
+                    .send(&IdentityVerificationResult::Rejected)
+                    .to_con()?;
+                con_bail!(
+                    "Client {client_hostname} presented a different identity than the one of its \
+                    pending pairing request. Remove the client to pair it again"
+                );
+            }
+
+            let pin = alvr_sockets::pairing_pin(&proof.public_key, &pairing_secret);
+            SERVER_DATA_MANAGER.write().update_client_list(
+                client_hostname.clone(),
+                ClientListAction::SetPairingRequest(Some(PairingRequest {
+                    public_key: proof.public_key,
+                    pairing_secret,
+                    pin,
+                })),
+            );
+
+            proto_socket
+                .send(&IdentityVerificationResult::PairingRequired { pairing_secret })
+                .to_con()?;
+            info!("Client {client_hostname} requires pairing. Enter the PIN shown on the headset");
+
+            return Ok(HandshakeResult::Completed);
+        }
+    }
+
+    proto_socket
+        .send(&IdentityVerificationResult::Verified)
+        .to_con()?;
+
+    let mut connection_result = proto_socket.recv(HANDSHAKE_ACTION_TIMEOUT)?;
+
+    let is_active_client = SERVER_DATA_MANAGER
+        .read()
+        .session()
+        .active_client
+        .as_ref()
+        .map(|active_client| *active_client == client_hostname)
+        .unwrap_or(true);
+
+    let mut resumed_session = None;
+    if let ClientConnectionResult::ResumeSession { token } = connection_result {
+        if let Some(negotiation) = is_active_client
+            .then(|| find_resumable_session(&client_hostname, &proof.public_key, &token))
+            .flatten()
+        {
+            proto_socket.send(&SessionResumeResult::Resumed).to_con()?;
+            resumed_session = Some(negotiation);
+        } else {
+            proto_socket.send(&SessionResumeResult::Rejected).to_con()?;
+            connection_result = proto_socket.recv(HANDSHAKE_ACTION_TIMEOUT)?;
+        }
+    }
+    let is_resumed = resumed_session.is_some();
+
+    let negotiation = if let Some(resumed_session) = resumed_session {
+        info!("Client {client_hostname} is resuming its session");
+
+        resumed_session
+    } else {
+        let ClientConnectionResult::ConnectionAccepted {
+            client_version,
+            capabilities,
+            display_name,
+            streaming_capabilities,
+            ..
+        } = connection_result
+        else {
+            connection_drop_guard.reason = None;
+            debug!("Found client in standby. Retrying");
+            return Ok(HandshakeResult::Completed);
+        };
+
+        SERVER_DATA_MANAGER.write().update_client_list(
+            client_hostname.clone(),
+            ClientListAction::SetDisplayName(display_name),
+        );
+
+        if client_version != ALVR_VERSION.to_string() {
+            info!("Client {client_hostname} has version {client_version}");
+        }
+
+        let Some(streaming_caps) = streaming_capabilities else {
+            connection_drop_guard.reason = Some(DisconnectReason::ProtocolMismatch);
+            con_bail!("Only streaming clients are supported for now");
+        };
+
+        if IS_STREAMING.value() {
+            if !SERVER_DATA_MANAGER
+                .read()
+                .settings()
+                .connection
+                .accept_spectators
+            {
+                connection_drop_guard.reason = None;
+                debug!("Client {client_hostname} cannot connect while another client is streaming");
+                return Ok(HandshakeResult::Completed);
+            }
+
+            spectator_pipeline(
+                proto_socket,
+                client_ip,
+                client_hostname,
+                &capabilities,
+                key_exchange,
+                proof.stream_public_key,
+                connection_drop_guard,
+            )?;
+
+            return Ok(HandshakeResult::Completed);
+        }
+
+        if !is_active_client {
+            connection_drop_guard.reason = None;
+            debug!("Client {client_hostname} is not the active client. Retrying later");
+            return Ok(HandshakeResult::Standby);
+        }
+
+        // The stream of a session waiting to be resumed cannot coexist with a new one
+        end_suspended_session(false);
+
+        // The settings overrides of the client stay applied until the stream is deinitialized
+        SERVER_DATA_MANAGER
+            .write()
+            .set_settings_client(Some(client_hostname.clone()));
+        connection_drop_guard.reset_settings_client = true;
+
+        connection_drop_guard.reason = Some(DisconnectReason::ProtocolMismatch);
+        let negotiation = negotiate_stream(&client_hostname, streaming_caps, &capabilities)?;
+        connection_drop_guard.reason = Some(DisconnectReason::SocketError);
+
+        negotiation
+    };
+
+    let StreamNegotiation {
+        settings,
+        capabilities,
+        streaming_caps,
+        codec,
+        stream_view_resolution,
+        target_view_resolution,
+        fps,
+        game_audio_sample_rate,
+        ..
+    } = negotiation.clone();
+
+    let (stream_keys, server_public_key) =
+        stream_key_exchange(&settings, key_exchange, proof.stream_public_key);
+
     let client_config = StreamConfigPacket {
-        session: {
-            let session = SERVER_DATA_MANAGER.read().session().clone();
-            serde_json::to_string(&session).to_con()?
+        session: negotiation.session_json.clone(),
+        negotiated: NegotiatedConfig::V1 {
+            view_resolution: stream_view_resolution,
+            refresh_rate_hint: fps,
+            game_audio_sample_rate,
+            codec,
+            stream_public_key: server_public_key,
+            capabilities: capabilities.clone(),
         },
-        negotiated: serde_json::json!({
-            "view_resolution": stream_view_resolution,
-            "refresh_rate_hint": fps,
-            "game_audio_sample_rate": game_audio_sample_rate,
-        })
-        .to_string(),
     };
     proto_socket.send(&client_config).to_con()?;
 
@@ -482,8 +1144,10 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
     new_openvr_config.target_eye_resolution_width = target_view_resolution.x;
     new_openvr_config.target_eye_resolution_height = target_view_resolution.y;
     new_openvr_config.refresh_rate = fps as _;
+    new_openvr_config.codec = matches!(codec, CodecType::Hevc) as _;
 
-    if SERVER_DATA_MANAGER.read().session().openvr_config != new_openvr_config {
+    // The driver keeps running with the configuration of a resumed session
+    if !is_resumed && SERVER_DATA_MANAGER.read().session().openvr_config != new_openvr_config {
         SERVER_DATA_MANAGER.write().session_mut().openvr_config = new_openvr_config;
 
         control_sender.send(&ServerControlPacket::Restarting).ok();
@@ -497,6 +1161,7 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
 
     let signal = control_receiver.recv(HANDSHAKE_ACTION_TIMEOUT)?;
     if !matches!(signal, ClientControlPacket::StreamReady) {
+        connection_drop_guard.reason = Some(DisconnectReason::ProtocolMismatch);
         con_bail!("Got unexpected packet waiting for stream ack");
     }
 
@@ -512,17 +1177,20 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
 
     *BITRATE_MANAGER.lock() = BitrateManager::new(settings.video.bitrate.history_size, fps);
 
-    let mut stream_socket = StreamSocketBuilder::connect_to_client(
-        HANDSHAKE_ACTION_TIMEOUT,
+    let shard_capture_path = settings.connection.debug.shard_capture.then(|| {
+        FILESYSTEM_LAYOUT.log_dir.join(format!(
+            "shards.{}.alvrcap",
+            chrono::Local::now().format("%F.%H-%M-%S")
+        ))
+    });
+    let (mut stream_socket, mut video_sender) = connect_stream_socket(
         client_ip,
-        settings.connection.stream_port,
-        settings.connection.stream_protocol,
-        settings.connection.server_send_buffer_bytes,
-        settings.connection.server_recv_buffer_bytes,
-        settings.connection.packet_size as _,
+        &settings,
+        &capabilities,
+        fps,
+        stream_keys,
+        shard_capture_path.as_deref(),
     )?;
-
-    let mut video_sender = stream_socket.request_stream(VIDEO);
     let game_audio_sender = stream_socket.request_stream(AUDIO);
     let microphone_receiver = stream_socket.subscribe_to_stream(AUDIO, MAX_UNREAD_PACKETS);
     let mut tracking_receiver =
@@ -530,34 +1198,95 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
     let haptics_sender = stream_socket.request_stream(HAPTICS);
     let mut statics_receiver =
         stream_socket.subscribe_to_stream::<ClientStatistics>(STATISTICS, MAX_UNREAD_PACKETS);
+    let send_scheduler = stream_socket.send_scheduler();
+
+    let bandwidth_probe_config = settings.connection.bandwidth_probe.clone().into_option();
+    let bandwidth_probe_sender = (capabilities.supports_feature(StreamFeature::BandwidthProbe)
+        && bandwidth_probe_config.is_some())
+    .then(|| stream_socket.request_stream(BANDWIDTH_PROBE));
 
     // Note: from here on, the function MUST be infallible. Failure to respect this might leave
     // lingering objects that prevent reconnection.
     IS_STREAMING.set(true);
+    connection_drop_guard.reset_settings_client = false;
+
+    let (disconnect_sender, disconnect_receiver) = mpsc::channel();
+    *DISCONNECT_CLIENT_NOTIFIER.lock() = Some(disconnect_sender);
+    *STREAM_NEGOTIATION.lock() = Some(negotiation.clone());
+
+    // A new token is issued for each stream
+    *RESUMABLE_SESSION.lock() = if let (Switch::Enabled(config), true) = (
+        &settings.connection.session_resumption,
+        capabilities.supports_packet("ServerControlPacket::SessionResumeToken"),
+    ) {
+        let token = generate_session_token();
+        let grace_period = Duration::from_secs(config.grace_period_s);
+        control_sender
+            .send(&ServerControlPacket::SessionResumeToken {
+                token,
+                grace_period,
+            })
+            .ok();
+
+        Some(ResumableSession {
+            client_hostname: client_hostname.clone(),
+            client_public_key: proof.public_key,
+            token,
+            grace_period,
+            negotiation,
+            deadline: None,
+            disconnect_reason: None,
+        })
+    } else {
+        None
+    };
 
     let (video_channel_sender, video_channel_receiver) =
         std::sync::mpsc::sync_channel(settings.connection.max_queued_server_video_frames);
-    *VIDEO_CHANNEL_SENDER.lock() = Some(video_channel_sender);
+    *VIDEO_CHANNEL_SENDER.lock() = Some((video_channel_sender, video_sender.buffer_pool()));
     *HAPTICS_SENDER.lock() = Some(haptics_sender);
 
     let video_send_thread = thread::spawn(move || {
         while IS_STREAMING.value() {
-            let VideoPacket { header, payload } =
-                match video_channel_receiver.recv_timeout(STREAMING_RECV_TIMEOUT) {
-                    Ok(packet) => packet,
-                    Err(RecvTimeoutError::Timeout) => continue,
-                    Err(RecvTimeoutError::Disconnected) => return,
-                };
+            let packet = match video_channel_receiver.recv_timeout(STREAMING_RECV_TIMEOUT) {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
 
-            let mut buffer = video_sender.get_buffer(&header).unwrap();
-            // todo: make encoder write to socket buffers directly to avoid copy
-            buffer
-                .get_range_mut(0, payload.len())
-                .copy_from_slice(&payload);
-            video_sender.send(buffer).ok();
+            if let Some(pacer) = video_sender.pacer_mut() {
+                let (bitrate_bps, frame_interval) = BITRATE_MANAGER.lock().pacing_target();
+                pacer.set_target(bitrate_bps, frame_interval);
+            }
+
+            video_sender.send_shared(&packet).ok();
+
+            let parity_bytes = video_sender.take_parity_bytes_sent();
+            let pacing_stats = video_sender
+                .pacer_mut()
+                .map(|pacer| pacer.take_statistics())
+                .unwrap_or_default();
+            if parity_bytes > 0 || pacing_stats.paced_packets > 0 {
+                if let Some(stats) = &mut *STATISTICS_MANAGER.lock() {
+                    stats.report_fec_parity_sent(parity_bytes);
+                    stats.report_pacing(pacing_stats);
+                }
+            }
         }
     });
 
+    let bandwidth_probe_thread = if let (Some(mut sender), Some(config)) =
+        (bandwidth_probe_sender, bandwidth_probe_config.clone())
+    {
+        thread::spawn(move || {
+            if let Err(e) = bandwidth_probe::send_probe(&mut sender, &config) {
+                warn!("Failed to send bandwidth probe: {e}");
+            }
+        })
+    } else {
+        thread::spawn(|| ())
+    };
+
     let game_audio_thread = if let Switch::Enabled(config) = settings.audio.game_audio {
         thread::spawn(move || {
             while IS_STREAMING.value() {
@@ -854,11 +1583,23 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                 Err(ConnectionError::TryAgain(_)) => continue,
                 Err(ConnectionError::Other(_)) => return,
             };
-            let Ok(client_stats) = data.get_header() else {
+            let Ok((client_stats, payload)) = data.get() else {
                 return;
             };
 
+            // Only the client statistics packet that follows the bandwidth probe has a payload
+            if !payload.is_empty() {
+                if let Some(config) = &bandwidth_probe_config {
+                    match bincode::deserialize::<BandwidthProbeReport>(payload) {
+                        Ok(report) => bandwidth_probe::handle_report(&report, config),
+                        Err(e) => warn!("Invalid bandwidth probe report: {e}"),
+                    }
+                }
+            }
+
             if let Some(stats) = &mut *STATISTICS_MANAGER.lock() {
+                stats.report_send_queue_depth(send_scheduler.take_peak_queue_depth());
+
                 let timestamp = client_stats.target_timestamp;
                 let decoder_latency = client_stats.video_decode;
                 let network_latency = stats.report_statistics(client_stats);
@@ -887,10 +1628,15 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                         client_hostname,
                         ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
                             should_be_removed: false,
+                            reason: DisconnectReason::SocketError,
                         }),
                     );
                     if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
-                        notifier.send(ClientDisconnectRequest::Disconnect).ok();
+                        notifier
+                            .send(ClientDisconnectRequest::ConnectionLost(
+                                DisconnectReason::SocketError,
+                            ))
+                            .ok();
                     }
 
                     return;
@@ -926,12 +1672,15 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
         let client_hostname = client_hostname.clone();
         move || {
             let mut disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
+            // None if the stream is stopped by the streamer
+            let mut connection_lost_reason = None;
             while IS_STREAMING.value() {
                 let packet = match control_receiver.recv(STREAMING_RECV_TIMEOUT) {
                     Ok(packet) => packet,
                     Err(ConnectionError::TryAgain(_)) => {
                         if Instant::now() > disconnection_deadline {
                             info!("Client disconnected. Timeout");
+                            connection_lost_reason = Some(DisconnectReason::Timeout);
                             break;
                         } else {
                             continue;
@@ -939,9 +1688,11 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                     }
                     Err(e) => {
                         info!("Client disconnected. Cause: {e}");
+                        connection_lost_reason = Some(DisconnectReason::SocketError);
                         break;
                     }
                 };
+                let receive_time = alvr_common::monotonic_time();
 
                 match packet {
                     ClientControlPacket::PlayspaceSync(packet) => {
@@ -955,6 +1706,8 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                                 config.position_recentering_mode,
                                 config.rotation_recentering_mode,
                             );
+
+                            hooks::run_stream_hooks(HookEvent::Recenter);
                         }
                     }
                     ClientControlPacket::RequestIdr => {
@@ -995,6 +1748,10 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                     ClientControlPacket::Battery(packet) => unsafe {
                         crate::SetBattery(packet.device_id, packet.gauge_value, packet.is_plugged);
 
+                        if packet.device_id == *HEAD_ID {
+                            hooks::report_battery(packet.gauge_value, packet.is_plugged);
+                        }
+
                         if let Some(stats) = &mut *STATISTICS_MANAGER.lock() {
                             stats.report_battery(
                                 packet.device_id,
@@ -1054,20 +1811,41 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                     ClientControlPacket::Log { level, message } => {
                         info!("Client {client_hostname}: [{level:?}] {message}")
                     }
+                    ClientControlPacket::TimeSyncRequest { client_send_time } => {
+                        // Locked before taking the send time, which must be as late as possible
+                        let mut sender = control_sender.lock();
+                        sender
+                            .send(&ServerControlPacket::TimeSyncResponse(TimeSyncResponse {
+                                client_send_time,
+                                server_receive_time: receive_time,
+                                server_send_time: alvr_common::monotonic_time(),
+                            }))
+                            .ok();
+                    }
+                    ClientControlPacket::ClockSync(report) => {
+                        if let Some(stats) = &mut *STATISTICS_MANAGER.lock() {
+                            stats.report_clock_sync(report);
+                        }
+                    }
                     _ => (),
                 }
 
                 disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
             }
 
-            SERVER_DATA_MANAGER.write().update_client_list(
-                client_hostname,
-                ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
-                    should_be_removed: false,
-                }),
-            );
-            if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
-                notifier.send(ClientDisconnectRequest::Disconnect).ok();
+            if let Some(reason) = connection_lost_reason {
+                SERVER_DATA_MANAGER.write().update_client_list(
+                    client_hostname,
+                    ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
+                        should_be_removed: false,
+                        reason,
+                    }),
+                );
+                if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
+                    notifier
+                        .send(ClientDisconnectRequest::ConnectionLost(reason))
+                        .ok();
+                }
             }
         }
     });
@@ -1086,11 +1864,16 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
                             client_hostname,
                             ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
                                 should_be_removed: false,
+                                reason: DisconnectReason::SocketError,
                             }),
                         );
 
                         if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
-                            notifier.send(ClientDisconnectRequest::Disconnect).ok();
+                            notifier
+                                .send(ClientDisconnectRequest::ConnectionLost(
+                                    DisconnectReason::SocketError,
+                                ))
+                                .ok();
                         }
 
                         return;
@@ -1110,69 +1893,104 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
         }
     });
 
-    {
-        let on_connect_script = settings.connection.on_connect_script;
-
-        if !on_connect_script.is_empty() {
-            info!("Running on connect script (connect): {on_connect_script}");
-            if let Err(e) = Command::new(&on_connect_script)
-                .env("ACTION", "connect")
-                .spawn()
-            {
-                warn!("Failed to run connect script: {e}");
-            }
+    if is_resumed {
+        // The encoder is still running, the client only needs the decoder configuration and an IDR
+        if let Some(config) = DECODER_CONFIG.lock().clone() {
+            control_sender
+                .lock()
+                .send(&ServerControlPacket::InitializeDecoder(config))
+                .ok();
+        }
+        unsafe { crate::RequestIDR() };
+    } else {
+        if settings.capture.startup_video_recording {
+            crate::create_recording_file();
         }
-    }
 
-    if settings.capture.startup_video_recording {
-        crate::create_recording_file();
+        unsafe { crate::InitializeStreaming() };
     }
 
-    unsafe { crate::InitializeStreaming() };
+    hooks::start_stream(
+        HookContext {
+            hostname: Some(client_hostname.clone()),
+            ip: Some(client_ip),
+            resolution: Some(stream_view_resolution),
+            fps: Some(fps),
+            codec: Some(codec),
+            ..Default::default()
+        },
+        !is_resumed,
+    );
 
     SERVER_DATA_MANAGER.write().update_client_list(
-        client_hostname,
+        client_hostname.clone(),
         ClientListAction::SetConnectionState(ConnectionState::Streaming),
     );
 
     thread::spawn(move || {
-        let _connection_drop_guard = _connection_drop_guard;
+        let _connection_drop_guard = connection_drop_guard;
 
         let res = disconnect_receiver.recv();
-        if matches!(res, Ok(ClientDisconnectRequest::ServerRestart)) {
-            control_sender
-                .lock()
-                .send(&ServerControlPacket::Restarting)
-                .ok();
+        let reason = res
+            .as_ref()
+            .map(|request| request.reason())
+            .unwrap_or(DisconnectReason::ServerShutdown);
+
+        // The client can be notified only if the connection is still up
+        if !matches!(res, Ok(ClientDisconnectRequest::ConnectionLost(_))) {
+            if capabilities.supports_packet("ServerControlPacket::Disconnecting") {
+                control_sender
+                    .lock()
+                    .send(&ServerControlPacket::Disconnecting(reason))
+                    .ok();
+            } else if reason == DisconnectReason::RestartRequired {
+                control_sender
+                    .lock()
+                    .send(&ServerControlPacket::Restarting)
+                    .ok();
+            }
+        }
+
+        {
+            let mut data_manager = SERVER_DATA_MANAGER.write();
+            let should_be_removed = matches!(
+                data_manager
+                    .client_list()
+                    .get(&client_hostname)
+                    .map(|entry| &entry.connection_state),
+                Some(ConnectionState::Disconnecting {
+                    should_be_removed: true,
+                    ..
+                })
+            );
+            data_manager.update_client_list(
+                client_hostname,
+                ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
+                    should_be_removed,
+                    reason,
+                }),
+            );
         }
 
         // This requests shutdown from threads
         IS_STREAMING.set(false);
+        *DISCONNECT_CLIENT_NOTIFIER.lock() = None;
+        *STREAM_NEGOTIATION.lock() = None;
         *VIDEO_CHANNEL_SENDER.lock() = None;
         *HAPTICS_SENDER.lock() = None;
 
-        *VIDEO_RECORDING_FILE.lock() = None;
-
-        unsafe { crate::DeinitializeStreaming() };
+        // After a connection loss, the stream resources are kept until the grace period is over
+        if !(matches!(res, Ok(ClientDisconnectRequest::ConnectionLost(_)))
+            && suspend_session(reason))
+        {
+            *RESUMABLE_SESSION.lock() = None;
 
-        let on_disconnect_script = SERVER_DATA_MANAGER
-            .read()
-            .settings()
-            .connection
-            .on_disconnect_script
-            .clone();
-        if !on_disconnect_script.is_empty() {
-            info!("Running on disconnect script (disconnect): {on_disconnect_script}");
-            if let Err(e) = Command::new(&on_disconnect_script)
-                .env("ACTION", "disconnect")
-                .spawn()
-            {
-                warn!("Failed to run disconnect script: {e}");
-            }
+            deinitialize_streaming(reason);
         }
 
         // ensure shutdown of threads
         video_send_thread.join().ok();
+        bandwidth_probe_thread.join().ok();
         game_audio_thread.join().ok();
         microphone_thread.join().ok();
         tracking_receive_thread.join().ok();
@@ -1183,6 +2001,312 @@ fn try_connect(mut client_ips: HashMap<IpAddr, String>) -> ConResult {
         lifecycle_check_thread.join().ok();
     });
 
+    Ok(HandshakeResult::Completed)
+}
+
+// Spectators receive the same video and game audio as the primary client. Their tracking, input
+// and statistics are ignored. The stream parameters are the ones negotiated with the primary client
+fn spectator_pipeline(
+    mut proto_socket: ProtoControlSocket,
+    client_ip: IpAddr,
+    client_hostname: String,
+    client_capabilities: &Capabilities,
+    key_exchange: StreamKeyExchange,
+    client_public_key: [u8; 32],
+    mut connection_drop_guard: ConnectionDropGuard,
+) -> ConResult {
+    let Some(negotiation) = STREAM_NEGOTIATION.lock().clone() else {
+        con_bail!("The primary client stopped streaming");
+    };
+    let settings = negotiation.settings;
+
+    connection_drop_guard.reason = Some(DisconnectReason::ProtocolMismatch);
+    let capabilities = Capabilities::current().intersection(client_capabilities);
+    if capabilities.negotiated_config_version < 1 {
+        con_bail!("Client {client_hostname} does not support any known stream configuration");
+    }
+    if !capabilities.supports_codec(negotiation.codec) {
+        con_bail!("Client {client_hostname} does not support the codec of the primary client");
+    }
+    check_required_features(&client_hostname, &settings, &capabilities)?;
+    connection_drop_guard.reason = Some(DisconnectReason::SocketError);
+
+    let (stream_keys, server_public_key) =
+        stream_key_exchange(&settings, key_exchange, client_public_key);
+
+    proto_socket
+        .send(&StreamConfigPacket {
+            session: negotiation.session_json,
+            negotiated: NegotiatedConfig::V1 {
+                view_resolution: negotiation.stream_view_resolution,
+                refresh_rate_hint: negotiation.fps,
+                game_audio_sample_rate: negotiation.game_audio_sample_rate,
+                codec: negotiation.codec,
+                stream_public_key: server_public_key,
+                capabilities: capabilities.clone(),
+            },
+        })
+        .to_con()?;
+
+    let (mut control_sender, mut control_receiver) =
+        proto_socket.split(STREAMING_RECV_TIMEOUT).to_con()?;
+
+    control_sender
+        .send(&ServerControlPacket::StartStream)
+        .to_con()?;
+
+    let signal = control_receiver.recv(HANDSHAKE_ACTION_TIMEOUT)?;
+    if !matches!(signal, ClientControlPacket::StreamReady) {
+        connection_drop_guard.reason = Some(DisconnectReason::ProtocolMismatch);
+        con_bail!("Got unexpected packet waiting for stream ack");
+    }
+
+    let (mut stream_socket, mut video_sender) = connect_stream_socket(
+        client_ip,
+        &settings,
+        &capabilities,
+        negotiation.fps,
+        stream_keys,
+        None,
+    )?;
+    let game_audio_sender = stream_socket.request_stream(AUDIO);
+
+    SERVER_DATA_MANAGER.write().update_client_list(
+        client_hostname.clone(),
+        ClientListAction::SetConnectionState(ConnectionState::Spectating),
+    );
+
+    let is_spectating = Arc::new(RelaxedAtomic::new(true));
+    let (disconnect_sender, disconnect_receiver) = mpsc::channel();
+
+    let (video_channel_sender, video_channel_receiver) =
+        std::sync::mpsc::sync_channel(settings.connection.max_queued_server_video_frames);
+    let dropped_packets = Arc::new(AtomicUsize::new(0));
+    SPECTATOR_VIDEO_SENDERS.lock().insert(
+        client_hostname.clone(),
+        SpectatorVideoSender {
+            sender: video_channel_sender,
+            dropped_packets: Arc::clone(&dropped_packets),
+        },
+    );
+
+    let video_send_thread = thread::spawn({
+        let client_hostname = client_hostname.clone();
+        let is_spectating = Arc::clone(&is_spectating);
+        move || {
+            let mut unreported_drops = 0;
+            let mut last_drop_report = Instant::now();
+            while is_spectating.value() {
+                let packet = match video_channel_receiver.recv_timeout(STREAMING_RECV_TIMEOUT) {
+                    Ok(packet) => packet,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+
+                // The client sees the gap in the packet indices and requests an IDR
+                let dropped_count = dropped_packets.swap(0, Ordering::Relaxed);
+                if dropped_count > 0 {
+                    video_sender.skip_packet();
+                    unreported_drops += dropped_count;
+                }
+                if unreported_drops > 0 && last_drop_report.elapsed() > SPECTATOR_DROP_LOG_INTERVAL
+                {
+                    warn!(
+                        "Dropped {unreported_drops} video packets for spectator {client_hostname}. \
+                        Reason: Can't push to network"
+                    );
+                    unreported_drops = 0;
+                    last_drop_report = Instant::now();
+                }
+
+                if let Some(pacer) = video_sender.pacer_mut() {
+                    let (bitrate_bps, frame_interval) = BITRATE_MANAGER.lock().pacing_target();
+                    pacer.set_target(bitrate_bps, frame_interval);
+                }
+
+                video_sender.send_shared(&packet).ok();
+            }
+        }
+    });
+
+    // The game audio is captured again, without muting the device
+    let game_audio_thread = if let Switch::Enabled(config) = settings.audio.game_audio {
+        let is_spectating = Arc::clone(&is_spectating);
+        thread::spawn(move || {
+            while is_spectating.value() {
+                let device = match AudioDevice::new_output(
+                    Some(settings.audio.linux_backend),
+                    config.device.as_ref(),
+                ) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("New audio device failed: {e:?}");
+                        thread::sleep(RETRY_CONNECT_MIN_INTERVAL);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = alvr_audio::record_audio_blocking(
+                    Arc::clone(&is_spectating),
+                    game_audio_sender.clone(),
+                    &device,
+                    2,
+                    false,
+                ) {
+                    error!("Audio record error: {e:?}");
+                }
+            }
+        })
+    } else {
+        thread::spawn(|| ())
+    };
+
+    let control_sender = Arc::new(Mutex::new(control_sender));
+
+    let keepalive_thread = thread::spawn({
+        let is_spectating = Arc::clone(&is_spectating);
+        let control_sender = Arc::clone(&control_sender);
+        let disconnect_sender = disconnect_sender.clone();
+        let client_hostname = client_hostname.clone();
+        move || {
+            while is_spectating.value() {
+                if let Err(e) = control_sender.lock().send(&ServerControlPacket::KeepAlive) {
+                    info!("Spectator {client_hostname} disconnected. Cause: {e:?}");
+                    disconnect_sender.send(DisconnectReason::SocketError).ok();
+
+                    return;
+                }
+
+                thread::sleep(KEEPALIVE_INTERVAL);
+            }
+        }
+    });
+
+    let control_receive_thread = thread::spawn({
+        let is_spectating = Arc::clone(&is_spectating);
+        let control_sender = Arc::clone(&control_sender);
+        let disconnect_sender = disconnect_sender.clone();
+        let client_hostname = client_hostname.clone();
+        move || {
+            let mut disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
+            while is_spectating.value() {
+                let packet = match control_receiver.recv(STREAMING_RECV_TIMEOUT) {
+                    Ok(packet) => packet,
+                    Err(ConnectionError::TryAgain(_)) => {
+                        if Instant::now() > disconnection_deadline {
+                            info!("Spectator {client_hostname} disconnected. Timeout");
+                            disconnect_sender.send(DisconnectReason::Timeout).ok();
+                            return;
+                        } else {
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        info!("Spectator {client_hostname} disconnected. Cause: {e}");
+                        disconnect_sender.send(DisconnectReason::SocketError).ok();
+                        return;
+                    }
+                };
+
+                if let ClientControlPacket::RequestIdr = packet {
+                    if let Some(config) = DECODER_CONFIG.lock().clone() {
+                        control_sender
+                            .lock()
+                            .send(&ServerControlPacket::InitializeDecoder(config))
+                            .ok();
+                    }
+                    unsafe { crate::RequestIDR() }
+                }
+
+                disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
+            }
+        }
+    });
+
+    let stream_receive_thread = thread::spawn({
+        let is_spectating = Arc::clone(&is_spectating);
+        let disconnect_sender = disconnect_sender.clone();
+        move || {
+            while is_spectating.value() {
+                match stream_socket.recv() {
+                    Ok(()) | Err(ConnectionError::TryAgain(_)) => (),
+                    Err(ConnectionError::Other(_)) => {
+                        disconnect_sender.send(DisconnectReason::SocketError).ok();
+
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    // Spectators are disconnected together with the primary client, or when removed from the
+    // client list
+    let lifecycle_check_thread = thread::spawn({
+        let is_spectating = Arc::clone(&is_spectating);
+        let client_hostname = client_hostname.clone();
+        move || {
+            while is_spectating.value()
+                && IS_STREAMING.value()
+                && SHOULD_CONNECT_TO_CLIENTS.value()
+                && SERVER_DATA_MANAGER
+                    .read()
+                    .client_list()
+                    .get(&client_hostname)
+                    .map(|c| c.connection_state == ConnectionState::Spectating)
+                    .unwrap_or(false)
+            {
+                thread::sleep(STREAMING_RECV_TIMEOUT);
+            }
+
+            let reason = if SHOULD_CONNECT_TO_CLIENTS.value() {
+                DisconnectReason::ClientRequest
+            } else {
+                DisconnectReason::ServerShutdown
+            };
+            disconnect_sender.send(reason).ok();
+        }
+    });
+
+    // The encoder is already running, the spectator needs the decoder configuration and an IDR
+    if let Some(config) = DECODER_CONFIG.lock().clone() {
+        control_sender
+            .lock()
+            .send(&ServerControlPacket::InitializeDecoder(config))
+            .ok();
+    }
+    unsafe { crate::RequestIDR() };
+
+    thread::spawn(move || {
+        let reason = disconnect_receiver
+            .recv()
+            .unwrap_or(DisconnectReason::ClientRequest);
+        if !matches!(
+            reason,
+            DisconnectReason::Timeout | DisconnectReason::SocketError
+        ) && capabilities.supports_packet("ServerControlPacket::Disconnecting")
+        {
+            control_sender
+                .lock()
+                .send(&ServerControlPacket::Disconnecting(reason))
+                .ok();
+        }
+
+        // A removed spectator is already in the Disconnecting state, which is kept
+        connection_drop_guard.reason = Some(reason);
+        let _connection_drop_guard = connection_drop_guard;
+
+        is_spectating.set(false);
+        SPECTATOR_VIDEO_SENDERS.lock().remove(&client_hostname);
+
+        video_send_thread.join().ok();
+        game_audio_thread.join().ok();
+        keepalive_thread.join().ok();
+        control_receive_thread.join().ok();
+        stream_receive_thread.join().ok();
+        lifecycle_check_thread.join().ok();
+    });
+
     Ok(())
 }
 
@@ -1191,7 +2315,7 @@ pub extern "C" fn send_video(timestamp_ns: u64, buffer_ptr: *mut u8, len: i32, i
     static STREAM_CORRUPTED: AtomicBool = AtomicBool::new(true);
     static LAST_IDR_INSTANT: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
 
-    if let Some(sender) = &*VIDEO_CHANNEL_SENDER.lock() {
+    if let Some((sender, buffer_pool)) = &*VIDEO_CHANNEL_SENDER.lock() {
         let buffer_size = len as usize;
 
         if is_idr {
@@ -1216,13 +2340,6 @@ pub extern "C" fn send_video(timestamp_ns: u64, buffer_ptr: *mut u8, len: i32, i
 
         let timestamp = Duration::from_nanos(timestamp_ns);
 
-        let mut payload = vec![0; buffer_size];
-
-        // use copy_nonoverlapping (aka memcpy) to avoid freeing memory allocated by C++
-        unsafe {
-            ptr::copy_nonoverlapping(buffer_ptr, payload.as_mut_ptr(), buffer_size);
-        }
-
         if !STREAM_CORRUPTED.load(Ordering::SeqCst)
             || !SERVER_DATA_MANAGER
                 .read()
@@ -1230,24 +2347,60 @@ pub extern "C" fn send_video(timestamp_ns: u64, buffer_ptr: *mut u8, len: i32, i
                 .connection
                 .avoid_video_glitching
         {
+            let header = VideoPacketHeader {
+                timestamp,
+                is_idr,
+                send_time: alvr_common::monotonic_time(),
+            };
+            let mut buffer = match buffer_pool.get_buffer(&header) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    error!("Failed to prepare video packet: {e}");
+                    return;
+                }
+            };
+            if is_idr {
+                buffer.mark_idr();
+            }
+
+            // This is the only copy of the frame in user space (unless the shards are encrypted):
+            // the shards, the mirror, the recording and the spectators all share this buffer.
+            // use copy_nonoverlapping (aka memcpy) to avoid freeing memory allocated by C++
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    buffer_ptr,
+                    buffer.get_range_mut(0, buffer_size).as_mut_ptr(),
+                    buffer_size,
+                );
+            }
+
+            let packet = Arc::new(buffer);
+
             if let Some(sender) = &*VIDEO_MIRROR_SENDER.lock() {
-                sender.send(payload.clone()).ok();
+                sender.send(packet.clone()).ok();
             }
 
             if let Some(file) = &mut *VIDEO_RECORDING_FILE.lock() {
-                file.write_all(&payload).ok();
+                file.write_all(packet.get()).ok();
             }
 
-            if matches!(
-                sender.try_send(VideoPacket {
-                    header: VideoPacketHeader { timestamp, is_idr },
-                    payload,
-                }),
-                Err(TrySendError::Full(_))
-            ) {
-                STREAM_CORRUPTED.store(true, Ordering::SeqCst);
-                unsafe { crate::RequestIDR() };
-                warn!("Dropping video packet. Reason: Can't push to network");
+            for spectator in SPECTATOR_VIDEO_SENDERS.lock().values() {
+                if matches!(
+                    spectator.sender.try_send(Arc::clone(&packet)),
+                    Err(TrySendError::Full(_))
+                ) {
+                    spectator.dropped_packets.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            match sender.try_send(packet) {
+                Ok(()) => hooks::report_video_frame(),
+                Err(TrySendError::Full(_)) => {
+                    STREAM_CORRUPTED.store(true, Ordering::SeqCst);
+                    unsafe { crate::RequestIDR() };
+                    warn!("Dropping video packet. Reason: Can't push to network");
+                }
+                Err(TrySendError::Disconnected(_)) => (),
             }
         } else {
             warn!("Dropping video packet. Reason: Waiting for IDR frame");
@@ -0,0 +1,364 @@
+// Hooks are user commands run on streamer events, configured in the connection settings. The
+// context of the event is passed as environment variables and as JSON on stdin. Each hook runs in
+// its own thread: its output is forwarded to the log and it is killed once its timeout is over.
+// Failures are logged as errors, which the dashboard shows as notifications.
+// Events that occur during a stream (bitrate drop, battery low, recenter...) carry the context of
+// the stream. Threshold events are triggered only when the threshold is crossed.
+
+use crate::SERVER_DATA_MANAGER;
+use alvr_common::{
+    anyhow::{bail, Result},
+    error,
+    glam::UVec2,
+    info, warn, LazyMutOpt, RelaxedAtomic,
+};
+use alvr_session::{CodecType, DisconnectReason, HookConfig, HookEvent};
+use serde_json::json;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static STREAM_CONTEXT: LazyMutOpt<HookContext> = alvr_common::lazy_mut_none();
+static STREAM_START_PENDING: RelaxedAtomic = RelaxedAtomic::new(false);
+static BITRATE_DROPPED: RelaxedAtomic = RelaxedAtomic::new(false);
+static BATTERY_LOW: RelaxedAtomic = RelaxedAtomic::new(false);
+
+#[derive(Clone, Default)]
+pub struct HookContext {
+    pub hostname: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub resolution: Option<UVec2>,
+    pub fps: Option<f32>,
+    pub codec: Option<CodecType>,
+    pub reason: Option<DisconnectReason>,
+    pub bitrate_mbps: Option<f32>,
+    pub battery_percent: Option<f32>,
+}
+
+impl HookContext {
+    // Fields that are not set are omitted
+    fn env_vars(&self, event: HookEvent) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("ALVR_EVENT", event_name(event).to_owned()),
+            // Used by the scripts written for on_connect_script and on_disconnect_script
+            ("ACTION", event_name(event).to_owned()),
+        ];
+
+        if let Some(hostname) = &self.hostname {
+            vars.push(("ALVR_HOSTNAME", hostname.clone()));
+        }
+        if let Some(ip) = self.ip {
+            vars.push(("ALVR_IP", ip.to_string()));
+        }
+        if let Some(resolution) = self.resolution {
+            vars.push((
+                "ALVR_RESOLUTION",
+                format!("{}x{}", resolution.x, resolution.y),
+            ));
+        }
+        if let Some(fps) = self.fps {
+            vars.push(("ALVR_FPS", fps.to_string()));
+        }
+        if let Some(codec) = self.codec {
+            vars.push(("ALVR_CODEC", format!("{codec:?}")));
+        }
+        if let Some(reason) = self.reason {
+            vars.push(("ALVR_REASON", format!("{reason:?}")));
+        }
+        if let Some(bitrate_mbps) = self.bitrate_mbps {
+            vars.push(("ALVR_BITRATE_MBPS", bitrate_mbps.to_string()));
+        }
+        if let Some(battery_percent) = self.battery_percent {
+            vars.push(("ALVR_BATTERY_PERCENT", battery_percent.to_string()));
+        }
+
+        vars
+    }
+
+    fn to_json(&self, event: HookEvent) -> serde_json::Value {
+        json!({
+            "event": event_name(event),
+            "hostname": self.hostname,
+            "ip": self.ip,
+            "resolution": self.resolution.map(|resolution| [resolution.x, resolution.y]),
+            "fps": self.fps,
+            "codec": self.codec,
+            "reason": self.reason,
+            "bitrate_mbps": self.bitrate_mbps,
+            "battery_percent": self.battery_percent,
+        })
+    }
+}
+
+fn event_name(event: HookEvent) -> &'static str {
+    match event {
+        HookEvent::Connect => "connect",
+        HookEvent::Disconnect => "disconnect",
+        HookEvent::StreamStart => "stream_start",
+        HookEvent::BitrateDrop => "bitrate_drop",
+        HookEvent::BatteryLow => "battery_low",
+        HookEvent::Recenter => "recenter",
+        HookEvent::SteamvrRestart => "steamvr_restart",
+    }
+}
+
+fn forward_output(hook_name: String, output: impl Read + Send + 'static, is_stderr: bool) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(|line| line.ok()) {
+            if is_stderr {
+                warn!("Hook \"{hook_name}\": {line}");
+            } else {
+                info!("Hook \"{hook_name}\": {line}");
+            }
+        }
+    });
+}
+
+fn run_hook(hook: &HookConfig, event: HookEvent, context: &HookContext) -> Result<()> {
+    if hook.command.is_empty() {
+        bail!("No command set");
+    }
+
+    info!("Running hook \"{}\" ({})", hook.name, event_name(event));
+
+    let mut child = Command::new(&hook.command)
+        .envs(context.env_vars(event))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The output threads are not joined: the pipes can stay open after the hook exits if it
+    // started other processes
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(hook.name.clone(), stdout, false);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(hook.name.clone(), stderr, true);
+    }
+
+    // Hooks are not required to read stdin. It is closed once written
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(context.to_json(event).to_string().as_bytes())
+            .ok();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout_s);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                bail!("Command failed with {status}");
+            }
+
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+
+            bail!("Timed out after {}s", hook.timeout_s);
+        }
+
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+}
+
+fn event_hooks(hooks: &[HookConfig], event: HookEvent) -> Vec<HookConfig> {
+    hooks
+        .iter()
+        .filter(|hook| hook.event == event)
+        .cloned()
+        .collect()
+}
+
+pub fn run_hooks(event: HookEvent, context: HookContext) {
+    let hooks = event_hooks(
+        &SERVER_DATA_MANAGER.read().settings().connection.hooks.hooks,
+        event,
+    );
+
+    for hook in hooks {
+        let context = context.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_hook(&hook, event, &context) {
+                error!("Hook \"{}\" failed: {e}", hook.name);
+            }
+        });
+    }
+}
+
+fn stream_context() -> HookContext {
+    STREAM_CONTEXT.lock().clone().unwrap_or_default()
+}
+
+// Runs the hooks of the event with the context of the current stream
+pub fn run_stream_hooks(event: HookEvent) {
+    run_hooks(event, stream_context());
+}
+
+// Resumed sessions don't trigger the connect event
+pub fn start_stream(context: HookContext, is_new_session: bool) {
+    *STREAM_CONTEXT.lock() = Some(context.clone());
+    STREAM_START_PENDING.set(true);
+    BITRATE_DROPPED.set(false);
+    BATTERY_LOW.set(false);
+
+    if is_new_session {
+        run_hooks(HookEvent::Connect, context);
+    }
+}
+
+pub fn end_stream(reason: DisconnectReason) {
+    STREAM_START_PENDING.set(false);
+
+    let context = STREAM_CONTEXT.lock().take();
+    if let Some(context) = context {
+        run_hooks(
+            HookEvent::Disconnect,
+            HookContext {
+                reason: Some(reason),
+                ..context
+            },
+        );
+    }
+}
+
+pub fn report_video_frame() {
+    if STREAM_START_PENDING.value() {
+        STREAM_START_PENDING.set(false);
+
+        run_stream_hooks(HookEvent::StreamStart);
+    }
+}
+
+pub fn report_bitrate(bitrate_bps: f32) {
+    let threshold_mbps = SERVER_DATA_MANAGER
+        .read()
+        .settings()
+        .connection
+        .hooks
+        .bitrate_drop_threshold_mbps;
+
+    let dropped = bitrate_bps < threshold_mbps * 1e6;
+    if dropped && !BITRATE_DROPPED.value() {
+        run_hooks(
+            HookEvent::BitrateDrop,
+            HookContext {
+                bitrate_mbps: Some(bitrate_bps / 1e6),
+                ..stream_context()
+            },
+        );
+    }
+    BITRATE_DROPPED.set(dropped);
+}
+
+// gauge_value range is [0, 1]
+pub fn report_battery(gauge_value: f32, is_plugged: bool) {
+    let threshold_percent = SERVER_DATA_MANAGER
+        .read()
+        .settings()
+        .connection
+        .hooks
+        .battery_low_threshold_percent;
+
+    let low = gauge_value * 100.0 < threshold_percent as f32 && !is_plugged;
+    if low && !BATTERY_LOW.value() {
+        run_hooks(
+            HookEvent::BatteryLow,
+            HookContext {
+                battery_percent: Some(gauge_value * 100.0),
+                ..stream_context()
+            },
+        );
+    }
+    BATTERY_LOW.set(low);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook(name: &str, event: HookEvent) -> HookConfig {
+        HookConfig {
+            name: name.into(),
+            event,
+            command: "".into(),
+            timeout_s: 10,
+        }
+    }
+
+    fn context() -> HookContext {
+        HookContext {
+            hostname: Some("client.alvr".into()),
+            resolution: Some(UVec2::new(1920, 1080)),
+            fps: Some(90.0),
+            reason: Some(DisconnectReason::Timeout),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hooks_are_filtered_by_event() {
+        let hooks = [
+            hook("connect 1", HookEvent::Connect),
+            hook("disconnect", HookEvent::Disconnect),
+            hook("connect 2", HookEvent::Connect),
+        ];
+
+        let names = |event| {
+            event_hooks(&hooks, event)
+                .into_iter()
+                .map(|hook| hook.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(HookEvent::Connect), ["connect 1", "connect 2"]);
+        assert_eq!(names(HookEvent::Disconnect), ["disconnect"]);
+        assert!(names(HookEvent::Recenter).is_empty());
+    }
+
+    #[test]
+    fn env_vars_contain_set_fields() {
+        let vars = context().env_vars(HookEvent::Disconnect);
+        let var = |name| {
+            vars.iter()
+                .find(|(var_name, _)| *var_name == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(var("ALVR_EVENT"), Some("disconnect"));
+        assert_eq!(var("ACTION"), Some("disconnect"));
+        assert_eq!(var("ALVR_HOSTNAME"), Some("client.alvr"));
+        assert_eq!(var("ALVR_RESOLUTION"), Some("1920x1080"));
+        assert_eq!(var("ALVR_FPS"), Some("90"));
+        assert_eq!(var("ALVR_REASON"), Some("Timeout"));
+        assert_eq!(var("ALVR_IP"), None);
+        assert_eq!(var("ALVR_BITRATE_MBPS"), None);
+    }
+
+    #[test]
+    fn json_contains_all_fields() {
+        let json = context().to_json(HookEvent::StreamStart);
+
+        assert_eq!(json["event"], "stream_start");
+        assert_eq!(json["hostname"], "client.alvr");
+        assert_eq!(json["resolution"], json!([1920, 1080]));
+        assert_eq!(json["fps"], 90.0);
+        assert!(json["ip"].is_null());
+        assert!(json["battery_percent"].is_null());
+    }
+
+    #[test]
+    fn hook_without_command_fails() {
+        let empty_hook = hook("empty", HookEvent::Connect);
+
+        assert!(run_hook(&empty_hook, HookEvent::Connect, &context()).is_err());
+    }
+}
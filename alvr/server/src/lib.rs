@@ -1,8 +1,11 @@
+mod bandwidth_probe;
 mod bitrate;
+mod congestion_control;
 mod connection;
 mod face_tracking;
 mod hand_gestures;
 mod haptics;
+mod hooks;
 mod input_mapping;
 mod logging_backend;
 mod openvr_props;
@@ -35,7 +38,8 @@ use alvr_events::EventType;
 use alvr_filesystem::{self as afs, Layout};
 use alvr_packets::{ClientListAction, DecoderInitializationConfig, VideoPacketHeader};
 use alvr_server_io::ServerDataManager;
-use alvr_session::{CodecType, ConnectionState};
+use alvr_session::{CodecType, ConnectionState, DisconnectReason, HookEvent};
+use alvr_sockets::Buffer;
 use bitrate::BitrateManager;
 use connection::{ClientDisconnectRequest, DISCONNECT_CLIENT_NOTIFIER, SHOULD_CONNECT_TO_CLIENTS};
 use statistics::StatisticsManager;
@@ -46,7 +50,7 @@ use std::{
     fs::File,
     io::Write,
     ptr,
-    sync::Once,
+    sync::{Arc, Once},
     thread,
     time::{Duration, Instant},
 };
@@ -72,12 +76,15 @@ static BITRATE_MANAGER: Lazy<Mutex<BitrateManager>> = Lazy::new(|| {
     ))
 });
 
-pub struct VideoPacket {
-    pub header: VideoPacketHeader,
-    pub payload: Vec<u8>,
-}
+// Encoded frames are written directly into socket buffers. The network, mirror and recording
+// sinks share the same buffer, which returns to the pool when the last of them drops it.
+pub type VideoPacket = Arc<Buffer<VideoPacketHeader>>;
+
+// Reference-counted video data sent to the mirror
+pub type VideoMirrorData = Arc<dyn AsRef<[u8]> + Send + Sync>;
 
-static VIDEO_MIRROR_SENDER: LazyMutOpt<broadcast::Sender<Vec<u8>>> = alvr_common::lazy_mut_none();
+static VIDEO_MIRROR_SENDER: LazyMutOpt<broadcast::Sender<VideoMirrorData>> =
+    alvr_common::lazy_mut_none();
 static VIDEO_RECORDING_FILE: LazyMutOpt<File> = alvr_common::lazy_mut_none();
 
 static FRAME_RENDER_VS_CSO: &[u8] = include_bytes!("../cpp/platform/win32/FrameRenderVS.cso");
@@ -106,12 +113,9 @@ fn to_ffi_quat(quat: Quat) -> FfiQuat {
 }
 
 pub fn create_recording_file() {
-    let codec = SERVER_DATA_MANAGER.read().settings().video.preferred_codec;
-    let ext = if matches!(codec, CodecType::H264) {
-        "h264"
-    } else {
-        "h265"
-    };
+    // The codec can differ from the preferred one if the client does not support it
+    let codec = SERVER_DATA_MANAGER.read().session().openvr_config.codec;
+    let ext = if codec == 0 { "h264" } else { "h265" };
 
     let path = FILESYSTEM_LAYOUT.log_dir.join(format!(
         "recording.{}.{ext}",
@@ -160,6 +164,7 @@ pub extern "C" fn shutdown_driver() {
                 hostname,
                 ClientListAction::SetConnectionState(ConnectionState::Disconnecting {
                     should_be_removed: false,
+                    reason: DisconnectReason::ServerShutdown,
                 }),
             );
         }
@@ -168,6 +173,7 @@ pub extern "C" fn shutdown_driver() {
     if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
         notifier.send(ClientDisconnectRequest::ServerShutdown).ok();
     }
+    connection::end_suspended_session(false);
 
     // apply openvr config for the next launch
     SERVER_DATA_MANAGER.write().session_mut().openvr_config = connection::contruct_openvr_config();
@@ -215,6 +221,8 @@ pub fn notify_restart_driver() {
 
 // This call is blocking
 pub fn restart_driver() {
+    hooks::run_stream_hooks(HookEvent::SteamvrRestart);
+
     SHOULD_CONNECT_TO_CLIENTS.set(false);
     if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
         notifier.send(ClientDisconnectRequest::ServerRestart).ok();
@@ -337,7 +345,7 @@ pub unsafe extern "C" fn HmdDriverFactory(
         unsafe { ptr::copy_nonoverlapping(buffer_ptr, config_buffer.as_mut_ptr(), len as usize) };
 
         if let Some(sender) = &*VIDEO_MIRROR_SENDER.lock() {
-            sender.send(config_buffer.clone()).ok();
+            sender.send(Arc::new(config_buffer.clone())).ok();
         }
 
         if let Some(file) = &mut *VIDEO_RECORDING_FILE.lock() {
@@ -406,6 +414,10 @@ pub unsafe extern "C" fn HmdDriverFactory(
             }
         }
 
+        if params.updated != 0 {
+            hooks::report_bitrate(params.bitrate_bps as f32);
+        }
+
         params
     }
 
@@ -1,59 +1,247 @@
-use alvr_common::{anyhow::Result, con_bail, ConResult, HandleTryAgain, ToCon, ALVR_NAME};
-use alvr_sockets::{CONTROL_PORT, HANDSHAKE_PACKET_SIZE_BYTES, LOCAL_IP};
+use alvr_common::{
+    anyhow::Result, con_bail, debug, ConResult, ConnectionError, HandleTryAgain, ToCon, ALVR_NAME,
+};
+use alvr_packets::HANDSHAKE_VERSION;
+use alvr_sockets::{
+    NetworkFilter, PeerType, ProtoControlSocket, RateLimiter, CONTROL_PORT,
+    HANDSHAKE_PACKET_SIZE_BYTES, MDNS_HANDSHAKE_VERSION_KEY, MDNS_HOSTNAME_KEY, MDNS_SERVICE_TYPE,
+    MDNS_WEB_PORT_KEY,
+};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
 use std::{
-    net::{IpAddr, UdpSocket},
-    time::Duration,
+    collections::HashMap,
+    net::{IpAddr, TcpListener, UdpSocket},
+    time::{Duration, Instant},
 };
+use sysinfo::{System, SystemExt};
+
+// Returns the client hostname
+fn parse_announcement(packet: &[u8]) -> ConResult<String> {
+    if packet.len() == HANDSHAKE_PACKET_SIZE_BYTES
+        && &packet[..ALVR_NAME.len()] == ALVR_NAME.as_bytes()
+        && packet[ALVR_NAME.len()..16].iter().all(|b| *b == 0)
+    {
+        // Clients with a different ALVR version can connect, as long as the handshake is the
+        // same. Then they negotiate the capabilities
+        let mut handshake_version_bytes = [0; 8];
+        handshake_version_bytes.copy_from_slice(&packet[16..24]);
+        let received_handshake_version = u64::from_le_bytes(handshake_version_bytes);
+
+        if received_handshake_version != HANDSHAKE_VERSION {
+            con_bail!("Found incompatible client! Upgrade or downgrade\nExpected handshake version {HANDSHAKE_VERSION}, Found {received_handshake_version}");
+        }
+
+        let mut hostname_bytes = [0; 32];
+        hostname_bytes.copy_from_slice(&packet[24..56]);
+        let hostname = std::str::from_utf8(&hostname_bytes)
+            .to_con()?
+            .trim_end_matches('\x00')
+            .to_owned();
+
+        Ok(hostname)
+    } else if packet.len() >= 16
+        && (&packet[..16] == b"\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00ALVR"
+            || &packet[..5] == b"\x01ALVR")
+    {
+        con_bail!("Found old client. Please upgrade")
+    } else {
+        // Unexpected packet.
+        // Note: no need to check for v12 and v13, not found in the wild anymore
+        con_bail!("Found unrelated packet during discovery")
+    }
+}
 
 pub struct WelcomeSocket {
     socket: UdpSocket,
+    read_timeout: Duration,
     buffer: [u8; HANDSHAKE_PACKET_SIZE_BYTES],
+    rate_limiter: RateLimiter,
 }
 
 impl WelcomeSocket {
+    // The socket is dual-stack, to receive both IPv4 broadcasts and IPv6 multicasts
     pub fn new(read_timeout: Duration) -> Result<Self> {
-        let socket = UdpSocket::bind((LOCAL_IP, CONTROL_PORT))?;
+        let socket = alvr_sockets::bind_dual_stack_udp(CONTROL_PORT)?;
         socket.set_read_timeout(Some(read_timeout))?;
 
         Ok(Self {
             socket,
+            read_timeout,
             buffer: [0; HANDSHAKE_PACKET_SIZE_BYTES],
+            rate_limiter: RateLimiter::new(),
         })
     }
 
+    // Packets from filtered or rate limited sources are dropped silently. Packets keep being read
+    // until an allowed one is found, so that a flooding device cannot hide the other clients. The
+    // read time is still bounded by the read timeout: each read waits only for the remaining time.
     // Returns: client IP, client hostname
-    pub fn recv(&mut self) -> ConResult<(String, IpAddr)> {
-        let (size, address) = self.socket.recv_from(&mut self.buffer).handle_try_again()?;
-
-        if size == HANDSHAKE_PACKET_SIZE_BYTES
-            && &self.buffer[..ALVR_NAME.len()] == ALVR_NAME.as_bytes()
-            && self.buffer[ALVR_NAME.len()..16].iter().all(|b| *b == 0)
-        {
-            let mut protocol_id_bytes = [0; 8];
-            protocol_id_bytes.copy_from_slice(&self.buffer[16..24]);
-            let received_protocol_id = u64::from_le_bytes(protocol_id_bytes);
-
-            if received_protocol_id != alvr_common::protocol_id() {
-                con_bail!("Found incompatible client! Upgrade or downgrade\nExpected protocol ID {}, Found {received_protocol_id}",
-                alvr_common::protocol_id());
+    pub fn recv(
+        &mut self,
+        filter: &NetworkFilter,
+        max_packets_per_second: u32,
+    ) -> ConResult<(String, IpAddr)> {
+        let deadline = Instant::now() + self.read_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return alvr_common::try_again();
+            }
+            self.socket.set_read_timeout(Some(remaining)).to_con()?;
+
+            let (size, address) = self.socket.recv_from(&mut self.buffer).handle_try_again()?;
+            let client_ip = alvr_sockets::peer_ip(address);
+
+            if !filter.is_allowed(client_ip)
+                || !self.rate_limiter.check(client_ip, max_packets_per_second)
+            {
+                continue;
+            }
+
+            let hostname = parse_announcement(&self.buffer[..size])?;
+
+            return Ok((hostname, client_ip));
+        }
+    }
+}
+
+// Connections that did not send their announcement within this time are closed
+const ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(3);
+// Further connections wait in the listen backlog
+const MAX_PENDING_CONNECTIONS: usize = 16;
+
+struct PendingConnection {
+    proto_socket: ProtoControlSocket,
+    client_ip: IpAddr,
+    deadline: Instant,
+}
+
+// Accepts the control connections of clients in active connection mode, used when the streamer
+// cannot find the client or connect to it (routed networks, VPNs, port forwarding). The client
+// sends its announcement first, then the handshake continues as for discovered clients.
+pub struct ClientListener {
+    listener: TcpListener,
+    pending: Vec<PendingConnection>,
+}
+
+impl ClientListener {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            listener: alvr_sockets::get_client_listener()?,
+            pending: vec![],
+        })
+    }
+
+    // Does not block. Connections from filtered IPs are closed before the announcement is read.
+    // The announcements of the accepted connections are read in the next calls, so that a slow or
+    // silent peer cannot stall the handshake loop
+    // Returns: control socket, client hostname, client IP
+    pub fn accept(
+        &mut self,
+        timeout: Duration,
+        filter: &NetworkFilter,
+    ) -> ConResult<(ProtoControlSocket, String, IpAddr)> {
+        while self.pending.len() < MAX_PENDING_CONNECTIONS {
+            match ProtoControlSocket::connect_to(timeout, PeerType::ActiveClient(&self.listener)) {
+                Ok((proto_socket, client_ip)) => {
+                    if filter.is_allowed(client_ip) {
+                        self.pending.push(PendingConnection {
+                            proto_socket,
+                            client_ip,
+                            deadline: Instant::now() + ANNOUNCEMENT_TIMEOUT,
+                        });
+                    } else {
+                        debug!("Connection from {client_ip} refused: network not allowed");
+                    }
+                }
+                Err(ConnectionError::TryAgain(_)) => break,
+                Err(e) => return Err(e),
             }
+        }
+
+        let mut index = 0;
+        while index < self.pending.len() {
+            let connection = &mut self.pending[index];
+            match connection.proto_socket.try_recv_announcement() {
+                Ok(packet) => {
+                    let connection = self.pending.swap_remove(index);
+                    let hostname = parse_announcement(&packet)?;
 
-            let mut hostname_bytes = [0; 32];
-            hostname_bytes.copy_from_slice(&self.buffer[24..56]);
-            let hostname = std::str::from_utf8(&hostname_bytes)
-                .to_con()?
-                .trim_end_matches('\x00')
-                .to_owned();
-
-            Ok((hostname, address.ip()))
-        } else if &self.buffer[..16] == b"\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00ALVR"
-            || &self.buffer[..5] == b"\x01ALVR"
-        {
-            con_bail!("Found old client. Please upgrade")
-        } else {
-            // Unexpected packet.
-            // Note: no need to check for v12 and v13, not found in the wild anymore
-            con_bail!("Found unrelated packet during discovery")
+                    return Ok((connection.proto_socket, hostname, connection.client_ip));
+                }
+                Err(ConnectionError::TryAgain(_)) if Instant::now() < connection.deadline => {
+                    index += 1;
+                }
+                Err(e) => {
+                    debug!(
+                        "Connection from {} closed without announcement: {e}",
+                        connection.client_ip
+                    );
+                    self.pending.swap_remove(index);
+                }
+            }
         }
+
+        alvr_common::try_again()
+    }
+}
+
+// Advertises the streamer as a DNS-SD service. Clients that find it send their announcement
+// directly to the WelcomeSocket, which works on networks that block broadcast.
+pub struct MdnsService {
+    daemon: ServiceDaemon,
+    fullname: String,
+    web_server_port: u16,
+}
+
+impl MdnsService {
+    pub fn new(web_server_port: u16) -> Result<Self> {
+        // DNS labels allow only letters, digits and hyphens
+        let hostname = System::new()
+            .host_name()
+            .unwrap_or_else(|| "alvr-streamer".into())
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+
+        let properties = HashMap::from([
+            (
+                MDNS_HANDSHAKE_VERSION_KEY.to_owned(),
+                HANDSHAKE_VERSION.to_string(),
+            ),
+            (MDNS_HOSTNAME_KEY.to_owned(), hostname.clone()),
+            (MDNS_WEB_PORT_KEY.to_owned(), web_server_port.to_string()),
+        ]);
+
+        let service_info = ServiceInfo::new(
+            MDNS_SERVICE_TYPE,
+            &hostname,
+            &format!("{hostname}.local."),
+            "",
+            CONTROL_PORT,
+            properties,
+        )?
+        .enable_addr_auto();
+        let fullname = service_info.get_fullname().to_owned();
+
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(service_info)?;
+
+        Ok(Self {
+            daemon,
+            fullname,
+            web_server_port,
+        })
+    }
+
+    pub fn web_server_port(&self) -> u16 {
+        self.web_server_port
+    }
+}
+
+impl Drop for MdnsService {
+    fn drop(&mut self) {
+        self.daemon.unregister(&self.fullname).ok();
+        self.daemon.shutdown().ok();
     }
 }
@@ -1,6 +1,7 @@
 use alvr_common::{SlidingWindowAverage, HEAD_ID};
 use alvr_events::{EventType, GraphStatistics, NominalBitrateStats, StatisticsSummary};
-use alvr_packets::ClientStatistics;
+use alvr_packets::{ClientStatistics, ClockSyncReport};
+use alvr_sockets::PacingStatistics;
 use std::{
     collections::{HashMap, VecDeque},
     time::{Duration, Instant},
@@ -51,6 +52,13 @@ pub struct StatisticsManager {
     video_bytes_partial_sum: usize,
     packets_lost_total: usize,
     packets_lost_partial_sum: usize,
+    fec_parity_bytes_total: usize,
+    fec_parity_bytes_partial_sum: usize,
+    fec_recovered_packets_total: usize,
+    fec_unrecovered_packets_total: usize,
+    send_queue_peak_shards: usize,
+    pacing_partial_stats: PacingStatistics,
+    last_clock_sync: Option<ClockSyncReport>,
     battery_gauges: HashMap<u64, BatteryData>,
     steamvr_pipeline_latency: Duration,
     total_pipeline_latency_average: SlidingWindowAverage<Duration>,
@@ -78,6 +86,13 @@ impl StatisticsManager {
             video_bytes_partial_sum: 0,
             packets_lost_total: 0,
             packets_lost_partial_sum: 0,
+            fec_parity_bytes_total: 0,
+            fec_parity_bytes_partial_sum: 0,
+            fec_recovered_packets_total: 0,
+            fec_unrecovered_packets_total: 0,
+            send_queue_peak_shards: 0,
+            pacing_partial_stats: PacingStatistics::default(),
+            last_clock_sync: None,
             battery_gauges: HashMap::new(),
             steamvr_pipeline_latency: Duration::from_secs_f32(
                 steamvr_pipeline_frames * nominal_server_frame_interval.as_secs_f32(),
@@ -169,6 +184,27 @@ impl StatisticsManager {
         self.packets_lost_partial_sum += 1;
     }
 
+    pub fn report_fec_parity_sent(&mut self, bytes_count: usize) {
+        self.fec_parity_bytes_total += bytes_count;
+        self.fec_parity_bytes_partial_sum += bytes_count;
+    }
+
+    // Total number of shards queued in the stream socket send scheduler
+    pub fn report_send_queue_depth(&mut self, shards_count: usize) {
+        self.send_queue_peak_shards = usize::max(self.send_queue_peak_shards, shards_count);
+    }
+
+    pub fn report_pacing(&mut self, stats: PacingStatistics) {
+        let partial = &mut self.pacing_partial_stats;
+        partial.paced_packets += stats.paced_packets;
+        partial.total_delay += stats.total_delay;
+        partial.max_delay = Duration::max(partial.max_delay, stats.max_delay);
+    }
+
+    pub fn report_clock_sync(&mut self, report: ClockSyncReport) {
+        self.last_clock_sync = Some(report);
+    }
+
     pub fn report_battery(&mut self, device_id: u64, gauge_value: f32, is_plugged: bool) {
         *self.battery_gauges.entry(device_id).or_default() = BatteryData {
             gauge_value,
@@ -183,6 +219,9 @@ impl StatisticsManager {
     // Called every frame. Some statistics are reported once every frame
     // Returns network latency
     pub fn report_statistics(&mut self, client_stats: ClientStatistics) -> Duration {
+        self.fec_recovered_packets_total += client_stats.fec_recovered_packets as usize;
+        self.fec_unrecovered_packets_total += client_stats.fec_unrecovered_packets as usize;
+
         if let Some(frame) = self
             .history_buffer
             .iter_mut()
@@ -202,21 +241,23 @@ impl StatisticsManager {
                 .frame_encoded
                 .saturating_duration_since(frame.frame_composed);
 
-            // The network latency cannot be estiamed directly. It is what's left of the total
-            // latency after subtracting all other latency intervals. In particular it contains the
-            // transport latency of the tracking packet and the interval between the first video
-            // packet is sent and the last video packet is received for a specific frame.
+            // The client measures the network latency of the video packet with the synchronized
+            // clocks. Until the clocks are synchronized, the network latency is estimated as what's
+            // left of the total latency after subtracting all other latency intervals. Then it also
+            // contains the transport latency of the tracking packet.
             // For safety, use saturating_sub to avoid a crash if for some reason the network
             // latency is miscalculated as negative.
-            let network_latency = frame.total_pipeline_latency.saturating_sub(
-                game_time_latency
-                    + server_compositor_latency
-                    + encoder_latency
-                    + client_stats.video_decode
-                    + client_stats.video_decoder_queue
-                    + client_stats.rendering
-                    + client_stats.vsync_queue,
-            );
+            let network_latency = client_stats.network_latency.unwrap_or_else(|| {
+                frame.total_pipeline_latency.saturating_sub(
+                    game_time_latency
+                        + server_compositor_latency
+                        + encoder_latency
+                        + client_stats.video_decode
+                        + client_stats.video_decoder_queue
+                        + client_stats.rendering
+                        + client_stats.vsync_queue,
+                )
+            });
 
             let client_fps = 1.0
                 / client_stats
@@ -249,6 +290,38 @@ impl StatisticsManager {
                     packets_lost_total: self.packets_lost_total,
                     packets_lost_per_sec: (self.packets_lost_partial_sum as f32 / interval_secs)
                         as _,
+                    fec_overhead_mbits_per_sec: self.fec_parity_bytes_partial_sum as f32 * 8.
+                        / 1e6
+                        / interval_secs,
+                    fec_overhead_percentage: if self.video_bytes_total > 0 {
+                        self.fec_parity_bytes_total as f32 / self.video_bytes_total as f32 * 100.
+                    } else {
+                        0.
+                    },
+                    fec_recovered_packets_total: self.fec_recovered_packets_total,
+                    fec_unrecovered_packets_total: self.fec_unrecovered_packets_total,
+                    send_queue_peak_shards: self.send_queue_peak_shards,
+                    paced_packets_per_sec: (self.pacing_partial_stats.paced_packets as f32
+                        / interval_secs) as _,
+                    pacing_delay_average_ms: if self.pacing_partial_stats.paced_packets > 0 {
+                        self.pacing_partial_stats.total_delay.as_secs_f32() * 1000.
+                            / self.pacing_partial_stats.paced_packets as f32
+                    } else {
+                        0.
+                    },
+                    pacing_delay_max_ms: self.pacing_partial_stats.max_delay.as_secs_f32() * 1000.,
+                    client_to_server_latency_ms: self
+                        .last_clock_sync
+                        .as_ref()
+                        .map(|sync| sync.client_to_server_latency.as_secs_f32() * 1000.),
+                    server_to_client_latency_ms: self
+                        .last_clock_sync
+                        .as_ref()
+                        .map(|sync| sync.server_to_client_latency.as_secs_f32() * 1000.),
+                    clock_drift_ppm: self
+                        .last_clock_sync
+                        .as_ref()
+                        .map(|sync| sync.mapping.drift_ppm as f32),
                     client_fps: client_fps as _,
                     server_fps: server_fps as _,
                     battery_hmd: (self
@@ -269,6 +342,9 @@ impl StatisticsManager {
                 self.video_packets_partial_sum = 0;
                 self.video_bytes_partial_sum = 0;
                 self.packets_lost_partial_sum = 0;
+                self.fec_parity_bytes_partial_sum = 0;
+                self.send_queue_peak_shards = 0;
+                self.pacing_partial_stats = PacingStatistics::default();
             }
 
             // While not accurate, this prevents NaNs and zeros that would cause a crash or pollute
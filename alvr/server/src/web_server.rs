@@ -1,5 +1,5 @@
 use crate::{
-    bindings::FfiButtonValue, connection::ClientDisconnectRequest, DECODER_CONFIG,
+    bindings::FfiButtonValue, connection::ClientDisconnectRequest, VideoMirrorData, DECODER_CONFIG,
     DISCONNECT_CLIENT_NOTIFIER, FILESYSTEM_LAYOUT, SERVER_DATA_MANAGER, STATISTICS_MANAGER,
     VIDEO_MIRROR_SENDER, VIDEO_RECORDING_FILE,
 };
@@ -9,8 +9,8 @@ use alvr_common::{
 };
 use alvr_events::{ButtonEvent, Event, EventType};
 use alvr_packets::{ButtonValue, ClientListAction, ServerRequest};
-use alvr_session::ConnectionState;
-use bytes::Buf;
+use alvr_session::{ConnectionState, DisconnectReason};
+use bytes::{Buf, Bytes};
 use futures::SinkExt;
 use headers::HeaderMapExt;
 use hyper::{
@@ -19,7 +19,7 @@ use hyper::{
 };
 use serde::de::DeserializeOwned;
 use serde_json as json;
-use std::{net::SocketAddr, thread};
+use std::{net::SocketAddr, sync::Arc, thread};
 use tokio::sync::broadcast::{self, error::RecvError};
 use tokio_tungstenite::{tungstenite::protocol, WebSocketStream};
 use tokio_util::codec::{BytesCodec, FramedRead};
@@ -74,21 +74,34 @@ async fn websocket<T: Clone + Send + 'static>(
             }
         });
 
-        let mut response = Response::builder()
-            .status(StatusCode::SWITCHING_PROTOCOLS)
-            .body(Body::empty())?;
-
-        let h = response.headers_mut();
-        h.typed_insert(headers::Upgrade::websocket());
-        h.typed_insert(headers::SecWebsocketAccept::from(key));
-        h.typed_insert(headers::Connection::upgrade());
-
-        Ok(response)
+        websocket_upgrade_response(key)
     } else {
         reply(StatusCode::BAD_REQUEST)
     }
 }
 
+fn websocket_upgrade_response(key: headers::SecWebsocketKey) -> Result<Response<Body>> {
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .body(Body::empty())?;
+
+    let h = response.headers_mut();
+    h.typed_insert(headers::Upgrade::websocket());
+    h.typed_insert(headers::SecWebsocketAccept::from(key));
+    h.typed_insert(headers::Connection::upgrade());
+
+    Ok(response)
+}
+
+// Lets the websocket message reference the shared video buffer instead of a copy
+struct VideoMirrorBytes(VideoMirrorData);
+
+impl AsRef<[u8]> for VideoMirrorBytes {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
 async fn http_api(
     request: Request<Body>,
     events_sender: broadcast::Sender<Event>,
@@ -115,6 +128,26 @@ async fn http_api(
                     }
                     ServerRequest::UpdateClientList { hostname, action } => {
                         let mut data_manager = SERVER_DATA_MANAGER.write();
+                        // Only the connected client is disconnected, if its own entry changes.
+                        // Spectators are disconnected by their own lifecycle check when removed
+                        let should_disconnect = data_manager
+                            .client_list()
+                            .get(&hostname)
+                            .map(|entry| {
+                                matches!(
+                                    entry.connection_state,
+                                    ConnectionState::Connecting
+                                        | ConnectionState::Connected
+                                        | ConnectionState::Streaming
+                                )
+                            })
+                            .unwrap_or(false)
+                            && !matches!(
+                                action,
+                                ClientListAction::SetDisplayName(_)
+                                    | ClientListAction::UpdateCurrentIp(_)
+                                    | ClientListAction::SetConnectionState(_)
+                            );
                         if matches!(action, ClientListAction::RemoveEntry) {
                             if let Some(entry) = data_manager.client_list().get(&hostname) {
                                 if entry.connection_state != ConnectionState::Disconnected {
@@ -123,6 +156,7 @@ async fn http_api(
                                         ClientListAction::SetConnectionState(
                                             ConnectionState::Disconnecting {
                                                 should_be_removed: true,
+                                                reason: DisconnectReason::ClientRequest,
                                             },
                                         ),
                                     );
@@ -134,8 +168,10 @@ async fn http_api(
                             data_manager.update_client_list(hostname, action);
                         }
 
-                        if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
-                            notifier.send(ClientDisconnectRequest::Disconnect).ok();
+                        if should_disconnect {
+                            if let Some(notifier) = &*DISCONNECT_CLIENT_NOTIFIER.lock() {
+                                notifier.send(ClientDisconnectRequest::Disconnect).ok();
+                            }
                         }
                     }
                     ServerRequest::GetAudioDevices => {
@@ -180,6 +216,9 @@ async fn http_api(
                     ServerRequest::RestartSteamvr => {
                         thread::spawn(crate::restart_driver);
                     }
+                    ServerRequest::SetActiveClient(hostname) => {
+                        crate::connection::set_active_client(hostname);
+                    }
                     ServerRequest::ShutdownSteamvr => {
                         // This lint is bugged with extern "C"
                         #[allow(clippy::redundant_closure)]
@@ -194,7 +233,7 @@ async fn http_api(
         }
         "/api/events" => {
             websocket(request, events_sender, |e| {
-                protocol::Message::Text(json::to_string(&e).unwrap())
+                protocol::Message::Text(json::to_string(&e).unwrap().into())
             })
             .await?
         }
@@ -212,10 +251,13 @@ async fn http_api(
             };
 
             if let Some(config) = &*DECODER_CONFIG.lock() {
-                sender.send(config.config_buffer.clone()).ok();
+                sender.send(Arc::new(config.config_buffer.clone())).ok();
             }
 
-            let res = websocket(request, sender, protocol::Message::Binary).await?;
+            let res = websocket(request, sender, |data| {
+                protocol::Message::Binary(Bytes::from_owner(VideoMirrorBytes(data)))
+            })
+            .await?;
 
             unsafe { crate::RequestIDR() };
 
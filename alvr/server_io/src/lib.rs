@@ -8,18 +8,19 @@ pub use openvrpaths::*;
 
 use alvr_common::{
     anyhow::{bail, Result},
-    error, info,
+    error, info, warn,
 };
-use alvr_events::EventType;
+use alvr_events::{ConnectionLifecycleEvent, EventType};
 use alvr_packets::{AudioDevicesList, ClientListAction, PathSegment, PathValuePair};
 use alvr_session::{ClientConnectionConfig, ConnectionState, SessionConfig, Settings};
 use cpal::traits::{DeviceTrait, HostTrait};
 use serde_json as json;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    fs,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fs, iter,
     ops::{Deref, DerefMut},
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 fn save_session(session: &SessionConfig, path: &Path) -> Result<()> {
@@ -28,11 +29,77 @@ fn save_session(session: &SessionConfig, path: &Path) -> Result<()> {
     Ok(())
 }
 
+// Note: "value" can be any session subtree, in json format.
+fn with_values(session: &SessionConfig, descs: &[PathValuePair]) -> Result<SessionConfig> {
+    let mut session_json = serde_json::to_value(session.clone()).unwrap();
+
+    for desc in descs {
+        let mut session_ref = &mut session_json;
+        for segment in &desc.path {
+            session_ref = match segment {
+                PathSegment::Name(name) => {
+                    if let Some(name) = session_ref.get_mut(name) {
+                        name
+                    } else {
+                        bail!("From path {:?}: segment \"{name}\" not found", desc.path);
+                    }
+                }
+                PathSegment::Index(index) => {
+                    if let Some(index) = session_ref.get_mut(index) {
+                        index
+                    } else {
+                        bail!("From path {:?}: segment [{index}] not found", desc.path);
+                    }
+                }
+            };
+        }
+        *session_ref = desc.value.clone();
+    }
+
+    Ok(serde_json::from_value(session_json)?)
+}
+
+// Session with the settings overrides of a client applied over the global settings. The overrides
+// are set like the values of ServerRequest::SetValues, with paths relative to session_settings
+pub fn with_client_overrides(session: &SessionConfig, hostname: &str) -> Result<SessionConfig> {
+    let overrides = session
+        .client_connections
+        .get(hostname)
+        .map(|client| {
+            client
+                .settings_overrides
+                .iter()
+                .map(|(path, value)| PathValuePair {
+                    path: iter::once(PathSegment::Name("session_settings".into()))
+                        .chain(alvr_packets::parse_path(path))
+                        .collect(),
+                    value: value.clone(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    with_values(session, &overrides)
+}
+
+// Settings with the overrides of the client applied, if any
+fn client_settings(session: &SessionConfig, hostname: Option<&str>) -> Settings {
+    if let Some(hostname) = hostname {
+        match with_client_overrides(session, hostname) {
+            Ok(client_session) => return client_session.to_settings(),
+            Err(e) => warn!("Invalid settings overrides for {hostname}: {e}"),
+        }
+    }
+
+    session.to_settings()
+}
+
 // SessionConfig wrapper that saves session.json on destruction.
 pub struct SessionLock<'a> {
     session_desc: &'a mut SessionConfig,
     session_path: &'a Path,
     settings: &'a mut Settings,
+    settings_client: Option<&'a str>,
 }
 
 impl Deref for SessionLock<'_> {
@@ -51,7 +118,7 @@ impl DerefMut for SessionLock<'_> {
 impl Drop for SessionLock<'_> {
     fn drop(&mut self) {
         save_session(self.session_desc, self.session_path).unwrap();
-        *self.settings = self.session_desc.to_settings();
+        *self.settings = client_settings(self.session_desc, self.settings_client);
         alvr_events::send_event(EventType::Session(Box::new(self.session_desc.clone())));
     }
 }
@@ -64,6 +131,8 @@ impl Drop for SessionLock<'_> {
 pub struct ServerDataManager {
     session: SessionConfig,
     settings: Settings,
+    // Client whose settings overrides are applied to settings()
+    settings_client: Option<String>,
     session_path: PathBuf,
 }
 
@@ -76,6 +145,7 @@ impl ServerDataManager {
         Self {
             session: session_desc.clone(),
             settings: session_desc.to_settings(),
+            settings_client: None,
             session_path: session_path.to_owned(),
         }
     }
@@ -137,6 +207,7 @@ impl ServerDataManager {
             session_desc: &mut self.session,
             session_path: &self.session_path,
             settings: &mut self.settings,
+            settings_client: self.settings_client.as_deref(),
         }
     }
 
@@ -144,36 +215,16 @@ impl ServerDataManager {
         &self.settings
     }
 
+    // Apply the settings overrides of a client to settings(), or remove them
+    pub fn set_settings_client(&mut self, hostname: Option<String>) {
+        self.settings_client = hostname;
+        self.settings = client_settings(&self.session, self.settings_client.as_deref());
+    }
+
     // Note: "value" can be any session subtree, in json format.
     pub fn set_values(&mut self, descs: Vec<PathValuePair>) -> Result<()> {
-        let mut session_json = serde_json::to_value(self.session.clone()).unwrap();
-
-        for desc in descs {
-            let mut session_ref = &mut session_json;
-            for segment in &desc.path {
-                session_ref = match segment {
-                    PathSegment::Name(name) => {
-                        if let Some(name) = session_ref.get_mut(name) {
-                            name
-                        } else {
-                            bail!("From path {:?}: segment \"{name}\" not found", desc.path);
-                        }
-                    }
-                    PathSegment::Index(index) => {
-                        if let Some(index) = session_ref.get_mut(index) {
-                            index
-                        } else {
-                            bail!("From path {:?}: segment [{index}] not found", desc.path);
-                        }
-                    }
-                };
-            }
-            *session_ref = desc.value.clone();
-        }
-
-        // session_json has been updated
-        self.session = serde_json::from_value(session_json)?;
-        self.settings = self.session.to_settings();
+        self.session = with_values(&self.session, &descs)?;
+        self.settings = client_settings(&self.session, self.settings_client.as_deref());
 
         save_session(&self.session, &self.session_path).unwrap();
         alvr_events::send_event(EventType::Session(Box::new(self.session.clone())));
@@ -191,6 +242,7 @@ impl ServerDataManager {
         let maybe_client_entry = client_connections.entry(hostname);
 
         let mut updated = false;
+        let mut overrides_updated = false;
         match action {
             ClientListAction::AddIfMissing {
                 trusted,
@@ -202,7 +254,10 @@ impl ServerDataManager {
                         current_ip: None,
                         manual_ips: manual_ips.into_iter().collect(),
                         trusted,
+                        public_key: None,
+                        pairing_request: None,
                         connection_state: ConnectionState::Disconnected,
+                        settings_overrides: BTreeMap::new(),
                     };
                     new_entry.insert(client_connection_desc);
 
@@ -223,6 +278,53 @@ impl ServerDataManager {
                     updated = true;
                 }
             }
+            ClientListAction::SetPublicKey(public_key) => {
+                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                    entry.get_mut().public_key = Some(public_key);
+
+                    updated = true;
+                }
+            }
+            ClientListAction::SetPairingRequest(request) => {
+                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                    let hostname = entry.key().clone();
+                    let client = entry.get_mut();
+
+                    // A pending request can be only cleared or renewed by the same key
+                    match (&client.pairing_request, &request) {
+                        (Some(pending), Some(request))
+                            if pending.public_key != request.public_key =>
+                        {
+                            warn!("Ignored pairing request of {hostname} with a different key");
+                        }
+                        _ => {
+                            client.pairing_request = request;
+
+                            updated = true;
+                        }
+                    }
+                }
+            }
+            ClientListAction::ConfirmPairing { pin } => {
+                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                    let hostname = entry.key().clone();
+                    let client = entry.get_mut();
+
+                    match client.pairing_request.take() {
+                        Some(request) if request.pin == pin.trim() => {
+                            client.public_key = Some(request.public_key);
+                            client.trusted = true;
+
+                            updated = true;
+                        }
+                        Some(request) => {
+                            warn!("Wrong pairing PIN for {hostname}");
+                            client.pairing_request = Some(request);
+                        }
+                        None => warn!("No pending pairing request for {hostname}"),
+                    }
+                }
+            }
             ClientListAction::SetManualIps(ips) => {
                 if let Entry::Occupied(mut entry) = maybe_client_entry {
                     entry.get_mut().manual_ips = ips.into_iter().collect();
@@ -232,11 +334,23 @@ impl ServerDataManager {
             }
             ClientListAction::RemoveEntry => {
                 if let Entry::Occupied(entry) = maybe_client_entry {
-                    entry.remove_entry();
+                    let (hostname, _) = entry.remove_entry();
+
+                    if self.session.active_client.as_ref() == Some(&hostname) {
+                        self.session.active_client = None;
+                    }
 
                     updated = true;
                 }
             }
+            ClientListAction::SetSettingsOverrides(overrides) => {
+                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                    entry.get_mut().settings_overrides = overrides;
+
+                    updated = true;
+                    overrides_updated = true;
+                }
+            }
             ClientListAction::UpdateCurrentIp(current_ip) => {
                 if let Entry::Occupied(mut entry) = maybe_client_entry {
                     if entry.get().current_ip != current_ip {
@@ -249,6 +363,16 @@ impl ServerDataManager {
             ClientListAction::SetConnectionState(state) => {
                 if let Entry::Occupied(mut entry) = maybe_client_entry {
                     if entry.get().connection_state != state {
+                        alvr_events::send_event(EventType::ConnectionLifecycle(
+                            ConnectionLifecycleEvent {
+                                hostname: entry.key().clone(),
+                                state: state.clone(),
+                                timestamp: SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default(),
+                            },
+                        ));
+
                         entry.get_mut().connection_state = state;
 
                         updated = true;
@@ -260,6 +384,10 @@ impl ServerDataManager {
         if updated {
             self.session.client_connections = client_connections;
 
+            if overrides_updated {
+                self.settings = client_settings(&self.session, self.settings_client.as_deref());
+            }
+
             save_session(&self.session, &self.session_path).unwrap();
             alvr_events::send_event(EventType::Session(Box::new(self.session.clone())));
         }
@@ -312,3 +440,55 @@ impl ServerDataManager {
 }
 
 pub fn prepare_client_list() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_client_overrides() {
+        let mut session = SessionConfig::default();
+        session.client_connections.insert(
+            "client".into(),
+            ClientConnectionConfig {
+                display_name: "Client".into(),
+                current_ip: None,
+                manual_ips: HashSet::new(),
+                trusted: true,
+                public_key: None,
+                pairing_request: None,
+                connection_state: ConnectionState::Disconnected,
+                settings_overrides: [
+                    ("video.preferred_fps".into(), json::json!(90.0)),
+                    ("headset.controllers.enabled".into(), json::json!(false)),
+                ]
+                .into_iter()
+                .collect(),
+            },
+        );
+
+        let settings = with_client_overrides(&session, "client")
+            .unwrap()
+            .to_settings();
+        assert_eq!(settings.video.preferred_fps, 90.0);
+        assert!(settings.headset.controllers.as_option().is_none());
+
+        // Other clients use the global settings
+        let settings = with_client_overrides(&session, "other")
+            .unwrap()
+            .to_settings();
+        assert_eq!(
+            settings.video.preferred_fps,
+            SessionConfig::default().to_settings().video.preferred_fps
+        );
+
+        session
+            .client_connections
+            .get_mut("client")
+            .unwrap()
+            .settings_overrides
+            .insert("video.not_a_setting".into(), json::json!(0));
+        assert!(with_client_overrides(&session, "client").is_err());
+    }
+}
@@ -12,7 +12,7 @@ use serde::{Deserialize, Serialize};
 use serde_json as json;
 use settings_schema::{NumberType, SchemaNode};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     net::IpAddr,
     path::PathBuf,
 };
@@ -100,13 +100,41 @@ pub struct OpenvrConfig {
     pub _controller_profile: i32,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // The client stopped responding
+    Timeout,
+    // Requested from the streamer, by removing the client or changing the active client
+    ClientRequest,
+    ServerShutdown,
+    // The client does not support the configuration required by the streamer
+    ProtocolMismatch,
+    // SteamVR restarts to apply a new configuration
+    RestartRequired,
+    SocketError,
+    // The client failed the identity verification or is not paired
+    Untrusted,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ConnectionState {
     Disconnected,
     Connecting,
     Connected,
     Streaming,
-    Disconnecting { should_be_removed: bool },
+    // Receiving the stream of the client in the Streaming state
+    Spectating,
+    Disconnecting {
+        should_be_removed: bool,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PairingRequest {
+    pub public_key: [u8; 32],
+    pub pairing_secret: [u8; 32],
+    pub pin: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -115,7 +143,18 @@ pub struct ClientConnectionConfig {
     pub current_ip: Option<IpAddr>,
     pub manual_ips: HashSet<IpAddr>,
     pub trusted: bool,
+    // Identity of the client, verified on each connection. Set after pairing or, for clients
+    // trusted without pairing, on the first connection.
+    #[serde(default)]
+    pub public_key: Option<[u8; 32]>,
+    // Pending until the user confirms the PIN shown on the headset
+    #[serde(default)]
+    pub pairing_request: Option<PairingRequest>,
     pub connection_state: ConnectionState,
+    // Sparse overlay of the session settings, applied when this client streams. Keys are paths in
+    // SessionSettings, in the format of the SetValues paths, for example "video.preferred_fps"
+    #[serde(default)]
+    pub settings_overrides: BTreeMap<String, json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -125,6 +164,10 @@ pub struct SessionConfig {
     pub openvr_config: OpenvrConfig,
     // The hashmap key is the hostname
     pub client_connections: HashMap<String, ClientConnectionConfig>,
+    // Hostname of the only client allowed to stream. If none, the first client that connects
+    // streams
+    #[serde(default)]
+    pub active_client: Option<String>,
     pub session_settings: SessionSettings,
 }
 
@@ -150,6 +193,7 @@ impl Default for SessionConfig {
                 ..<_>::default()
             },
             client_connections: HashMap::new(),
+            active_client: None,
             session_settings: settings::session_settings_default(),
         }
     }
@@ -171,6 +215,8 @@ impl SessionConfig {
             return Ok(());
         }
 
+        let json_value = &migrate_connection_scripts(json_value);
+
         // Note: unwrap is safe because current session is expected to serialize correctly
         let old_session_json = json::to_value(&self).unwrap();
         let old_session_fields = old_session_json.as_object().unwrap();
@@ -231,6 +277,48 @@ impl SessionConfig {
     }
 }
 
+// on_connect_script and on_disconnect_script have been replaced by the hooks of the Connect and
+// Disconnect events. Scripts that are set are converted to hooks
+fn migrate_connection_scripts(session_json: &json::Value) -> json::Value {
+    let mut session_json = session_json.clone();
+
+    let Some(connection_json) = session_json
+        .get_mut("session_settings")
+        .and_then(|session_settings| session_settings.get_mut("connection"))
+        .and_then(|connection| connection.as_object_mut())
+    else {
+        return session_json;
+    };
+
+    let hooks = [
+        ("on_connect_script", "Connect script", "Connect"),
+        ("on_disconnect_script", "Disconnect script", "Disconnect"),
+    ]
+    .into_iter()
+    .filter_map(|(field, name, event)| {
+        let command = connection_json.remove(field)?.as_str()?.to_owned();
+
+        // Unset fields are filled by the extrapolation with the default hook
+        (!command.is_empty()).then(|| {
+            json::json!({
+                "name": name,
+                "event": { "variant": event },
+                "command": command,
+            })
+        })
+    })
+    .collect::<Vec<_>>();
+
+    if !hooks.is_empty() {
+        connection_json.insert(
+            "hooks".into(),
+            json::json!({ "hooks": { "content": hooks } }),
+        );
+    }
+
+    session_json
+}
+
 // Current data extrapolation strategy: match both field name and value type exactly.
 // Integer bounds are not validated, if they do not match the schema, deserialization will fail and
 // all data is lost.
@@ -636,4 +724,23 @@ mod tests {
         assert_eq!(settings.video.preferred_fps, 60.0);
         assert!(settings.headset.controllers.as_option().is_none());
     }
+
+    #[test]
+    fn test_session_extrapolation_connection_scripts() {
+        let mut session_json = json::to_value(SessionConfig::default()).unwrap();
+        let connection_json = session_json["session_settings"]["connection"]
+            .as_object_mut()
+            .unwrap();
+        connection_json.remove("hooks");
+        connection_json.insert("on_connect_script".into(), json::json!("connect.sh"));
+        connection_json.insert("on_disconnect_script".into(), json::json!(""));
+
+        let mut session = SessionConfig::default();
+        session.merge_from_json(&session_json).unwrap();
+
+        let hooks = session.to_settings().connection.hooks.hooks;
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(hooks[0].event, HookEvent::Connect);
+        assert_eq!(hooks[0].command, "connect.sh");
+    }
 }
@@ -291,6 +291,49 @@ pub enum BitrateMode {
         #[schema(flag = "real-time")]
         decoder_latency_limiter: Switch<DecoderLatencyLimiter>,
     },
+
+    #[schema(strings(
+        help = r#"Reduce the bitrate as soon as the network latency starts growing, before the network queues fill up.
+The bitrate is then increased slowly while the latency is stable."#
+    ))]
+    #[schema(collapsible)]
+    DelayGradient {
+        #[schema(strings(display_name = "Initial bitrate"))]
+        #[schema(gui(slider(min = 1, max = 1000, logarithmic)), suffix = "Mbps")]
+        initial_bitrate_mbps: u64,
+
+        #[schema(strings(display_name = "Maximum bitrate"))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1, max = 1000, logarithmic)), suffix = "Mbps")]
+        max_bitrate_mbps: Switch<u64>,
+
+        #[schema(strings(display_name = "Minimum bitrate"))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1, max = 100, logarithmic)), suffix = "Mbps")]
+        min_bitrate_mbps: Switch<u64>,
+
+        #[schema(strings(
+            help = "When the network is congested, the bitrate is set to the received bitrate multiplied by this value"
+        ))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 0.5, max = 0.95, step = 0.01)))]
+        decrease_multiplier: f32,
+
+        #[schema(strings(
+            display_name = "Increase per second",
+            help = "Bitrate increase per second while the network latency is stable"
+        ))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1.0, max = 50.0, step = 1.0)), suffix = "%")]
+        increase_percentage: f32,
+
+        #[schema(strings(
+            help = "Number of frames used to estimate the latency trend. Higher values are less sensitive to jitter but react slower"
+        ))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 5, max = 100)))]
+        trendline_window_size: usize,
+    },
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
@@ -885,14 +928,226 @@ pub enum SocketProtocol {
     Udp,
     #[schema(strings(display_name = "TCP"))]
     Tcp,
+    #[schema(strings(display_name = "QUIC"))]
+    Quic {
+        #[schema(strings(
+            help = r#"Packets of these streams are sent as unreliable QUIC datagrams, the other streams are reliable.
+Tracking: 0, Haptics: 1, Audio: 2, Video: 3, Statistics: 4"#
+        ))]
+        unreliable_stream_ids: Vec<u16>,
+    },
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
+#[schema(gui = "button_group")]
+pub enum DiscoveryMethod {
+    #[schema(strings(display_name = "UDP broadcast"))]
+    Broadcast,
+    #[schema(strings(display_name = "mDNS"))]
+    Mdns,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct DiscoveryConfig {
+    #[schema(strings(
+        help = "mDNS: the streamer advertises an _alvr._tcp service that the client looks for, then the client announces itself directly to the streamer. Use this on networks that block broadcast. Clients that don't find the service fall back to UDP broadcast."
+    ))]
+    pub method: DiscoveryMethod,
+
     #[schema(strings(
         help = "Allow untrusted clients to connect without confirmation. This is not recommended for security reasons."
     ))]
     pub auto_trust_clients: bool,
+
+    #[schema(strings(
+        help = "Discovery packets above this rate are dropped, per source IP. Clients send a few packets per second."
+    ))]
+    #[schema(gui(slider(min = 1, max = 100, logarithmic)), suffix = " packets/s")]
+    pub max_packets_per_second: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct ForwardErrorCorrectionConfig {
+    #[schema(strings(help = "Tracking: 0, Haptics: 1, Audio: 2, Video: 3, Statistics: 4"))]
+    pub stream_id: u16,
+
+    #[schema(strings(
+        help = "Number of data shards protected by each parity shard. A lower value allows to recover more lost shards but uses more bandwidth."
+    ))]
+    #[schema(gui(slider(min = 1, max = 100, logarithmic)), suffix = " shards")]
+    pub data_shards_per_parity_shard: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct ShardRetransmissionConfig {
+    #[schema(strings(help = "Tracking: 0, Haptics: 1, Audio: 2, Video: 3, Statistics: 4"))]
+    pub stream_id: u16,
+
+    #[schema(strings(
+        help = "Lost shards are requested again only until this time has passed since the first shard of the packet was received. After that the packet is considered lost."
+    ))]
+    #[schema(gui(slider(min = 0.1, max = 3.0, step = 0.1)), suffix = " frames")]
+    pub deadline_frame_intervals: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct StreamPriorityConfig {
+    #[schema(strings(
+        help = "Tracking: 0, Haptics: 1, Audio: 2, Video: 3, Statistics: 4, Bandwidth probe: 5"
+    ))]
+    pub stream_id: u16,
+
+    #[schema(strings(help = "Shards of streams with higher priority are sent first"))]
+    #[schema(gui(slider(min = 0, max = 10)))]
+    pub priority: u8,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct BandwidthProbeConfig {
+    #[schema(gui(slider(min = 1, max = 20)))]
+    pub bursts_count: u32,
+
+    #[schema(strings(help = "Each packet is about 1 KB"))]
+    #[schema(gui(slider(min = 10, max = 1000, logarithmic)), suffix = " packets")]
+    pub packets_per_burst: u32,
+
+    #[schema(gui(slider(min = 10, max = 500, logarithmic)), suffix = "ms")]
+    pub burst_interval_ms: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct VideoPacingConfig {
+    #[schema(strings(
+        help = "Maximum fraction of the frame interval used to send a video frame. Higher values reduce packet loss on keyframes but increase latency"
+    ))]
+    #[schema(gui(slider(min = 0.05, max = 1.0, step = 0.05)))]
+    pub frame_interval_fraction: f32,
+
+    #[schema(strings(help = "Smaller video frames are sent without pacing"))]
+    #[schema(gui(slider(min = 1, max = 200, logarithmic)), suffix = " KB")]
+    pub min_paced_frame_size_kb: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct SessionResumptionConfig {
+    #[schema(strings(
+        help = "Time after a connection loss during which the client can resume the session"
+    ))]
+    #[schema(gui(slider(min = 1, max = 60)), suffix = "s")]
+    pub grace_period_s: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+    StreamStart,
+    BitrateDrop,
+    BatteryLow,
+    Recenter,
+    #[schema(strings(display_name = "SteamVR restart"))]
+    SteamvrRestart,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct HookConfig {
+    pub name: String,
+
+    pub event: HookEvent,
+
+    #[schema(strings(help = "Path of the executable or script to run"))]
+    pub command: String,
+
+    #[schema(strings(help = "The hook is killed if it is still running after this time"))]
+    #[schema(gui(slider(min = 1, max = 120, logarithmic)), suffix = "s")]
+    pub timeout_s: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct HooksConfig {
+    #[schema(strings(
+        help = r#"Commands run when an event occurs. The context of the event is passed as environment variables (ALVR_EVENT, ALVR_HOSTNAME, ALVR_IP, ALVR_RESOLUTION, ALVR_FPS, ALVR_CODEC, ALVR_REASON, ...) and as JSON on stdin.
+Connect and disconnect refer to the streaming session, stream start to the first video frame sent to the client. The output of the hooks is written to the log. Failures are shown as notifications."#
+    ))]
+    #[schema(flag = "real-time")]
+    pub hooks: Vec<HookConfig>,
+
+    #[schema(strings(
+        help = "The bitrate drop event is triggered when the bitrate requested to the encoder goes below this value"
+    ))]
+    #[schema(flag = "real-time")]
+    #[schema(gui(slider(min = 1.0, max = 100.0, logarithmic)), suffix = "Mbps")]
+    pub bitrate_drop_threshold_mbps: f32,
+
+    #[schema(strings(
+        help = "The battery low event is triggered when the headset battery goes below this level while not charging"
+    ))]
+    #[schema(flag = "real-time")]
+    #[schema(gui(slider(min = 1, max = 50)), suffix = "%")]
+    pub battery_low_threshold_percent: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
+#[schema(gui = "button_group")]
+pub enum ImpairmentDirection {
+    #[schema(strings(display_name = "Streamer to client"))]
+    StreamerToClient,
+    #[schema(strings(display_name = "Client to streamer"))]
+    ClientToStreamer,
+    Both,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct NetworkImpairmentConfig {
+    #[schema(strings(help = "The impairments are applied to the packets sent in this direction"))]
+    pub direction: ImpairmentDirection,
+
+    #[schema(gui(slider(min = 0.0, max = 50.0, step = 0.5)), suffix = "%")]
+    pub packet_loss_percentage: f32,
+
+    #[schema(gui(slider(min = 0, max = 500)), suffix = "ms")]
+    pub latency_ms: u64,
+
+    #[schema(strings(
+        help = "Each packet is delayed by a random amount between -jitter and +jitter in addition to the latency. This can reorder packets."
+    ))]
+    #[schema(gui(slider(min = 0, max = 100)), suffix = "ms")]
+    pub jitter_ms: u64,
+
+    #[schema(strings(help = "Packets held back and sent after the next one"))]
+    #[schema(gui(slider(min = 0.0, max = 50.0, step = 0.5)), suffix = "%")]
+    pub reorder_percentage: f32,
+
+    #[schema(gui(slider(min = 0.0, max = 50.0, step = 0.5)), suffix = "%")]
+    pub duplication_percentage: f32,
+
+    #[schema(strings(
+        help = "Token bucket rate limiter. Packets that would be queued for too long are dropped."
+    ))]
+    #[schema(gui(slider(min = 1.0, max = 1000.0, logarithmic)), suffix = "Mbps")]
+    pub bandwidth_limit_mbps: Switch<f32>,
+
+    #[schema(strings(help = "Use a fixed seed to make the sequence of impairments repeatable"))]
+    pub random_seed: Switch<u64>,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct ConnectionDebugConfig {
+    #[schema(strings(
+        help = "Emulate a bad network on the stream socket, to reproduce issues. Do not use while playing."
+    ))]
+    pub network_impairment: Switch<NetworkImpairmentConfig>,
+
+    #[schema(strings(
+        help = r#"Write all shards sent and received on the stream socket to a capture file, that can be inspected with alvr_capture_tool. The file is saved in the log folder (client: app data folder).
+The capture includes the video stream and grows quickly."#
+    ))]
+    pub shard_capture: bool,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -907,12 +1162,34 @@ pub enum SocketBufferSize {
 pub struct ConnectionConfig {
     #[schema(strings(
         help = r#"UDP: Faster, but less stable than TCP. Try this if your network is well optimized and free of interference.
-TCP: Slower than UDP, but more stable. Pick this if you experience video or audio stutters with UDP."#
+TCP: Slower than UDP, but more stable. Pick this if you experience video or audio stutters with UDP.
+QUIC: Congestion controlled and encrypted. Reliable and unreliable streams share the same connection."#
     ))]
     pub stream_protocol: SocketProtocol,
 
     pub client_discovery: Switch<DiscoveryConfig>,
 
+    #[schema(strings(
+        help = r#"Accept control connections from clients that connect to the streamer address directly, on TCP port 9943. Use this when the client cannot be found with discovery, for example through a VPN or port forwarding.
+Clients that connect this way must be added to the client list from the dashboard first, then trusted or paired."#
+    ))]
+    pub accept_client_connections: bool,
+
+    #[schema(strings(
+        help = "Only clients with an IP in these networks are discovered or accepted when they connect directly, in CIDR notation (192.168.1.0/24, fd00::/8). If empty, clients from any network are allowed."
+    ))]
+    pub allowed_networks: Vec<String>,
+
+    #[schema(strings(
+        help = "Clients with an IP in these networks are ignored, even if they are in the allowed networks"
+    ))]
+    pub denied_networks: Vec<String>,
+
+    #[schema(strings(
+        help = "Clients that connect while another client is streaming join as spectators: they receive the same video and game audio, but their tracking and input are ignored"
+    ))]
+    pub accept_spectators: bool,
+
     pub stream_port: u16,
     pub web_server_port: u16,
     pub osc_local_port: u16,
@@ -947,22 +1224,57 @@ For now works only on Windows+Nvidia"#
     #[schema(flag = "steamvr-restart")]
     pub aggressive_keyframe_resend: bool,
 
+    pub hooks: HooksConfig,
+
+    #[schema(gui(slider(min = 1024, max = 65507, logarithmic)), suffix = "B")]
+    pub packet_size: i32,
+
     #[schema(strings(
-        help = "This script will be ran when the headset connects. Env var ACTION will be set to `connect`."
+        help = r#"Send parity shards for the packets of the selected streams, so that packets with lost shards can be rebuilt without requesting a new IDR frame.
+Each parity shard can rebuild one lost shard of its group. The groups are interleaved, so a burst of consecutive lost shards can be rebuilt if it is not longer than the number of parity shards of the packet. Two lost shards of the same group cannot be rebuilt.
+This is only useful with UDP."#
     ))]
-    pub on_connect_script: String,
+    pub forward_error_correction: Vec<ForwardErrorCorrectionConfig>,
 
     #[schema(strings(
-        help = "This script will be ran when the headset disconnects, or when SteamVR shuts down. Env var ACTION will be set to `disconnect`."
+        help = r#"The receiver asks the sender to send again the lost shards of the packets of the selected streams, before the packet is considered lost.
+This works only with UDP."#
     ))]
-    #[schema(flag = "real-time")]
-    pub on_disconnect_script: String,
+    pub shard_retransmission: Vec<ShardRetransmissionConfig>,
 
-    #[schema(gui(slider(min = 1024, max = 65507, logarithmic)), suffix = "B")]
-    pub packet_size: i32,
+    #[schema(strings(
+        help = r#"Encrypt and authenticate all packets sent over the stream socket (tracking, audio, video, etc). The key is negotiated for each session.
+This increases CPU usage."#
+    ))]
+    pub stream_encryption: bool,
+
+    #[schema(strings(
+        help = r#"Priority of each stream when sending. Queued shards of lower priority streams (like video) are sent after the shards of higher priority streams.
+Streams not listed have priority 0."#
+    ))]
+    pub stream_priorities: Vec<StreamPriorityConfig>,
+
+    #[schema(strings(
+        help = r#"Send bursts of padding packets when the stream starts, to measure the bandwidth of the network. The result is used as initial bitrate by the adaptive bitrate modes, and shown in the statistics tab.
+This requires a client that supports it."#
+    ))]
+    pub bandwidth_probe: Switch<BandwidthProbeConfig>,
+
+    #[schema(strings(
+        help = "Spread the packets of large video frames over time instead of sending them in a single burst, to avoid overflowing the Wi-Fi queues. The sending rate follows the target bitrate"
+    ))]
+    pub video_pacing: Switch<VideoPacingConfig>,
+
+    #[schema(strings(
+        help = r#"If the connection is lost, keep the stream running on the streamer for a while. A client that reconnects in time resumes the session without negotiating it again and without restarting SteamVR.
+This requires a client that supports it."#
+    ))]
+    pub session_resumption: Switch<SessionResumptionConfig>,
 
     #[schema(suffix = " frames")]
     pub statistics_history_size: usize,
+
+    pub debug: ConnectionDebugConfig,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -1129,6 +1441,21 @@ pub fn session_settings_default() -> SettingsDefault {
                             },
                         },
                     },
+                    DelayGradient: BitrateModeDelayGradientDefault {
+                        gui_collapsed: true,
+                        initial_bitrate_mbps: 30,
+                        max_bitrate_mbps: SwitchDefault {
+                            enabled: false,
+                            content: 100,
+                        },
+                        min_bitrate_mbps: SwitchDefault {
+                            enabled: true,
+                            content: 5,
+                        },
+                        decrease_multiplier: 0.85,
+                        increase_percentage: 8.0,
+                        trendline_window_size: 20,
+                    },
                     variant: BitrateModeDefaultVariant::ConstantMbps,
                 },
                 adapt_to_framerate: SwitchDefault {
@@ -1458,13 +1785,36 @@ pub fn session_settings_default() -> SettingsDefault {
             gui_collapsed: false,
             stream_protocol: SocketProtocolDefault {
                 variant: SocketProtocolDefaultVariant::Udp,
+                Quic: SocketProtocolQuicDefault {
+                    unreliable_stream_ids: VectorDefault {
+                        gui_collapsed: true,
+                        element: 3,
+                        content: vec![3],
+                    },
+                },
             },
             client_discovery: SwitchDefault {
                 enabled: true,
                 content: DiscoveryConfigDefault {
+                    method: DiscoveryMethodDefault {
+                        variant: DiscoveryMethodDefaultVariant::Broadcast,
+                    },
                     auto_trust_clients: cfg!(debug_assertions),
+                    max_packets_per_second: 10,
                 },
             },
+            accept_client_connections: false,
+            allowed_networks: VectorDefault {
+                gui_collapsed: true,
+                element: "192.168.1.0/24".into(),
+                content: vec![],
+            },
+            denied_networks: VectorDefault {
+                gui_collapsed: true,
+                element: "192.168.1.0/24".into(),
+                content: vec![],
+            },
+            accept_spectators: false,
             web_server_port: 8082,
             stream_port: 9944,
             osc_local_port: 9942,
@@ -1475,10 +1825,116 @@ pub fn session_settings_default() -> SettingsDefault {
             max_queued_server_video_frames: 1024,
             avoid_video_glitching: false,
             aggressive_keyframe_resend: false,
-            on_connect_script: "".into(),
-            on_disconnect_script: "".into(),
+            hooks: HooksConfigDefault {
+                gui_collapsed: true,
+                hooks: VectorDefault {
+                    gui_collapsed: true,
+                    element: HookConfigDefault {
+                        name: "My hook".into(),
+                        event: HookEventDefault {
+                            variant: HookEventDefaultVariant::Connect,
+                        },
+                        command: "".into(),
+                        timeout_s: 10,
+                    },
+                    content: vec![],
+                },
+                bitrate_drop_threshold_mbps: 10.0,
+                battery_low_threshold_percent: 20,
+            },
             packet_size: 1400,
+            forward_error_correction: VectorDefault {
+                gui_collapsed: true,
+                element: ForwardErrorCorrectionConfigDefault {
+                    stream_id: 3,
+                    data_shards_per_parity_shard: 10,
+                },
+                content: vec![],
+            },
+            shard_retransmission: VectorDefault {
+                gui_collapsed: true,
+                element: ShardRetransmissionConfigDefault {
+                    stream_id: 3,
+                    deadline_frame_intervals: 1.0,
+                },
+                content: vec![],
+            },
+            stream_encryption: false,
+            stream_priorities: VectorDefault {
+                gui_collapsed: true,
+                element: StreamPriorityConfigDefault {
+                    stream_id: 3,
+                    priority: 0,
+                },
+                content: vec![
+                    StreamPriorityConfigDefault {
+                        stream_id: 0, // tracking
+                        priority: 3,
+                    },
+                    StreamPriorityConfigDefault {
+                        stream_id: 1, // haptics
+                        priority: 3,
+                    },
+                    StreamPriorityConfigDefault {
+                        stream_id: 4, // statistics
+                        priority: 2,
+                    },
+                    StreamPriorityConfigDefault {
+                        stream_id: 2, // audio
+                        priority: 1,
+                    },
+                ],
+            },
+            bandwidth_probe: SwitchDefault {
+                enabled: true,
+                content: BandwidthProbeConfigDefault {
+                    gui_collapsed: true,
+                    bursts_count: 5,
+                    packets_per_burst: 100,
+                    burst_interval_ms: 50,
+                },
+            },
+            video_pacing: SwitchDefault {
+                enabled: true,
+                content: VideoPacingConfigDefault {
+                    gui_collapsed: true,
+                    frame_interval_fraction: 0.3,
+                    min_paced_frame_size_kb: 32,
+                },
+            },
+            session_resumption: SwitchDefault {
+                enabled: true,
+                content: SessionResumptionConfigDefault {
+                    gui_collapsed: true,
+                    grace_period_s: 10,
+                },
+            },
             statistics_history_size: 256,
+            debug: ConnectionDebugConfigDefault {
+                gui_collapsed: true,
+                network_impairment: SwitchDefault {
+                    enabled: false,
+                    content: NetworkImpairmentConfigDefault {
+                        direction: ImpairmentDirectionDefault {
+                            variant: ImpairmentDirectionDefaultVariant::StreamerToClient,
+                        },
+                        packet_loss_percentage: 0.0,
+                        latency_ms: 0,
+                        jitter_ms: 0,
+                        reorder_percentage: 0.0,
+                        duplication_percentage: 0.0,
+                        bandwidth_limit_mbps: SwitchDefault {
+                            enabled: false,
+                            content: 100.0,
+                        },
+                        random_seed: SwitchDefault {
+                            enabled: false,
+                            content: 0,
+                        },
+                    },
+                },
+                shard_capture: false,
+            },
         },
         logging: LoggingConfigDefault {
             gui_collapsed: false,
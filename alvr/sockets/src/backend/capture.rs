@@ -0,0 +1,413 @@
+// Capture of the shards sent and received through StreamSocket, used to diagnose stutters and
+// packet loss offline. Shards are captured before encryption and after decryption.
+//
+// Capture file format (integers are little endian):
+// Header:
+//   magic: b"ALVRSHRD"
+//   version: u8
+//   shard wire format version: u8, only since capture version 2 (before, always 1)
+//   max packet size: u32, needed to reconstruct packets from the shards
+//   FEC streams count: u16, followed for each stream by
+//     stream ID: u16
+//     data shards per parity shard: u32
+// Records, until the end of the file:
+//   direction: u8, 0 = sent, 1 = received
+//   timestamp: u64, microseconds since the start of the capture
+//   shard length: u32
+//   shard: the whole shard, prefix included
+//
+// The shard prefix formats are described in wire_format.rs. NACKs use stream ID 65535.
+
+use super::{SocketReader, SocketWriter, MAX_SHARD_SIZE};
+use crate::wire_format::{ShardPrefix, WireFormat, SHARD_LENGTH_FIELD_SIZE, SHARD_PREFIX_SIZE};
+use alvr_common::{
+    anyhow::{anyhow, bail, Result},
+    debug,
+    parking_lot::Mutex,
+    ConResult, ConnectionError,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    mem,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const CAPTURE_MAGIC: &[u8; 8] = b"ALVRSHRD";
+const CAPTURE_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaptureDirection {
+    Sent,
+    Received,
+}
+
+pub struct CapturedShard {
+    pub direction: CaptureDirection,
+    pub timestamp: Duration,
+    pub shard: Vec<u8>,
+    prefix: ShardPrefix,
+}
+
+impl CapturedShard {
+    pub fn stream_id(&self) -> u16 {
+        self.prefix.stream_id
+    }
+
+    pub fn packet_index(&self) -> u32 {
+        self.prefix.packet_index
+    }
+
+    pub fn shards_count(&self) -> usize {
+        self.prefix.shards_count
+    }
+
+    pub fn shard_index(&self) -> usize {
+        self.prefix.shard_index
+    }
+
+    /// SHARD_FLAG_* bits. Always 0 with the v1 wire format
+    pub fn flags(&self) -> u8 {
+        self.prefix.flags
+    }
+
+    pub fn is_parity(&self) -> bool {
+        self.shard_index() >= self.shards_count()
+    }
+
+    /// Shard data after the prefix
+    pub fn payload(&self) -> &[u8] {
+        &self.shard[SHARD_PREFIX_SIZE..]
+    }
+}
+
+pub(crate) struct ShardCaptureFile {
+    file: BufWriter<File>,
+    start_instant: Instant,
+}
+
+impl ShardCaptureFile {
+    pub fn create(
+        path: &Path,
+        wire_format: WireFormat,
+        max_packet_size: usize,
+        fec_group_sizes: &HashMap<u16, usize>,
+    ) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(CAPTURE_MAGIC)?;
+        file.write_all(&[CAPTURE_VERSION, wire_format.version()])?;
+        file.write_all(&(max_packet_size as u32).to_le_bytes())?;
+        file.write_all(&(fec_group_sizes.len() as u16).to_le_bytes())?;
+        for (stream_id, group_size) in fec_group_sizes {
+            file.write_all(&stream_id.to_le_bytes())?;
+            file.write_all(&(*group_size as u32).to_le_bytes())?;
+        }
+
+        Ok(Self {
+            file,
+            start_instant: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, direction: CaptureDirection, shard: &[u8]) {
+        let direction = match direction {
+            CaptureDirection::Sent => 0_u8,
+            CaptureDirection::Received => 1,
+        };
+        let timestamp = self.start_instant.elapsed().as_micros() as u64;
+
+        let res = self
+            .file
+            .write_all(&[direction])
+            .and_then(|_| self.file.write_all(&timestamp.to_le_bytes()))
+            .and_then(|_| self.file.write_all(&(shard.len() as u32).to_le_bytes()))
+            .and_then(|_| self.file.write_all(shard));
+        if let Err(e) = res {
+            debug!("Failed to write shard capture: {e}");
+        }
+    }
+}
+
+pub(crate) struct CaptureSocketWriter {
+    inner: Box<dyn SocketWriter>,
+    capture: Arc<Mutex<ShardCaptureFile>>,
+}
+
+impl CaptureSocketWriter {
+    pub fn new(inner: Box<dyn SocketWriter>, capture: Arc<Mutex<ShardCaptureFile>>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl SocketWriter for CaptureSocketWriter {
+    fn send(&mut self, shard: &[u8]) -> Result<()> {
+        self.capture.lock().write(CaptureDirection::Sent, shard);
+
+        self.inner.send(shard)
+    }
+
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        self.capture
+            .lock()
+            .write(CaptureDirection::Sent, &[prefix, data].concat());
+
+        self.inner.send_parts(prefix, data)
+    }
+}
+
+// Shards can be read in multiple calls (with TCP), so the data is accumulated until a whole shard
+// is available
+pub(crate) struct CaptureSocketReader {
+    inner: Box<dyn SocketReader>,
+    capture: Arc<Mutex<ShardCaptureFile>>,
+    wire_format: WireFormat,
+    pending: Vec<u8>,
+}
+
+impl CaptureSocketReader {
+    pub fn new(
+        inner: Box<dyn SocketReader>,
+        capture: Arc<Mutex<ShardCaptureFile>>,
+        wire_format: WireFormat,
+    ) -> Self {
+        Self {
+            inner,
+            capture,
+            wire_format,
+            pending: vec![],
+        }
+    }
+}
+
+impl SocketReader for CaptureSocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        let size = self.inner.recv(buffer)?;
+        self.pending.extend_from_slice(&buffer[..size]);
+
+        while self.pending.len() >= SHARD_LENGTH_FIELD_SIZE {
+            let Some(shard_length) = self
+                .wire_format
+                .shard_length(&self.pending)
+                .filter(|length| (SHARD_LENGTH_FIELD_SIZE..=MAX_SHARD_SIZE).contains(length))
+            else {
+                // The shard boundaries cannot be found anymore in a byte stream. The connection is
+                // closed, instead of capturing garbage
+                self.pending.clear();
+                return Err(ConnectionError::Other(anyhow!(
+                    "Invalid shard length while capturing received shards"
+                )));
+            };
+
+            if self.pending.len() < shard_length {
+                break;
+            }
+
+            self.capture
+                .lock()
+                .write(CaptureDirection::Received, &self.pending[..shard_length]);
+            self.pending.drain(..shard_length);
+        }
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.inner.peek(buffer)
+    }
+
+    fn is_datagram(&self) -> bool {
+        self.inner.is_datagram()
+    }
+}
+
+pub struct ShardCaptureReader {
+    file: BufReader<File>,
+    wire_format: WireFormat,
+    max_packet_size: usize,
+    fec_group_sizes: HashMap<u16, usize>,
+}
+
+impl ShardCaptureReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; CAPTURE_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            bail!("Not a shard capture file");
+        }
+
+        let mut version = [0; 1];
+        file.read_exact(&mut version)?;
+        let wire_format = match version[0] {
+            1 => WireFormat::V1,
+            CAPTURE_VERSION => {
+                let mut wire_format = [0; 1];
+                file.read_exact(&mut wire_format)?;
+                WireFormat::from_version(wire_format[0])
+                    .ok_or_else(|| anyhow!("Unsupported wire format version {}", wire_format[0]))?
+            }
+            version => bail!("Unsupported shard capture version {version}"),
+        };
+
+        let max_packet_size = read_u32(&mut file)? as usize;
+
+        let mut fec_group_sizes = HashMap::new();
+        for _ in 0..read_u16(&mut file)? {
+            let stream_id = read_u16(&mut file)?;
+            let group_size = read_u32(&mut file)? as usize;
+            fec_group_sizes.insert(stream_id, group_size);
+        }
+
+        Ok(Self {
+            file,
+            wire_format,
+            max_packet_size,
+            fec_group_sizes,
+        })
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Data shards per parity shard for each stream ID with FEC enabled
+    pub fn fec_group_sizes(&self) -> &HashMap<u16, usize> {
+        &self.fec_group_sizes
+    }
+
+    fn read_shard(&mut self) -> Result<Option<CapturedShard>> {
+        let mut direction = [0; 1];
+        match self.file.read_exact(&mut direction) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let direction = match direction[0] {
+            0 => CaptureDirection::Sent,
+            1 => CaptureDirection::Received,
+            value => bail!("Invalid shard direction {value}"),
+        };
+
+        let mut timestamp = [0; mem::size_of::<u64>()];
+        self.file.read_exact(&mut timestamp)?;
+        let timestamp = Duration::from_micros(u64::from_le_bytes(timestamp));
+
+        let shard_length = read_u32(&mut self.file)? as usize;
+        if !(SHARD_PREFIX_SIZE..=MAX_SHARD_SIZE).contains(&shard_length) {
+            bail!("Invalid shard length {shard_length}");
+        }
+        let mut shard = vec![0; shard_length];
+        self.file.read_exact(&mut shard)?;
+
+        let prefix = self
+            .wire_format
+            .read_prefix(&shard)
+            .ok_or_else(|| anyhow!("Invalid shard prefix"))?;
+
+        Ok(Some(CapturedShard {
+            direction,
+            timestamp,
+            shard,
+            prefix,
+        }))
+    }
+}
+
+impl Iterator for ShardCaptureReader {
+    type Item = Result<CapturedShard>;
+
+    // A truncated capture (for example after a crash) ends with an error
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_shard().transpose()
+    }
+}
+
+fn read_u16(file: &mut impl Read) -> Result<u16> {
+    let mut bytes = [0; mem::size_of::<u16>()];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(file: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; mem::size_of::<u32>()];
+    file.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// Feeds the captured shards of one direction to a StreamSocket. The capture must be consumed from
+// a single thread.
+pub(crate) struct ReplaySocketReader {
+    capture: ShardCaptureReader,
+    direction: CaptureDirection,
+    shard: Vec<u8>,
+    cursor: usize,
+}
+
+impl ReplaySocketReader {
+    pub fn new(capture: ShardCaptureReader, direction: CaptureDirection) -> Self {
+        Self {
+            capture,
+            direction,
+            shard: vec![],
+            cursor: 0,
+        }
+    }
+
+    fn fill_shard(&mut self) -> ConResult {
+        while self.cursor >= self.shard.len() {
+            match self.capture.next() {
+                Some(Ok(shard)) => {
+                    if shard.direction == self.direction {
+                        self.shard = shard.shard;
+                        self.cursor = 0;
+                    }
+                }
+                Some(Err(e)) => return Err(ConnectionError::Other(e)),
+                None => return Err(ConnectionError::Other(anyhow!("End of capture"))),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SocketReader for ReplaySocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let size = usize::min(buffer.len(), self.shard.len() - self.cursor);
+        buffer[..size].copy_from_slice(&self.shard[self.cursor..][..size]);
+        self.cursor += size;
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let size = usize::min(buffer.len(), self.shard.len() - self.cursor);
+        buffer[..size].copy_from_slice(&self.shard[self.cursor..][..size]);
+
+        Ok(size)
+    }
+}
+
+// Shards sent during replay (NACKs) are discarded
+pub(crate) struct NullSocketWriter;
+
+impl SocketWriter for NullSocketWriter {
+    fn send(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
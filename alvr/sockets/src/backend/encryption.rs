@@ -0,0 +1,536 @@
+// Authenticated encryption of shards. Each shard is encrypted separately with ChaCha20-Poly1305,
+// the shard prefix is authenticated but not encrypted. The nonce is a per-direction counter,
+// incremented for each shard sent, so it is never reused even if the same shard is sent again (for
+// example when retransmitted). The counter is appended to the encrypted payload, followed by the
+// tag. The receiver rejects counters already seen or too old with a sliding window.
+// The packet index of the prefix is not used as nonce nor for replay protection: it is shared by
+// all shards of a packet, it is repeated by retransmitted shards and each stream counts packets
+// separately, so it doesn't identify a shard. A window over packet indices would also reject the
+// retransmitted shards.
+// Each direction uses a different key, derived from an ephemeral X25519 key exchange performed
+// during the handshake. The keys are moved into the socket of a single session, so the shards of
+// older sessions fail authentication and cannot be replayed.
+
+use super::{SocketReader, SocketWriter, MAX_SHARD_SIZE};
+use crate::wire_format::{
+    WireFormat, SHARD_FLAG_ENCRYPTED, SHARD_LENGTH_FIELD_SIZE, SHARD_PREFIX_SIZE,
+};
+use alvr_common::{
+    anyhow::{anyhow, Result},
+    con_bail, debug, ConResult,
+};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::mem;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const TAG_SIZE: usize = 16;
+const COUNTER_SIZE: usize = mem::size_of::<u64>();
+pub const ENCRYPTION_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+// Shards are accepted out of order as long as they are not older than this number of shards
+// compared to the most recent one
+const REPLAY_WINDOW_SHARDS: u64 = 4096;
+
+const SERVER_TO_CLIENT_INFO: &[u8] = b"ALVR stream server to client";
+const CLIENT_TO_SERVER_INFO: &[u8] = b"ALVR stream client to server";
+
+pub struct StreamKeys {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+}
+
+pub struct StreamKeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl StreamKeyExchange {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+
+        Self { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key.to_bytes()
+    }
+
+    fn derive_key(shared_secret: &[u8], info: &[u8]) -> [u8; 32] {
+        let mut key = [0; 32];
+        // The output size is valid, this cannot fail
+        Hkdf::<Sha256>::new(None, shared_secret)
+            .expand(info, &mut key)
+            .unwrap();
+
+        key
+    }
+
+    pub fn server_stream_keys(self, client_public_key: [u8; 32]) -> StreamKeys {
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(client_public_key));
+
+        StreamKeys {
+            send_key: Self::derive_key(shared_secret.as_bytes(), SERVER_TO_CLIENT_INFO),
+            recv_key: Self::derive_key(shared_secret.as_bytes(), CLIENT_TO_SERVER_INFO),
+        }
+    }
+
+    pub fn client_stream_keys(self, server_public_key: [u8; 32]) -> StreamKeys {
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(server_public_key));
+
+        StreamKeys {
+            send_key: Self::derive_key(shared_secret.as_bytes(), CLIENT_TO_SERVER_INFO),
+            recv_key: Self::derive_key(shared_secret.as_bytes(), SERVER_TO_CLIENT_INFO),
+        }
+    }
+}
+
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..COUNTER_SIZE].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+// Each bit marks a received counter, at position counter % REPLAY_WINDOW_SHARDS
+struct ReplayWindow {
+    highest_counter: Option<u64>,
+    bitmap: [u64; (REPLAY_WINDOW_SHARDS / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            highest_counter: None,
+            bitmap: [0; (REPLAY_WINDOW_SHARDS / 64) as usize],
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let position = counter % REPLAY_WINDOW_SHARDS;
+
+        ((position / 64) as usize, 1 << (position % 64))
+    }
+
+    fn is_replayed(&self, counter: u64) -> bool {
+        let Some(highest_counter) = self.highest_counter else {
+            return false;
+        };
+
+        if counter > highest_counter {
+            false
+        } else if highest_counter - counter >= REPLAY_WINDOW_SHARDS {
+            true
+        } else {
+            let (word, mask) = Self::bit(counter);
+            self.bitmap[word] & mask != 0
+        }
+    }
+
+    // Must be called only for authenticated shards, not replayed
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest_counter {
+            Some(highest_counter) if counter <= highest_counter => (),
+            Some(highest_counter) if counter - highest_counter < REPLAY_WINDOW_SHARDS => {
+                // Forget the counters that slide out of the window
+                for skipped_counter in highest_counter + 1..counter {
+                    let (word, mask) = Self::bit(skipped_counter);
+                    self.bitmap[word] &= !mask;
+                }
+                self.highest_counter = Some(counter);
+            }
+            _ => {
+                self.bitmap.fill(0);
+                self.highest_counter = Some(counter);
+            }
+        }
+
+        let (word, mask) = Self::bit(counter);
+        self.bitmap[word] |= mask;
+    }
+}
+
+pub struct EncryptedSocketWriter {
+    inner: Box<dyn SocketWriter>,
+    cipher: ChaCha20Poly1305,
+    wire_format: WireFormat,
+    next_counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl EncryptedSocketWriter {
+    pub fn new(inner: Box<dyn SocketWriter>, keys: &StreamKeys, wire_format: WireFormat) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.send_key)),
+            wire_format,
+            next_counter: 0,
+            buffer: vec![],
+        }
+    }
+}
+
+impl SocketWriter for EncryptedSocketWriter {
+    fn send(&mut self, shard: &[u8]) -> Result<()> {
+        self.send_parts(shard, &[])
+    }
+
+    // The shard is encrypted into the buffer of the writer, this is the only copy of the data
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(prefix);
+        self.buffer.extend_from_slice(data);
+
+        // The length field must account for the counter and the tag
+        let length = self
+            .wire_format
+            .shard_length(prefix)
+            .ok_or_else(|| anyhow!("Invalid shard prefix"))?
+            + ENCRYPTION_OVERHEAD;
+        self.wire_format.set_shard_length(&mut self.buffer, length);
+        self.wire_format
+            .add_flags(&mut self.buffer, SHARD_FLAG_ENCRYPTED);
+
+        let counter = self.next_counter;
+        self.next_counter += 1;
+
+        let (prefix, payload) = self.buffer.split_at_mut(SHARD_PREFIX_SIZE);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&counter_nonce(counter), prefix, payload)
+            .map_err(|_| anyhow!("Shard encryption failed"))?;
+        self.buffer.extend_from_slice(&counter.to_le_bytes());
+        self.buffer.extend_from_slice(&tag);
+
+        self.inner.send(&self.buffer)
+    }
+}
+
+pub struct EncryptedSocketReader {
+    inner: Box<dyn SocketReader>,
+    cipher: ChaCha20Poly1305,
+    wire_format: WireFormat,
+    encrypted_shard: Vec<u8>,
+    encrypted_cursor: usize,
+    shard: Vec<u8>,
+    shard_cursor: usize,
+    replay_window: ReplayWindow,
+}
+
+impl EncryptedSocketReader {
+    pub fn new(inner: Box<dyn SocketReader>, keys: &StreamKeys, wire_format: WireFormat) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&keys.recv_key)),
+            wire_format,
+            encrypted_shard: vec![],
+            encrypted_cursor: 0,
+            shard: vec![],
+            shard_cursor: 0,
+            replay_window: ReplayWindow::new(),
+        }
+    }
+
+    // Receive and decrypt a whole shard, if the previous one has been fully read
+    fn fill_shard(&mut self) -> ConResult {
+        if self.shard_cursor < self.shard.len() {
+            return Ok(());
+        }
+
+        if self.encrypted_shard.is_empty() {
+            let mut length_bytes = [0; SHARD_LENGTH_FIELD_SIZE];
+            if self.inner.peek(&mut length_bytes)? < length_bytes.len() {
+                return alvr_common::try_again();
+            }
+
+            let Some(shard_length) =
+                self.wire_format
+                    .shard_length(&length_bytes)
+                    .filter(|length| {
+                        (SHARD_PREFIX_SIZE + ENCRYPTION_OVERHEAD..=MAX_SHARD_SIZE).contains(length)
+                    })
+            else {
+                // In a byte stream the shard boundaries are lost
+                if !self.inner.is_datagram() {
+                    con_bail!("Invalid encrypted shard length");
+                }
+
+                debug!("Discarding malformed encrypted shard");
+                self.inner.recv(&mut length_bytes)?;
+                return alvr_common::try_again();
+            };
+
+            self.encrypted_shard.resize(shard_length, 0);
+            self.encrypted_cursor = 0;
+        }
+
+        // This loop may bail out at any time if a timeout is reached. The state is kept for the next
+        // call
+        while self.encrypted_cursor < self.encrypted_shard.len() {
+            self.encrypted_cursor += self
+                .inner
+                .recv(&mut self.encrypted_shard[self.encrypted_cursor..])?;
+        }
+
+        let mut shard = mem::take(&mut self.encrypted_shard);
+        if shard.len() < SHARD_PREFIX_SIZE + ENCRYPTION_OVERHEAD {
+            debug!("Discarding malformed encrypted shard");
+            return alvr_common::try_again();
+        }
+
+        let (payload, tag) = shard.split_at_mut(shard.len() - TAG_SIZE);
+        let (payload, counter_bytes) = payload.split_at_mut(payload.len() - COUNTER_SIZE);
+        let (prefix, payload) = payload.split_at_mut(SHARD_PREFIX_SIZE);
+
+        // The counter is authenticated as part of the nonce
+        let counter = u64::from_le_bytes((&*counter_bytes).try_into().unwrap());
+        if self.replay_window.is_replayed(counter) {
+            debug!("Discarding replayed shard");
+            return alvr_common::try_again();
+        }
+
+        if self
+            .cipher
+            .decrypt_in_place_detached(
+                &counter_nonce(counter),
+                prefix,
+                payload,
+                Tag::from_slice(tag),
+            )
+            .is_err()
+        {
+            debug!("Discarding shard that failed authentication");
+            return alvr_common::try_again();
+        }
+        self.replay_window.mark_received(counter);
+
+        shard.truncate(shard.len() - ENCRYPTION_OVERHEAD);
+        self.wire_format.set_shard_length(&mut shard, shard.len());
+
+        // Recycle the allocation of the previous shard
+        self.encrypted_shard = mem::replace(&mut self.shard, shard);
+        self.encrypted_shard.clear();
+        self.shard_cursor = 0;
+
+        Ok(())
+    }
+}
+
+impl SocketReader for EncryptedSocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let size = usize::min(buffer.len(), self.shard.len() - self.shard_cursor);
+        buffer[..size].copy_from_slice(&self.shard[self.shard_cursor..][..size]);
+        self.shard_cursor += size;
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let size = usize::min(buffer.len(), self.shard.len() - self.shard_cursor);
+        buffer[..size].copy_from_slice(&self.shard[self.shard_cursor..][..size]);
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire_format::ShardPrefix;
+    use alvr_common::parking_lot::Mutex;
+    use socket2::Socket;
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::Arc,
+        time::Duration,
+    };
+
+    const WIRE_FORMAT: WireFormat = WireFormat::V2;
+
+    fn keys() -> (StreamKeys, StreamKeys) {
+        let server_exchange = StreamKeyExchange::new();
+        let client_exchange = StreamKeyExchange::new();
+        let server_public_key = server_exchange.public_key();
+        let client_public_key = client_exchange.public_key();
+
+        (
+            server_exchange.server_stream_keys(client_public_key),
+            client_exchange.client_stream_keys(server_public_key),
+        )
+    }
+
+    // Returns a plain sender socket and the receiving socket
+    fn udp_pair() -> (UdpSocket, Socket) {
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.connect(sender.local_addr().unwrap()).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        (sender, Socket::from(receiver))
+    }
+
+    fn shard(payload: &[u8]) -> Vec<u8> {
+        let mut shard = vec![0; SHARD_PREFIX_SIZE];
+        shard.extend_from_slice(payload);
+        WIRE_FORMAT.write_prefix(
+            &mut shard,
+            &ShardPrefix {
+                shard_length: SHARD_PREFIX_SIZE + payload.len(),
+                stream_id: 3,
+                shards_count: 1,
+                ..Default::default()
+            },
+        );
+
+        shard
+    }
+
+    // Captures the encrypted shards instead of sending them
+    struct RecordingSocketWriter(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl SocketWriter for RecordingSocketWriter {
+        fn send(&mut self, buffer: &[u8]) -> Result<()> {
+            self.0.lock().push(buffer.to_vec());
+            Ok(())
+        }
+    }
+
+    fn recv_payload(reader: &mut EncryptedSocketReader) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; MAX_SHARD_SIZE];
+        let size = reader.recv(&mut buffer).ok()?;
+
+        Some(buffer[SHARD_PREFIX_SIZE..size].to_vec())
+    }
+
+    fn encrypted_shards(keys: &StreamKeys, payloads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let shards = Arc::new(Mutex::new(vec![]));
+        let mut writer = EncryptedSocketWriter::new(
+            Box::new(RecordingSocketWriter(Arc::clone(&shards))),
+            keys,
+            WIRE_FORMAT,
+        );
+        for payload in payloads {
+            writer.send(&shard(payload)).unwrap();
+        }
+
+        let shards = shards.lock();
+        shards.clone()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (server_keys, client_keys) = keys();
+        let (sender, receiver) = udp_pair();
+
+        let mut writer = EncryptedSocketWriter::new(Box::new(sender), &server_keys, WIRE_FORMAT);
+        let mut reader = EncryptedSocketReader::new(Box::new(receiver), &client_keys, WIRE_FORMAT);
+
+        // The same shard sent twice (like a retransmission) is encrypted with different nonces
+        writer.send(&shard(&[1, 2, 3])).unwrap();
+        writer.send(&shard(&[1, 2, 3])).unwrap();
+
+        assert_eq!(recv_payload(&mut reader).unwrap(), [1, 2, 3]);
+        assert_eq!(recv_payload(&mut reader).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn nonces_are_not_reused() {
+        let (server_keys, _) = keys();
+
+        let shards = encrypted_shards(&server_keys, &[&[1, 2, 3], &[1, 2, 3]]);
+        assert_ne!(shards[0], shards[1]);
+    }
+
+    #[test]
+    fn tampered_shards_are_rejected() {
+        let (server_keys, client_keys) = keys();
+        let (sender, receiver) = udp_pair();
+
+        let mut shards = encrypted_shards(&server_keys, &[&[1, 2, 3], &[4, 5, 6], &[7, 8, 9]]);
+        // Payload, counter and authenticated prefix
+        shards[0][SHARD_PREFIX_SIZE] ^= 1;
+        let counter_position = shards[1].len() - ENCRYPTION_OVERHEAD;
+        shards[1][counter_position] ^= 1;
+        shards[2][4] ^= 1;
+
+        let mut reader = EncryptedSocketReader::new(Box::new(receiver), &client_keys, WIRE_FORMAT);
+        for shard in &shards {
+            sender.send(shard).unwrap();
+            assert!(recv_payload(&mut reader).is_none());
+        }
+    }
+
+    #[test]
+    fn replayed_shards_are_rejected() {
+        let (server_keys, client_keys) = keys();
+        let (sender, receiver) = udp_pair();
+
+        let shards = encrypted_shards(&server_keys, &[&[1], &[2], &[3]]);
+
+        let mut reader = EncryptedSocketReader::new(Box::new(receiver), &client_keys, WIRE_FORMAT);
+        // Reordered shards are accepted, each only once
+        for (idx, expected) in [
+            (1, Some(vec![2])),
+            (0, Some(vec![1])),
+            (1, None),
+            (2, Some(vec![3])),
+            (0, None),
+        ] {
+            sender.send(&shards[idx]).unwrap();
+            assert_eq!(recv_payload(&mut reader), expected);
+        }
+    }
+
+    #[test]
+    fn shards_of_older_sessions_are_rejected() {
+        let (old_server_keys, _) = keys();
+        let (server_keys, client_keys) = keys();
+        let (sender, receiver) = udp_pair();
+
+        // Both shards have counter 0
+        let old_shards = encrypted_shards(&old_server_keys, &[&[1]]);
+        let shards = encrypted_shards(&server_keys, &[&[2]]);
+
+        let mut reader = EncryptedSocketReader::new(Box::new(receiver), &client_keys, WIRE_FORMAT);
+        sender.send(&old_shards[0]).unwrap();
+        assert!(recv_payload(&mut reader).is_none());
+        sender.send(&shards[0]).unwrap();
+        assert_eq!(recv_payload(&mut reader).unwrap(), [2]);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new();
+
+        window.mark_received(10);
+        assert!(window.is_replayed(10));
+        assert!(!window.is_replayed(9));
+        assert!(!window.is_replayed(11));
+
+        window.mark_received(10 + REPLAY_WINDOW_SHARDS);
+        // Slid out of the window
+        assert!(window.is_replayed(10));
+        assert!(window.is_replayed(9));
+        assert!(!window.is_replayed(11));
+        assert!(window.is_replayed(10 + REPLAY_WINDOW_SHARDS));
+
+        // A large jump forgets all the received counters
+        window.mark_received(10 * REPLAY_WINDOW_SHARDS);
+        assert!(!window.is_replayed(10 * REPLAY_WINDOW_SHARDS - 1));
+    }
+}
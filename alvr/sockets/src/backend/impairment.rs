@@ -0,0 +1,241 @@
+// Network impairment emulation, used to reproduce bad network conditions without a real lossy
+// network. Shards sent through ImpairedSocketWriter can be dropped, duplicated, reordered, delayed
+// and rate limited with a token bucket. Impairments are applied on the sending side, where delays
+// can be emulated precisely; to impair both directions, the writers of both peers must be wrapped.
+// If no delay is configured (no latency, jitter or bandwidth limit), shards are forwarded
+// synchronously and, with a fixed random seed, the impaired sequence of shards is deterministic.
+// Otherwise shards are sent by a background thread when their delay expires. Note that jitter can
+// reorder shards too.
+
+use super::SocketWriter;
+use alvr_common::{
+    anyhow::Result,
+    debug,
+    parking_lot::{Condvar, Mutex, MutexGuard},
+    settings_schema::Switch,
+};
+use alvr_session::NetworkImpairmentConfig;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// Size of the token bucket, that is the maximum burst sent at full speed
+const TOKEN_BUCKET_SIZE_BYTES: f64 = 64.0 * 1024.0;
+// Shards that would be delayed more than this by the bandwidth limit are dropped, like a router
+// with a full queue would do
+const MAX_SHAPING_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Default)]
+struct DelayQueueState {
+    shards: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    next_sequence: u64,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct DelayQueue {
+    state: Mutex<DelayQueueState>,
+    condvar: Condvar,
+}
+
+impl DelayQueue {
+    fn push(&self, release_instant: Instant, shard: Vec<u8>) {
+        let mut state = self.state.lock();
+
+        // The sequence number keeps the send order for shards with the same release instant
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state
+            .shards
+            .push(Reverse((release_instant, sequence, shard)));
+
+        self.condvar.notify_one();
+    }
+
+    fn stop(&self) {
+        self.state.lock().stopped = true;
+        self.condvar.notify_one();
+    }
+
+    fn send_loop(&self, mut socket: Box<dyn SocketWriter>) {
+        let mut state = self.state.lock();
+
+        while !state.stopped {
+            let Some(Reverse((release_instant, ..))) = state.shards.peek() else {
+                self.condvar.wait(&mut state);
+                continue;
+            };
+
+            if *release_instant > Instant::now() {
+                let release_instant = *release_instant;
+                self.condvar.wait_until(&mut state, release_instant);
+                continue;
+            }
+
+            if let Some(Reverse((_, _, shard))) = state.shards.pop() {
+                MutexGuard::unlocked(&mut state, || {
+                    if let Err(e) = socket.send(&shard) {
+                        debug!("Failed to send delayed shard: {e}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+enum ImpairedOutput {
+    Direct(Box<dyn SocketWriter>),
+    Delayed {
+        queue: Arc<DelayQueue>,
+        thread: Option<JoinHandle<()>>,
+    },
+}
+
+pub struct ImpairedSocketWriter {
+    config: NetworkImpairmentConfig,
+    rng: StdRng,
+    held_shard: Option<Vec<u8>>,
+    bucket_tokens: f64,
+    bucket_last_update: Instant,
+    output: ImpairedOutput,
+}
+
+impl ImpairedSocketWriter {
+    pub fn new(inner: Box<dyn SocketWriter>, config: NetworkImpairmentConfig) -> Self {
+        let rng = if let Switch::Enabled(seed) = config.random_seed {
+            StdRng::seed_from_u64(seed)
+        } else {
+            StdRng::from_entropy()
+        };
+
+        let output = if config.latency_ms > 0
+            || config.jitter_ms > 0
+            || matches!(config.bandwidth_limit_mbps, Switch::Enabled(_))
+        {
+            let queue = Arc::new(DelayQueue::default());
+            let thread = thread::spawn({
+                let queue = Arc::clone(&queue);
+                move || queue.send_loop(inner)
+            });
+
+            ImpairedOutput::Delayed {
+                queue,
+                thread: Some(thread),
+            }
+        } else {
+            ImpairedOutput::Direct(inner)
+        };
+
+        Self {
+            config,
+            rng,
+            held_shard: None,
+            bucket_tokens: TOKEN_BUCKET_SIZE_BYTES,
+            bucket_last_update: Instant::now(),
+            output,
+        }
+    }
+
+    fn roll(&mut self, percentage: f32) -> bool {
+        percentage > 0.0 && self.rng.gen::<f32>() * 100.0 < percentage
+    }
+
+    // Returns None if the shard must be dropped
+    fn shaping_delay(&mut self, shard_size: usize) -> Option<Duration> {
+        let Switch::Enabled(limit_mbps) = self.config.bandwidth_limit_mbps else {
+            return Some(Duration::ZERO);
+        };
+        let bytes_per_sec = f64::max(limit_mbps as f64, 0.001) * 1e6 / 8.0;
+
+        let now = Instant::now();
+        self.bucket_tokens = f64::min(
+            self.bucket_tokens + (now - self.bucket_last_update).as_secs_f64() * bytes_per_sec,
+            TOKEN_BUCKET_SIZE_BYTES,
+        );
+        self.bucket_last_update = now;
+
+        // The tokens can become negative, which accounts for the shards still waiting to be sent
+        let missing_tokens = shard_size as f64 - self.bucket_tokens;
+        let delay = if missing_tokens > 0.0 {
+            Duration::from_secs_f64(missing_tokens / bytes_per_sec)
+        } else {
+            Duration::ZERO
+        };
+
+        if delay > MAX_SHAPING_DELAY {
+            return None;
+        }
+
+        self.bucket_tokens -= shard_size as f64;
+
+        Some(delay)
+    }
+
+    fn forward(&mut self, shard: &[u8]) -> Result<()> {
+        let Some(shaping_delay) = self.shaping_delay(shard.len()) else {
+            return Ok(());
+        };
+
+        let jitter_ms = self.config.jitter_ms as i64;
+        let jitter_ms = if jitter_ms > 0 {
+            self.rng.gen_range(-jitter_ms..=jitter_ms)
+        } else {
+            0
+        };
+        let delay = shaping_delay
+            + Duration::from_millis(i64::max(self.config.latency_ms as i64 + jitter_ms, 0) as u64);
+
+        match &mut self.output {
+            ImpairedOutput::Direct(socket) => socket.send(shard),
+            ImpairedOutput::Delayed { queue, .. } => {
+                queue.push(Instant::now() + delay, shard.to_vec());
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SocketWriter for ImpairedSocketWriter {
+    fn send(&mut self, shard: &[u8]) -> Result<()> {
+        if self.roll(self.config.packet_loss_percentage) {
+            return Ok(());
+        }
+
+        // A reordered shard is held back and sent after the next one
+        if self.held_shard.is_none() && self.roll(self.config.reorder_percentage) {
+            self.held_shard = Some(shard.to_vec());
+
+            return Ok(());
+        }
+
+        self.forward(shard)?;
+
+        if self.roll(self.config.duplication_percentage) {
+            self.forward(shard)?;
+        }
+
+        if let Some(held_shard) = self.held_shard.take() {
+            self.forward(&held_shard)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ImpairedSocketWriter {
+    fn drop(&mut self) {
+        if let ImpairedOutput::Delayed { queue, thread } = &mut self.output {
+            queue.stop();
+
+            if let Some(thread) = thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
@@ -1,10 +1,23 @@
+pub mod capture;
+pub mod encryption;
+pub mod impairment;
+pub mod quic;
 pub mod tcp;
 pub mod udp;
 
 use alvr_common::{anyhow::Result, ConResult};
 
+// Shards bigger than this are considered malformed. This is bigger than the maximum UDP payload.
+pub const MAX_SHARD_SIZE: usize = u16::MAX as usize;
+
 pub trait SocketWriter: Send {
     fn send(&mut self, buffer: &[u8]) -> Result<()>;
+
+    // Send a shard whose prefix is stored apart from its data. Implementations should avoid joining
+    // the two parts, the default implementation copies them into a temporary buffer
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        self.send(&[prefix, data].concat())
+    }
 }
 
 // Trait used to abstract different socket (or other input/output) implementations. The funtionality
@@ -15,5 +28,11 @@ pub trait SocketReader: Send {
     // packet (size of MTU) otherwise data will be corrupted. The size of the data is
     fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize>;
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize>;
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize>;
+
+    // Whether each recv() consumes a whole shard, discarding the bytes that don't fit into the
+    // buffer (like UDP). Otherwise the rest of the shard is returned by the next recv() calls
+    fn is_datagram(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,467 @@
+// QUIC backend. All ALVR streams are multiplexed over a single congestion controlled and encrypted
+// connection. Shards of unreliable streams are sent as QUIC datagrams, while each reliable stream
+// is mapped to its own unidirectional QUIC stream, so a lost shard of one stream does not block the
+// others.
+// Note: the client generates a new self-signed certificate for each connection and the server has
+// no way of knowing it beforehand, so the certificate is not verified. The connection is encrypted
+// but not authenticated.
+
+use crate::{
+    wire_format::{WireFormat, SHARD_LENGTH_FIELD_SIZE},
+    KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT,
+};
+
+use super::{SocketReader, SocketWriter, MAX_SHARD_SIZE};
+use alvr_common::{
+    anyhow::{anyhow, Result},
+    con_bail, AnyhowToCon, ConResult, HandleTryAgain, ToCon,
+};
+use alvr_session::SocketBufferSize;
+use bytes::BytesMut;
+use quinn::{
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, SendStream, ServerConfig,
+    TokioRuntime, TransportConfig,
+};
+use socket2::{Protocol, Type};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    net::IpAddr,
+    sync::{mpsc, Arc},
+    time::{Duration, SystemTime},
+};
+use tokio::runtime::Runtime;
+
+const SERVER_NAME: &str = "alvr.client";
+
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::Certificate,
+        _: &[rustls::Certificate],
+        _: &rustls::ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn transport_config() -> Result<Arc<TransportConfig>> {
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    config.max_idle_timeout(Some(IdleTimeout::try_from(KEEPALIVE_TIMEOUT)?));
+
+    Ok(Arc::new(config))
+}
+
+fn new_endpoint(
+    runtime: &Runtime,
+    port: u16,
+    send_buffer_bytes: SocketBufferSize,
+    recv_buffer_bytes: SocketBufferSize,
+    server_config: Option<ServerConfig>,
+) -> Result<Endpoint> {
+    let socket = crate::bind_dual_stack(Type::DGRAM, Protocol::UDP, port)?;
+
+    crate::set_socket_buffers(&socket, send_buffer_bytes, recv_buffer_bytes).ok();
+
+    // The socket is registered to the tokio reactor
+    let _guard = runtime.enter();
+
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        socket.into(),
+        Arc::new(TokioRuntime),
+    )?)
+}
+
+pub struct QuicListener {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    unreliable_stream_ids: HashSet<u16>,
+}
+
+pub fn bind(
+    port: u16,
+    send_buffer_bytes: SocketBufferSize,
+    recv_buffer_bytes: SocketBufferSize,
+    unreliable_stream_ids: &[u16],
+) -> Result<QuicListener> {
+    let runtime = Arc::new(Runtime::new()?);
+
+    let certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()])?;
+    let mut server_config = ServerConfig::with_single_cert(
+        vec![rustls::Certificate(certificate.serialize_der()?)],
+        rustls::PrivateKey(certificate.serialize_private_key_der()),
+    )?;
+    server_config.transport_config(transport_config()?);
+
+    let endpoint = new_endpoint(
+        &runtime,
+        port,
+        send_buffer_bytes,
+        recv_buffer_bytes,
+        Some(server_config),
+    )?;
+
+    Ok(QuicListener {
+        runtime,
+        endpoint,
+        unreliable_stream_ids: unreliable_stream_ids.iter().cloned().collect(),
+    })
+}
+
+pub fn accept_from_server(
+    listener: &QuicListener,
+    server_ip: Option<IpAddr>,
+    timeout: Duration,
+    wire_format: WireFormat,
+) -> ConResult<(QuicSender, QuicReceiver)> {
+    let runtime = &listener.runtime;
+
+    let Ok(connecting) =
+        runtime.block_on(tokio::time::timeout(timeout, listener.endpoint.accept()))
+    else {
+        return alvr_common::try_again();
+    };
+    let connecting = connecting.to_con()?;
+
+    if let Some(ip) = server_ip {
+        let server_ip = crate::peer_ip(connecting.remote_address());
+        if server_ip != crate::canonical_ip(ip) {
+            con_bail!("Connected to wrong client: Expected: {ip}, Found {server_ip}");
+        }
+    }
+
+    let Ok(connection) = runtime.block_on(tokio::time::timeout(timeout, connecting)) else {
+        return alvr_common::try_again();
+    };
+
+    Ok(split_connection(
+        Arc::clone(runtime),
+        listener.endpoint.clone(),
+        connection.to_con()?,
+        listener.unreliable_stream_ids.clone(),
+        timeout,
+        wire_format,
+    ))
+}
+
+pub fn connect_to_client(
+    timeout: Duration,
+    client_ip: IpAddr,
+    port: u16,
+    send_buffer_bytes: SocketBufferSize,
+    recv_buffer_bytes: SocketBufferSize,
+    unreliable_stream_ids: &[u16],
+    wire_format: WireFormat,
+) -> ConResult<(QuicSender, QuicReceiver)> {
+    let runtime = Arc::new(Runtime::new().to_con()?);
+
+    let mut client_config = ClientConfig::new(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+            .with_no_client_auth(),
+    ));
+    client_config.transport_config(transport_config().to_con()?);
+
+    // Unlike UDP, the port of the server can be chosen freely. This allows loopback connections.
+    let endpoint =
+        new_endpoint(&runtime, 0, send_buffer_bytes, recv_buffer_bytes, None).to_con()?;
+
+    let connecting = endpoint
+        .connect_with(
+            client_config,
+            crate::peer_socket_addr(client_ip, port, endpoint.local_addr().to_con()?.is_ipv6()),
+            SERVER_NAME,
+        )
+        .to_con()?;
+    let Ok(connection) = runtime.block_on(tokio::time::timeout(timeout, connecting)) else {
+        return alvr_common::try_again();
+    };
+
+    Ok(split_connection(
+        runtime,
+        endpoint,
+        connection.to_con()?,
+        unreliable_stream_ids.iter().cloned().collect(),
+        timeout,
+        wire_format,
+    ))
+}
+
+fn split_connection(
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    connection: Connection,
+    unreliable_stream_ids: HashSet<u16>,
+    timeout: Duration,
+    wire_format: WireFormat,
+) -> (QuicSender, QuicReceiver) {
+    let (shard_sender, shard_receiver) = mpsc::channel();
+
+    runtime.spawn({
+        let connection = connection.clone();
+        let shard_sender = shard_sender.clone();
+        async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                if shard_sender.send(datagram.to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    runtime.spawn({
+        let connection = connection.clone();
+        async move {
+            while let Ok(mut stream) = connection.accept_uni().await {
+                let shard_sender = shard_sender.clone();
+                tokio::spawn(async move {
+                    // Shards are sent back to back. The first field of the shard prefix is used to
+                    // split them.
+                    let mut length_bytes = [0; SHARD_LENGTH_FIELD_SIZE];
+                    while stream.read_exact(&mut length_bytes).await.is_ok() {
+                        let Some(shard_length) =
+                            wire_format.shard_length(&length_bytes).filter(|length| {
+                                (SHARD_LENGTH_FIELD_SIZE..=MAX_SHARD_SIZE).contains(length)
+                            })
+                        else {
+                            break;
+                        };
+
+                        let mut shard = vec![0; shard_length];
+                        shard[..SHARD_LENGTH_FIELD_SIZE].copy_from_slice(&length_bytes);
+                        if stream
+                            .read_exact(&mut shard[SHARD_LENGTH_FIELD_SIZE..])
+                            .await
+                            .is_err()
+                            || shard_sender.send(shard).is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+    });
+
+    (
+        QuicSender {
+            runtime: Arc::clone(&runtime),
+            _endpoint: endpoint,
+            connection,
+            unreliable_stream_ids,
+            wire_format,
+            streams: HashMap::new(),
+        },
+        QuicReceiver {
+            _runtime: runtime,
+            shard_receiver,
+            timeout,
+            current_shard: ReceivedShard::default(),
+        },
+    )
+}
+
+pub struct QuicSender {
+    runtime: Arc<Runtime>,
+    _endpoint: Endpoint,
+    connection: Connection,
+    unreliable_stream_ids: HashSet<u16>,
+    wire_format: WireFormat,
+    streams: HashMap<u16, SendStream>,
+}
+
+impl SocketWriter for QuicSender {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        self.send_parts(buffer, &[])
+    }
+
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        let stream_id = self
+            .wire_format
+            .read_prefix(prefix)
+            .ok_or_else(|| anyhow!("Invalid shard prefix"))?
+            .stream_id;
+
+        if self.unreliable_stream_ids.contains(&stream_id)
+            && self
+                .connection
+                .max_datagram_size()
+                .map(|size| prefix.len() + data.len() <= size)
+                .unwrap_or(false)
+        {
+            // Datagrams are owned by quinn, so the shard is copied once
+            let mut datagram = BytesMut::with_capacity(prefix.len() + data.len());
+            datagram.extend_from_slice(prefix);
+            datagram.extend_from_slice(data);
+            self.connection.send_datagram(datagram.freeze())?;
+
+            return Ok(());
+        }
+
+        // Shards that don't fit into a datagram are sent over the reliable stream
+        let stream = match self.streams.entry(stream_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(self.runtime.block_on(self.connection.open_uni())?)
+            }
+        };
+        self.runtime.block_on(async {
+            stream.write_all(prefix).await?;
+            stream.write_all(data).await
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ReceivedShard {
+    buffer: Vec<u8>,
+    cursor: usize,
+}
+
+pub struct QuicReceiver {
+    _runtime: Arc<Runtime>,
+    shard_receiver: mpsc::Receiver<Vec<u8>>,
+    timeout: Duration,
+    current_shard: ReceivedShard,
+}
+
+impl QuicReceiver {
+    // Make sure the current shard has unread bytes
+    fn fill_shard(&mut self) -> ConResult {
+        let shard = &mut self.current_shard;
+
+        if shard.cursor == shard.buffer.len() {
+            shard.buffer = self
+                .shard_receiver
+                .recv_timeout(self.timeout)
+                .handle_try_again()?;
+            shard.cursor = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl SocketReader for QuicReceiver {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let shard = &mut self.current_shard;
+        let size = usize::min(buffer.len(), shard.buffer.len() - shard.cursor);
+        buffer[..size].copy_from_slice(&shard.buffer[shard.cursor..][..size]);
+        shard.cursor += size;
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill_shard()?;
+
+        let shard = &self.current_shard;
+        let size = usize::min(buffer.len(), shard.buffer.len() - shard.cursor);
+        buffer[..size].copy_from_slice(&shard.buffer[shard.cursor..][..size]);
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire_format::{ShardPrefix, SHARD_PREFIX_SIZE};
+    use std::{net::Ipv4Addr, thread};
+
+    const PORT: u16 = 9950;
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    const WIRE_FORMAT: WireFormat = WireFormat::V2;
+
+    fn shard(stream_id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut shard = vec![0; SHARD_PREFIX_SIZE];
+        shard.extend_from_slice(payload);
+        WIRE_FORMAT.write_prefix(
+            &mut shard,
+            &ShardPrefix {
+                shard_length: SHARD_PREFIX_SIZE + payload.len(),
+                stream_id,
+                shards_count: 1,
+                ..Default::default()
+            },
+        );
+
+        shard
+    }
+
+    fn recv_shard(receiver: &mut QuicReceiver) -> Vec<u8> {
+        let mut length_bytes = [0; SHARD_LENGTH_FIELD_SIZE];
+        assert_eq!(
+            receiver.peek(&mut length_bytes).unwrap(),
+            SHARD_LENGTH_FIELD_SIZE
+        );
+
+        let mut shard = vec![0; WIRE_FORMAT.shard_length(&length_bytes).unwrap()];
+        let mut cursor = 0;
+        while cursor < shard.len() {
+            cursor += receiver.recv(&mut shard[cursor..]).unwrap();
+        }
+
+        shard
+    }
+
+    #[test]
+    fn loopback() {
+        let listener = bind(
+            PORT,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+            &[1],
+        )
+        .unwrap();
+
+        let server_thread = thread::spawn(|| {
+            connect_to_client(
+                TIMEOUT,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                PORT,
+                SocketBufferSize::Default,
+                SocketBufferSize::Default,
+                &[1],
+                WIRE_FORMAT,
+            )
+            .unwrap()
+        });
+
+        let (mut client_sender, mut client_receiver) =
+            accept_from_server(&listener, None, TIMEOUT, WIRE_FORMAT).unwrap();
+        let (mut server_sender, mut server_receiver) = server_thread.join().unwrap();
+
+        let reliable_shard = shard(0, &[1, 2, 3]);
+        let unreliable_shard = shard(1, &[4, 5]);
+        let big_shard = shard(1, &[6; 4000]);
+
+        client_sender.send(&reliable_shard).unwrap();
+        client_sender.send(&unreliable_shard).unwrap();
+        client_sender.send(&big_shard).unwrap();
+
+        let mut received = (0..3)
+            .map(|_| recv_shard(&mut server_receiver))
+            .collect::<Vec<_>>();
+        received.sort();
+        let mut expected = vec![reliable_shard.clone(), unreliable_shard, big_shard];
+        expected.sort();
+        assert_eq!(received, expected);
+
+        server_sender.send(&reliable_shard).unwrap();
+        assert_eq!(recv_shard(&mut client_receiver), reliable_shard);
+    }
+}
@@ -1,9 +1,9 @@
-use crate::LOCAL_IP;
-
 use super::{SocketReader, SocketWriter};
 use alvr_common::{anyhow::Result, con_bail, ConResult, HandleTryAgain, ToCon};
 use alvr_session::SocketBufferSize;
+use socket2::{Protocol, Type};
 use std::{
+    io::IoSlice,
     io::Read,
     io::Write,
     net::{IpAddr, SocketAddr, TcpListener, TcpStream},
@@ -16,7 +16,8 @@ pub fn bind(
     send_buffer_bytes: SocketBufferSize,
     recv_buffer_bytes: SocketBufferSize,
 ) -> Result<TcpListener> {
-    let socket = TcpListener::bind((LOCAL_IP, port))?.into();
+    let socket = crate::bind_dual_stack(Type::STREAM, Protocol::TCP, port)?;
+    socket.listen(128)?;
 
     crate::set_socket_buffers(&socket, send_buffer_bytes, recv_buffer_bytes).ok();
     socket.set_read_timeout(Some(timeout))?;
@@ -33,11 +34,9 @@ pub fn accept_from_server(
     let (socket, server_address) = listener.accept().handle_try_again()?;
 
     if let Some(ip) = server_ip {
-        if server_address.ip() != ip {
-            con_bail!(
-                "Connected to wrong client: Expected: {ip}, Found {}",
-                server_address.ip()
-            );
+        let server_ip = crate::peer_ip(server_address);
+        if server_ip != crate::canonical_ip(ip) {
+            con_bail!("Connected to wrong client: Expected: {ip}, Found {server_ip}");
         }
     }
 
@@ -54,12 +53,26 @@ pub fn connect_to_client(
     send_buffer_bytes: SocketBufferSize,
     recv_buffer_bytes: SocketBufferSize,
 ) -> ConResult<(TcpStream, TcpStream)> {
-    let split_timeout = timeout / client_ips.len() as u32;
+    let addresses = client_ips
+        .iter()
+        .map(|ip| crate::peer_socket_addr(*ip, port, false))
+        .collect::<Vec<_>>();
+
+    connect_to_addresses(timeout, &addresses, send_buffer_bytes, recv_buffer_bytes)
+}
+
+// Unlike connect_to_client(), the scope ID of IPv6 addresses is used as is
+pub fn connect_to_addresses(
+    timeout: Duration,
+    addresses: &[SocketAddr],
+    send_buffer_bytes: SocketBufferSize,
+    recv_buffer_bytes: SocketBufferSize,
+) -> ConResult<(TcpStream, TcpStream)> {
+    let split_timeout = timeout / addresses.len() as u32;
 
     let mut res = alvr_common::try_again();
-    for ip in client_ips {
-        res = TcpStream::connect_timeout(&SocketAddr::new(*ip, port), split_timeout)
-            .handle_try_again();
+    for address in addresses {
+        res = TcpStream::connect_timeout(address, split_timeout).handle_try_again();
 
         if res.is_ok() {
             break;
@@ -83,6 +96,19 @@ impl SocketWriter for TcpStream {
 
         Ok(())
     }
+
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        // Whatever is left after a partial write is written separately
+        let written = self.write_vectored(&[IoSlice::new(prefix), IoSlice::new(data)])?;
+        if written < prefix.len() {
+            self.write_all(&prefix[written..])?;
+            self.write_all(data)?;
+        } else {
+            self.write_all(&data[written - prefix.len()..])?;
+        }
+
+        Ok(())
+    }
 }
 
 impl SocketReader for TcpStream {
@@ -90,7 +116,7 @@ impl SocketReader for TcpStream {
         Read::read(self, buffer).handle_try_again()
     }
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize> {
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
         TcpStream::peek(self, buffer).handle_try_again()
     }
 }
@@ -1,11 +1,10 @@
-use crate::LOCAL_IP;
-
 use super::{SocketReader, SocketWriter};
 use alvr_common::{anyhow::Result, ConResult, HandleTryAgain};
 use alvr_session::SocketBufferSize;
-use socket2::{MaybeUninitSlice, Socket};
+use socket2::{MaybeUninitSlice, Protocol, SockRef, Socket, Type};
 use std::{
     ffi::c_int,
+    io::IoSlice,
     mem,
     net::{IpAddr, UdpSocket},
     time::Duration,
@@ -18,7 +17,7 @@ pub fn bind(
     send_buffer_bytes: SocketBufferSize,
     recv_buffer_bytes: SocketBufferSize,
 ) -> Result<UdpSocket> {
-    let socket = UdpSocket::bind((LOCAL_IP, port))?.into();
+    let socket = crate::bind_dual_stack(Type::DGRAM, Protocol::UDP, port)?;
 
     crate::set_socket_buffers(&socket, send_buffer_bytes, recv_buffer_bytes).ok();
 
@@ -31,7 +30,8 @@ pub fn connect(
     port: u16,
     timeout: Duration,
 ) -> Result<(UdpSocket, Socket)> {
-    socket.connect((peer_ip, port))?;
+    let ipv6_socket = socket.local_addr()?.is_ipv6();
+    socket.connect(crate::peer_socket_addr(peer_ip, port, ipv6_socket))?;
     socket.set_read_timeout(Some(timeout))?;
 
     Ok((socket.try_clone()?, socket.try_clone()?.into()))
@@ -43,6 +43,12 @@ impl SocketWriter for UdpSocket {
 
         Ok(())
     }
+
+    fn send_parts(&mut self, prefix: &[u8], data: &[u8]) -> Result<()> {
+        SockRef::from(&*self).send_vectored(&[IoSlice::new(prefix), IoSlice::new(data)])?;
+
+        Ok(())
+    }
 }
 
 impl SocketReader for Socket {
@@ -50,7 +56,7 @@ impl SocketReader for Socket {
         Socket::recv(self, unsafe { mem::transmute(buffer) }).handle_try_again()
     }
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize> {
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
         #[cfg(windows)]
         const FLAGS: c_int = 0x02 | 0x8000; // MSG_PEEK | MSG_PARTIAL
         #[cfg(not(windows))]
@@ -62,4 +68,8 @@ impl SocketReader for Socket {
             .handle_try_again()?
             .0)
     }
+
+    fn is_datagram(&self) -> bool {
+        true
+    }
 }
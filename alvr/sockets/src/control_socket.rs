@@ -1,13 +1,14 @@
 use crate::backend::{tcp, SocketReader, SocketWriter};
 
-use super::CONTROL_PORT;
-use alvr_common::{anyhow::Result, ConResult, HandleTryAgain, ToCon};
+use super::{CONTROL_PORT, HANDSHAKE_PACKET_SIZE_BYTES};
+use alvr_common::{anyhow::Result, con_bail, ConResult, HandleTryAgain, ToCon};
 use alvr_session::SocketBufferSize;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
+    io::{Read, Write},
     marker::PhantomData,
     mem,
-    net::{IpAddr, TcpListener, TcpStream},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
     time::{Duration, Instant},
 };
 
@@ -134,6 +135,20 @@ pub fn get_server_listener(timeout: Duration) -> Result<TcpListener> {
     Ok(listener)
 }
 
+// Used by the streamer to accept clients that connect actively, instead of waiting to be found.
+// The listener is non-blocking and can be polled
+pub fn get_client_listener() -> Result<TcpListener> {
+    let listener = tcp::bind(
+        Duration::from_secs(1),
+        CONTROL_PORT,
+        SocketBufferSize::Default,
+        SocketBufferSize::Default,
+    )?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
 // Proto-control-socket that can send and receive any packet. After the split, only the packets of
 // the specified types can be exchanged
 pub struct ProtoControlSocket {
@@ -143,6 +158,9 @@ pub struct ProtoControlSocket {
 pub enum PeerType<'a> {
     AnyClient(Vec<IpAddr>),
     Server(&'a TcpListener),
+    // Active connection mode: the client connects to the streamer, then sends its announcement
+    ServerAddress(SocketAddr),
+    ActiveClient(&'a TcpListener),
 }
 
 impl ProtoControlSocket {
@@ -159,9 +177,25 @@ impl ProtoControlSocket {
                 .0
             }
             PeerType::Server(listener) => tcp::accept_from_server(listener, None, timeout)?.0,
+            PeerType::ServerAddress(address) => {
+                tcp::connect_to_addresses(
+                    timeout,
+                    &[address],
+                    SocketBufferSize::Default,
+                    SocketBufferSize::Default,
+                )?
+                .0
+            }
+            PeerType::ActiveClient(listener) => {
+                let socket = tcp::accept_from_server(listener, None, timeout)?.0;
+                // On some platforms the socket inherits the non-blocking mode of the listener
+                socket.set_nonblocking(false).to_con()?;
+
+                socket
+            }
         };
 
-        let peer_ip = socket.peer_addr().to_con()?.ip();
+        let peer_ip = crate::peer_ip(socket.peer_addr().to_con()?);
 
         Ok((Self { inner: socket }, peer_ip))
     }
@@ -170,6 +204,33 @@ impl ProtoControlSocket {
         framed_send(&mut self.inner, &mut vec![], packet)
     }
 
+    // The announcement is sent unframed, so the streamer doesn't allocate buffers of arbitrary size
+    // for peers that are not identified yet
+    pub fn send_announcement(&mut self, packet: &[u8; HANDSHAKE_PACKET_SIZE_BYTES]) -> Result<()> {
+        self.inner.write_all(packet)?;
+
+        Ok(())
+    }
+
+    // Does not block: returns TryAgain until the whole announcement has been received
+    pub fn try_recv_announcement(&mut self) -> ConResult<[u8; HANDSHAKE_PACKET_SIZE_BYTES]> {
+        let mut packet = [0; HANDSHAKE_PACKET_SIZE_BYTES];
+
+        self.inner.set_nonblocking(true).to_con()?;
+        let res = self.inner.peek(&mut packet).handle_try_again();
+        self.inner.set_nonblocking(false).to_con()?;
+
+        match res? {
+            0 => con_bail!("Connection closed before the announcement"),
+            HANDSHAKE_PACKET_SIZE_BYTES => {
+                self.inner.read_exact(&mut packet).to_con()?;
+
+                Ok(packet)
+            }
+            _ => alvr_common::try_again(),
+        }
+    }
+
     pub fn recv<R: DeserializeOwned>(&mut self, timeout: Duration) -> ConResult<R> {
         framed_recv(&mut self.inner, &mut vec![], &mut None, timeout)
     }
@@ -0,0 +1,207 @@
+// Client identity verification. Each client holds a persistent Ed25519 keypair. During the
+// handshake on the control socket, the server sends a random nonce that the client must sign,
+// together with the ephemeral stream keys of both peers, which authenticates the key exchange.
+// The first time a client connects, it is paired by confirming in the dashboard the PIN shown on
+// the headset. The PIN is derived from the public key of the client and a pairing secret of the
+// server, so it is never sent over the network. The server sends only a commitment (hash) of the
+// secret with the challenge, and reveals the secret after the client has sent its public key. The
+// client checks the secret against the commitment. This way an attacker cannot search offline for
+// a key that gives the same PIN as the headset: the key must be chosen before the secret is known.
+// The secret is kept while the pairing request is pending, so the PIN shown on the headset doesn't
+// change when it reconnects, and it is revealed only to the client with the key of the request.
+// After pairing, the public key is stored in the session and checked for every connection.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+const CHALLENGE_CONTEXT: &[u8] = b"ALVR identity challenge";
+const PAIRING_PIN_CONTEXT: &[u8] = b"ALVR pairing PIN";
+const PAIRING_COMMITMENT_CONTEXT: &[u8] = b"ALVR pairing commitment";
+
+// Used for the nonces of the challenge and for the pairing secrets
+pub fn generate_identity_nonce() -> [u8; 32] {
+    let mut nonce = [0; 32];
+    OsRng.fill_bytes(&mut nonce);
+
+    nonce
+}
+
+pub struct ClientIdentity {
+    signing_key: SigningKey,
+}
+
+impl ClientIdentity {
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(secret_key),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign_challenge(
+        &self,
+        nonce: &[u8; 32],
+        server_stream_public_key: &[u8; 32],
+        client_stream_public_key: &[u8; 32],
+    ) -> Vec<u8> {
+        self.signing_key
+            .sign(&challenge_transcript(
+                nonce,
+                server_stream_public_key,
+                client_stream_public_key,
+            ))
+            .to_bytes()
+            .to_vec()
+    }
+}
+
+fn challenge_transcript(
+    nonce: &[u8; 32],
+    server_stream_public_key: &[u8; 32],
+    client_stream_public_key: &[u8; 32],
+) -> Vec<u8> {
+    [
+        CHALLENGE_CONTEXT,
+        nonce,
+        server_stream_public_key,
+        client_stream_public_key,
+    ]
+    .concat()
+}
+
+pub fn verify_identity_challenge(
+    public_key: &[u8; 32],
+    nonce: &[u8; 32],
+    server_stream_public_key: &[u8; 32],
+    client_stream_public_key: &[u8; 32],
+    signature: &[u8],
+) -> bool {
+    let Ok(verifying_key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+
+    verifying_key
+        .verify(
+            &challenge_transcript(nonce, server_stream_public_key, client_stream_public_key),
+            &signature,
+        )
+        .is_ok()
+}
+
+pub fn pairing_commitment(pairing_secret: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(PAIRING_COMMITMENT_CONTEXT)
+        .chain_update(pairing_secret)
+        .finalize()
+        .into()
+}
+
+// 6 digits PIN
+pub fn pairing_pin(public_key: &[u8; 32], pairing_secret: &[u8; 32]) -> String {
+    let hash = Sha256::new()
+        .chain_update(PAIRING_PIN_CONTEXT)
+        .chain_update(public_key)
+        .chain_update(pairing_secret)
+        .finalize();
+
+    let value = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+
+    format!("{:06}", value % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 32] = [1; 32];
+    const SERVER_STREAM_KEY: [u8; 32] = [2; 32];
+    const CLIENT_STREAM_KEY: [u8; 32] = [3; 32];
+
+    #[test]
+    fn challenge_signature() {
+        let identity = ClientIdentity::from_secret_key(&[4; 32]);
+        let public_key = identity.public_key();
+        let signature = identity.sign_challenge(&NONCE, &SERVER_STREAM_KEY, &CLIENT_STREAM_KEY);
+
+        assert!(verify_identity_challenge(
+            &public_key,
+            &NONCE,
+            &SERVER_STREAM_KEY,
+            &CLIENT_STREAM_KEY,
+            &signature
+        ));
+
+        // Any change of the transcript is detected
+        assert!(!verify_identity_challenge(
+            &public_key,
+            &generate_identity_nonce(),
+            &SERVER_STREAM_KEY,
+            &CLIENT_STREAM_KEY,
+            &signature
+        ));
+        assert!(!verify_identity_challenge(
+            &public_key,
+            &NONCE,
+            &[5; 32],
+            &CLIENT_STREAM_KEY,
+            &signature
+        ));
+        assert!(!verify_identity_challenge(
+            &public_key,
+            &NONCE,
+            &SERVER_STREAM_KEY,
+            &[5; 32],
+            &signature
+        ));
+
+        // Signed by another client
+        let other_identity = ClientIdentity::from_secret_key(&[5; 32]);
+        assert!(!verify_identity_challenge(
+            &other_identity.public_key(),
+            &NONCE,
+            &SERVER_STREAM_KEY,
+            &CLIENT_STREAM_KEY,
+            &signature
+        ));
+
+        assert!(!verify_identity_challenge(
+            &public_key,
+            &NONCE,
+            &SERVER_STREAM_KEY,
+            &CLIENT_STREAM_KEY,
+            &signature[..32]
+        ));
+    }
+
+    #[test]
+    fn pairing_pin_is_stable() {
+        let public_key = ClientIdentity::from_secret_key(&[4; 32]).public_key();
+        let pairing_secret = generate_identity_nonce();
+
+        let pin = pairing_pin(&public_key, &pairing_secret);
+        assert_eq!(pin.len(), 6);
+        assert!(pin.chars().all(|c| c.is_ascii_digit()));
+
+        // The streamer and the headset compute the same PIN
+        assert_eq!(pairing_pin(&public_key, &pairing_secret), pin);
+
+        // A new pairing request shows a different PIN
+        assert_ne!(pairing_pin(&public_key, &generate_identity_nonce()), pin);
+    }
+
+    #[test]
+    fn pairing_commitment_matches_secret() {
+        let pairing_secret = generate_identity_nonce();
+        let commitment = pairing_commitment(&pairing_secret);
+
+        assert_eq!(pairing_commitment(&pairing_secret), commitment);
+        assert_ne!(pairing_commitment(&generate_identity_nonce()), commitment);
+    }
+}
@@ -0,0 +1,514 @@
+// Dual-stack (IPv6 and IPv4) support. IPv4 peers of dual-stack sockets are seen as IPv4-mapped
+// IPv6 addresses: they are converted back to IPv4, so that the same peer has the same IpAddr
+// whatever socket saw it. IPv6 link-local addresses are usable only together with the interface
+// (scope ID) they have been seen on, which IpAddr can't store: the scope IDs of the peers are
+// remembered when their addresses are first seen, and reused when connecting back to them.
+
+use crate::LOCAL_IP;
+use alvr_common::{
+    anyhow::{anyhow, bail, Error, Result},
+    debug,
+    once_cell::sync::Lazy,
+    parking_lot::Mutex,
+    warn,
+};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Discovery packets are sent to all the nodes of the link, like IPv4 broadcasts, so no multicast
+// group has to be joined to receive them
+pub const DISCOVERY_MULTICAST_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+// The standard library can't list the network interfaces, so their indices are probed
+const MAX_INTERFACE_INDEX: u32 = 256;
+// Sources that sent no packet for this long are forgotten by the rate limiter
+const RATE_LIMIT_SOURCE_EXPIRY: Duration = Duration::from_secs(10);
+// Bounds the memory used when the source IPs are spoofed. Packets from new sources are dropped
+// until some tracked sources expire
+const MAX_RATE_LIMITED_SOURCES: usize = 1024;
+
+static IPV6_SCOPE_IDS: Lazy<Mutex<HashMap<Ipv6Addr, u32>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn is_ipv6_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Returns the IP of a peer, remembering the scope ID of link-local IPv6 addresses
+pub fn peer_ip(address: SocketAddr) -> IpAddr {
+    if let SocketAddr::V6(address) = address {
+        if address.scope_id() != 0 && is_ipv6_link_local(address.ip()) {
+            IPV6_SCOPE_IDS
+                .lock()
+                .insert(*address.ip(), address.scope_id());
+        }
+    }
+
+    canonical_ip(address.ip())
+}
+
+/// Returns the address to reach a peer from a socket. IPv4 addresses are mapped to IPv6 if the
+/// socket is IPv6 (dual-stack)
+pub fn peer_socket_addr(ip: IpAddr, port: u16, ipv6_socket: bool) -> SocketAddr {
+    match canonical_ip(ip) {
+        IpAddr::V4(ip) if ipv6_socket => {
+            SocketAddr::V6(SocketAddrV6::new(ip.to_ipv6_mapped(), port, 0, 0))
+        }
+        IpAddr::V4(ip) => SocketAddr::from((ip, port)),
+        IpAddr::V6(ip) => {
+            let scope_id = IPV6_SCOPE_IDS.lock().get(&ip).cloned().unwrap_or(0);
+
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+        }
+    }
+}
+
+/// Parses an IP address with an optional port, like 192.168.1.2, fe80::1%3 or [fe80::1%3]:9943.
+/// Link-local IPv6 addresses without scope ID get the scope ID they have last been seen with.
+/// Returns None if the address is not an IP address (it could be a hostname)
+pub fn parse_peer_address(address: &str, default_port: u16) -> Option<SocketAddr> {
+    let address = address.trim();
+
+    let (host, port) = match address.strip_prefix('[') {
+        Some(address) => {
+            let (host, port) = address.split_once(']')?;
+            let port = match port.strip_prefix(':') {
+                Some(port) => port.parse().ok()?,
+                None if port.is_empty() => default_port,
+                None => return None,
+            };
+
+            (host, port)
+        }
+        // Only IPv4 addresses can have a port without brackets
+        None => match SocketAddrV4::from_str(address) {
+            Ok(address) => return Some(SocketAddr::V4(address)),
+            Err(_) => (address, default_port),
+        },
+    };
+
+    match host.split_once('%') {
+        Some((ip, scope_id)) => {
+            let ip = Ipv6Addr::from_str(ip).ok()?;
+            let scope_id = scope_id.parse().ok()?;
+
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+        }
+        None => Some(peer_socket_addr(IpAddr::from_str(host).ok()?, port, false)),
+    }
+}
+
+/// Sends a packet to an IPv6 multicast group on all the interfaces. Otherwise the packet would
+/// leave from a single interface chosen by the OS, which on multi-homed hosts could be the wrong
+/// one. Returns true if the packet has been sent on at least one interface
+pub fn send_multicast_ipv6(socket: &UdpSocket, packet: &[u8], group: Ipv6Addr, port: u16) -> bool {
+    let socket_ref = SockRef::from(socket);
+
+    let mut sent = false;
+    for index in 1..=MAX_INTERFACE_INDEX {
+        // Fails if there is no interface with this index
+        if socket_ref.set_multicast_if_v6(index).is_ok() {
+            sent |= socket
+                .send_to(packet, SocketAddrV6::new(group, port, 0, index))
+                .is_ok();
+        }
+    }
+
+    sent
+}
+
+/// IP network in CIDR notation, like 192.168.1.0/24 or fd00::/8. A single address is a network
+/// with the full prefix length
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IpNetwork {
+    ip: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn new(ip: IpAddr, prefix_len: u8) -> Result<Self> {
+        let ip = canonical_ip(ip);
+        let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            bail!("Prefix length {prefix_len} is larger than {max_prefix_len}");
+        }
+
+        Ok(Self { ip, prefix_len })
+    }
+
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 addresses
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, canonical_ip(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (ip, prefix_len) = match s.split_once('/') {
+            Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let ip = IpAddr::from_str(ip).map_err(|e| anyhow!("Invalid IP \"{ip}\": {e}"))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .map_err(|e| anyhow!("Invalid prefix length \"{prefix_len}\": {e}"))?,
+            None if canonical_ip(ip).is_ipv4() => 32,
+            None => 128,
+        };
+
+        Self::new(ip, prefix_len)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix_len)
+    }
+}
+
+/// Allow and deny rules for peer IPs. Deny rules take precedence. Invalid entries are skipped, with
+/// a warning when the rules change.
+#[derive(Default)]
+pub struct NetworkFilter {
+    entries: (Vec<String>, Vec<String>),
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+}
+
+impl NetworkFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn parse_networks(entries: &[String]) -> Vec<IpNetwork> {
+        entries
+            .iter()
+            .filter_map(|entry| match entry.parse() {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    warn!("Ignoring invalid network \"{entry}\": {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn update(&mut self, allowed_networks: &[String], denied_networks: &[String]) {
+        if self.entries.0 == allowed_networks && self.entries.1 == denied_networks {
+            return;
+        }
+
+        self.allowed = Self::parse_networks(allowed_networks);
+        self.denied = Self::parse_networks(denied_networks);
+        self.entries = (allowed_networks.to_vec(), denied_networks.to_vec());
+    }
+
+    /// If all the allowed networks are invalid, no IP is allowed
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        (self.entries.0.is_empty() || self.allowed.iter().any(|network| network.contains(ip)))
+            && !self.denied.iter().any(|network| network.contains(ip))
+    }
+}
+
+// Token bucket, refilled at the maximum rate, which holds up to one second of packets
+struct SourceRateLimit {
+    tokens: f32,
+    last_update: Instant,
+    limited: bool,
+}
+
+/// Per source IP packet rate limit
+#[derive(Default)]
+pub struct RateLimiter {
+    sources: HashMap<IpAddr, SourceRateLimit>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if the packet must be dropped
+    pub fn check(&mut self, ip: IpAddr, max_packets_per_second: u32) -> bool {
+        self.check_at(ip, max_packets_per_second, Instant::now())
+    }
+
+    fn check_at(&mut self, ip: IpAddr, max_packets_per_second: u32, now: Instant) -> bool {
+        let max_tokens = max_packets_per_second.max(1) as f32;
+
+        if !self.sources.contains_key(&ip) && self.sources.len() >= MAX_RATE_LIMITED_SOURCES {
+            self.sources
+                .retain(|_, source| now - source.last_update < RATE_LIMIT_SOURCE_EXPIRY);
+
+            if self.sources.len() >= MAX_RATE_LIMITED_SOURCES {
+                return false;
+            }
+        }
+
+        let source = self.sources.entry(ip).or_insert(SourceRateLimit {
+            tokens: max_tokens,
+            last_update: now,
+            limited: false,
+        });
+        source.tokens = f32::min(
+            source.tokens + (now - source.last_update).as_secs_f32() * max_tokens,
+            max_tokens,
+        );
+        source.last_update = now;
+
+        if source.tokens >= 1.0 {
+            source.tokens -= 1.0;
+            source.limited = false;
+
+            true
+        } else {
+            if !source.limited {
+                warn!("Too many packets from {ip}, dropping them");
+                source.limited = true;
+            }
+
+            false
+        }
+    }
+}
+
+fn bind_ipv6(ty: Type, protocol: Protocol, port: u16) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, ty, Some(protocol))?;
+    socket.set_only_v6(false)?;
+    // Same as the std TcpListener
+    #[cfg(not(windows))]
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+
+    Ok(socket)
+}
+
+// Binds to all the IPv6 and IPv4 interfaces, or only to the IPv4 ones if IPv6 is not available
+pub(crate) fn bind_dual_stack(ty: Type, protocol: Protocol, port: u16) -> Result<Socket> {
+    match bind_ipv6(ty, protocol, port) {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            debug!("IPv6 not available, binding to IPv4 only: {e}");
+
+            let socket = Socket::new(Domain::IPV4, ty, Some(protocol))?;
+            #[cfg(not(windows))]
+            if ty == Type::STREAM {
+                socket.set_reuse_address(true)?;
+            }
+            socket.bind(&SocketAddr::new(LOCAL_IP, port).into())?;
+
+            Ok(socket)
+        }
+    }
+}
+
+pub fn bind_dual_stack_udp(port: u16) -> Result<UdpSocket> {
+    Ok(bind_dual_stack(Type::DGRAM, Protocol::UDP, port)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn dual_stack_loopback() {
+        let receiver = bind_dual_stack_udp(0).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        let mut buffer = [0; 4];
+        for ip in [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ] {
+            let sender = bind_dual_stack_udp(0).unwrap();
+            let ipv6_socket = sender.local_addr().unwrap().is_ipv6();
+            sender
+                .send_to(&[1, 2, 3, 4], peer_socket_addr(ip, port, ipv6_socket))
+                .unwrap();
+
+            let (size, address) = receiver.recv_from(&mut buffer).unwrap();
+            assert_eq!(size, 4);
+            assert_eq!(peer_ip(address), ip);
+        }
+    }
+
+    #[test]
+    fn peer_address() {
+        assert_eq!(
+            parse_peer_address("192.168.1.2", 9943),
+            Some("192.168.1.2:9943".parse().unwrap())
+        );
+        assert_eq!(
+            parse_peer_address("192.168.1.2:1234", 9943),
+            Some("192.168.1.2:1234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_peer_address("fd00::1", 9943),
+            Some("[fd00::1]:9943".parse().unwrap())
+        );
+        assert_eq!(
+            parse_peer_address("[fd00::1]:1234", 9943),
+            Some("[fd00::1]:1234".parse().unwrap())
+        );
+
+        let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 9943, 0, 3));
+        assert_eq!(parse_peer_address("fe80::1%3", 9943), Some(scoped));
+        assert_eq!(parse_peer_address("[fe80::1%3]", 9943), Some(scoped));
+        assert_eq!(
+            parse_peer_address("[fe80::1%3]:1234", 9943),
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                1234,
+                0,
+                3
+            )))
+        );
+
+        // The scope ID seen last is reused
+        peer_ip(SocketAddr::V6(SocketAddrV6::new(
+            "fe80::2".parse().unwrap(),
+            1234,
+            0,
+            5,
+        )));
+        assert_eq!(
+            parse_peer_address("fe80::2", 9943),
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                "fe80::2".parse().unwrap(),
+                9943,
+                0,
+                5
+            )))
+        );
+
+        assert_eq!(parse_peer_address("streamer.local", 9943), None);
+        assert_eq!(parse_peer_address("fe80::1%eth0", 9943), None);
+        assert_eq!(parse_peer_address("[fe80::1]x", 9943), None);
+    }
+
+    #[test]
+    fn ip_network_contains() {
+        let network = "192.168.1.0/24".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("192.168.1.42".parse().unwrap()));
+        assert!(!network.contains("192.168.2.42".parse().unwrap()));
+        // Peers of dual-stack sockets
+        assert!(network.contains("::ffff:192.168.1.42".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+
+        let network = "fd00::/8".parse::<IpNetwork>().unwrap();
+        assert!(network.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+
+        let any = "0.0.0.0/0".parse::<IpNetwork>().unwrap();
+        assert!(any.contains("10.0.0.1".parse().unwrap()));
+
+        let single = "10.0.0.1".parse::<IpNetwork>().unwrap();
+        assert!(single.contains("10.0.0.1".parse().unwrap()));
+        assert!(!single.contains("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_network_invalid() {
+        assert!("192.168.1.0/33".parse::<IpNetwork>().is_err());
+        assert!("192.168.1/24".parse::<IpNetwork>().is_err());
+        assert!("fd00::/abc".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn network_filter() {
+        let mut filter = NetworkFilter::new();
+        assert!(filter.is_allowed("10.0.0.1".parse().unwrap()));
+
+        filter.update(
+            &["192.168.1.0/24".into(), "fd00::/8".into()],
+            &["192.168.1.13".into()],
+        );
+        assert!(filter.is_allowed("192.168.1.42".parse().unwrap()));
+        assert!(filter.is_allowed("::ffff:192.168.1.42".parse().unwrap()));
+        assert!(filter.is_allowed("fd12::1".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.1.13".parse().unwrap()));
+        assert!(!filter.is_allowed("10.0.0.1".parse().unwrap()));
+
+        // Only deny rules
+        filter.update(&[], &["10.0.0.0/8".into()]);
+        assert!(!filter.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(filter.is_allowed("192.168.1.13".parse().unwrap()));
+
+        // Invalid allowed networks don't allow everything
+        filter.update(&["192.168.1/24".into()], &[]);
+        assert!(!filter.is_allowed("192.168.1.42".parse().unwrap()));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = RateLimiter::new();
+        let ip = "192.168.1.42".parse().unwrap();
+        let other_ip = "192.168.1.43".parse().unwrap();
+        let start = Instant::now();
+
+        // Up to one second of packets are accepted at once
+        for _ in 0..10 {
+            assert!(limiter.check_at(ip, 10, start));
+        }
+        assert!(!limiter.check_at(ip, 10, start));
+        assert!(limiter.check_at(other_ip, 10, start));
+
+        // One packet every 100 ms
+        assert!(!limiter.check_at(ip, 10, start + Duration::from_millis(50)));
+        assert!(limiter.check_at(ip, 10, start + Duration::from_millis(150)));
+        assert!(!limiter.check_at(ip, 10, start + Duration::from_millis(170)));
+    }
+
+    #[test]
+    fn rate_limit_sources_are_capped() {
+        let mut limiter = RateLimiter::new();
+        let start = Instant::now();
+
+        for idx in 0..MAX_RATE_LIMITED_SOURCES as u32 {
+            assert!(limiter.check_at(Ipv4Addr::from(idx).into(), 10, start));
+        }
+        let new_ip = "192.168.1.42".parse().unwrap();
+        assert!(!limiter.check_at(new_ip, 10, start));
+        // Tracked sources are still accepted
+        assert!(limiter.check_at(Ipv4Addr::from(0_u32).into(), 10, start));
+
+        // Expired sources make room for new ones
+        assert!(limiter.check_at(new_ip, 10, start + RATE_LIMIT_SOURCE_EXPIRY));
+        assert_eq!(limiter.sources.len(), 1);
+    }
+}
@@ -1,6 +1,11 @@
 mod backend;
 mod control_socket;
+mod identity;
+mod ip;
+mod pacer;
+mod send_scheduler;
 mod stream_socket;
+mod wire_format;
 
 use alvr_common::{anyhow::Result, info};
 use alvr_session::SocketBufferSize;
@@ -9,8 +14,17 @@ use std::{
     time::Duration,
 };
 
+pub use backend::{
+    capture::{CaptureDirection, CapturedShard, ShardCaptureReader},
+    encryption::{StreamKeyExchange, StreamKeys},
+};
 pub use control_socket::*;
+pub use identity::*;
+pub use ip::*;
+pub use pacer::{Pacer, PacingStatistics};
+pub use send_scheduler::SendScheduler;
 pub use stream_socket::*;
+pub use wire_format::*;
 
 pub const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
 pub const CONTROL_PORT: u16 = 9943;
@@ -18,6 +32,13 @@ pub const HANDSHAKE_PACKET_SIZE_BYTES: usize = 56; // this may change in future
 pub const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(500);
 pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(2);
 
+// DNS-SD service advertised by the streamer when mDNS discovery is enabled. The port of the
+// service is CONTROL_PORT, where the client sends its announcement.
+pub const MDNS_SERVICE_TYPE: &str = "_alvr._tcp.local.";
+pub const MDNS_HANDSHAKE_VERSION_KEY: &str = "handshake_version";
+pub const MDNS_HOSTNAME_KEY: &str = "hostname";
+pub const MDNS_WEB_PORT_KEY: &str = "web_port";
+
 fn set_socket_buffers(
     socket: &socket2::Socket,
     send_buffer_bytes: SocketBufferSize,
@@ -0,0 +1,107 @@
+// Send pacing for large packets:
+// Sending all the shards of a large packet (like an IDR frame) back to back overflows the queues of
+// Wi-Fi drivers and access points, so shards are dropped exactly on keyframes. The pacer spreads
+// the shards of large packets over a fraction of the frame interval. The sending rate is derived
+// from the target bitrate: a packet of average size for the bitrate takes the whole window, smaller
+// packets take less time, larger packets are sent faster to still fit in the window. This way the
+// pacing delay never exceeds the window and packets don't accumulate behind each other.
+// Waits are coarse (OS sleep), shards whose send time has passed are sent back to back.
+
+use std::{mem, time::Duration};
+
+// Shorter waits are skipped, the OS sleep is not precise enough for them
+pub(crate) const MIN_PACING_WAIT: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PacingStatistics {
+    pub paced_packets: usize,
+    // Sum of the delays of the last shard of each paced packet, compared to sending it in a burst
+    pub total_delay: Duration,
+    pub max_delay: Duration,
+}
+
+#[derive(Clone)]
+pub struct Pacer {
+    frame_interval_fraction: f32,
+    min_paced_packet_bytes: usize,
+    target_bitrate_bps: f32,
+    frame_interval: Duration,
+    statistics: PacingStatistics,
+}
+
+impl Pacer {
+    pub fn new(frame_interval_fraction: f32, min_paced_packet_bytes: usize) -> Self {
+        Self {
+            frame_interval_fraction,
+            min_paced_packet_bytes,
+            target_bitrate_bps: 0.0,
+            frame_interval: Duration::ZERO,
+            statistics: PacingStatistics::default(),
+        }
+    }
+
+    /// Pacing is disabled until the target is set
+    pub fn set_target(&mut self, target_bitrate_bps: f32, frame_interval: Duration) {
+        self.target_bitrate_bps = target_bitrate_bps;
+        self.frame_interval = frame_interval;
+    }
+
+    // Interval between consecutive shards. None if the packet should be sent in a single burst
+    pub(crate) fn shard_interval(
+        &self,
+        packet_bytes: usize,
+        shards_count: usize,
+    ) -> Option<Duration> {
+        if packet_bytes < self.min_paced_packet_bytes
+            || shards_count < 2
+            || self.target_bitrate_bps <= 0.0
+        {
+            return None;
+        }
+
+        let window = self.frame_interval.mul_f32(self.frame_interval_fraction);
+        let duration = Duration::from_secs_f32(
+            packet_bytes as f32 * 8.0 * self.frame_interval_fraction / self.target_bitrate_bps,
+        )
+        .min(window);
+        if duration < MIN_PACING_WAIT {
+            return None;
+        }
+
+        // The first shard is sent immediately, the last one at the end of the window
+        Some(duration / (shards_count - 1) as u32)
+    }
+
+    pub(crate) fn report_delay(&mut self, delay: Duration) {
+        self.statistics.paced_packets += 1;
+        self.statistics.total_delay += delay;
+        self.statistics.max_delay = self.statistics.max_delay.max(delay);
+    }
+
+    /// Pacing statistics since the last call
+    pub fn take_statistics(&mut self) -> PacingStatistics {
+        mem::take(&mut self.statistics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_packets_fit_in_window() {
+        let mut pacer = Pacer::new(0.5, 10_000);
+        pacer.set_target(30e6, Duration::from_millis(10));
+
+        // Too small
+        assert!(pacer.shard_interval(5_000, 4).is_none());
+
+        // 37.5 KB is the average frame size at 30 Mbps and 100 FPS: it takes the whole window
+        let interval = pacer.shard_interval(37_500, 26).unwrap();
+        assert!(((interval * 25).as_secs_f32() - 0.005).abs() < 1e-4);
+
+        // An IDR frame is sent faster, still within the window
+        let interval = pacer.shard_interval(200_000, 138).unwrap();
+        assert!((interval * 137).as_secs_f32() <= 0.005 + 1e-4);
+    }
+}
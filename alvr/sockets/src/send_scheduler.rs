@@ -0,0 +1,321 @@
+// Priority-aware send scheduling:
+// All StreamSenders share the same socket. When the socket is idle and nothing is queued, a shard
+// is sent directly. Otherwise senders push their shards into a per-priority queue and then flush
+// the queue. The thread that holds the socket sends the shards with the highest priority first,
+// including the ones pushed by other threads in the meantime, and hands the socket over to the
+// other flushing threads every few shards. A flush returns once no shard with the priority of the
+// caller (or higher) is queued, so a small tracking or haptics packet preempts the queued shards of
+// a large video packet, and its sender doesn't wait for the video packet to be sent.
+// Shards with the same priority are sent in FIFO order.
+// Queued shards don't copy their data: they hold a reference to the packet buffer and the range of
+// their data, and store their prefix inline.
+
+use crate::{backend::SocketWriter, wire_format::SHARD_PREFIX_SIZE};
+use alvr_common::{
+    anyhow::Result,
+    parking_lot::{Mutex, MutexGuard},
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    mem,
+    ops::Range,
+    sync::Arc,
+};
+
+// Used for NACKs and other internal control shards
+pub(crate) const MAX_PRIORITY: u8 = u8::MAX;
+
+// Number of shards sent for each acquisition of the socket
+const MAX_SHARDS_PER_LOCK: usize = 8;
+
+// Memory shared by the shards of a packet
+pub(crate) trait ShardData: Send + Sync {
+    fn bytes(&self) -> &[u8];
+}
+
+impl ShardData for Vec<u8> {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+pub(crate) struct QueuedShard {
+    pub stream_id: u16,
+    pub prefix: [u8; SHARD_PREFIX_SIZE],
+    pub data: Arc<dyn ShardData>,
+    pub range: Range<usize>,
+}
+
+impl QueuedShard {
+    // For small control shards, which are copied
+    pub fn from_bytes(stream_id: u16, shard: &[u8]) -> Self {
+        let mut prefix = [0; SHARD_PREFIX_SIZE];
+        prefix.copy_from_slice(&shard[..SHARD_PREFIX_SIZE]);
+        let data = shard[SHARD_PREFIX_SIZE..].to_vec();
+
+        Self {
+            stream_id,
+            prefix,
+            range: 0..data.len(),
+            data: Arc::new(data),
+        }
+    }
+
+    fn send(&self, socket: &mut dyn SocketWriter) -> Result<()> {
+        socket.send_parts(&self.prefix, &self.data.bytes()[self.range.clone()])
+    }
+}
+
+#[derive(Default)]
+struct SendQueues {
+    queues: BTreeMap<Reverse<u8>, VecDeque<QueuedShard>>,
+    depths: HashMap<u16, usize>,
+    total_depth: usize,
+    peak_total_depth: usize,
+}
+
+impl SendQueues {
+    // Pops the shard with the highest priority, if its priority is at least min_priority
+    fn pop(&mut self, min_priority: u8) -> Option<QueuedShard> {
+        let shard = self
+            .queues
+            .range_mut(..=Reverse(min_priority))
+            .map(|(_, queue)| queue)
+            .find(|queue| !queue.is_empty())?
+            .pop_front()?;
+
+        if let Some(depth) = self.depths.get_mut(&shard.stream_id) {
+            *depth = depth.saturating_sub(1);
+        }
+        self.total_depth = self.total_depth.saturating_sub(1);
+
+        Some(shard)
+    }
+}
+
+pub struct SendScheduler {
+    socket: Mutex<Box<dyn SocketWriter>>,
+    priorities: HashMap<u16, u8>,
+    queues: Mutex<SendQueues>,
+}
+
+impl SendScheduler {
+    // Streams without an assigned priority have priority 0 (lowest)
+    pub(crate) fn new(socket: Box<dyn SocketWriter>, priorities: HashMap<u16, u8>) -> Self {
+        Self {
+            socket: Mutex::new(socket),
+            priorities,
+            queues: Mutex::new(SendQueues::default()),
+        }
+    }
+
+    pub(crate) fn priority(&self, stream_id: u16) -> u8 {
+        self.priorities.get(&stream_id).cloned().unwrap_or(0)
+    }
+
+    pub(crate) fn enqueue(&self, priority: u8, shard: QueuedShard) {
+        let mut queues = self.queues.lock();
+
+        *queues.depths.entry(shard.stream_id).or_default() += 1;
+        queues.total_depth += 1;
+        queues.peak_total_depth = usize::max(queues.peak_total_depth, queues.total_depth);
+
+        queues
+            .queues
+            .entry(Reverse(priority))
+            .or_default()
+            .push_back(shard);
+    }
+
+    // Send the shard right away if nothing is queued and no other thread is sending, otherwise
+    // queue it. Queued shards are sent by the next flush
+    pub(crate) fn send_or_enqueue(&self, priority: u8, shard: QueuedShard) -> Result<()> {
+        {
+            // The queue lock is taken first, so that no shard can be queued before this one is sent
+            let queues = self.queues.lock();
+            if queues.total_depth == 0 {
+                if let Some(mut socket) = self.socket.try_lock() {
+                    drop(queues);
+
+                    return shard.send(&mut **socket);
+                }
+            }
+        }
+
+        self.enqueue(priority, shard);
+
+        Ok(())
+    }
+
+    // Send the queued shards with priority of at least min_priority, from the highest priority.
+    // Shards with lower priority are left to the threads that queued them. The queue may be
+    // drained by other threads in the meantime.
+    pub(crate) fn flush(&self, min_priority: u8) -> Result<()> {
+        loop {
+            let mut socket = self.socket.lock();
+
+            for _ in 0..MAX_SHARDS_PER_LOCK {
+                let Some(shard) = self.queues.lock().pop(min_priority) else {
+                    return Ok(());
+                };
+
+                shard.send(&mut **socket)?;
+            }
+
+            // Let the threads waiting for the socket send their shards, if they have higher priority
+            MutexGuard::unlock_fair(socket);
+        }
+    }
+
+    pub(crate) fn send(&self, priority: u8, shard: QueuedShard) -> Result<()> {
+        self.send_or_enqueue(priority, shard)?;
+        self.flush(priority)
+    }
+
+    /// Number of shards currently queued for each stream
+    pub fn queue_depths(&self) -> HashMap<u16, usize> {
+        self.queues.lock().depths.clone()
+    }
+
+    /// Maximum number of shards queued for all streams together since the last call
+    pub fn take_peak_queue_depth(&self) -> usize {
+        let mut queues = self.queues.lock();
+        let current_depth = queues.total_depth;
+
+        mem::replace(&mut queues.peak_total_depth, current_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only the data of the shards is recorded
+    struct RecordingSocketWriter(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl SocketWriter for RecordingSocketWriter {
+        fn send(&mut self, buffer: &[u8]) -> Result<()> {
+            self.0.lock().push(buffer.to_vec());
+            Ok(())
+        }
+
+        fn send_parts(&mut self, _: &[u8], data: &[u8]) -> Result<()> {
+            self.send(data)
+        }
+    }
+
+    fn scheduler() -> (SendScheduler, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let sent_shards = Arc::new(Mutex::new(vec![]));
+        let scheduler = SendScheduler::new(
+            Box::new(RecordingSocketWriter(Arc::clone(&sent_shards))),
+            [(0, 1), (3, 0)].into_iter().collect(),
+        );
+
+        (scheduler, sent_shards)
+    }
+
+    fn shard(stream_id: u16, data: &[u8]) -> QueuedShard {
+        QueuedShard {
+            stream_id,
+            prefix: [0; SHARD_PREFIX_SIZE],
+            data: Arc::new(data.to_vec()),
+            range: 0..data.len(),
+        }
+    }
+
+    #[test]
+    fn higher_priority_first() {
+        let (scheduler, sent_shards) = scheduler();
+
+        for idx in 0..3 {
+            scheduler.enqueue(scheduler.priority(3), shard(3, &[3, idx]));
+        }
+        scheduler.enqueue(scheduler.priority(0), shard(0, &[0, 0]));
+        scheduler.enqueue(MAX_PRIORITY, shard(0, &[0, 1]));
+
+        scheduler.flush(0).unwrap();
+
+        assert_eq!(
+            *sent_shards.lock(),
+            [[0, 1], [0, 0], [3, 0], [3, 1], [3, 2]]
+        );
+    }
+
+    #[test]
+    fn flush_leaves_lower_priorities() {
+        let (scheduler, sent_shards) = scheduler();
+
+        for idx in 0..(MAX_SHARDS_PER_LOCK * 2) as u8 {
+            scheduler.enqueue(scheduler.priority(3), shard(3, &[3, idx]));
+        }
+        scheduler
+            .send(scheduler.priority(0), shard(0, &[0, 0]))
+            .unwrap();
+
+        assert_eq!(*sent_shards.lock(), [[0, 0]]);
+        assert_eq!(scheduler.queue_depths()[&3], MAX_SHARDS_PER_LOCK * 2);
+
+        scheduler.flush(scheduler.priority(3)).unwrap();
+        assert_eq!(sent_shards.lock().len(), MAX_SHARDS_PER_LOCK * 2 + 1);
+    }
+
+    #[test]
+    fn idle_socket_is_used_directly() {
+        let (scheduler, sent_shards) = scheduler();
+
+        scheduler.send_or_enqueue(0, shard(3, &[3, 0])).unwrap();
+        assert_eq!(*sent_shards.lock(), [[3, 0]]);
+        assert_eq!(scheduler.take_peak_queue_depth(), 0);
+
+        // Shards are not sent before the ones already queued
+        scheduler.enqueue(0, shard(3, &[3, 1]));
+        scheduler.send_or_enqueue(0, shard(3, &[3, 2])).unwrap();
+        assert_eq!(scheduler.queue_depths()[&3], 2);
+
+        scheduler.flush(0).unwrap();
+        assert_eq!(*sent_shards.lock(), [[3, 0], [3, 1], [3, 2]]);
+    }
+
+    #[test]
+    fn queued_shards_share_the_packet() {
+        let (scheduler, sent_shards) = scheduler();
+
+        let packet: Arc<dyn ShardData> = Arc::new(vec![0, 1, 2, 3, 4]);
+        for range in [0..2, 2..4, 4..5] {
+            scheduler.enqueue(
+                0,
+                QueuedShard {
+                    stream_id: 3,
+                    prefix: [0; SHARD_PREFIX_SIZE],
+                    data: Arc::clone(&packet),
+                    range,
+                },
+            );
+        }
+        assert_eq!(Arc::strong_count(&packet), 4);
+
+        scheduler.flush(0).unwrap();
+
+        assert_eq!(Arc::strong_count(&packet), 1);
+        assert_eq!(*sent_shards.lock(), [vec![0, 1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn peak_depth_of_all_streams() {
+        let (scheduler, _) = scheduler();
+
+        scheduler.enqueue(0, shard(3, &[3]));
+        scheduler.enqueue(0, shard(3, &[3]));
+        scheduler.flush(0).unwrap();
+        scheduler.enqueue(1, shard(0, &[0]));
+        scheduler.enqueue(1, shard(0, &[0]));
+        scheduler.enqueue(0, shard(3, &[3]));
+
+        // The peaks of the streams happened at different times
+        assert_eq!(scheduler.take_peak_queue_depth(), 3);
+        scheduler.flush(0).unwrap();
+        assert_eq!(scheduler.take_peak_queue_depth(), 3);
+        assert_eq!(scheduler.take_peak_queue_depth(), 0);
+    }
+}
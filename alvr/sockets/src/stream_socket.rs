@@ -6,44 +6,200 @@
 
 // Performance analysis:
 // We want to minimize the transmission time for various sizes of packets.
-// The current code locks the write socket *per shard* and not *per packet*. This leds to the best
-// performance outcome given that the possible packets can be either very small (one shard) or very
-// large (hundreds/thousands of shards, for video). if we don't allow interleaving shards, a very
-// small packet will need to wait a long time before getting received if there was an ongoing
-// transmission of a big packet before. If we allow interleaving shards, small packets can be
-// transmitted quicker, with only minimal latency increase for the ongoing transmission of the big
-// packet.
+// The possible packets can be either very small (one shard) or very large (hundreds/thousands of
+// shards, for video). if we don't allow interleaving shards, a very small packet will need to wait
+// a long time before getting received if there was an ongoing transmission of a big packet before.
+// Shards are queued into the send scheduler, which interleaves them by stream priority, so small
+// high priority packets can be transmitted quicker, with only minimal latency increase for the
+// ongoing transmission of the big packet.
 // Note: We can't clone the underlying socket for each StreamSender and the mutex around the socket
 // cannot be removed. This is because we need to make sure at least shards are written whole.
 
-use crate::backend::{tcp, udp, SocketReader, SocketWriter};
+use crate::backend::{
+    capture::{
+        CaptureDirection, CaptureSocketReader, CaptureSocketWriter, NullSocketWriter,
+        ReplaySocketReader, ShardCaptureFile, ShardCaptureReader,
+    },
+    encryption::{self, EncryptedSocketReader, EncryptedSocketWriter, StreamKeys},
+    impairment::ImpairedSocketWriter,
+    quic, tcp, udp, SocketReader, SocketWriter, MAX_SHARD_SIZE,
+};
+use crate::pacer::{Pacer, MIN_PACING_WAIT};
+use crate::send_scheduler::{self, QueuedShard, SendScheduler, ShardData};
+use crate::wire_format::{
+    ShardPrefix, WireFormat, SHARD_FLAG_EXTENSION, SHARD_FLAG_IDR, SHARD_FLAG_PARITY,
+    SHARD_FLAG_RETRANSMIT, SHARD_PREFIX_SIZE,
+};
 use alvr_common::{
-    anyhow::Result, debug, parking_lot::Mutex, AnyhowToCon, ConResult, HandleTryAgain, ToCon,
+    anyhow::Result, con_bail, debug, parking_lot::Mutex, warn, AnyhowToCon, ConResult,
+    HandleTryAgain, ToCon,
+};
+use alvr_session::{
+    ForwardErrorCorrectionConfig, NetworkImpairmentConfig, ShardRetransmissionConfig,
+    SocketBufferSize, SocketProtocol, StreamPriorityConfig,
 };
-use alvr_session::{SocketBufferSize, SocketProtocol};
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     marker::PhantomData,
     mem,
     net::{IpAddr, TcpListener, UdpSocket},
+    ops::Range,
+    path::Path,
     sync::{mpsc, Arc},
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
-const SHARD_PREFIX_SIZE: usize = mem::size_of::<u32>() // packet length - field itself (4 bytes)
-    + mem::size_of::<u16>() // stream ID
-    + mem::size_of::<u32>() // packet index
-    + mem::size_of::<u32>() // shards count
-    + mem::size_of::<u32>(); // shards index
+// Parity shards start with the packet size (without prefix), which is needed to recover the last
+// data shard.
+const PARITY_HEADER_SIZE: usize = mem::size_of::<u32>();
+
+// Forward error correction (FEC):
+// Data shards are split in groups of at most `data_shards_per_parity_shard` shards. For each group,
+// an additional parity shard is sent, with shard index starting from `shards_count`. The parity
+// payload is the XOR of all data shards of the group (each padded to the maximum shard data size).
+// The receiver can rebuild at most one lost data shard per group.
+// The groups are interleaved: with N groups, data shard i belongs to group i % N. A burst of up to
+// N consecutive lost shards hits each group at most once, so it can be recovered. Longer bursts, or
+// losses spread so that two shards of the same group are lost, cannot be recovered.
+// When FEC is enabled for a stream, the data shards are smaller to leave space for the parity
+// header in the parity shards.
+fn max_shard_data_size(max_packet_size: usize, fec_group_size: Option<usize>) -> usize {
+    if fec_group_size.is_some() {
+        max_packet_size - SHARD_PREFIX_SIZE - PARITY_HEADER_SIZE
+    } else {
+        max_packet_size - SHARD_PREFIX_SIZE
+    }
+}
+
+fn parity_shards_count(shards_count: usize, group_size: usize) -> usize {
+    (shards_count + group_size - 1) / group_size
+}
 
-/// Memory buffer that contains a hidden prefix
+// Selective retransmission (NACK):
+// The receiver keeps track of the data shards received for each packet. Shards are sent in order,
+// so when a shard index is skipped (or a shard of a newer packet arrives) the missing shards are
+// requested once by sending a NACK. The sender keeps a reference to the last sent packets in a
+// bounded cache and sends the requested shards again, marked as retransmitted. After the deadline,
+// missing shards are not requested anymore and the packet is considered lost.
+// The NACK is a single shard with stream ID NACK_STREAM_ID and the index of the incomplete packet,
+// with payload the stream ID of the packet followed by the list of missing shard indices (all
+// little endian).
+const NACK_STREAM_ID: u16 = u16::MAX;
+const MAX_RETRANSMIT_CACHE_PACKETS: usize = 16;
+
+fn send_nack(
+    scheduler: &SendScheduler,
+    wire_format: WireFormat,
+    max_packet_size: usize,
+    stream_id: u16,
+    packet_index: u32,
+    shard_indices: &[usize],
+) {
+    let max_indices_count =
+        (max_packet_size - SHARD_PREFIX_SIZE - mem::size_of::<u16>()) / mem::size_of::<u32>();
+
+    for indices in shard_indices.chunks(max_indices_count) {
+        let mut nack = vec![0; SHARD_PREFIX_SIZE];
+        nack.extend_from_slice(&stream_id.to_le_bytes());
+        for idx in indices {
+            nack.extend_from_slice(&(*idx as u32).to_le_bytes());
+        }
+        // The first requested index is used as shard index, so that NACKs for the same packet are
+        // still unique
+        wire_format.write_prefix(
+            &mut nack,
+            &ShardPrefix {
+                shard_length: nack.len(),
+                flags: 0,
+                stream_id: NACK_STREAM_ID,
+                packet_index,
+                shards_count: 1,
+                shard_index: indices[0],
+            },
+        );
+
+        if let Err(e) = scheduler.send(
+            send_scheduler::MAX_PRIORITY,
+            QueuedShard::from_bytes(NACK_STREAM_ID, &nack),
+        ) {
+            debug!("Failed to send NACK: {e}");
+        }
+    }
+}
+
+// Range of the data of a shard inside the packet buffer, which starts with the space reserved for
+// the prefix of the first shard
+fn shard_data_range(
+    shard_index: usize,
+    max_shard_data_size: usize,
+    packet_size: usize,
+) -> Range<usize> {
+    let start = SHARD_PREFIX_SIZE + shard_index * max_shard_data_size;
+
+    start..usize::min(start + max_shard_data_size, packet_size)
+}
+
+// The packet buffer is shared with the send queue, so caching a packet doesn't copy it. Its memory
+// goes back to the buffer pool when the packet is evicted from the cache.
+struct CachedPacket {
+    index: u32,
+    timestamp: Instant,
+    flags: u8,
+    shards_count: usize,
+    max_shard_data_size: usize,
+    data: Arc<dyn ShardData>,
+}
+
+struct RetransmitCache {
+    retention: Duration,
+    packets: VecDeque<CachedPacket>,
+}
+
+impl RetransmitCache {
+    fn new(deadline: Duration) -> Self {
+        Self {
+            // Account for the network latency of the packet and of the NACK
+            retention: deadline * 2,
+            packets: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, packet: CachedPacket) {
+        while let Some(oldest) = self.packets.front() {
+            if self.packets.len() < MAX_RETRANSMIT_CACHE_PACKETS
+                && oldest.timestamp.elapsed() < self.retention
+            {
+                break;
+            }
+
+            self.packets.pop_front();
+        }
+
+        self.packets.push_back(packet);
+    }
+
+    fn get_packet(&self, packet_index: u32) -> Option<&CachedPacket> {
+        self.packets
+            .iter()
+            .find(|packet| packet.index == packet_index)
+            .filter(|packet| packet.timestamp.elapsed() < self.retention)
+    }
+}
+
+type RecycledBuffers = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Memory buffer that contains a hidden prefix. Buffers obtained from a StreamSender or a
+/// BufferPool give back their memory to the pool when dropped.
 #[derive(Default)]
 pub struct Buffer<H = ()> {
     inner: Vec<u8>,
     hidden_offset: usize, // this corresponds to prefix + header
     length: usize,
+    flags: u8, // shard flags, for the v2 wire format
+    pool: Option<RecycledBuffers>,
     _phantom: PhantomData<H>,
 }
 
@@ -82,79 +238,318 @@ impl<H> Buffer<H> {
         self.inner.resize(self.hidden_offset + length, 0);
         self.length = length;
     }
+
+    /// Flag the shards of this packet as part of an IDR frame. Ignored with the v1 wire format
+    pub fn mark_idr(&mut self) {
+        self.flags |= SHARD_FLAG_IDR;
+    }
+}
+
+impl<H> AsRef<[u8]> for Buffer<H> {
+    fn as_ref(&self) -> &[u8] {
+        self.get()
+    }
+}
+
+// Shards are sent from the whole buffer, including the space reserved for the prefix and the header
+impl<H: Send + Sync> ShardData for Buffer<H> {
+    fn bytes(&self) -> &[u8] {
+        &self.inner[..self.hidden_offset + self.length]
+    }
+}
+
+impl<H> Drop for Buffer<H> {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.lock().push(mem::take(&mut self.inner));
+        }
+    }
+}
+
+/// Pool of buffers of a stream. It can be cloned and moved to other threads, to let producers
+/// write packets directly into socket buffers, that are then sent with the StreamSender.
+pub struct BufferPool<H> {
+    buffers: RecycledBuffers,
+    _phantom: PhantomData<H>,
+}
+
+impl<H> Clone for BufferPool<H> {
+    fn clone(&self) -> Self {
+        Self {
+            buffers: Arc::clone(&self.buffers),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<H> BufferPool<H> {
+    fn new() -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(vec![])),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<H: Serialize> BufferPool<H> {
+    pub fn get_buffer(&self, header: &H) -> Result<Buffer<H>> {
+        let mut buffer = self.buffers.lock().pop().unwrap_or_default();
+
+        let header_size = bincode::serialized_size(header)? as usize;
+        let hidden_offset = SHARD_PREFIX_SIZE + header_size;
+
+        if buffer.len() < hidden_offset {
+            buffer.resize(hidden_offset, 0);
+        }
+
+        bincode::serialize_into(&mut buffer[SHARD_PREFIX_SIZE..hidden_offset], header)?;
+
+        Ok(Buffer {
+            inner: buffer,
+            hidden_offset,
+            length: 0,
+            flags: 0,
+            pool: Some(Arc::clone(&self.buffers)),
+            _phantom: PhantomData,
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct StreamSender<H> {
-    inner: Arc<Mutex<Box<dyn SocketWriter>>>,
+    inner: Arc<SendScheduler>,
     stream_id: u16,
+    priority: u8,
+    wire_format: WireFormat,
     max_packet_size: usize,
+    fec_group_size: Option<usize>,
     // if the packet index overflows the worst that happens is a false positive packet loss
     next_packet_index: u32,
-    used_buffers: Vec<Vec<u8>>,
+    buffer_pool: BufferPool<H>,
+    parity_buffer: Arc<Vec<u8>>,
+    parity_bytes_sent: usize,
+    retransmit_cache: Option<Arc<Mutex<RetransmitCache>>>,
+    pacer: Option<Pacer>,
     _phantom: PhantomData<H>,
 }
 
-impl<H> StreamSender<H> {
-    /// Shard and send a buffer with zero copies and zero allocations.
-    /// The prefix of each shard is written over the previously sent shard to avoid reallocations.
-    pub fn send(&mut self, mut buffer: Buffer<H>) -> Result<()> {
-        let max_shard_data_size = self.max_packet_size - SHARD_PREFIX_SIZE;
+impl<H: Send + Sync + 'static> StreamSender<H> {
+    /// Spread the shards of large packets over time. This blocks the caller while the packet is
+    /// being sent
+    pub fn set_pacer(&mut self, pacer: Option<Pacer>) {
+        self.pacer = pacer;
+    }
+
+    pub fn pacer_mut(&mut self) -> Option<&mut Pacer> {
+        self.pacer.as_mut()
+    }
+
+    // With pacing, flush the shards queued so far and wait until the send time of the next shard
+    fn wait_for_shard_slot(
+        &self,
+        start: Instant,
+        shard_interval: Option<Duration>,
+        slot: usize,
+    ) -> Result<()> {
+        let Some(interval) = shard_interval else {
+            return Ok(());
+        };
+
+        let send_time = start + interval * slot as u32;
+        let now = Instant::now();
+        if send_time > now + MIN_PACING_WAIT {
+            self.inner.flush(self.priority)?;
+
+            thread::sleep(send_time.saturating_duration_since(Instant::now()));
+        }
+
+        Ok(())
+    }
+
+    /// Shard and send a buffer. The shards reference the buffer, so their data is not copied.
+    /// Shards are sent directly while the socket is idle, otherwise they are queued and the queue
+    /// is flushed at the end, so that shards of higher priority streams can be sent in between.
+    pub fn send(&mut self, buffer: Buffer<H>) -> Result<()> {
+        self.send_shared(&Arc::new(buffer))
+    }
+
+    /// Same as send, but the buffer can be shared with other consumers of the packet. The buffer is
+    /// left untouched, the prefix of each shard is stored in the send queue.
+    pub fn send_shared(&mut self, buffer: &Arc<Buffer<H>>) -> Result<()> {
+        let max_shard_data_size = max_shard_data_size(self.max_packet_size, self.fec_group_size);
         let actual_buffer_size = buffer.hidden_offset + buffer.length;
         let data_size = actual_buffer_size - SHARD_PREFIX_SIZE;
         let shards_count = (data_size as f32 / max_shard_data_size as f32).ceil() as usize;
 
+        let parity_shards_count = if let Some(group_size) = self.fec_group_size {
+            self.prepare_parity_shards(
+                &buffer.inner[SHARD_PREFIX_SIZE..actual_buffer_size],
+                buffer.flags,
+                shards_count,
+                group_size,
+                max_shard_data_size,
+            )
+        } else {
+            0
+        };
+
+        let pacing_start = Instant::now();
+        let shard_interval = self
+            .pacer
+            .as_ref()
+            .and_then(|pacer| pacer.shard_interval(data_size, shards_count + parity_shards_count));
+
+        let packet_data: Arc<dyn ShardData> = Arc::clone(buffer) as _;
+
         for idx in 0..shards_count {
-            // this overlaps with the previous shard, this is intended behavior and allows to
-            // reduce allocations
-            let packet_start_position = idx * max_shard_data_size;
-            let sub_buffer = &mut buffer.inner[packet_start_position..];
-
-            // NB: true shard length (account for last shard that is smaller)
-            let packet_length = usize::min(
-                self.max_packet_size,
-                actual_buffer_size - packet_start_position,
+            self.wait_for_shard_slot(pacing_start, shard_interval, idx)?;
+
+            let range = shard_data_range(idx, max_shard_data_size, actual_buffer_size);
+
+            let mut prefix = [0; SHARD_PREFIX_SIZE];
+            self.wire_format.write_prefix(
+                &mut prefix,
+                &ShardPrefix {
+                    // NB: true shard length (account for last shard that is smaller)
+                    shard_length: SHARD_PREFIX_SIZE + range.len(),
+                    flags: buffer.flags,
+                    stream_id: self.stream_id,
+                    packet_index: self.next_packet_index,
+                    shards_count,
+                    shard_index: idx,
+                },
             );
 
-            // todo: switch to little endian
-            // todo: do not remove sizeof<u32> for packet length
-            sub_buffer[0..4]
-                .copy_from_slice(&((packet_length - mem::size_of::<u32>()) as u32).to_be_bytes());
-            sub_buffer[4..6].copy_from_slice(&self.stream_id.to_be_bytes());
-            sub_buffer[6..10].copy_from_slice(&self.next_packet_index.to_be_bytes());
-            sub_buffer[10..14].copy_from_slice(&(shards_count as u32).to_be_bytes());
-            sub_buffer[14..18].copy_from_slice(&(idx as u32).to_be_bytes());
+            self.inner.send_or_enqueue(
+                self.priority,
+                QueuedShard {
+                    stream_id: self.stream_id,
+                    prefix,
+                    data: Arc::clone(&packet_data),
+                    range,
+                },
+            )?;
+        }
 
-            self.inner.lock().send(&sub_buffer[..packet_length])?;
+        if let Some(cache) = &self.retransmit_cache {
+            cache.lock().insert(CachedPacket {
+                index: self.next_packet_index,
+                timestamp: Instant::now(),
+                flags: buffer.flags,
+                shards_count,
+                max_shard_data_size,
+                data: packet_data,
+            });
         }
 
-        self.next_packet_index += 1;
+        let parity_shard_size = SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + max_shard_data_size;
+        let parity_data: Arc<dyn ShardData> = Arc::clone(&self.parity_buffer) as _;
+        for idx in 0..parity_shards_count {
+            self.wait_for_shard_slot(pacing_start, shard_interval, shards_count + idx)?;
+
+            let shard_start = idx * parity_shard_size;
+            let mut prefix = [0; SHARD_PREFIX_SIZE];
+            prefix.copy_from_slice(&self.parity_buffer[shard_start..][..SHARD_PREFIX_SIZE]);
+
+            self.inner.send_or_enqueue(
+                self.priority,
+                QueuedShard {
+                    stream_id: self.stream_id,
+                    prefix,
+                    data: Arc::clone(&parity_data),
+                    range: shard_start + SHARD_PREFIX_SIZE..shard_start + parity_shard_size,
+                },
+            )?;
+
+            self.parity_bytes_sent += parity_shard_size;
+        }
+
+        if let (Some(pacer), Some(_)) = (&mut self.pacer, shard_interval) {
+            pacer.report_delay(pacing_start.elapsed());
+        }
+
+        self.inner.flush(self.priority)?;
 
-        self.used_buffers.push(buffer.inner);
+        self.next_packet_index += 1;
 
         Ok(())
     }
-}
 
-impl<H: Serialize> StreamSender<H> {
-    pub fn get_buffer(&mut self, header: &H) -> Result<Buffer<H>> {
-        let mut buffer = self.used_buffers.pop().unwrap_or_default();
+    // Returns the number of parity shards written into parity_buffer
+    fn prepare_parity_shards(
+        &mut self,
+        data: &[u8],
+        flags: u8,
+        shards_count: usize,
+        group_size: usize,
+        max_shard_data_size: usize,
+    ) -> usize {
+        let parity_shards_count = parity_shards_count(shards_count, group_size);
+        let parity_shard_size = SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + max_shard_data_size;
+
+        // The parity shards of the previous packet may still be referenced by the send queue, if
+        // sending failed
+        if Arc::get_mut(&mut self.parity_buffer).is_none() {
+            self.parity_buffer = Arc::new(vec![]);
+        }
+        let parity_buffer = Arc::get_mut(&mut self.parity_buffer).unwrap();
 
-        let header_size = bincode::serialized_size(header)? as usize;
-        let hidden_offset = SHARD_PREFIX_SIZE + header_size;
+        parity_buffer.clear();
+        parity_buffer.resize(parity_shards_count * parity_shard_size, 0);
 
-        if buffer.len() < hidden_offset {
-            buffer.resize(hidden_offset, 0);
+        for (idx, chunk) in data.chunks(max_shard_data_size).enumerate() {
+            let parity_shard = &mut parity_buffer
+                [(idx % parity_shards_count) * parity_shard_size..][..parity_shard_size];
+
+            for (parity_byte, data_byte) in parity_shard[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..]
+                .iter_mut()
+                .zip(chunk)
+            {
+                *parity_byte ^= data_byte;
+            }
         }
 
-        bincode::serialize_into(&mut buffer[SHARD_PREFIX_SIZE..hidden_offset], header)?;
+        for idx in 0..parity_shards_count {
+            let parity_shard = &mut parity_buffer[idx * parity_shard_size..][..parity_shard_size];
+
+            self.wire_format.write_prefix(
+                parity_shard,
+                &ShardPrefix {
+                    shard_length: parity_shard_size,
+                    flags: flags | SHARD_FLAG_PARITY,
+                    stream_id: self.stream_id,
+                    packet_index: self.next_packet_index,
+                    shards_count,
+                    shard_index: shards_count + idx,
+                },
+            );
+            parity_shard[SHARD_PREFIX_SIZE..][..PARITY_HEADER_SIZE]
+                .copy_from_slice(&(data.len() as u32).to_le_bytes());
+        }
 
-        Ok(Buffer {
-            inner: buffer,
-            hidden_offset,
-            length: 0,
-            _phantom: PhantomData,
-        })
+        parity_shards_count
+    }
+
+    /// Consume a packet index without sending anything. The receiver sees a gap in the packet
+    /// indices and reports a packet loss, as if the packet was lost on the network
+    pub fn skip_packet(&mut self) {
+        self.next_packet_index += 1;
+    }
+
+    /// Returns the number of bytes used by parity shards since the last call
+    pub fn take_parity_bytes_sent(&mut self) -> usize {
+        mem::take(&mut self.parity_bytes_sent)
+    }
+}
+
+impl<H: Serialize + Send + Sync + 'static> StreamSender<H> {
+    pub fn get_buffer(&mut self, header: &H) -> Result<Buffer<H>> {
+        self.buffer_pool.get_buffer(header)
+    }
+
+    /// The pool shares the buffers of this sender
+    pub fn buffer_pool(&self) -> BufferPool<H> {
+        self.buffer_pool.clone()
     }
 
     pub fn send_header(&mut self, header: &H) -> Result<()> {
@@ -168,6 +563,8 @@ pub struct ReceiverData<H> {
     size: usize, // counting the prefix
     used_buffer_queue: mpsc::Sender<Vec<u8>>,
     had_packet_loss: bool,
+    recovered_shards_count: usize,
+    arrival_instant: Instant,
     _phantom: PhantomData<H>,
 }
 
@@ -175,6 +572,17 @@ impl<H> ReceiverData<H> {
     pub fn had_packet_loss(&self) -> bool {
         self.had_packet_loss
     }
+
+    /// Number of shards of this packet that were lost and rebuilt using parity shards
+    pub fn recovered_shards_count(&self) -> usize {
+        self.recovered_shards_count
+    }
+
+    /// Time at which the socket received the last shard of this packet. Unlike the time at which
+    /// the packet is taken from the receiver, it does not depend on the consumer thread
+    pub fn arrival_instant(&self) -> Instant {
+        self.arrival_instant
+    }
 }
 
 impl<H: DeserializeOwned> ReceiverData<H> {
@@ -202,6 +610,8 @@ struct ReconstructedPacket {
     index: u32,
     buffer: Vec<u8>,
     size: usize, // contains prefix
+    recovered_shards_count: usize,
+    arrival_instant: Instant,
 }
 
 pub struct StreamReceiver<H> {
@@ -256,6 +666,8 @@ impl<H: DeserializeOwned + Serialize> StreamReceiver<H> {
             size: packet.size,
             used_buffer_queue: self.used_buffer_queue.clone(),
             had_packet_loss,
+            recovered_shards_count: packet.recovered_shards_count,
+            arrival_instant: packet.arrival_instant,
             _phantom: PhantomData,
         })
     }
@@ -264,6 +676,7 @@ impl<H: DeserializeOwned + Serialize> StreamReceiver<H> {
 pub enum StreamSocketBuilder {
     Tcp(TcpListener),
     Udp(UdpSocket),
+    Quic(quic::QuicListener),
 }
 
 impl StreamSocketBuilder {
@@ -284,16 +697,40 @@ impl StreamSocketBuilder {
                 send_buffer_bytes,
                 recv_buffer_bytes,
             )?),
+            SocketProtocol::Quic {
+                unreliable_stream_ids,
+            } => StreamSocketBuilder::Quic(quic::bind(
+                port,
+                send_buffer_bytes,
+                recv_buffer_bytes,
+                &unreliable_stream_ids,
+            )?),
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn accept_from_server(
         self,
         server_ip: IpAddr,
         port: u16,
+        wire_format: WireFormat,
         max_packet_size: usize,
         timeout: Duration,
+        forward_error_correction: &[ForwardErrorCorrectionConfig],
+        shard_retransmission: &[ShardRetransmissionConfig],
+        frame_interval: Duration,
+        stream_priorities: &[StreamPriorityConfig],
+        encryption_keys: Option<StreamKeys>,
+        network_impairment: Option<NetworkImpairmentConfig>,
+        capture_path: Option<&Path>,
     ) -> ConResult<StreamSocket> {
+        // Retransmission is not needed with TCP
+        let retransmission_deadlines = if matches!(self, StreamSocketBuilder::Udp(_)) {
+            retransmission_deadlines(shard_retransmission, frame_interval)
+        } else {
+            HashMap::new()
+        };
+
         let (send_socket, receive_socket): (Box<dyn SocketWriter>, Box<dyn SocketReader>) =
             match self {
                 StreamSocketBuilder::Udp(socket) => {
@@ -308,17 +745,26 @@ impl StreamSocketBuilder {
 
                     (Box::new(send_socket), Box::new(receive_socket))
                 }
+                StreamSocketBuilder::Quic(listener) => {
+                    let (send_socket, receive_socket) =
+                        quic::accept_from_server(&listener, Some(server_ip), timeout, wire_format)?;
+
+                    (Box::new(send_socket), Box::new(receive_socket))
+                }
             };
 
-        Ok(StreamSocket {
-            // +4 is a workaround to retain compatibilty with old protocol
-            // todo: remove +4
-            max_packet_size: max_packet_size + 4,
-            send_socket: Arc::new(Mutex::new(send_socket)),
+        Ok(StreamSocket::new(
+            wire_format,
+            max_packet_size,
+            send_socket,
             receive_socket,
-            shard_recv_state: None,
-            stream_recv_components: HashMap::new(),
-        })
+            forward_error_correction,
+            retransmission_deadlines,
+            stream_priorities,
+            encryption_keys,
+            network_impairment,
+            capture_path,
+        ))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -329,8 +775,23 @@ impl StreamSocketBuilder {
         protocol: SocketProtocol,
         send_buffer_bytes: SocketBufferSize,
         recv_buffer_bytes: SocketBufferSize,
+        wire_format: WireFormat,
         max_packet_size: usize,
+        forward_error_correction: &[ForwardErrorCorrectionConfig],
+        shard_retransmission: &[ShardRetransmissionConfig],
+        frame_interval: Duration,
+        stream_priorities: &[StreamPriorityConfig],
+        encryption_keys: Option<StreamKeys>,
+        network_impairment: Option<NetworkImpairmentConfig>,
+        capture_path: Option<&Path>,
     ) -> ConResult<StreamSocket> {
+        // Retransmission is not needed with TCP
+        let retransmission_deadlines = if matches!(protocol, SocketProtocol::Udp) {
+            retransmission_deadlines(shard_retransmission, frame_interval)
+        } else {
+            HashMap::new()
+        };
+
         let (send_socket, receive_socket): (Box<dyn SocketWriter>, Box<dyn SocketReader>) =
             match protocol {
                 SocketProtocol::Udp => {
@@ -349,22 +810,69 @@ impl StreamSocketBuilder {
                         recv_buffer_bytes,
                     )?;
 
+                    (Box::new(send_socket), Box::new(receive_socket))
+                }
+                SocketProtocol::Quic {
+                    unreliable_stream_ids,
+                } => {
+                    let (send_socket, receive_socket) = quic::connect_to_client(
+                        timeout,
+                        client_ip,
+                        port,
+                        send_buffer_bytes,
+                        recv_buffer_bytes,
+                        &unreliable_stream_ids,
+                        wire_format,
+                    )?;
+
                     (Box::new(send_socket), Box::new(receive_socket))
                 }
             };
 
-        Ok(StreamSocket {
-            // +4 is a workaround to retain compatibilty with old protocol
-            // todo: remove +4
-            max_packet_size: max_packet_size + 4,
-            send_socket: Arc::new(Mutex::new(send_socket)),
+        Ok(StreamSocket::new(
+            wire_format,
+            max_packet_size,
+            send_socket,
             receive_socket,
-            shard_recv_state: None,
-            stream_recv_components: HashMap::new(),
-        })
+            forward_error_correction,
+            retransmission_deadlines,
+            stream_priorities,
+            encryption_keys,
+            network_impairment,
+            capture_path,
+        ))
     }
 }
 
+fn fec_group_sizes(configs: &[ForwardErrorCorrectionConfig]) -> HashMap<u16, usize> {
+    configs
+        .iter()
+        .filter(|config| config.data_shards_per_parity_shard > 0)
+        .map(|config| {
+            (
+                config.stream_id,
+                config.data_shards_per_parity_shard as usize,
+            )
+        })
+        .collect()
+}
+
+fn retransmission_deadlines(
+    configs: &[ShardRetransmissionConfig],
+    frame_interval: Duration,
+) -> HashMap<u16, Duration> {
+    configs
+        .iter()
+        .filter(|config| config.deadline_frame_intervals > 0.0)
+        .map(|config| {
+            (
+                config.stream_id,
+                frame_interval.mul_f32(config.deadline_frame_intervals),
+            )
+        })
+        .collect()
+}
+
 struct RecvState {
     shard_length: usize, // contains prefix length itself
     stream_id: u16,
@@ -374,12 +882,118 @@ struct RecvState {
     packet_cursor: usize, // counts also the prefix bytes
     overwritten_data_backup: Option<[u8; SHARD_PREFIX_SIZE]>,
     should_discard: bool,
+    // Shards with an extension area have been already received whole, without the extension
+    stripped_shard: Option<Vec<u8>>,
 }
 
 struct InProgressPacket {
     buffer: Vec<u8>,
     buffer_length: usize,
+    shards_count: usize,
     received_shard_indices: HashSet<usize>,
+    first_shard_instant: Instant,
+    nack_cursor: usize, // data shards before this index are received or have been requested
+    // Each parity shard (prefix included) is stored in its own slot
+    parity_buffer: Vec<u8>,
+    received_parity_indices: HashSet<usize>,
+    recovered_shards_count: usize,
+}
+
+impl InProgressPacket {
+    fn new(buffer: Vec<u8>, shards_count: usize) -> Self {
+        Self {
+            buffer,
+            buffer_length: 0,
+            shards_count,
+            // todo: find a way to skipping this allocation
+            received_shard_indices: HashSet::with_capacity(shards_count),
+            first_shard_instant: Instant::now(),
+            nack_cursor: 0,
+            parity_buffer: vec![],
+            received_parity_indices: HashSet::new(),
+            recovered_shards_count: 0,
+        }
+    }
+
+    // Rebuild the only missing data shard of a group, if the parity shard is available.
+    fn try_recover_shard(
+        &mut self,
+        group_index: usize,
+        groups_count: usize,
+        shards_count: usize,
+        max_shard_data_size: usize,
+    ) {
+        if group_index >= groups_count || !self.received_parity_indices.contains(&group_index) {
+            return;
+        }
+
+        let group_indices = || (group_index..shards_count).step_by(groups_count);
+
+        let mut missing_indices =
+            group_indices().filter(|idx| !self.received_shard_indices.contains(idx));
+        let (Some(missing_index), None) = (missing_indices.next(), missing_indices.next()) else {
+            // Either nothing to recover or too many shards lost
+            return;
+        };
+
+        let parity_shard_size = SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + max_shard_data_size;
+        let parity_shard =
+            &mut self.parity_buffer[group_index * parity_shard_size..][..parity_shard_size];
+
+        let data_size = u32::from_le_bytes(
+            parity_shard[SHARD_PREFIX_SIZE..][..PARITY_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        if data_size <= missing_index * max_shard_data_size {
+            // Malformed parity shard
+            return;
+        }
+        let shard_data_size = |idx: usize| {
+            usize::min(
+                max_shard_data_size,
+                data_size.saturating_sub(idx * max_shard_data_size),
+            )
+        };
+
+        let missing_size = shard_data_size(missing_index);
+        let missing_start = SHARD_PREFIX_SIZE + missing_index * max_shard_data_size;
+
+        self.buffer_length = usize::max(self.buffer_length, missing_start + missing_size);
+        if self.buffer.len() < self.buffer_length {
+            self.buffer.resize(self.buffer_length, 0);
+        }
+
+        // XOR all other shards of the group into the parity payload. What remains is the payload of
+        // the missing shard. Note: the parity shard is not needed anymore after this.
+        let recovered_data =
+            &mut parity_shard[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..][..missing_size];
+        for idx in group_indices().filter(|idx| *idx != missing_index) {
+            let shard_start = SHARD_PREFIX_SIZE + idx * max_shard_data_size;
+            let shard_end = usize::min(shard_start + shard_data_size(idx), self.buffer.len());
+            let shard_data = &self.buffer[shard_start..shard_end];
+
+            for (recovered_byte, data_byte) in recovered_data.iter_mut().zip(shard_data) {
+                *recovered_byte ^= data_byte;
+            }
+        }
+
+        self.buffer[missing_start..][..missing_size].copy_from_slice(recovered_data);
+
+        self.received_shard_indices.insert(missing_index);
+        self.received_parity_indices.remove(&group_index);
+        self.recovered_shards_count += 1;
+    }
+
+    // Returns the data shards before end_index which have not been received nor requested yet
+    fn take_missing_shards(&mut self, end_index: usize) -> Vec<usize> {
+        let missing_indices = (self.nack_cursor..end_index)
+            .filter(|idx| !self.received_shard_indices.contains(idx))
+            .collect();
+        self.nack_cursor = usize::max(self.nack_cursor, end_index);
+
+        missing_indices
+    }
 }
 
 struct StreamRecvComponents {
@@ -388,26 +1002,177 @@ struct StreamRecvComponents {
     packet_queue: mpsc::Sender<ReconstructedPacket>,
     in_progress_packets: HashMap<u32, InProgressPacket>,
     discarded_shards_sink: InProgressPacket,
+    fec_group_size: Option<usize>,
+    retransmission_deadline: Option<Duration>,
+    last_completed_packet_index: Option<u32>,
 }
 
 // Note: used buffers don't *have* to be split by stream ID, but doing so improves memory usage
 // todo: impose cap on number of created buffers to avoid OOM crashes
 pub struct StreamSocket {
+    wire_format: WireFormat,
     max_packet_size: usize,
-    send_socket: Arc<Mutex<Box<dyn SocketWriter>>>,
+    send_scheduler: Arc<SendScheduler>,
     receive_socket: Box<dyn SocketReader>,
+    fec_group_sizes: HashMap<u16, usize>,
+    retransmission_deadlines: HashMap<u16, Duration>,
+    retransmit_caches: HashMap<u16, Arc<Mutex<RetransmitCache>>>,
     shard_recv_state: Option<RecvState>,
+    whole_shard_prefix: Option<ShardPrefix>,
+    whole_shard: Vec<u8>,
+    whole_shard_cursor: usize,
+    discarded_bytes: usize, // remaining bytes of a discarded shard
     stream_recv_components: HashMap<u16, StreamRecvComponents>,
 }
 
 impl StreamSocket {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        wire_format: WireFormat,
+        max_packet_size: usize,
+        send_socket: Box<dyn SocketWriter>,
+        receive_socket: Box<dyn SocketReader>,
+        forward_error_correction: &[ForwardErrorCorrectionConfig],
+        retransmission_deadlines: HashMap<u16, Duration>,
+        stream_priorities: &[StreamPriorityConfig],
+        encryption_keys: Option<StreamKeys>,
+        network_impairment: Option<NetworkImpairmentConfig>,
+        capture_path: Option<&Path>,
+    ) -> Self {
+        // The impairment must be applied to the shards as they are sent on the network
+        let send_socket: Box<dyn SocketWriter> = if let Some(config) = network_impairment {
+            Box::new(ImpairedSocketWriter::new(send_socket, config))
+        } else {
+            send_socket
+        };
+
+        let (send_socket, receive_socket, max_packet_size): (
+            Box<dyn SocketWriter>,
+            Box<dyn SocketReader>,
+            _,
+        ) = if let Some(keys) = &encryption_keys {
+            (
+                Box::new(EncryptedSocketWriter::new(send_socket, keys, wire_format)),
+                Box::new(EncryptedSocketReader::new(
+                    receive_socket,
+                    keys,
+                    wire_format,
+                )),
+                // Leave space for the nonce counter and the authentication tag
+                max_packet_size - encryption::ENCRYPTION_OVERHEAD,
+            )
+        } else {
+            (send_socket, receive_socket, max_packet_size)
+        };
+
+        let fec_group_sizes = fec_group_sizes(forward_error_correction);
+
+        // Shards are captured unencrypted
+        let maybe_capture = capture_path.and_then(|path| {
+            match ShardCaptureFile::create(path, wire_format, max_packet_size, &fec_group_sizes) {
+                Ok(capture) => Some(Arc::new(Mutex::new(capture))),
+                Err(e) => {
+                    warn!("Failed to create shard capture file: {e}");
+                    None
+                }
+            }
+        });
+        let (send_socket, receive_socket): (Box<dyn SocketWriter>, Box<dyn SocketReader>) =
+            if let Some(capture) = maybe_capture {
+                (
+                    Box::new(CaptureSocketWriter::new(send_socket, Arc::clone(&capture))),
+                    Box::new(CaptureSocketReader::new(
+                        receive_socket,
+                        capture,
+                        wire_format,
+                    )),
+                )
+            } else {
+                (send_socket, receive_socket)
+            };
+
+        let retransmit_caches = retransmission_deadlines
+            .iter()
+            .map(|(stream_id, deadline)| {
+                (
+                    *stream_id,
+                    Arc::new(Mutex::new(RetransmitCache::new(*deadline))),
+                )
+            })
+            .collect();
+
+        Self {
+            wire_format,
+            // +4 is a workaround to retain compatibilty with old protocol
+            // todo: remove +4
+            max_packet_size: max_packet_size + 4,
+            send_scheduler: Arc::new(SendScheduler::new(
+                send_socket,
+                stream_priorities
+                    .iter()
+                    .map(|config| (config.stream_id, config.priority))
+                    .collect(),
+            )),
+            receive_socket,
+            fec_group_sizes,
+            retransmission_deadlines,
+            retransmit_caches,
+            shard_recv_state: None,
+            whole_shard_prefix: None,
+            whole_shard: vec![],
+            whole_shard_cursor: 0,
+            discarded_bytes: 0,
+            stream_recv_components: HashMap::new(),
+        }
+    }
+
+    /// Create a socket that receives the captured shards of one direction, to reconstruct the
+    /// packets offline. recv() fails when the end of the capture is reached.
+    pub fn replay_capture(capture: ShardCaptureReader, direction: CaptureDirection) -> Self {
+        let wire_format = capture.wire_format();
+        let max_packet_size = capture.max_packet_size();
+        let forward_error_correction = capture
+            .fec_group_sizes()
+            .iter()
+            .map(|(stream_id, group_size)| ForwardErrorCorrectionConfig {
+                stream_id: *stream_id,
+                data_shards_per_parity_shard: *group_size as u32,
+            })
+            .collect::<Vec<_>>();
+
+        Self::new(
+            wire_format,
+            max_packet_size,
+            Box::new(NullSocketWriter),
+            Box::new(ReplaySocketReader::new(capture, direction)),
+            &forward_error_correction,
+            HashMap::new(),
+            &[],
+            None,
+            None,
+            None,
+        )
+    }
+
+    // Can be used to inspect the send queues
+    pub fn send_scheduler(&self) -> Arc<SendScheduler> {
+        Arc::clone(&self.send_scheduler)
+    }
+
     pub fn request_stream<T>(&self, stream_id: u16) -> StreamSender<T> {
         StreamSender {
-            inner: Arc::clone(&self.send_socket),
+            inner: Arc::clone(&self.send_scheduler),
             stream_id,
+            priority: self.send_scheduler.priority(stream_id),
+            wire_format: self.wire_format,
             max_packet_size: self.max_packet_size,
+            fec_group_size: self.fec_group_sizes.get(&stream_id).cloned(),
             next_packet_index: 0,
-            used_buffers: vec![],
+            buffer_pool: BufferPool::new(),
+            parity_buffer: Arc::new(vec![]),
+            parity_bytes_sent: 0,
+            retransmit_cache: self.retransmit_caches.get(&stream_id).cloned(),
+            pacer: None,
             _phantom: PhantomData,
         }
     }
@@ -434,11 +1199,10 @@ impl StreamSocket {
                 used_buffer_receiver,
                 packet_queue: packet_sender,
                 in_progress_packets: HashMap::new(),
-                discarded_shards_sink: InProgressPacket {
-                    buffer: vec![],
-                    buffer_length: 0,
-                    received_shard_indices: HashSet::new(),
-                },
+                discarded_shards_sink: InProgressPacket::new(vec![], 0),
+                fec_group_size: self.fec_group_sizes.get(&stream_id).cloned(),
+                retransmission_deadline: self.retransmission_deadlines.get(&stream_id).cloned(),
+                last_completed_packet_index: None,
             },
         );
 
@@ -451,33 +1215,74 @@ impl StreamSocket {
     }
 
     pub fn recv(&mut self) -> ConResult {
+        self.recv_discarded_bytes()?;
+
         let shard_recv_state_mut = if let Some(state) = &mut self.shard_recv_state {
             state
         } else {
-            let mut bytes = [0; SHARD_PREFIX_SIZE];
-            let count = self.receive_socket.peek(&mut bytes)?;
-            if count < SHARD_PREFIX_SIZE {
-                return alvr_common::try_again();
+            let prefix = if let Some(prefix) = self.whole_shard_prefix {
+                // Resume receiving a shard that must be received whole
+                prefix
+            } else {
+                let mut bytes = [0; SHARD_PREFIX_SIZE];
+                let count = self.receive_socket.peek(&mut bytes)?;
+                if count < SHARD_PREFIX_SIZE {
+                    return alvr_common::try_again();
+                }
+
+                let Some(prefix) = self
+                    .wire_format
+                    .read_prefix(&bytes)
+                    .filter(|prefix| prefix.shard_length >= SHARD_PREFIX_SIZE)
+                else {
+                    debug!("Discarding shard with unsupported prefix");
+                    return self.discard_shard(self.wire_format.shard_length(&bytes));
+                };
+
+                prefix
+            };
+
+            let is_nack = prefix.stream_id == NACK_STREAM_ID;
+            if is_nack
+                && (self.retransmit_caches.is_empty() || prefix.shard_length > self.max_packet_size)
+            {
+                debug!("Discarding unexpected NACK");
+                return self.discard_shard(Some(prefix.shard_length));
+            }
+
+            let mut stripped_shard = None;
+            if prefix.flags & SHARD_FLAG_EXTENSION != 0 || is_nack {
+                let shard = self.recv_whole_shard(prefix)?;
+                let Some(shard) = self.wire_format.strip_extension(shard) else {
+                    debug!("Discarding shard with malformed extension area");
+                    return alvr_common::try_again();
+                };
+
+                stripped_shard = Some(shard);
             }
+            let prefix = stripped_shard
+                .as_ref()
+                .and_then(|shard| self.wire_format.read_prefix(shard))
+                .unwrap_or(prefix);
+
+            if is_nack {
+                if let Some(nack) = stripped_shard.filter(|nack| nack.len() > SHARD_PREFIX_SIZE) {
+                    self.resend_shards(prefix.packet_index, &nack[SHARD_PREFIX_SIZE..]);
+                }
 
-            // todo: switch to little endian
-            // todo: do not remove sizeof<u32> for packet length
-            let shard_length = mem::size_of::<u32>()
-                + u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
-            let stream_id = u16::from_be_bytes(bytes[4..6].try_into().unwrap());
-            let packet_index = u32::from_be_bytes(bytes[6..10].try_into().unwrap());
-            let shards_count = u32::from_be_bytes(bytes[10..14].try_into().unwrap()) as usize;
-            let shard_index = u32::from_be_bytes(bytes[14..18].try_into().unwrap()) as usize;
+                return Ok(());
+            }
 
             self.shard_recv_state.insert(RecvState {
-                shard_length,
-                stream_id,
-                packet_index,
-                shards_count,
-                shard_index,
+                shard_length: prefix.shard_length,
+                stream_id: prefix.stream_id,
+                packet_index: prefix.packet_index,
+                shards_count: prefix.shards_count,
+                shard_index: prefix.shard_index,
                 packet_cursor: 0,
                 overwritten_data_backup: None,
                 should_discard: false,
+                stripped_shard,
             })
         };
 
@@ -492,6 +1297,43 @@ impl StreamSocket {
             return alvr_common::try_again();
         };
 
+        let fec_group_size = components.fec_group_size;
+        let max_shard_data_size = max_shard_data_size(self.max_packet_size, fec_group_size);
+
+        // Parity shards usually arrive after the packet has been already reconstructed. They must not
+        // start a new packet.
+        let is_late_shard = components
+            .last_completed_packet_index
+            .map(|idx| wrapping_cmp(shard_recv_state_mut.packet_index, idx) != Ordering::Greater)
+            .unwrap_or(false);
+
+        let retransmission_deadline = components.retransmission_deadline;
+        if let Some(deadline) = retransmission_deadline {
+            if !shard_recv_state_mut.should_discard
+                && !is_late_shard
+                && !components
+                    .in_progress_packets
+                    .contains_key(&shard_recv_state_mut.packet_index)
+            {
+                // A new packet started, the last shards of the previous packets are probably lost
+                for (idx, packet) in &mut components.in_progress_packets {
+                    if wrapping_cmp(*idx, shard_recv_state_mut.packet_index) == Ordering::Less
+                        && packet.first_shard_instant.elapsed() < deadline
+                    {
+                        let missing_indices = packet.take_missing_shards(packet.shards_count);
+                        send_nack(
+                            &self.send_scheduler,
+                            self.wire_format,
+                            self.max_packet_size,
+                            shard_recv_state_mut.stream_id,
+                            *idx,
+                            &missing_indices,
+                        );
+                    }
+                }
+            }
+        }
+
         let in_progress_packet = if shard_recv_state_mut.should_discard {
             &mut components.discarded_shards_sink
         } else if let Some(packet) = components
@@ -499,31 +1341,30 @@ impl StreamSocket {
             .get_mut(&shard_recv_state_mut.packet_index)
         {
             packet
-        } else if let Some(buffer) = components.used_buffer_receiver.try_recv().ok().or_else(|| {
-            // By default, try to dequeue a used buffer. In case none were found, recycle one of the
-            // in progress packets, chances are these buffers are "dead" because one of their shards
-            // has been dropped by the network.
-            let idx = *components.in_progress_packets.iter().next()?.0;
-            Some(components.in_progress_packets.remove(&idx).unwrap().buffer)
-        }) {
+        } else if let Some(buffer) = (!is_late_shard)
+            .then(|| {
+                // By default, try to dequeue a used buffer. In case none were found, recycle one of
+                // the in progress packets, chances are these buffers are "dead" because one of their
+                // shards has been dropped by the network.
+                components.used_buffer_receiver.try_recv().ok().or_else(|| {
+                    let idx = *components.in_progress_packets.iter().next()?.0;
+                    Some(components.in_progress_packets.remove(&idx).unwrap().buffer)
+                })
+            })
+            .flatten()
+        {
             // NB: Can't use entry pattern because we want to allow bailing out on the line above
             components.in_progress_packets.insert(
                 shard_recv_state_mut.packet_index,
-                InProgressPacket {
-                    buffer,
-                    buffer_length: 0,
-                    // todo: find a way to skipping this allocation
-                    received_shard_indices: HashSet::with_capacity(
-                        shard_recv_state_mut.shards_count,
-                    ),
-                },
+                InProgressPacket::new(buffer, shard_recv_state_mut.shards_count),
             );
             components
                 .in_progress_packets
                 .get_mut(&shard_recv_state_mut.packet_index)
                 .unwrap()
         } else {
-            // This branch may be hit in case the thread related to the stream hangs for some reason
+            // This branch may be hit in case the thread related to the stream hangs for some reason,
+            // or if the shard belongs to a packet that has been already reconstructed
             shard_recv_state_mut.should_discard = true;
             shard_recv_state_mut.packet_cursor = 0; // reset cursor from old shards
                                                     // always write at the start of the packet so the buffer doesn't grow much
@@ -532,16 +1373,29 @@ impl StreamSocket {
             &mut components.discarded_shards_sink
         };
 
-        let max_shard_data_size = self.max_packet_size - SHARD_PREFIX_SIZE;
-        // Note: there is no prefix offset, since we want to write the prefix too.
-        let packet_start_index = shard_recv_state_mut.shard_index * max_shard_data_size;
+        let is_parity_shard = fec_group_size.is_some()
+            && shard_recv_state_mut.shard_index >= shard_recv_state_mut.shards_count;
 
         // Prepare buffer to accomodate receiving shard
-        {
+        let (target_buffer, shard_start_index) = if is_parity_shard {
+            let parity_shard_size = SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + max_shard_data_size;
+            let parity_index = shard_recv_state_mut.shard_index - shard_recv_state_mut.shards_count;
+            let shard_start_index = parity_index * parity_shard_size;
+
+            let required_length = shard_start_index + shard_recv_state_mut.shard_length;
+            if in_progress_packet.parity_buffer.len() < required_length {
+                in_progress_packet.parity_buffer.resize(required_length, 0);
+            }
+
+            (&mut in_progress_packet.parity_buffer, shard_start_index)
+        } else {
+            // Note: there is no prefix offset, since we want to write the prefix too.
+            let shard_start_index = shard_recv_state_mut.shard_index * max_shard_data_size;
+
             // Note: this contains the prefix offset
             in_progress_packet.buffer_length = usize::max(
                 in_progress_packet.buffer_length,
-                packet_start_index + shard_recv_state_mut.shard_length,
+                shard_start_index + shard_recv_state_mut.shard_length,
             );
 
             if in_progress_packet.buffer.len() < in_progress_packet.buffer_length {
@@ -549,9 +1403,11 @@ impl StreamSocket {
                     .buffer
                     .resize(in_progress_packet.buffer_length, 0);
             }
-        }
 
-        let sub_buffer = &mut in_progress_packet.buffer[packet_start_index..];
+            (&mut in_progress_packet.buffer, shard_start_index)
+        };
+
+        let sub_buffer = &mut target_buffer[shard_start_index..];
 
         // Read shard into the single contiguous buffer
         {
@@ -561,6 +1417,11 @@ impl StreamSocket {
                     Some(sub_buffer[..SHARD_PREFIX_SIZE].try_into().unwrap())
             }
 
+            if let Some(shard) = shard_recv_state_mut.stripped_shard.take() {
+                sub_buffer[..shard.len()].copy_from_slice(&shard);
+                shard_recv_state_mut.packet_cursor = shard.len();
+            }
+
             // This loop may bail out at any time if a timeout is reached. This is correctly handled by
             // the previous code.
             while shard_recv_state_mut.packet_cursor < shard_recv_state_mut.shard_length {
@@ -578,14 +1439,60 @@ impl StreamSocket {
         }
 
         if !shard_recv_state_mut.should_discard {
-            in_progress_packet
-                .received_shard_indices
-                .insert(shard_recv_state_mut.shard_index);
+            let group_index = if is_parity_shard {
+                let parity_index =
+                    shard_recv_state_mut.shard_index - shard_recv_state_mut.shards_count;
+                in_progress_packet
+                    .received_parity_indices
+                    .insert(parity_index);
+
+                Some(parity_index)
+            } else {
+                in_progress_packet
+                    .received_shard_indices
+                    .insert(shard_recv_state_mut.shard_index);
+
+                fec_group_size.map(|size| {
+                    shard_recv_state_mut.shard_index
+                        % parity_shards_count(shard_recv_state_mut.shards_count, size)
+                })
+            };
+
+            if let (Some(group_index), Some(group_size)) = (group_index, fec_group_size) {
+                in_progress_packet.try_recover_shard(
+                    group_index,
+                    parity_shards_count(shard_recv_state_mut.shards_count, group_size),
+                    shard_recv_state_mut.shards_count,
+                    max_shard_data_size,
+                );
+            }
+
+            if let Some(deadline) = retransmission_deadline {
+                if in_progress_packet.first_shard_instant.elapsed() < deadline {
+                    // Parity shards are sent after all data shards
+                    let end_index = if is_parity_shard {
+                        shard_recv_state_mut.shards_count
+                    } else {
+                        shard_recv_state_mut.shard_index + 1
+                    };
+
+                    let missing_indices = in_progress_packet.take_missing_shards(end_index);
+                    send_nack(
+                        &self.send_scheduler,
+                        self.wire_format,
+                        self.max_packet_size,
+                        shard_recv_state_mut.stream_id,
+                        shard_recv_state_mut.packet_index,
+                        &missing_indices,
+                    );
+                }
+            }
         }
 
         // Check if packet is complete and send
         if in_progress_packet.received_shard_indices.len() == shard_recv_state_mut.shards_count {
             let size = in_progress_packet.buffer_length;
+            let recovered_shards_count = in_progress_packet.recovered_shards_count;
             components
                 .packet_queue
                 .send(ReconstructedPacket {
@@ -596,9 +1503,13 @@ impl StreamSocket {
                         .unwrap()
                         .buffer,
                     size,
+                    recovered_shards_count,
+                    arrival_instant: Instant::now(),
                 })
                 .ok();
 
+            components.last_completed_packet_index = Some(shard_recv_state_mut.packet_index);
+
             // Keep only shards with later packet index (using wrapping logic)
             while let Some((idx, _)) = components.in_progress_packets.iter().find(|(idx, _)| {
                 wrapping_cmp(**idx, shard_recv_state_mut.packet_index) == Ordering::Less
@@ -616,4 +1527,576 @@ impl StreamSocket {
 
         Ok(())
     }
+
+    // Shards with an extension area and NACKs are received whole, so that the extension can be
+    // stripped and the rest of the shard placed like any other shard. The state is kept if a
+    // timeout is reached
+    fn recv_whole_shard(&mut self, prefix: ShardPrefix) -> ConResult<Vec<u8>> {
+        if self.receive_socket.is_datagram() {
+            let mut shard = mem::take(&mut self.whole_shard);
+            shard.resize(prefix.shard_length, 0);
+            let size = self.receive_socket.recv(&mut shard)?;
+            shard.truncate(size);
+
+            return Ok(shard);
+        }
+
+        if self.whole_shard_prefix.is_none() {
+            self.whole_shard.resize(prefix.shard_length, 0);
+            self.whole_shard_cursor = 0;
+            self.whole_shard_prefix = Some(prefix);
+        }
+
+        while self.whole_shard_cursor < self.whole_shard.len() {
+            self.whole_shard_cursor += self
+                .receive_socket
+                .recv(&mut self.whole_shard[self.whole_shard_cursor..])?;
+        }
+
+        self.whole_shard_prefix = None;
+
+        Ok(mem::take(&mut self.whole_shard))
+    }
+
+    // Skip a shard that cannot be processed. In a byte stream (TCP, QUIC streams) the shard must be
+    // consumed whole, otherwise its remaining bytes would be parsed as the next shard. If the length
+    // of the shard is unknown, the shard boundaries are lost and the connection is closed
+    fn discard_shard(&mut self, shard_length: Option<usize>) -> ConResult {
+        if self.receive_socket.is_datagram() {
+            // The rest of the datagram is dropped
+            self.receive_socket.recv(&mut [0; SHARD_PREFIX_SIZE])?;
+        } else if let Some(length) =
+            shard_length.filter(|length| (SHARD_PREFIX_SIZE..=MAX_SHARD_SIZE).contains(length))
+        {
+            self.discarded_bytes = length;
+            self.recv_discarded_bytes()?;
+        } else {
+            con_bail!("Invalid shard length, cannot find the next shard");
+        }
+
+        alvr_common::try_again()
+    }
+
+    fn recv_discarded_bytes(&mut self) -> ConResult {
+        let mut buffer = [0; 1024];
+        while self.discarded_bytes > 0 {
+            let size = usize::min(self.discarded_bytes, buffer.len());
+            self.discarded_bytes -= self.receive_socket.recv(&mut buffer[..size])?;
+        }
+
+        Ok(())
+    }
+
+    fn resend_shards(&self, packet_index: u32, nack_payload: &[u8]) {
+        if nack_payload.len() < mem::size_of::<u16>() {
+            return;
+        }
+        let stream_id = u16::from_le_bytes(nack_payload[0..2].try_into().unwrap());
+
+        let Some(cache) = self.retransmit_caches.get(&stream_id) else {
+            debug!("Ignoring NACK for stream {stream_id}, retransmission is not enabled");
+            return;
+        };
+        let priority = self.send_scheduler.priority(stream_id);
+
+        // The cache is not locked while sending
+        let Some((flags, shards_count, max_shard_data_size, packet_data)) =
+            cache.lock().get_packet(packet_index).map(|packet| {
+                (
+                    packet.flags,
+                    packet.shards_count,
+                    packet.max_shard_data_size,
+                    Arc::clone(&packet.data),
+                )
+            })
+        else {
+            return;
+        };
+
+        for index_bytes in nack_payload[2..].chunks_exact(mem::size_of::<u32>()) {
+            let shard_index = u32::from_le_bytes(index_bytes.try_into().unwrap()) as usize;
+            if shard_index >= shards_count {
+                continue;
+            }
+
+            let range =
+                shard_data_range(shard_index, max_shard_data_size, packet_data.bytes().len());
+
+            let mut prefix = [0; SHARD_PREFIX_SIZE];
+            self.wire_format.write_prefix(
+                &mut prefix,
+                &ShardPrefix {
+                    shard_length: SHARD_PREFIX_SIZE + range.len(),
+                    flags: flags | SHARD_FLAG_RETRANSMIT,
+                    stream_id,
+                    packet_index,
+                    shards_count,
+                    shard_index,
+                },
+            );
+
+            let res = self.send_scheduler.send_or_enqueue(
+                priority,
+                QueuedShard {
+                    stream_id,
+                    prefix,
+                    data: Arc::clone(&packet_data),
+                    range,
+                },
+            );
+            if let Err(e) = res {
+                debug!("Failed to resend shards: {e}");
+                return;
+            }
+        }
+
+        if let Err(e) = self.send_scheduler.flush(priority) {
+            debug!("Failed to resend shards: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alvr_common::{settings_schema::Switch, ConnectionError};
+    use alvr_session::ImpairmentDirection;
+
+    const PACKET_SIZE: usize = 1400;
+    const STREAM_ID: u16 = 3;
+    const PAYLOAD_SIZE: usize = 3000; // 3 shards
+
+    fn impairment_config() -> NetworkImpairmentConfig {
+        NetworkImpairmentConfig {
+            direction: ImpairmentDirection::Both,
+            packet_loss_percentage: 0.0,
+            latency_ms: 0,
+            jitter_ms: 0,
+            reorder_percentage: 0.0,
+            duplication_percentage: 0.0,
+            bandwidth_limit_mbps: Switch::Disabled,
+            random_seed: Switch::Enabled(42),
+        }
+    }
+
+    type ShardQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+    struct MemorySocketWriter(ShardQueue);
+
+    impl SocketWriter for MemorySocketWriter {
+        fn send(&mut self, shard: &[u8]) -> Result<()> {
+            self.0.lock().push_back(shard.to_vec());
+
+            Ok(())
+        }
+    }
+
+    // Like UDP, each recv() consumes a whole shard. Otherwise, like TCP, the rest of the shard is
+    // returned by the next recv() calls
+    struct MemorySocketReader {
+        queue: ShardQueue,
+        is_datagram: bool,
+        cursor: usize,
+    }
+
+    impl SocketReader for MemorySocketReader {
+        fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+            let size = self.peek(buffer)?;
+
+            let mut queue = self.queue.lock();
+            self.cursor += size;
+            if self.is_datagram || self.cursor == queue[0].len() {
+                queue.pop_front();
+                self.cursor = 0;
+            }
+
+            Ok(size)
+        }
+
+        fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+            let queue = self.queue.lock();
+            let Some(shard) = queue.front() else {
+                return alvr_common::try_again();
+            };
+
+            let size = usize::min(buffer.len(), shard.len() - self.cursor);
+            buffer[..size].copy_from_slice(&shard[self.cursor..][..size]);
+
+            Ok(size)
+        }
+
+        fn is_datagram(&self) -> bool {
+            self.is_datagram
+        }
+    }
+
+    // Drops the shards selected by the filter, before they are sent
+    struct FilteredSocketWriter<F> {
+        inner: MemorySocketWriter,
+        should_drop: F,
+    }
+
+    impl<F: FnMut(&ShardPrefix) -> bool + Send> SocketWriter for FilteredSocketWriter<F> {
+        fn send(&mut self, shard: &[u8]) -> Result<()> {
+            let prefix = WireFormat::V2.read_prefix(shard).unwrap();
+            if !(self.should_drop)(&prefix) {
+                self.inner.send(shard)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct SocketPairConfig {
+        network_impairment: Option<NetworkImpairmentConfig>,
+        forward_error_correction: Vec<ForwardErrorCorrectionConfig>,
+        retransmission_deadlines: HashMap<u16, Duration>,
+        byte_stream: bool,
+    }
+
+    struct SocketPair {
+        sender: StreamSocket,
+        receiver: StreamSocket,
+        sender_to_receiver: ShardQueue,
+        receiver_to_sender: ShardQueue,
+    }
+
+    // The sockets are connected in memory, so the shards are delivered only when the sockets
+    // receive. Only the sender is impaired and filtered. Without delays, the impairment is
+    // deterministic
+    fn socket_pair(
+        config: SocketPairConfig,
+        should_drop: impl FnMut(&ShardPrefix) -> bool + Send + 'static,
+    ) -> SocketPair {
+        let sender_to_receiver = ShardQueue::default();
+        let receiver_to_sender = ShardQueue::default();
+
+        let new_stream_socket =
+            |send_socket: Box<dyn SocketWriter>, receive_queue: &ShardQueue, network_impairment| {
+                StreamSocket::new(
+                    WireFormat::V2,
+                    PACKET_SIZE,
+                    send_socket,
+                    Box::new(MemorySocketReader {
+                        queue: Arc::clone(receive_queue),
+                        is_datagram: !config.byte_stream,
+                        cursor: 0,
+                    }),
+                    &config.forward_error_correction,
+                    config.retransmission_deadlines.clone(),
+                    &[],
+                    None,
+                    network_impairment,
+                    None,
+                )
+            };
+
+        let filtered_writer = Box::new(FilteredSocketWriter {
+            inner: MemorySocketWriter(Arc::clone(&sender_to_receiver)),
+            should_drop,
+        });
+        let receiver_writer = Box::new(MemorySocketWriter(Arc::clone(&receiver_to_sender)));
+
+        SocketPair {
+            sender: new_stream_socket(
+                filtered_writer,
+                &receiver_to_sender,
+                config.network_impairment,
+            ),
+            receiver: new_stream_socket(receiver_writer, &sender_to_receiver, None),
+            sender_to_receiver,
+            receiver_to_sender,
+        }
+    }
+
+    fn impaired_socket_pair(network_impairment: NetworkImpairmentConfig) -> SocketPair {
+        socket_pair(
+            SocketPairConfig {
+                network_impairment: Some(network_impairment),
+                ..Default::default()
+            },
+            |_| false,
+        )
+    }
+
+    fn send_packets(sender: &mut StreamSender<u32>, count: u32, payload_size: usize) {
+        for idx in 0..count {
+            let mut buffer = sender.get_buffer(&idx).unwrap();
+            buffer.get_range_mut(0, payload_size).fill(idx as u8);
+            sender.send(buffer).unwrap();
+        }
+    }
+
+    // Delivers the queued shards, including the NACKs sent back to the sender, until both queues
+    // are empty. Returns the headers of the received packets, whether a packet loss was reported
+    // and the number of shards recovered with FEC
+    fn receive_packets(
+        pair: &mut SocketPair,
+        receiver: &mut StreamReceiver<u32>,
+        payload_size: usize,
+    ) -> (Vec<u32>, bool, usize) {
+        while !pair.sender_to_receiver.lock().is_empty()
+            || !pair.receiver_to_sender.lock().is_empty()
+        {
+            pair.receiver.recv().ok();
+            pair.sender.recv().ok();
+        }
+
+        let mut headers = vec![];
+        let mut had_packet_loss = false;
+        let mut recovered_shards_count = 0;
+        while let Ok(data) = receiver.recv(Duration::ZERO) {
+            let (header, payload) = data.get().unwrap();
+            assert_eq!(payload.len(), payload_size);
+            assert!(payload.iter().all(|byte| *byte == header as u8));
+
+            headers.push(header);
+            had_packet_loss |= data.had_packet_loss();
+            recovered_shards_count += data.recovered_shards_count();
+        }
+
+        (headers, had_packet_loss, recovered_shards_count)
+    }
+
+    #[test]
+    fn duplicated_shards_are_ignored() {
+        let mut pair = impaired_socket_pair(NetworkImpairmentConfig {
+            duplication_percentage: 100.0,
+            ..impairment_config()
+        });
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 10, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, (0..10).collect::<Vec<_>>());
+        assert!(!had_packet_loss);
+    }
+
+    #[test]
+    fn reordered_shards_are_reassembled() {
+        let mut pair = impaired_socket_pair(NetworkImpairmentConfig {
+            reorder_percentage: 50.0,
+            ..impairment_config()
+        });
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        // The last shard could be held back forever, the last packet is not checked
+        send_packets(&mut sender, 11, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers[..10], (0..10).collect::<Vec<_>>());
+        assert!(!had_packet_loss);
+    }
+
+    #[test]
+    fn lost_packets_are_reported() {
+        let mut pair = impaired_socket_pair(NetworkImpairmentConfig {
+            packet_loss_percentage: 30.0,
+            ..impairment_config()
+        });
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 20, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert!(headers.len() < 20);
+        assert!(headers.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            had_packet_loss,
+            headers.windows(2).any(|pair| pair[1] != pair[0] + 1)
+        );
+    }
+
+    #[test]
+    fn skipped_packets_are_reported() {
+        let mut pair = impaired_socket_pair(impairment_config());
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 1, PAYLOAD_SIZE);
+        sender.skip_packet();
+        send_packets(&mut sender, 1, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, [0, 0]);
+        assert!(had_packet_loss);
+    }
+
+    #[test]
+    fn shared_buffers_are_not_modified() {
+        let mut pair = impaired_socket_pair(impairment_config());
+        let mut sender: StreamSender<u32> = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        let mut buffer = sender.buffer_pool().get_buffer(&7).unwrap();
+        buffer.get_range_mut(0, PAYLOAD_SIZE).fill(7);
+        let buffer = Arc::new(buffer);
+
+        sender.send_shared(&buffer).unwrap();
+        sender.send_shared(&buffer).unwrap();
+        assert!(buffer.get().iter().all(|byte| *byte == 7));
+
+        let (headers, ..) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+        assert_eq!(headers, [7, 7]);
+    }
+
+    fn fec_config(data_shards_per_parity_shard: u32) -> SocketPairConfig {
+        SocketPairConfig {
+            forward_error_correction: vec![ForwardErrorCorrectionConfig {
+                stream_id: STREAM_ID,
+                data_shards_per_parity_shard,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lost_shards_are_recovered_with_parity() {
+        // One parity shard for the 3 data shards of each packet
+        let mut pair = socket_pair(fec_config(3), |prefix| prefix.shard_index == 1);
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 10, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, recovered_shards_count) =
+            receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, (0..10).collect::<Vec<_>>());
+        assert!(!had_packet_loss);
+        assert_eq!(recovered_shards_count, 10);
+    }
+
+    #[test]
+    fn too_many_lost_shards_are_not_recovered() {
+        let mut pair = socket_pair(fec_config(3), |prefix| {
+            prefix.packet_index == 5 && prefix.shard_index < 2
+        });
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 10, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, recovered_shards_count) =
+            receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, [0, 1, 2, 3, 4, 6, 7, 8, 9]);
+        assert!(had_packet_loss);
+        assert_eq!(recovered_shards_count, 0);
+    }
+
+    #[test]
+    fn bursts_of_lost_shards_are_recovered() {
+        // 11 data shards in 3 interleaved groups of at most 4 shards. Each group loses one shard
+        let payload_size = PAYLOAD_SIZE * 5;
+        let mut pair = socket_pair(fec_config(4), |prefix| (4..7).contains(&prefix.shard_index));
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 10, payload_size);
+        let (headers, had_packet_loss, recovered_shards_count) =
+            receive_packets(&mut pair, &mut receiver, payload_size);
+
+        assert_eq!(headers, (0..10).collect::<Vec<_>>());
+        assert!(!had_packet_loss);
+        assert_eq!(recovered_shards_count, 30);
+    }
+
+    #[test]
+    fn lost_shards_are_retransmitted() {
+        // Only the first transmission of the shards is lost
+        let mut pair = socket_pair(
+            SocketPairConfig {
+                retransmission_deadlines: [(STREAM_ID, Duration::from_secs(1))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            |prefix| {
+                prefix.stream_id == STREAM_ID
+                    && prefix.shard_index == 1
+                    && prefix.flags & SHARD_FLAG_RETRANSMIT == 0
+            },
+        );
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 10, PAYLOAD_SIZE);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, (0..10).collect::<Vec<_>>());
+        assert!(!had_packet_loss);
+    }
+
+    // Received as the first shard of the stream
+    fn push_shard(pair: &SocketPair, prefix: ShardPrefix) {
+        let mut shard = vec![0; prefix.shard_length];
+        WireFormat::V2.write_prefix(&mut shard, &prefix);
+
+        pair.sender_to_receiver.lock().push_front(shard);
+    }
+
+    #[test]
+    fn invalid_shards_are_skipped_in_datagrams() {
+        let mut pair = socket_pair(SocketPairConfig::default(), |_| false);
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 3, PAYLOAD_SIZE);
+        pair.sender_to_receiver.lock().push_front(vec![u8::MAX; 32]);
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, [0, 1, 2]);
+        assert!(!had_packet_loss);
+    }
+
+    #[test]
+    fn invalid_shards_close_byte_streams() {
+        let mut pair = socket_pair(
+            SocketPairConfig {
+                byte_stream: true,
+                ..Default::default()
+            },
+            |_| false,
+        );
+
+        pair.sender_to_receiver.lock().push_front(vec![u8::MAX; 32]);
+
+        assert!(matches!(
+            pair.receiver.recv(),
+            Err(ConnectionError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn unexpected_nacks_are_skipped_in_byte_streams() {
+        // Retransmission is not enabled
+        let mut pair = socket_pair(
+            SocketPairConfig {
+                byte_stream: true,
+                ..Default::default()
+            },
+            |_| false,
+        );
+        let mut sender = pair.sender.request_stream(STREAM_ID);
+        let mut receiver = pair.receiver.subscribe_to_stream(STREAM_ID, 32);
+
+        send_packets(&mut sender, 3, PAYLOAD_SIZE);
+        push_shard(
+            &pair,
+            ShardPrefix {
+                shard_length: SHARD_PREFIX_SIZE + 100,
+                stream_id: NACK_STREAM_ID,
+                shards_count: 1,
+                ..Default::default()
+            },
+        );
+        let (headers, had_packet_loss, _) = receive_packets(&mut pair, &mut receiver, PAYLOAD_SIZE);
+
+        assert_eq!(headers, [0, 1, 2]);
+        assert!(!had_packet_loss);
+    }
 }
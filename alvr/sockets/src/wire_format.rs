@@ -0,0 +1,244 @@
+// Wire formats of the shard prefix. The format is negotiated during the handshake: v1 is the legacy
+// big endian prefix, v2 is little endian, versioned and carries per-shard flags. Both prefixes have
+// the same size, and the shard length can be read from the first SHARD_LENGTH_FIELD_SIZE bytes of
+// both, so the code that splits shards only needs to know which format is in use.
+//
+// v1 (big endian):
+//   packet length: u32, length of the shard minus 4 bytes
+//   stream ID: u16
+//   packet index: u32
+//   shards count: u32, number of data shards of the packet
+//   shard index: u32, parity shards have index >= shards count
+//
+// v2 (little endian):
+//   version: u8, always 2
+//   flags: u8, see SHARD_FLAG_*
+//   shard length: u16, length of the whole shard, prefix and extension area included
+//   stream ID: u16
+//   packet index: u32
+//   shards count: u32
+//   shard index: u32
+//   extension area, only if SHARD_FLAG_EXTENSION is set:
+//     length: u16, length of the extension data
+//     extension data
+// Unknown extensions are skipped by the receiver. Unknown flags are ignored.
+
+use std::mem;
+
+pub(crate) const SHARD_PREFIX_SIZE: usize = mem::size_of::<u32>() // shard length (v2: version, flags, length)
+    + mem::size_of::<u16>() // stream ID
+    + mem::size_of::<u32>() // packet index
+    + mem::size_of::<u32>() // shards count
+    + mem::size_of::<u32>(); // shards index
+
+pub(crate) const SHARD_LENGTH_FIELD_SIZE: usize = mem::size_of::<u32>();
+
+const EXTENSION_LENGTH_SIZE: usize = mem::size_of::<u16>();
+
+pub const SHARD_FLAG_IDR: u8 = 1 << 0;
+pub const SHARD_FLAG_PARITY: u8 = 1 << 1;
+pub const SHARD_FLAG_RETRANSMIT: u8 = 1 << 2;
+pub const SHARD_FLAG_ENCRYPTED: u8 = 1 << 3;
+pub const SHARD_FLAG_EXTENSION: u8 = 1 << 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WireFormat {
+    #[default]
+    V1,
+    V2,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct ShardPrefix {
+    pub shard_length: usize, // contains prefix length itself
+    pub flags: u8,           // always 0 with v1
+    pub stream_id: u16,
+    pub packet_index: u32,
+    pub shards_count: usize,
+    pub shard_index: usize,
+}
+
+impl WireFormat {
+    pub fn version(self) -> u8 {
+        match self {
+            WireFormat::V1 => 1,
+            WireFormat::V2 => 2,
+        }
+    }
+
+    pub(crate) fn from_version(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(WireFormat::V1),
+            2 => Some(WireFormat::V2),
+            _ => None,
+        }
+    }
+
+    /// Flags are dropped with v1
+    pub(crate) fn write_prefix(self, buffer: &mut [u8], prefix: &ShardPrefix) {
+        match self {
+            WireFormat::V1 => {
+                buffer[0..4].copy_from_slice(
+                    &((prefix.shard_length - SHARD_LENGTH_FIELD_SIZE) as u32).to_be_bytes(),
+                );
+                buffer[4..6].copy_from_slice(&prefix.stream_id.to_be_bytes());
+                buffer[6..10].copy_from_slice(&prefix.packet_index.to_be_bytes());
+                buffer[10..14].copy_from_slice(&(prefix.shards_count as u32).to_be_bytes());
+                buffer[14..18].copy_from_slice(&(prefix.shard_index as u32).to_be_bytes());
+            }
+            WireFormat::V2 => {
+                buffer[0] = self.version();
+                buffer[1] = prefix.flags;
+                buffer[2..4].copy_from_slice(&(prefix.shard_length as u16).to_le_bytes());
+                buffer[4..6].copy_from_slice(&prefix.stream_id.to_le_bytes());
+                buffer[6..10].copy_from_slice(&prefix.packet_index.to_le_bytes());
+                buffer[10..14].copy_from_slice(&(prefix.shards_count as u32).to_le_bytes());
+                buffer[14..18].copy_from_slice(&(prefix.shard_index as u32).to_le_bytes());
+            }
+        }
+    }
+
+    /// Returns None if the prefix is incomplete or has a different version
+    pub(crate) fn read_prefix(self, bytes: &[u8]) -> Option<ShardPrefix> {
+        let bytes = bytes.get(..SHARD_PREFIX_SIZE)?;
+
+        Some(match self {
+            WireFormat::V1 => ShardPrefix {
+                shard_length: self.shard_length(bytes)?,
+                flags: 0,
+                stream_id: u16::from_be_bytes(bytes[4..6].try_into().unwrap()),
+                packet_index: u32::from_be_bytes(bytes[6..10].try_into().unwrap()),
+                shards_count: u32::from_be_bytes(bytes[10..14].try_into().unwrap()) as usize,
+                shard_index: u32::from_be_bytes(bytes[14..18].try_into().unwrap()) as usize,
+            },
+            WireFormat::V2 => ShardPrefix {
+                shard_length: self.shard_length(bytes)?,
+                flags: bytes[1],
+                stream_id: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+                packet_index: u32::from_le_bytes(bytes[6..10].try_into().unwrap()),
+                shards_count: u32::from_le_bytes(bytes[10..14].try_into().unwrap()) as usize,
+                shard_index: u32::from_le_bytes(bytes[14..18].try_into().unwrap()) as usize,
+            },
+        })
+    }
+
+    /// Length of the whole shard, read from its first SHARD_LENGTH_FIELD_SIZE bytes. Returns None
+    /// if the bytes are too few or have a different version
+    pub(crate) fn shard_length(self, bytes: &[u8]) -> Option<usize> {
+        let bytes = bytes.get(..SHARD_LENGTH_FIELD_SIZE)?;
+
+        match self {
+            WireFormat::V1 => Some(
+                SHARD_LENGTH_FIELD_SIZE + u32::from_be_bytes(bytes.try_into().unwrap()) as usize,
+            ),
+            WireFormat::V2 => (bytes[0] == self.version())
+                .then(|| u16::from_le_bytes(bytes[2..4].try_into().unwrap()) as usize),
+        }
+    }
+
+    pub(crate) fn set_shard_length(self, shard: &mut [u8], shard_length: usize) {
+        match self {
+            WireFormat::V1 => shard[0..4]
+                .copy_from_slice(&((shard_length - SHARD_LENGTH_FIELD_SIZE) as u32).to_be_bytes()),
+            WireFormat::V2 => shard[2..4].copy_from_slice(&(shard_length as u16).to_le_bytes()),
+        }
+    }
+
+    /// Flags are not stored with v1
+    pub(crate) fn add_flags(self, shard: &mut [u8], flags: u8) {
+        if self == WireFormat::V2 {
+            shard[1] |= flags;
+        }
+    }
+
+    /// Remove the extension area of a v2 shard in place, if any. Returns None if the shard is
+    /// malformed
+    pub(crate) fn strip_extension(self, mut shard: Vec<u8>) -> Option<Vec<u8>> {
+        let mut prefix = self.read_prefix(&shard)?;
+        if prefix.flags & SHARD_FLAG_EXTENSION == 0 {
+            return Some(shard);
+        }
+
+        let extension_length = u16::from_le_bytes(
+            shard
+                .get(SHARD_PREFIX_SIZE..SHARD_PREFIX_SIZE + EXTENSION_LENGTH_SIZE)?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let extension_end = SHARD_PREFIX_SIZE + EXTENSION_LENGTH_SIZE + extension_length;
+        if extension_end > shard.len() {
+            return None;
+        }
+
+        shard.drain(SHARD_PREFIX_SIZE..extension_end);
+        prefix.shard_length = shard.len();
+        prefix.flags &= !SHARD_FLAG_EXTENSION;
+        self.write_prefix(&mut shard, &prefix);
+
+        Some(shard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_roundtrip() {
+        let prefix = ShardPrefix {
+            shard_length: 1400,
+            flags: SHARD_FLAG_IDR | SHARD_FLAG_RETRANSMIT,
+            stream_id: 3,
+            packet_index: 70000,
+            shards_count: 12,
+            shard_index: 5,
+        };
+
+        for format in [WireFormat::V1, WireFormat::V2] {
+            let mut bytes = [0; SHARD_PREFIX_SIZE];
+            format.write_prefix(&mut bytes, &prefix);
+
+            let read = format.read_prefix(&bytes).unwrap();
+            assert_eq!(read.shard_length, prefix.shard_length);
+            assert_eq!(read.stream_id, prefix.stream_id);
+            assert_eq!(read.packet_index, prefix.packet_index);
+            assert_eq!(read.shards_count, prefix.shards_count);
+            assert_eq!(read.shard_index, prefix.shard_index);
+            assert_eq!(
+                read.flags,
+                if format == WireFormat::V2 {
+                    prefix.flags
+                } else {
+                    0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn extension_is_stripped() {
+        let payload = [1, 2, 3, 4];
+        let extension = [9; 5];
+
+        let mut shard = vec![0; SHARD_PREFIX_SIZE];
+        shard.extend_from_slice(&(extension.len() as u16).to_le_bytes());
+        shard.extend_from_slice(&extension);
+        shard.extend_from_slice(&payload);
+        let prefix = ShardPrefix {
+            shard_length: shard.len(),
+            flags: SHARD_FLAG_EXTENSION | SHARD_FLAG_IDR,
+            stream_id: 1,
+            ..Default::default()
+        };
+        WireFormat::V2.write_prefix(&mut shard, &prefix);
+
+        let stripped = WireFormat::V2.strip_extension(shard).unwrap();
+        let stripped_prefix = WireFormat::V2.read_prefix(&stripped).unwrap();
+        assert_eq!(
+            stripped_prefix.shard_length,
+            SHARD_PREFIX_SIZE + payload.len()
+        );
+        assert_eq!(stripped_prefix.flags, SHARD_FLAG_IDR);
+        assert_eq!(&stripped[SHARD_PREFIX_SIZE..], &payload);
+    }
+}